@@ -12,6 +12,7 @@ use crate::{
     monitoring::incident::Incident,
     mqtt::{
         client::Client,
+        client_certificate::ClientCertificate,
         client_message::{ClientMessage, Connect},
         disconnect_config::DisconnectConfig,
         messages_config::{self, MessagesConfig},
@@ -24,6 +25,7 @@ use crate::{
 };
 use chrono::{DateTime, Utc};
 use std::f64::consts::PI;
+use std::path::Path;
 const LOW_BATERRY_LEVEL: i64 = 20;
 pub const DRONE_SPEED: f64 = 0.001;
 const TOLERANCE_FACTOR: f64 = 0.6;
@@ -33,6 +35,12 @@ const COORDINATE_SCALE_FACTOR: f64 = 100.0;
 const FULL_BATTERY: i64 = 100;
 const ANGLE_SCALING_FACTOR: f64 = 0.6; // este valor hace que en cada tick los drones avancen mas o menos
 const REQUIRED_DRONES_TO_SOLVE_INCIDENT: i8 = 2;
+
+/// Certificado de dispositivo con el que los Drones se autentican ante el Broker, en lugar de un password.
+/// Las claves no estan en el repositorio: se generan con la CA de clientes del Broker(ver informe). Si no
+/// existen, los Drones se conectan sin certificado.
+const DRONE_CERTIFICATE_FILE_PATH: &str = "./src/drones/certs/drone_cert.pem";
+const DRONE_PRIVATE_KEY_FILE_PATH: &str = "./src/drones/certs/drone_key.pem";
 #[derive(Debug, Clone)]
 #[allow(clippy::type_complexity)]
 pub struct Drone {
@@ -138,7 +146,8 @@ impl Drone {
 
     /// Crea el Client a traves del cual el Drone va a comunicarse con la red.
     ///
-    /// Este Client se va a construir a partir de la configuracion brindada en su archivo de configuracion.
+    /// Este Client se va a construir a partir de la configuracion brindada en su archivo de configuracion, y
+    /// presenta el certificado de dispositivo de los Drones(si esta instalado) cuando el Broker pide certificados de clientes.
     ///
     /// Una vez conectado, se envian packets que van a suscribir al Drone a sus topics de interes(ver metodo subscribe_to_topics).
     ///
@@ -151,12 +160,24 @@ impl Drone {
         send_to_drone_channel: Sender<ClientMessage>,
         send_from_drone_channel: Sender<Box<dyn MessagesConfig + Send>>,
     ) -> Result<Client, DroneError> {
-        match Client::new(
-            receive_from_drone_channel,
-            address,
-            connect_config.clone(),
-            send_to_drone_channel,
-        ) {
+        let client = if Path::new(DRONE_PRIVATE_KEY_FILE_PATH).exists() {
+            Client::with_client_certificate(
+                receive_from_drone_channel,
+                address,
+                connect_config.clone(),
+                send_to_drone_channel,
+                ClientCertificate::new(DRONE_CERTIFICATE_FILE_PATH, DRONE_PRIVATE_KEY_FILE_PATH),
+            )
+        } else {
+            Client::new(
+                receive_from_drone_channel,
+                address,
+                connect_config.clone(),
+                send_to_drone_channel,
+            )
+        };
+
+        match client {
             Ok(client) => {
                 println!(
                     "I'm Drone {}, and my Client has been connected successfully!",
@@ -179,37 +200,18 @@ impl Drone {
     ) -> Result<(), DroneError> {
         let subscribe_properties =
             SubscribeProperties::new(1, connect_config.properties.user_properties);
-        let topics = vec!["incident", "attending_incident"];
-
-        for topic_name in topics {
-            Self::subscribe_to_topic(
-                &topic_name.to_string(),
-                &subscribe_properties,
-                &connect_config.client_id,
-                &send_from_drone_channel,
-            )?;
-        }
-
-        Ok(())
-    }
+        let topics = vec!["incident".to_string(), "attending_incident".to_string()];
+        let client_id = connect_config.client_id;
 
-    fn subscribe_to_topic(
-        topic_name: &String,
-        subscribe_properties: &SubscribeProperties,
-        client_id: &String,
-        send_from_channel: &Sender<Box<dyn MessagesConfig + Send>>,
-    ) -> Result<(), DroneError> {
-        let subscribe_config = SubscribeConfig::new(
-            topic_name.clone(),
-            subscribe_properties.clone(),
-            client_id.clone(),
-        );
+        // Todas las subscripciones viajan en un unico Subscribe.
+        let subscribe_config =
+            SubscribeConfig::new(topics.clone(), subscribe_properties, client_id.clone());
 
-        match send_from_channel.send(Box::new(subscribe_config)) {
+        match send_from_drone_channel.send(Box::new(subscribe_config)) {
             Ok(_) => {
                 println!(
-                    "Drone {} suscribed to topic {} successfully",
-                    client_id, topic_name
+                    "Drone {} suscribed to topics {:?} successfully",
+                    client_id, topics
                 );
                 Ok(())
             }
@@ -224,7 +226,7 @@ impl Drone {
     /// a su Client, y ademas se desconecta a su Client.
     pub fn disconnect(&mut self) -> Result<(), ProtocolError> {
         let disconnect_config =
-            DisconnectConfig::new(0x00_u8, 1, "normal".to_string(), self.id.to_string());
+            DisconnectConfig::new(0x00_u8, Some(1), "normal".to_string(), self.id.to_string());
         let send_to_client_channel = match self.send_to_client_channel.lock() {
             Ok(channel) => channel,
             Err(_) => {
@@ -13,22 +13,33 @@ pub mod mqtt {
     pub mod publish {
         pub mod publish_config;
         pub mod publish_properties;
+        pub mod topic_aliases;
     }
 
     pub mod disconnect_config;
     pub mod messages_config;
     pub mod subscribe_config;
 
+    pub mod acl;
+    pub mod authenticator;
+    pub mod broker_config;
     pub mod broker_message;
+    pub mod broker_stream;
     pub mod client;
+    pub mod client_certificate;
     pub mod client_config;
     pub mod client_message;
     pub mod connack_properties;
+    pub mod credentials;
+    pub mod flow_control;
     pub mod protocol_error;
     pub mod reason_code;
+    pub mod scram;
     pub mod subscribe_properties;
     pub mod subscription;
     pub mod topic;
+    pub mod topic_policy;
+    pub mod websocket;
 
     pub mod broker;
 
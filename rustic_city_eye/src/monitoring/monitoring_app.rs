@@ -12,12 +12,14 @@ use crate::monitoring::incident::Incident;
 use crate::mqtt::client::ClientTrait;
 use crate::mqtt::client_message::Connect;
 use crate::mqtt::disconnect_config::DisconnectConfig;
+use crate::mqtt::scram::ScramClient;
 use crate::mqtt::{
     client_message::{self, ClientMessage},
     messages_config::MessagesConfig,
     publish::publish_config::PublishConfig,
     subscribe_config::SubscribeConfig,
     subscribe_properties::SubscribeProperties,
+    subscription::SubscriptionOptions,
     {client::Client, protocol_error::ProtocolError},
 };
 
@@ -135,15 +137,16 @@ impl MonitoringApp {
             connect_config.username = Some(username);
         }
 
-        if let Some(password) = password {
-            connect_config.password = Some(password);
-        }
+        // El password no viaja en el Connect: la aplicacion se autentica con SCRAM-SHA-256.
+        let username = connect_config.username.clone().unwrap_or_default();
+        let authenticator = ScramClient::new(username, password.unwrap_or_default());
 
-        match Client::new(
+        match Client::with_authenticator(
             receive_from_monitoring_channel,
             address,
             connect_config.clone(),
             send_to_monitoring_channel,
+            Box::new(authenticator),
         ) {
             Ok(client) => {
                 println!("I'm the MonitoringApp, and my Client is connected successfully!");
@@ -168,43 +171,26 @@ impl MonitoringApp {
         let subscribe_properties =
             SubscribeProperties::new(1, connect_config.properties.user_properties);
         let topics = vec![
-            "drone_locations",
-            "camera_update",
-            "incident_resolved",
-            "incident",
+            "drone_locations".to_string(),
+            "camera_update".to_string(),
+            "incident_resolved".to_string(),
+            "incident".to_string(),
         ];
 
-        for topic_name in topics {
-            Self::subscribe_to_topic(
-                &topic_name.to_string(),
-                &subscribe_properties,
-                &connect_config.client_id,
-                &send_from_monitoring_channel,
-            )?;
-        }
-
-        Ok(())
-    }
-
-    /// Se encarga de suscribir a la MonitoringApp a un topic determinado.
-    fn subscribe_to_topic(
-        topic_name: &String,
-        subscribe_properties: &SubscribeProperties,
-        client_id: &str,
-        send_from_monitoring_channel: &Sender<Box<dyn MessagesConfig + Send>>,
-    ) -> Result<(), ProtocolError> {
-        let client_id_owned = client_id.to_owned();
+        // Todas las subscripciones viajan en un unico Subscribe.
+        // La MonitoringApp publica los incidentes, por lo que no necesita recibir los suyos.
         let subscribe_config = SubscribeConfig::new(
-            topic_name.clone(),
-            subscribe_properties.clone(),
-            client_id_owned.clone(),
-        );
+            topics.clone(),
+            subscribe_properties,
+            connect_config.client_id,
+        )
+        .with_options("incident", SubscriptionOptions::new(true, false, 0));
 
         match send_from_monitoring_channel.send(Box::new(subscribe_config)) {
             Ok(_) => {
                 println!(
-                    "Monitoring App suscribed to topic {} successfully",
-                    topic_name
+                    "Monitoring App suscribed to topics {:?} successfully",
+                    topics
                 );
                 Ok(())
             }
@@ -462,7 +448,7 @@ impl MonitoringApp {
     pub fn disconnect(&mut self) -> Result<(), ProtocolError> {
         let disconnect_config = DisconnectConfig::new(
             0x00_u8,
-            1,
+            Some(1),
             "normal".to_string(),
             self.monitoring_app_client.get_client_id(),
         );
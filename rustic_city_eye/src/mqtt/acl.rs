@@ -0,0 +1,217 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use super::{protocol_error::ProtocolError, topic::Topic};
+
+/// Sujeto de una regla que aplica a todos los clientes.
+const ANY_CLIENT: &str = "*";
+
+/// En el topic filter de una regla, se reemplaza por el client_id del cliente que realiza la accion.
+const CLIENT_ID_PLACEHOLDER: &str = "%c";
+
+/// Accion sobre un topic que se autoriza mediante la ACL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AclAction {
+    Publish,
+    Subscribe,
+}
+
+/// Regla de la ACL: permite a un cliente(identificado por su client_id o su username) publicar o
+/// subscribirse a los topics que coincidan con un topic filter.
+#[derive(Debug, Clone, PartialEq)]
+struct AclRule {
+    subject: String,
+    action: AclAction,
+    filter: String,
+}
+
+/// Lista de control de acceso que usa el Broker para autorizar los publish y subscribe de cada cliente.
+///
+/// Un cliente solo puede realizar las acciones que le permita alguna de las reglas; el resto se rechazan.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    /// Lee la ACL desde un archivo.
+    ///
+    /// Cada linea contiene una regla con el formato `<client_id o username>, <publish|subscribe>, <topic filter>`.
+    /// El sujeto '*' aplica a todos los clientes, y en el topic filter '%c' se reemplaza por el client_id.
+    /// Las lineas vacias y las que empiezan con '#' se ignoran.
+    pub fn read_acl_file(file_path: &str) -> Result<Acl, ProtocolError> {
+        let file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => return Err(ProtocolError::ReadingAclFileError),
+        };
+
+        let mut rules = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line.trim().to_string(),
+                Err(_) => return Err(ProtocolError::ReadingAclFileError),
+            };
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            rules.push(Acl::parse_rule(&line)?);
+        }
+
+        Ok(Acl { rules })
+    }
+
+    fn parse_rule(line: &str) -> Result<AclRule, ProtocolError> {
+        let parts: Vec<&str> = line.split(',').map(|part| part.trim()).collect();
+        if parts.len() != 3 || parts[0].is_empty() || !Topic::is_valid_filter(parts[2]) {
+            return Err(ProtocolError::ReadingAclFileError);
+        }
+
+        let action = match parts[1].to_lowercase().as_str() {
+            "publish" => AclAction::Publish,
+            "subscribe" => AclAction::Subscribe,
+            _ => return Err(ProtocolError::ReadingAclFileError),
+        };
+
+        Ok(AclRule {
+            subject: parts[0].to_string(),
+            action,
+            filter: parts[2].to_string(),
+        })
+    }
+
+    /// Indica si el cliente puede realizar la accion: publicar en el topic name, o subscribirse al topic filter.
+    ///
+    /// Para subscribirse, el topic filter pedido debe estar contenido en el de alguna regla. En las shared
+    /// subscriptions se autoriza el topic filter sin el prefijo '$share/<grupo>/'.
+    pub fn is_allowed(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        action: AclAction,
+        topic: &str,
+    ) -> bool {
+        let topic = match Topic::parse_shared_filter(topic) {
+            Some((_, filter)) if action == AclAction::Subscribe => filter,
+            _ => topic,
+        };
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.action == action)
+            .filter(|rule| {
+                rule.subject == ANY_CLIENT
+                    || rule.subject == client_id
+                    || Some(rule.subject.as_str()) == username
+            })
+            .any(|rule| {
+                let filter = rule.filter.replace(CLIENT_ID_PLACEHOLDER, client_id);
+                match action {
+                    AclAction::Publish => Topic::filter_matches(&filter, topic),
+                    AclAction::Subscribe => Acl::filter_covers(&filter, topic),
+                }
+            })
+    }
+
+    /// Indica si todos los topics que coinciden con el topic filter pedido tambien coinciden con el permitido.
+    fn filter_covers(allowed: &str, requested: &str) -> bool {
+        let mut allowed_levels = allowed.split('/');
+        let mut requested_levels = requested.split('/');
+
+        loop {
+            match (allowed_levels.next(), requested_levels.next()) {
+                (Some("#"), _) => return true,
+                (Some("+"), Some(requested_level)) => {
+                    if requested_level == "#" {
+                        return false;
+                    }
+                }
+                (Some(allowed_level), Some(requested_level)) => {
+                    if allowed_level != requested_level {
+                        return false;
+                    }
+                }
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(rules: &[&str]) -> Result<Acl, ProtocolError> {
+        Ok(Acl {
+            rules: rules
+                .iter()
+                .map(|rule| Acl::parse_rule(rule))
+                .collect::<Result<Vec<AclRule>, ProtocolError>>()?,
+        })
+    }
+
+    #[test]
+    fn test_01_rules_by_client_id_and_username() -> Result<(), ProtocolError> {
+        let acl = acl(&[
+            "camera_system, publish, camera_update",
+            "drone, publish, drone_locations",
+            "*, subscribe, incident",
+        ])?;
+
+        assert!(acl.is_allowed("camera_system", None, AclAction::Publish, "camera_update"));
+        assert!(acl.is_allowed("7", Some("drone"), AclAction::Publish, "drone_locations"));
+        assert!(!acl.is_allowed(
+            "7",
+            Some("drone"),
+            AclAction::Publish,
+            "single_camera_disconnect"
+        ));
+        assert!(!acl.is_allowed("7", Some("drone"), AclAction::Subscribe, "drone_locations"));
+        assert!(acl.is_allowed("7", Some("drone"), AclAction::Subscribe, "incident"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_02_subscribing_with_wildcards() -> Result<(), ProtocolError> {
+        let acl = acl(&[
+            "monitoring_app, subscribe, drones/+/status",
+            "*, subscribe, responses/%c",
+        ])?;
+        let can_subscribe =
+            |filter: &str| acl.is_allowed("monitoring_app", None, AclAction::Subscribe, filter);
+
+        assert!(can_subscribe("drones/7/status"));
+        assert!(can_subscribe("drones/+/status"));
+        assert!(can_subscribe("$share/ui/drones/+/status"));
+        assert!(!can_subscribe("drones/#"));
+        assert!(!can_subscribe("drones/+/+"));
+
+        assert!(can_subscribe("responses/monitoring_app"));
+        assert!(!can_subscribe("responses/7"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_03_reading_acl_file() -> Result<(), ProtocolError> {
+        let acl = Acl::read_acl_file("./src/monitoring/acl.txt")?;
+
+        assert!(acl.is_allowed("monitoring_app", None, AclAction::Publish, "incident"));
+        assert!(!acl.is_allowed(
+            "3",
+            Some("drone"),
+            AclAction::Publish,
+            "single_camera_disconnect"
+        ));
+
+        assert!(Acl::parse_rule("drone, borrar, incident").is_err());
+        assert!(Acl::parse_rule("drone, publish").is_err());
+        assert!(Acl::read_acl_file("./este/archivo/no/existe").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+/// Resultado de procesar los authentication data recibidos del otro extremo durante una autenticacion extendida.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthStep {
+    /// Hay que responder con un Auth(reason code 0x18) con estos datos, y esperar la respuesta.
+    Continue(Vec<u8>),
+
+    /// La autenticacion termino bien. Del lado del Broker, los datos se envian en el Connack.
+    Success(Vec<u8>),
+
+    /// La autenticacion fallo con el reason code indicado.
+    Failure(u8),
+}
+
+/// Un metodo de autenticacion extendida(MQTT 5), que se lleva a cabo intercambiando packets Auth entre
+/// el Client y el Broker antes del Connack.
+///
+/// Del lado del Client, los datos iniciales viajan en el Connect y cada challenge del Broker se responde con
+/// `step`; del lado del Broker, `step` procesa los datos del Connect y de cada Auth que envia el Client.
+pub trait Authenticator: Send {
+    /// Nombre del metodo, que viaja en la propiedad authentication method.
+    fn method(&self) -> String;
+
+    /// Datos que el Client envia en el Connect para comenzar el intercambio.
+    fn initial_data(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Procesa los datos recibidos del otro extremo y devuelve el proximo paso del intercambio.
+    fn step(&mut self, data: &[u8]) -> AuthStep;
+}
+
+/// Crea, a partir del client_id del Connect, el Authenticator con el que el Broker autentica una conexion.
+pub type AuthenticatorFactory = Arc<dyn Fn(&str) -> Box<dyn Authenticator> + Send + Sync>;
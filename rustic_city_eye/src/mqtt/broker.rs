@@ -1,73 +1,181 @@
 use std::{
-    collections::HashMap,
-    fs::File,
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File},
     io::{stdin, BufRead, BufReader},
-    net::{Shutdown, TcpListener, TcpStream},
+    net::{TcpListener, TcpStream},
     process::exit,
     sync::{
         mpsc::{self, Receiver, Sender},
         Arc, Mutex, RwLock,
     },
     thread,
+    time::{Duration, Instant},
 };
 
 use rustls::{
     pki_types::{CertificateDer, PrivateKeyDer},
-    ServerConfig, ServerConnection, StreamOwned,
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore, ServerConfig, ServerConnection, StreamOwned,
 };
 use rustls_pemfile::{certs, private_key};
 
 use crate::mqtt::{
+    acl::{Acl, AclAction},
+    authenticator::{AuthStep, Authenticator, AuthenticatorFactory},
+    broker_config::BrokerConfig,
     broker_message::BrokerMessage,
+    broker_stream::{BrokerStream, Listener, ListenerProtocol},
+    client_certificate::{common_name, CertificateIdentity, ClientCertificate},
     client_config::ClientConfig,
     client_message::ClientMessage,
     connack_properties::ConnackProperties,
+    credentials::CredentialStore,
+    flow_control::FlowControl,
     protocol_error::ProtocolError,
     protocol_return::ProtocolReturn,
-    reason_code::{SUB_ID_DUP_HEX, SUCCESS_HEX, UNSPECIFIED_ERROR_HEX},
+    reason_code::{
+        BAD_AUTHENTICATION_METHOD_HEX, CONTINUE_AUTHENTICATION_HEX, DISCONNECT_WITH_WILL_HEX,
+        MALFORMED_PACKET_HEX, NOT_AUTHORIZED_HEX, NO_SUBSCRIPTION_EXISTED_HEX,
+        PACKET_ID_NOT_FOUND_HEX, PACKET_TOO_LARGE_HEX, RECEIVE_MAXIMUM_EXCEEDED_HEX,
+        SUB_ID_DUP_HEX, SUCCESS_HEX, TOPIC_ALIAS_INVALID_HEX, TOPIC_FILTER_INVALID_HEX,
+        TOPIC_NAME_INVALID_HEX, UNSPECIFIED_ERROR_HEX,
+    },
+    scram::{ScramServer, SCRAM_SHA_256},
     subscription::Subscription,
     topic::Topic,
+    topic_policy::TopicPolicy,
+    websocket::WebSocketStream,
 };
 
 use crate::utils::payload_types::PayloadTypes;
+use crate::utils::reader::is_packet_too_large;
 use crate::utils::threadpool::ThreadPool;
 
-use super::connect::last_will::LastWill;
+use super::{
+    client_message::Connect,
+    connect::last_will::LastWill,
+    publish::{
+        publish_properties::{PublishProperties, TopicProperties},
+        topic_aliases::TopicAliases,
+    },
+};
 
-static SERVER_ARGS: usize = 2;
+/// Cada cuanto se revisa si vencio el will delay interval de algun last will pendiente.
+const WILL_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
-const THREADPOOL_SIZE: usize = 30;
+/// Tiempo que se espera el Puback de un subscriptor antes de reenviarle un Publish con QoS 1.
+const IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct Broker {
-    address: String,
+    /// Direcciones en las que el Broker escucha conexiones, cada una con su protocolo(TCP, TLS o WebSocket).
+    /// Por defecto se escucha con TLS en el puerto indicado al iniciarlo.
+    listeners: Vec<Listener>,
+
+    /// Cantidad de threads del threadpool con el que se atienden las conexiones.
+    threadpool_size: usize,
+
+    /// Maximo topic alias que aceptan los Publish enviados por los clientes. Se informa en el Connack.
+    topic_alias_maximum: u16,
+
+    /// Maximo de Publish con QoS > 0 sin confirmar que el broker acepta recibir de cada cliente. Se informa en el Connack.
+    receive_maximum: u16,
+
+    /// Tamaño maximo, en bytes, de los packets que acepta el broker. Se informa en el Connack.
+    maximum_packet_size: u32,
+
+    /// Directorio en el que se persisten las sesiones de los clientes.
+    sessions_directory: String,
+
+    /// Certificado y clave privada con los que el Broker se identifica en las conexiones TLS.
+    certificate_file: String,
+    private_key_file: String,
 
     ///Contiene a todos los Topics.
     /// Se identifican con un topic_name unico para cada topic.
-    topics: HashMap<String, Topic>,
+    /// Los topics se crean la primera vez que un cliente publica o se subscribe a ellos.
+    topics: Arc<RwLock<HashMap<String, Topic>>>,
+
+    /// Politica que indica que topics pueden crearse. Por defecto se permiten todos.
+    topic_policy: TopicPolicy,
+
+    /// Reglas que indican a que topics puede publicar o subscribirse cada cliente.
+    /// Si no se indica un archivo de ACL, los clientes autenticados pueden usar cualquier topic.
+    acl: Option<Acl>,
+
+    /// Username con el que se conecto cada cliente, para aplicarle las reglas de la ACL.
+    client_usernames: Arc<RwLock<HashMap<String, String>>>,
+
+    /// Contiene las subscripciones cuyos topic filters usan wildcards('+' o '#').
+    /// Como no se corresponden con un unico Topic, se guardan todas juntas y se
+    /// matchean contra el topic_name de cada publish.
+    wildcard_subscriptions: Topic,
+
+    /// Contiene las shared subscriptions('$share/<group>/<filter>'), con su topic filter completo.
+    /// Cada publish que coincida con el filtro se le entrega a un unico miembro de cada grupo.
+    shared_subscriptions: Topic,
+
+    /// Proximo miembro al que se le entrega un publish, por cada shared subscription(round-robin).
+    shared_subscription_cursors: Arc<RwLock<HashMap<String, usize>>>,
 
     /// El u16 corresponde al packet_id del package, y dentro
     /// de esa clave se guarda el package.
     packets: Arc<RwLock<HashMap<u16, ClientMessage>>>,
 
+    /// Publish con QoS 2 que ya fueron distribuidos, pero cuyo Pubrel todavia no llego, por client_id y packet_id.
+    /// Si un cliente reenvia un Publish con alguno de sus ids pendientes, no se lo vuelve a distribuir.
+    qos2_pending_releases: Arc<RwLock<HashSet<(String, u16)>>>,
+
+    /// Publish con QoS 1 y 2 entregados a cada cliente, cuyo Puback o Pubcomp todavia no llego.
+    /// Se guardan por client_id y packet_id, junto al momento del ultimo envio, para reenviarlos
+    /// con el dup_flag seteado cuando vence el timeout o cuando el cliente se reconecta.
+    /// Cuando llega el Pubrec de un Publish con QoS 2, en su lugar queda el Pubrel, que es lo que se reenvia.
+    #[allow(clippy::type_complexity)]
+    in_flight_messages: Arc<RwLock<HashMap<String, HashMap<u16, (ClientMessage, Instant)>>>>,
+
+    /// Ultimo packet id asignado a un Publish entregado a cada cliente. Los packet ids de las entregas son
+    /// propios de cada subscriptor, independientes de los que uso el publisher.
+    delivery_packet_ids: Arc<RwLock<HashMap<String, u16>>>,
+
+    /// Last wills de clientes que perdieron la conexion, junto al momento en que deben publicarse.
+    /// Si el cliente se reconecta antes, retomando su sesion, el last will se descarta.
+    pending_wills: Arc<RwLock<HashMap<String, (LastWill, Instant)>>>,
+
+    /// Topic aliases usados en los Publish que se envian a cada cliente conectado.
+    /// El maximo de cada tabla es el topic alias maximum que el cliente indico en su Connect.
+    /// El lock de cada tabla se mantiene mientras se escribe el Publish al que se le asigno el alias.
+    #[allow(clippy::type_complexity)]
+    topic_aliases: Arc<RwLock<HashMap<String, Arc<Mutex<TopicAliases>>>>>,
+
+    /// Receive maximum y maximum packet size que cada cliente conectado indico en su Connect.
+    flow_control: Arc<RwLock<HashMap<String, FlowControl>>>,
+
+    /// Publish con QoS > 0 que no se le enviaron a un cliente porque tenia su receive maximum completo.
+    /// Se le envian en orden a medida que confirma sus mensajes in-flight. Junto al mensaje original
+    /// se guarda el Publish armado para su subscripcion.
+    #[allow(clippy::type_complexity)]
+    queued_deliveries:
+        Arc<RwLock<HashMap<String, VecDeque<(ClientMessage, BrokerMessage, Instant)>>>>,
+
+    /// Lock de la sesion de cada cliente. ClientConfig lee, modifica y guarda el archivo de sesion completo,
+    /// asi que cada modificacion se hace con este lock tomado para no perder las que ocurren a la vez.
+    session_locks: Arc<RwLock<HashMap<String, Arc<Mutex<()>>>>>,
+
     /// Contiene los clientes conectados al broker.
     #[allow(clippy::type_complexity)]
-    clients_ids: Arc<
-        RwLock<
-            HashMap<
-                String,
-                (
-                    Option<Arc<StreamOwned<ServerConnection, TcpStream>>>,
-                    Option<LastWill>,
-                ),
-            >,
-        >,
-    >,
+    clients_ids: Arc<RwLock<HashMap<String, (Option<Arc<BrokerStream>>, Option<LastWill>)>>>,
 
-    /// Los datos de autenticacion de los clientes se guardan en un HashMap, en el cual
-    /// las claves son los client_ids, y los valores son
-    /// tuplas que contienen el username y password.
-    clients_auth_info: HashMap<String, (String, Vec<u8>)>,
+    /// Las credenciales de los clientes que se autentican con password, indexadas por client_id.
+    /// Los passwords se guardan hasheados con una salt.
+    clients_auth_info: CredentialStore,
+
+    /// Metodos de autenticacion extendida que soporta el Broker, indexados por su nombre.
+    /// Se llevan a cabo intercambiando packets Auth con el cliente antes de enviarle el Connack.
+    authenticators: HashMap<String, AuthenticatorFactory>,
+
+    /// Si el Broker pide certificados de clientes(TLS mutuo), indica a que campo del Connect se mapea el
+    /// common name del certificado. Es None si los clientes no presentan certificados.
+    client_certificate_identity: Option<CertificateIdentity>,
 
     /// Esta es la configuracion que el Broker usa para crear las conexiones
     /// con TLS. ServerConfig es un struct proveniente del crate rustls.
@@ -75,47 +183,180 @@ pub struct Broker {
 }
 
 impl Broker {
+    /// Crea el Broker a partir de los argumentos con los que se lo inicio. Ver BrokerConfig::from_args.
     pub fn new(args: Vec<String>) -> Result<Broker, ProtocolError> {
-        let address = Broker::process_starting_args(args)?;
+        Broker::from_config(BrokerConfig::from_args(&args)?)
+    }
+
+    pub fn from_config(config: BrokerConfig) -> Result<Broker, ProtocolError> {
+        PayloadTypes::set_write_version(config.payload_write_version);
+        let topic_policy = Broker::read_topic_policy(&config.topic_policy_file)?;
+        let acl = match &config.acl_file {
+            Some(file_path) => Some(Acl::read_acl_file(file_path)?),
+            None => None,
+        };
+        if let Err(e) = fs::create_dir_all(&config.sessions_directory) {
+            return Err(ProtocolError::ServerConfigError(format!(
+                "{}: {}",
+                config.sessions_directory, e
+            )));
+        }
 
-        let topics = Broker::get_broker_starting_topics("./src/monitoring/topics.txt")?;
-        let clients_auth_info = Broker::process_clients_file("./src/monitoring/clients.txt")?;
+        let topics = Arc::new(RwLock::new(HashMap::new()));
+        let wildcard_subscriptions = Topic::new();
+        let shared_subscriptions = Topic::new();
+        let shared_subscription_cursors = Arc::new(RwLock::new(HashMap::new()));
+        let clients_auth_info = Broker::process_clients_file(&config.clients_file)?;
+        let scram_credentials = clients_auth_info.clone();
+        let mut authenticators: HashMap<String, AuthenticatorFactory> = HashMap::new();
+        authenticators.insert(
+            SCRAM_SHA_256.to_string(),
+            Arc::new(move |client_id: &str| -> Box<dyn Authenticator> {
+                Box::new(ScramServer::new(scram_credentials.get(client_id).cloned()))
+            }),
+        );
         let clients_ids = Arc::new(RwLock::new(HashMap::new()));
         let packets = Arc::new(RwLock::new(HashMap::new()));
-
-        let server_config = Broker::set_server_config(
-            "./src/mqtt/certs/cert.pem",
-            "./src/mqtt/certs/private_key.pem",
-        )?;
-
-        Ok(Broker {
-            address,
+        let qos2_pending_releases = Arc::new(RwLock::new(HashSet::new()));
+        let in_flight_messages = Arc::new(RwLock::new(HashMap::new()));
+        let delivery_packet_ids = Arc::new(RwLock::new(HashMap::new()));
+        let pending_wills = Arc::new(RwLock::new(HashMap::new()));
+        let topic_aliases = Arc::new(RwLock::new(HashMap::new()));
+        let flow_control = Arc::new(RwLock::new(HashMap::new()));
+        let client_usernames = Arc::new(RwLock::new(HashMap::new()));
+        let queued_deliveries = Arc::new(RwLock::new(HashMap::new()));
+        let session_locks = Arc::new(RwLock::new(HashMap::new()));
+
+        let server_config =
+            Broker::set_server_config(&config.certificate_file, &config.private_key_file, None)?;
+
+        let mut broker = Broker {
+            listeners: config.listeners,
+            threadpool_size: config.threadpool_size,
+            topic_alias_maximum: config.topic_alias_maximum,
+            receive_maximum: config.receive_maximum,
+            maximum_packet_size: config.maximum_packet_size,
+            sessions_directory: config.sessions_directory,
+            certificate_file: config.certificate_file,
+            private_key_file: config.private_key_file,
             topics,
+            topic_policy,
+            acl,
+            client_usernames,
+            wildcard_subscriptions,
+            shared_subscriptions,
+            shared_subscription_cursors,
             clients_auth_info,
+            authenticators,
             packets,
+            qos2_pending_releases,
+            in_flight_messages,
+            delivery_packet_ids,
+            pending_wills,
+            topic_aliases,
+            flow_control,
+            queued_deliveries,
+            session_locks,
             clients_ids,
+            client_certificate_identity: None,
             server_config: Arc::new(server_config),
-        })
+        };
+
+        if let Some(ca_file_path) = &config.client_ca_file {
+            broker.require_client_certificates(ca_file_path, config.certificate_identity)?;
+        }
+
+        Ok(broker)
     }
 
+    /// Arma la configuracion TLS del Broker. Si se indica un client_verifier, los clientes deben presentar
+    /// un certificado que este acepte; en caso contrario, no se les pide certificado.
     fn set_server_config(
         certs_file_path: &str,
         private_key_file_path: &str,
+        client_verifier: Option<Arc<dyn ClientCertVerifier>>,
     ) -> Result<ServerConfig, ProtocolError> {
         let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
         let certs = Broker::get_certs(certs_file_path)?;
 
         let private_key = Broker::get_private_key(private_key_file_path)?;
 
-        match ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, private_key)
-        {
+        let builder = ServerConfig::builder();
+        let builder = match client_verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
+        match builder.with_single_cert(certs, private_key) {
             Ok(config) => Ok(config),
             Err(e) => Err(ProtocolError::ServerConfigError(e.to_string())),
         }
     }
 
+    /// Pasa a exigir que los clientes presenten, durante el handshake TLS, un certificado firmado por la CA del
+    /// archivo indicado. Los clientes que lo presentan no necesitan enviar un password: el common name del
+    /// certificado se mapea al client_id o al username del Connect, segun identity.
+    pub fn require_client_certificates(
+        &mut self,
+        ca_file_path: &str,
+        identity: CertificateIdentity,
+    ) -> Result<(), ProtocolError> {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let mut roots = RootCertStore::empty();
+        for certificate in ClientCertificate::read_certificates(ca_file_path)? {
+            if let Err(e) = roots.add(certificate) {
+                return Err(ProtocolError::ReadingCertificateError(e.to_string()));
+            }
+        }
+
+        let verifier = match WebPkiClientVerifier::builder(Arc::new(roots)).build() {
+            Ok(verifier) => verifier,
+            Err(e) => return Err(ProtocolError::ServerConfigError(e.to_string())),
+        };
+        let server_config = Broker::set_server_config(
+            &self.certificate_file,
+            &self.private_key_file,
+            Some(verifier),
+        )?;
+
+        self.server_config = Arc::new(server_config);
+        self.client_certificate_identity = Some(identity);
+        Ok(())
+    }
+
+    /// Devuelve el common name del certificado que presento el cliente en el handshake TLS, si el Broker
+    /// pide certificados de clientes.
+    fn client_certificate_name(&self, client_stream_ref: &Arc<BrokerStream>) -> Option<String> {
+        self.client_certificate_identity?;
+
+        let certificates = client_stream_ref.peer_certificates()?;
+        common_name(certificates.first()?.as_ref())
+    }
+
+    /// Mapea el common name del certificado del cliente al Connect: segun la configuracion del Broker, el client_id
+    /// debe coincidir con el, o se lo toma como username. Devuelve el reason code del Connack.
+    ///
+    /// Si el Broker pide certificados de clientes, se rechaza a los clientes que no presentaron uno con common name.
+    fn apply_certificate_identity(
+        &self,
+        connect: &mut Connect,
+        client_stream_ref: &Arc<BrokerStream>,
+    ) -> u8 {
+        let name = match self.client_certificate_name(client_stream_ref) {
+            Some(name) => name,
+            None if self.client_certificate_identity.is_some() => return NOT_AUTHORIZED_HEX,
+            None => return SUCCESS_HEX,
+        };
+
+        match self.client_certificate_identity {
+            Some(CertificateIdentity::ClientId) if connect.client_id != name => NOT_AUTHORIZED_HEX,
+            Some(CertificateIdentity::Username) => {
+                connect.username = Some(name);
+                SUCCESS_HEX
+            }
+            _ => SUCCESS_HEX,
+        }
+    }
+
     fn open_file(file_path: &str) -> Result<BufReader<File>, ProtocolError> {
         let file = match File::open(file_path) {
             Ok(file) => file,
@@ -149,88 +390,105 @@ impl Broker {
         }
     }
 
-    fn process_starting_args(args: Vec<String>) -> Result<String, ProtocolError> {
-        if args.len() != SERVER_ARGS {
-            let app_name = &args[0];
-            println!("Usage:\n{:?} <puerto>", app_name);
-            return Err(ProtocolError::InvalidNumberOfArguments);
+    /// Si se indica un archivo de politica de topics, se lo lee.
+    /// En caso contrario, se permite la creacion de cualquier topic.
+    fn read_topic_policy(file_path: &Option<String>) -> Result<TopicPolicy, ProtocolError> {
+        match file_path {
+            Some(file_path) => TopicPolicy::read_policy_file(file_path),
+            None => Ok(TopicPolicy::AllowAll),
         }
+    }
 
-        let address = "0.0.0.0:".to_owned() + &args[1];
-        Ok(address)
+    /// Reemplaza la politica de topics del Broker.
+    pub fn set_topic_policy(&mut self, topic_policy: TopicPolicy) {
+        self.topic_policy = topic_policy;
     }
 
-    /// Recibe un path a un archivo de configuracion de topics y devuelve un HashMap con los topics.
-    fn get_broker_starting_topics(
-        file_path: &str,
-    ) -> Result<HashMap<String, Topic>, ProtocolError> {
-        let mut topics = HashMap::new();
-        let topic_readings = Broker::process_topic_config_file(file_path)?;
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = Some(acl);
+    }
 
-        for topic in topic_readings {
-            topics.insert(topic, Topic::new());
+    /// Guarda el username del cliente que se conecta, y verifica que la ACL le permita publicar su last will.
+    ///
+    /// Devuelve el reason code del Connack: si el last will no esta autorizado, se rechaza la conexion.
+    fn authorize_connection(
+        &self,
+        connect: &Connect,
+        will_message: &Option<LastWill>,
+    ) -> Result<u8, ProtocolError> {
+        let mut usernames = self
+            .client_usernames
+            .write()
+            .map_err(|_| ProtocolError::LockError)?;
+        match &connect.username {
+            Some(username) => usernames.insert(connect.client_id.clone(), username.clone()),
+            None => usernames.remove(&connect.client_id),
+        };
+        drop(usernames);
+
+        if let Some(will_message) = will_message {
+            if !self.is_authorized(
+                &connect.client_id,
+                AclAction::Publish,
+                will_message.get_topic(),
+            )? {
+                self.client_usernames
+                    .write()
+                    .map_err(|_| ProtocolError::LockError)?
+                    .remove(&connect.client_id);
+                return Ok(NOT_AUTHORIZED_HEX);
+            }
         }
 
-        Ok(topics)
+        Ok(SUCCESS_HEX)
     }
 
-    ///Abro y devuelvo las lecturas del archivo de topics.
-    fn process_topic_config_file(file_path: &str) -> Result<Vec<String>, ProtocolError> {
-        let file = match File::open(file_path) {
-            Ok(file) => file,
-            Err(_) => return Err(ProtocolError::ReadingTopicConfigFileError),
+    /// Indica si la ACL le permite al cliente publicar en el topic o subscribirse al topic filter.
+    fn is_authorized(
+        &self,
+        client_id: &str,
+        action: AclAction,
+        topic: &str,
+    ) -> Result<bool, ProtocolError> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Ok(true),
         };
 
-        let readings = Broker::read_topic_config_file(&file)?;
-
-        Ok(readings)
+        let usernames = self
+            .client_usernames
+            .read()
+            .map_err(|_| ProtocolError::LockError)?;
+        Ok(acl.is_allowed(
+            client_id,
+            usernames.get(client_id).map(|username| username.as_str()),
+            action,
+            topic,
+        ))
     }
 
-    ///Devuelvo las lecturas que haga en el archivo de topics.
-    fn read_topic_config_file(file: &File) -> Result<Vec<String>, ProtocolError> {
-        let reader = BufReader::new(file).lines();
-        let mut readings = Vec::new();
-
-        for line in reader {
-            match line {
-                Ok(line) => readings.push(line),
-                Err(_err) => return Err(ProtocolError::ReadingTopicConfigFileError),
-            }
+    /// Devuelve el Topic con el nombre indicado, creandolo en caso de que todavia no exista.
+    ///
+    /// Si la politica de topics no permite usar ese topic, se devuelve None.
+    fn get_or_create_topic(&self, topic_name: &str) -> Result<Option<Topic>, ProtocolError> {
+        if !self.topic_policy.is_allowed(topic_name) {
+            return Ok(None);
         }
 
-        Ok(readings)
-    }
-
-    ///Abro y devuelvo las lecturas del archivo de clients.
-    fn process_clients_file(
-        file_path: &str,
-    ) -> Result<HashMap<String, (String, Vec<u8>)>, ProtocolError> {
-        let file = match File::open(file_path) {
-            Ok(file) => file,
-            Err(_) => return Err(ProtocolError::ReadingClientsFileError),
-        };
-
-        let readings = Broker::read_clients_file(&file)?;
+        let mut topics = self.topics.write().map_err(|_| ProtocolError::LockError)?;
+        let topic = topics.entry(topic_name.to_string()).or_default();
 
-        Ok(readings)
+        Ok(Some(topic.clone()))
     }
 
-    ///Devuelvo las lecturas que haga en el archivo de clients.
-    fn read_clients_file(file: &File) -> Result<HashMap<String, (String, Vec<u8>)>, ProtocolError> {
-        let reader = BufReader::new(file).lines();
-        let mut readings = HashMap::new();
-
-        for line in reader.map_while(Result::ok) {
-            let parts: Vec<&str> = line.split(',').collect();
-            if parts.len() == 3 {
-                let client_id = parts[0].trim().to_string();
-                let username = parts[1].trim().to_string();
-                let password = parts[2].trim().to_string().into_bytes();
-                readings.insert(client_id, (username, password));
-            }
-        }
+    /// Agrega un metodo de autenticacion extendida, o reemplaza al que ya tenia ese nombre.
+    pub fn register_authenticator(&mut self, method: &str, factory: AuthenticatorFactory) {
+        self.authenticators.insert(method.to_string(), factory);
+    }
 
-        Ok(readings)
+    ///Abro y devuelvo las credenciales del archivo de clients.
+    fn process_clients_file(file_path: &str) -> Result<CredentialStore, ProtocolError> {
+        CredentialStore::read_from_file(file_path)
     }
 
     /// Intenta crear el binding en el address indicado. Retorna un ProtocolError en caso de fallar.
@@ -245,38 +503,107 @@ impl Broker {
     }
 
     /// Ejecuta el servidor.
-    /// Crea un listener en el IP address del broker y, para
+    /// Crea un listener en cada address configurado del broker y, para
     /// cada conexión entrante se maneja un nuevo Client.
     pub fn server_run(&mut self) -> Result<(), ProtocolError> {
-        let listener = Broker::bind_to_address(&self.address)?;
+        self.check_listeners()?;
+
+        let mut listeners = Vec::new();
+        for listener in &self.listeners {
+            listeners.push((
+                listener.protocol,
+                Broker::bind_to_address(&listener.address)?,
+            ));
+        }
 
-        let threadpool = ThreadPool::new(THREADPOOL_SIZE);
+        let threadpool = Arc::new(ThreadPool::new(self.threadpool_size));
 
         let broker_ref = Arc::new(Mutex::new(self.clone()));
 
         self.spawn_command_listener(&threadpool, broker_ref);
+        self.spawn_in_flight_retransmitter(&threadpool);
+        self.spawn_will_publisher(&threadpool);
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = listeners
+                .into_iter()
+                .map(|(protocol, listener)| {
+                    let mut broker = self.clone();
+                    let threadpool = &threadpool;
+                    scope.spawn(move || {
+                        broker.listen_for_connections(&listener, protocol, threadpool)
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                match handle.join() {
+                    Ok(result) => result?,
+                    Err(_) => return Err(ProtocolError::StreamError),
+                }
+            }
+            Ok(())
+        })
+    }
 
-        self.listen_for_connections(&listener, &threadpool)?;
+    /// Si el Broker pide certificados de clientes, todos sus listeners deben ser TLS: por un listener TCP o
+    /// WebSocket los clientes no pueden presentar un certificado.
+    fn check_listeners(&self) -> Result<(), ProtocolError> {
+        if self.client_certificate_identity.is_none() {
+            return Ok(());
+        }
 
-        Ok(())
+        match self
+            .listeners
+            .iter()
+            .find(|listener| listener.protocol != ListenerProtocol::Tls)
+        {
+            Some(listener) => Err(ProtocolError::ServerConfigError(format!(
+                "Con certificados de clientes, el listener {} debe ser TLS",
+                listener.address
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Reemplaza los listeners del Broker. Cada uno escucha en su propio address.
+    pub fn set_listeners(&mut self, listeners: Vec<Listener>) {
+        self.listeners = listeners;
     }
 
     /// Escucha conexiones entrantes y las procesa.
-    /// Ante una nueva conexion, se crea un TLS stream del crate rustls para manejar la comunicacion
+    /// Ante una nueva conexion, se arma el stream que corresponde al protocolo del listener para manejar la comunicacion.
+    ///
+    /// El handshake de WebSocket se hace en el threadpool, para que un cliente lento no demore a las conexiones
+    /// siguientes. Si falla, se descarta esa conexion.
     fn listen_for_connections(
         &mut self,
         listener: &TcpListener,
-        threadpool: &ThreadPool,
+        protocol: ListenerProtocol,
+        threadpool: &Arc<ThreadPool>,
     ) -> Result<(), ProtocolError> {
         loop {
-            match listener.accept() {
-                Ok((stream, _)) => {
-                    let stream = self.build_tls_stream(stream)?;
-
-                    self.handle_client(stream, threadpool)?;
-                }
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
                 Err(_) => return Err(ProtocolError::StreamError),
+            };
+
+            if protocol == ListenerProtocol::WebSocket {
+                let broker = self.clone();
+                let handshake_threadpool = Arc::clone(threadpool);
+                threadpool.execute(move || {
+                    let result = broker
+                        .build_stream(stream, protocol)
+                        .and_then(|stream| broker.handle_client(stream, &handshake_threadpool));
+                    if let Err(e) = result {
+                        eprintln!("{}", e);
+                    }
+                });
+                continue;
             }
+
+            let stream = self.build_stream(stream, protocol)?;
+            self.handle_client(stream, threadpool)?;
         }
     }
 
@@ -290,12 +617,11 @@ impl Broker {
     /// Ambos threads terminaran en caso de error, o en caso de recibir un packet Disconnect de parte del Client.
     fn handle_client(
         &self,
-        stream: Arc<StreamOwned<ServerConnection, TcpStream>>,
+        stream: Arc<BrokerStream>,
         threadpool: &ThreadPool,
     ) -> Result<(), ProtocolError> {
         let self_clone = self.clone();
-
-        let clients_ids = Arc::clone(&self.clients_ids);
+        let writer = self.clone();
 
         let stream_write_half = Arc::clone(&stream);
         let stream_read_half = Arc::clone(&stream);
@@ -316,44 +642,32 @@ impl Broker {
             );
         });
         let _handle_write_messages = threadpool.execute(move || {
-            Broker::handle_write_messages(
+            writer.handle_write_messages(
                 stream_write_half,
                 disconnect_notifier_receiver,
                 stream_error_notifier_receiver,
                 message_to_write_receiver,
                 client_id_receiver,
-                clients_ids,
             )
         });
 
         Ok(())
     }
 
-    #[allow(clippy::type_complexity)]
     /// Aqui se maneja el enviado de mensajes de parte del Broker a los distintos Clients.
     ///
     /// Se recibe un packet a traves de un channel, se conforma el packet en respuesta a ese mensaje, y se
     /// lo envia a traves del Stream con el Client.
     ///
     /// En caso de recibir una notificacion para desconectarse, se corta el loop y por consiguiente el thread de escritura.
-    /// En caso de haber un error, se enviara el last will message del Client.
+    /// En caso de haber un error, la conexion se considera perdida: se corta el loop y se programa el last will del Client.
     fn handle_write_messages(
-        stream: Arc<StreamOwned<ServerConnection, TcpStream>>,
+        &self,
+        stream: Arc<BrokerStream>,
         disconnect_notifier_receiver: Receiver<()>,
         stream_error_notifier_receiver: Receiver<ProtocolError>,
         message_to_write_receiver: Receiver<BrokerMessage>,
         client_id_receiver: Receiver<String>,
-        clients_ids: Arc<
-            RwLock<
-                HashMap<
-                    String,
-                    (
-                        Option<Arc<StreamOwned<ServerConnection, TcpStream>>>,
-                        Option<LastWill>,
-                    ),
-                >,
-            >,
-        >,
     ) -> Result<(), ProtocolError> {
         loop {
             if disconnect_notifier_receiver.try_recv().is_ok() {
@@ -361,7 +675,7 @@ impl Broker {
             }
 
             if let Ok(message) = message_to_write_receiver.try_recv() {
-                match message.write_to(stream.get_ref()) {
+                match message.write_to(stream.as_ref()) {
                     Ok(_) => {}
                     Err(e) => {
                         eprintln!("{}", e);
@@ -371,30 +685,15 @@ impl Broker {
             }
 
             if let Ok(err) = stream_error_notifier_receiver.try_recv() {
+                eprintln!("{}", err);
+                while let Ok(message) = message_to_write_receiver.try_recv() {
+                    _ = message.write_to(stream.as_ref());
+                }
                 if let Ok(client_id) = client_id_receiver.try_recv() {
-                    if err == ProtocolError::StreamError
-                        || err == ProtocolError::AbnormalDisconnection
-                    {
-                        let clients_ids_guard = match clients_ids.read() {
-                            Ok(clients_ids_guard) => clients_ids_guard,
-                            Err(_) => break,
-                        };
-                        if let Some((_, will_message)) = clients_ids_guard.get(&*client_id) {
-                            let will_message = match will_message {
-                                Some(will_message) => Broker::get_last_will_message(will_message),
-                                None => break,
-                            };
-
-                            match will_message.write_to(stream.get_ref()) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    eprintln!("{}", e);
-                                    break;
-                                }
-                            }
-                        }
-                    }
+                    self.handle_abnormal_disconnection(&client_id)?;
                 }
+                _ = stream.shutdown();
+                break;
             }
         }
 
@@ -419,12 +718,41 @@ impl Broker {
         });
     }
 
-    fn build_tls_stream(
-        &self,
-        stream: TcpStream,
-    ) -> Result<Arc<StreamOwned<ServerConnection, TcpStream>>, ProtocolError> {
+    /// Crea un thread que periodicamente reenvia los Publish con QoS 1 que no fueron confirmados a tiempo.
+    fn spawn_in_flight_retransmitter(&self, threadpool: &ThreadPool) {
+        let broker = self.clone();
+
+        threadpool.execute(move || loop {
+            thread::sleep(IN_FLIGHT_TIMEOUT);
+
+            if let Err(e) = broker.retransmit_in_flight_messages() {
+                eprintln!("{}", e);
+            }
+        });
+    }
+
+    /// Crea un thread que publica los last wills cuyo will delay interval ya vencio, y descarta las sesiones
+    /// que expiraron.
+    fn spawn_will_publisher(&self, threadpool: &ThreadPool) {
+        let broker = self.clone();
+
+        threadpool.execute(move || loop {
+            thread::sleep(WILL_CHECK_INTERVAL);
+
+            if let Err(e) = broker.publish_expired_wills() {
+                eprintln!("{}", e);
+            }
+            if let Err(e) = broker.remove_expired_sessions() {
+                eprintln!("{}", e);
+            }
+        });
+    }
+
+    fn build_tls_stream(&self, stream: TcpStream) -> Result<Arc<BrokerStream>, ProtocolError> {
         match ServerConnection::new(self.server_config.clone()) {
-            Ok(c) => Ok(Arc::new(StreamOwned::new(c, stream))),
+            Ok(c) => Ok(Arc::new(BrokerStream::Tls(Box::new(StreamOwned::new(
+                c, stream,
+            ))))),
             Err(e) => {
                 eprintln!("{}", e);
                 Err(ProtocolError::StreamError)
@@ -432,6 +760,21 @@ impl Broker {
         }
     }
 
+    /// Arma la conexion con el cliente segun el protocolo del listener por el que se conecto.
+    fn build_stream(
+        &self,
+        stream: TcpStream,
+        protocol: ListenerProtocol,
+    ) -> Result<Arc<BrokerStream>, ProtocolError> {
+        match protocol {
+            ListenerProtocol::Tcp => Ok(Arc::new(BrokerStream::Tcp(stream))),
+            ListenerProtocol::Tls => self.build_tls_stream(stream),
+            ListenerProtocol::WebSocket => Ok(Arc::new(BrokerStream::WebSocket(
+                WebSocketStream::accept(stream)?,
+            ))),
+        }
+    }
+
     /// Constantemente se estara leyendo el Stream para encontrar los packets que envia el Client.
     /// En caso de recibir un mensaje nuevo, se lo envia a traves de un channel al thread de escritura
     /// para asi poder conformar una respuesta.
@@ -439,37 +782,325 @@ impl Broker {
     /// En caso de recibir un Disconnect, se envia la notificacion al thread de escritura, y este termina con su ejecucion.
     fn handle_read_messages(
         &self,
-        stream: Arc<StreamOwned<ServerConnection, TcpStream>>,
+        stream: Arc<BrokerStream>,
         message_to_write_sender: &Sender<BrokerMessage>,
         client_id_sender: Sender<String>,
         disconnect_notifier_sender: Sender<()>,
         stream_error_notifier_sender: Sender<ProtocolError>,
     ) -> Result<(), ProtocolError> {
+        let mut topic_aliases = TopicAliases::new(self.topic_alias_maximum);
+        let mut enhanced_auth = None;
+        let mut client_id = String::new();
+
         loop {
             let stream_ref = Arc::clone(&stream);
 
-            if let Ok(message) = ClientMessage::read_from(stream.get_ref()) {
-                match self.handle_message(
-                    message,
-                    message_to_write_sender,
-                    stream_ref,
-                    client_id_sender.clone(),
-                ) {
-                    Ok(return_val) => {
-                        if return_val == ProtocolReturn::DisconnectRecieved {
-                            match disconnect_notifier_sender.send(()) {
-                                Ok(_) => return Ok(()),
-                                Err(e) => return Err(ProtocolError::SendError(e.to_string())),
+            match ClientMessage::read_with_limit(stream.as_ref(), self.maximum_packet_size) {
+                Ok(message) => {
+                    if let ClientMessage::Connect(connect) = &message {
+                        Broker::set_keep_alive_timeout(&stream, connect.keep_alive)?;
+                        client_id = connect.client_id.clone();
+                    }
+                    let message = message.with_client_id(&client_id);
+
+                    let message = match self.handle_enhanced_auth(
+                        message,
+                        &mut enhanced_auth,
+                        message_to_write_sender,
+                        &stream_ref,
+                        &client_id_sender,
+                    ) {
+                        Ok(Some(message)) => message,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            return stream_error_notifier_sender
+                                .send(err)
+                                .map_err(|e| ProtocolError::SendError(e.to_string()))
+                        }
+                    };
+
+                    let message = match Broker::resolve_topic_alias(message, &mut topic_aliases) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            return Broker::close_connection_with_error(
+                                TOPIC_ALIAS_INVALID_HEX,
+                                err,
+                                message_to_write_sender,
+                                &stream_error_notifier_sender,
+                            );
+                        }
+                    };
+
+                    if self.exceeds_receive_maximum(&client_id, &message)? {
+                        return Broker::close_connection_with_error(
+                            RECEIVE_MAXIMUM_EXCEEDED_HEX,
+                            ProtocolError::ReceiveMaximumExceeded,
+                            message_to_write_sender,
+                            &stream_error_notifier_sender,
+                        );
+                    }
+
+                    match self.handle_message(
+                        message,
+                        message_to_write_sender,
+                        stream_ref,
+                        client_id_sender.clone(),
+                    ) {
+                        Ok(return_val) => {
+                            if return_val == ProtocolReturn::DisconnectRecieved {
+                                match disconnect_notifier_sender.send(()) {
+                                    Ok(_) => return Ok(()),
+                                    Err(e) => return Err(ProtocolError::SendError(e.to_string())),
+                                }
                             }
                         }
+                        Err(err) => match stream_error_notifier_sender.send(err) {
+                            Ok(_) => return Ok(()),
+                            Err(e) => return Err(ProtocolError::SendError(e.to_string())),
+                        },
                     }
-                    Err(err) => match stream_error_notifier_sender.send(err) {
-                        Ok(_) => return Ok(()),
-                        Err(e) => return Err(ProtocolError::SendError(e.to_string())),
-                    },
+                }
+                Err(e) if Broker::is_connection_lost(&e) => {
+                    println!("Connection lost: {}", e);
+
+                    return stream_error_notifier_sender
+                        .send(ProtocolError::AbnormalDisconnection)
+                        .map_err(|e| ProtocolError::SendError(e.to_string()));
+                }
+                // Luego de un packet mal formado o demasiado grande no se puede seguir leyendo el stream.
+                Err(e) if is_packet_too_large(&e) => {
+                    return Broker::close_connection_with_error(
+                        PACKET_TOO_LARGE_HEX,
+                        ProtocolError::PacketTooLarge,
+                        message_to_write_sender,
+                        &stream_error_notifier_sender,
+                    );
+                }
+                Err(e) => {
+                    println!("Malformed packet: {}", e);
+
+                    return Broker::close_connection_with_error(
+                        MALFORMED_PACKET_HEX,
+                        ProtocolError::MalformedPacket,
+                        message_to_write_sender,
+                        &stream_error_notifier_sender,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Le envia al cliente un Disconnect con el reason code del error, y notifica el error para que se cierre la conexion.
+    fn close_connection_with_error(
+        reason_code: u8,
+        err: ProtocolError,
+        message_to_write_sender: &Sender<BrokerMessage>,
+        stream_error_notifier_sender: &Sender<ProtocolError>,
+    ) -> Result<(), ProtocolError> {
+        let disconnect = BrokerMessage::Disconnect {
+            reason_code,
+            session_expiry_interval: 0,
+            reason_string: err.to_string(),
+            user_properties: Vec::new(),
+        };
+        message_to_write_sender
+            .send(disconnect)
+            .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+        stream_error_notifier_sender
+            .send(err)
+            .map_err(|e| ProtocolError::SendError(e.to_string()))
+    }
+
+    /// Si el cliente no envia ningun packet durante una vez y media su keep alive, la lectura del stream
+    /// falla por timeout y se considera que la conexion se perdio. Un keep alive de 0 desactiva el mecanismo.
+    fn set_keep_alive_timeout(
+        stream: &Arc<BrokerStream>,
+        keep_alive: u16,
+    ) -> Result<(), ProtocolError> {
+        let timeout = match keep_alive {
+            0 => None,
+            seconds => Some(Duration::from_millis(seconds as u64 * 1500)),
+        };
+
+        stream
+            .set_read_timeout(timeout)
+            .map_err(|_| ProtocolError::StreamError)
+    }
+
+    /// La conexion se considera perdida si el cliente la cerro sin enviar un Disconnect, o si vencio su keep alive.
+    fn is_connection_lost(error: &std::io::Error) -> bool {
+        matches!(
+            error.kind(),
+            std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::WouldBlock
+                | std::io::ErrorKind::TimedOut
+        )
+    }
+
+    /// Maneja la perdida de la conexion con un cliente sin haber recibido su Disconnect.
+    ///
+    /// Se lo quita de los clientes conectados, se programa la publicacion de su last will(si tenia uno) y se
+    /// mantiene su sesion segun el session expiry interval del Connect.
+    fn handle_abnormal_disconnection(&self, client_id: &str) -> Result<(), ProtocolError> {
+        let client = self
+            .clients_ids
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id);
+
+        _ = self.with_session_lock(client_id, || {
+            ClientConfig::end_session(&self.sessions_directory, client_id.to_string(), None)
+        })?;
+        self.remove_connection_state(client_id)?;
+
+        if let Some((_, Some(will_message))) = client {
+            self.schedule_last_will(client_id, will_message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Los topic aliases, los limites de flujo y el username solo valen durante una conexion.
+    ///
+    /// Los Publish que esperaban lugar en el receive maximum del cliente se guardan junto a sus mensajes
+    /// offline, si su sesion sigue vigente.
+    fn remove_connection_state(&self, client_id: &str) -> Result<(), ProtocolError> {
+        self.topic_aliases
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id);
+        self.flow_control
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id);
+        self.client_usernames
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id);
+        self.qos2_pending_releases
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .retain(|(pending_client_id, _)| pending_client_id != client_id);
+
+        let queued = self
+            .queued_deliveries
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id)
+            .unwrap_or_default();
+        if queued.is_empty() {
+            return Ok(());
+        }
+
+        self.with_session_lock(client_id, || {
+            if ClientConfig::session_is_valid(&self.sessions_directory, client_id.to_string()) {
+                for (message, _, _) in queued {
+                    let _ = ClientConfig::add_offline_message(
+                        &self.sessions_directory,
+                        client_id.to_string(),
+                        message,
+                    );
                 }
             }
+        })
+    }
+
+    /// Ejecuta operation con el lock de la sesion del cliente tomado. Ver session_locks.
+    fn with_session_lock<T>(
+        &self,
+        client_id: &str,
+        operation: impl FnOnce() -> T,
+    ) -> Result<T, ProtocolError> {
+        let session_lock = self
+            .session_locks
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .entry(client_id.to_string())
+            .or_default()
+            .clone();
+        let _guard = session_lock.lock().map_err(|_| ProtocolError::LockError)?;
+
+        Ok(operation())
+    }
+
+    /// Si el last will tiene un will delay interval, queda pendiente hasta que este venza. Si no, se publica en el momento.
+    fn schedule_last_will(
+        &self,
+        client_id: &str,
+        will_message: LastWill,
+    ) -> Result<(), ProtocolError> {
+        // El last will se publica al vencer el will delay interval o al terminar la sesion, lo que ocurra primero.
+        let session_expiry_interval =
+            ClientConfig::get_session_expiry_interval(&self.sessions_directory, client_id)
+                .unwrap_or(0);
+        let delay = will_message
+            .get_properties()
+            .get_last_will_delay_interval()
+            .min(session_expiry_interval);
+        if delay == 0 {
+            return self.publish_last_will(will_message);
+        }
+
+        self.pending_wills
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .insert(
+                client_id.to_string(),
+                (
+                    will_message,
+                    Instant::now() + Duration::from_secs(delay as u64),
+                ),
+            );
+
+        Ok(())
+    }
+
+    /// Descarta el last will pendiente de un cliente, devolviendolo si existia.
+    fn cancel_pending_will(&self, client_id: &str) -> Result<Option<LastWill>, ProtocolError> {
+        Ok(self
+            .pending_wills
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id)
+            .map(|(will_message, _)| will_message))
+    }
+
+    /// Publica los last wills pendientes cuyo will delay interval ya vencio.
+    fn publish_expired_wills(&self) -> Result<(), ProtocolError> {
+        let expired: Vec<LastWill> = {
+            let mut pending_wills = self
+                .pending_wills
+                .write()
+                .map_err(|_| ProtocolError::LockError)?;
+            let now = Instant::now();
+            let expired_ids: Vec<String> = pending_wills
+                .iter()
+                .filter(|(_, (_, publish_at))| *publish_at <= now)
+                .map(|(client_id, _)| client_id.clone())
+                .collect();
+
+            expired_ids
+                .iter()
+                .filter_map(|client_id| pending_wills.remove(client_id))
+                .map(|(will_message, _)| will_message)
+                .collect()
+        };
+
+        for will_message in expired {
+            self.publish_last_will(will_message)?;
         }
+
+        Ok(())
+    }
+
+    fn publish_last_will(&self, will_message: LastWill) -> Result<(), ProtocolError> {
+        let will = Broker::get_last_will_message(&will_message);
+        self.handle_publish(will, will_message.get_topic().to_string(), None)?;
+
+        Ok(())
     }
 
     fn process_input_command<R: BufRead>(&mut self, reader: R) -> Result<(), ProtocolError> {
@@ -517,7 +1148,13 @@ impl Broker {
                 retain_flag: *retain_flag,
                 payload: payload.clone(),
                 dup_flag: *dup_flag,
-                properties: properties.clone(),
+                properties: PublishProperties {
+                    topic_properties: TopicProperties {
+                        topic_alias: 0,
+                        response_topic: properties.topic_properties.response_topic.clone(),
+                    },
+                    ..properties.clone()
+                },
             }),
             _ => Err(ProtocolError::UnspecifiedError(
                 "Error al convertir el mensaje".to_string(),
@@ -525,6 +1162,42 @@ impl Broker {
         }
     }
 
+    /// El Publish tal como se le entrega a un subscriptor: con la menor QoS entre la del Publish y la
+    /// otorgada a su subscripcion.
+    fn message_for_subscriber(
+        message: &ClientMessage,
+        subscription: &Subscription,
+    ) -> ClientMessage {
+        let mut message = message.clone();
+        if let ClientMessage::Publish { qos, .. } = &mut message {
+            *qos = (*qos).min(subscription.options.maximum_qos as usize);
+        }
+        message
+    }
+
+    /// Arma el Publish que recibe un subscriptor: lleva la QoS otorgada y el subscription identifier de su
+    /// subscripcion, y conserva el retain_flag solo si la subscripcion tiene seteado Retain As Published.
+    fn convert_to_delivery(
+        message: &ClientMessage,
+        subscription: &Subscription,
+    ) -> Result<BrokerMessage, ProtocolError> {
+        let mut delivery = Broker::convert_to_broker_message(message)?;
+        if let BrokerMessage::PublishDelivery {
+            qos,
+            retain_flag,
+            properties,
+            ..
+        } = &mut delivery
+        {
+            *qos = (*qos).min(subscription.options.maximum_qos as usize);
+            if !subscription.options.retain_as_published {
+                *retain_flag = 0;
+            }
+            properties.subscription_identifier = subscription.subscription_identifier;
+        }
+        Ok(delivery)
+    }
+
     /// Envia un mensaje a un usuario.
     ///
     /// Retorna Ok si el mensaje fue enviado, Err si el usuario está offline.
@@ -535,7 +1208,27 @@ impl Broker {
     ) -> Result<(), bool> {
         let clients = self.clients_ids.read().map_err(|_| false)?;
         if let Some((Some(stream), _)) = clients.get(&user.client_id) {
-            message.write_to(stream.get_ref()).map_err(|_| true)?;
+            let client_topic_aliases = self
+                .topic_aliases
+                .read()
+                .map_err(|_| false)?
+                .get(&user.client_id)
+                .cloned();
+
+            // El alias se asigna y el Publish se escribe sin soltar la tabla del cliente: si no, otro thread
+            // podria escribir un Publish que usa el alias antes que el Publish que lo establece.
+            match client_topic_aliases {
+                Some(client_topic_aliases) => {
+                    let mut client_topic_aliases =
+                        client_topic_aliases.lock().map_err(|_| false)?;
+                    Broker::apply_topic_alias(&mut client_topic_aliases, message)
+                        .as_ref()
+                        .unwrap_or(message)
+                        .write_to(stream.as_ref())
+                }
+                None => message.write_to(stream.as_ref()),
+            }
+            .map_err(|_| true)?;
             println!("Mensaje sent to {}", user.client_id);
             Ok(())
         } else {
@@ -543,35 +1236,218 @@ impl Broker {
         }
     }
 
-    /// Maneja el envio de un mensaje a un topic.
+    /// Si el cliente acepta topic aliases, el Publish se le envia usando el alias asignado a su topic.
     ///
-    /// Retorna el reason code correspondiente a si el envio fue exitoso o no.
-    fn handle_publish(
-        &self,
-        message: ClientMessage,
-        mut topics: HashMap<String, Topic>,
-        topic_name: String,
-    ) -> Result<u8, ProtocolError> {
-        let mensaje = Broker::convert_to_broker_message(&message)?;
-        let topic = topics
-            .get_mut(&topic_name)
-            .ok_or(ProtocolError::UnspecifiedError(
-                "Topic not found".to_string(),
-            ))?;
-        let users = topic.get_users_from_topic();
-        for user in users {
-            match self.send_message_to_user(&user, &mensaje) {
-                Ok(_) => (),
+    /// Devuelve None si el mensaje no es un Publish.
+    fn apply_topic_alias(
+        topic_aliases: &mut TopicAliases,
+        message: &BrokerMessage,
+    ) -> Option<BrokerMessage> {
+        match message {
+            BrokerMessage::PublishDelivery {
+                packet_id,
+                topic_name,
+                qos,
+                retain_flag,
+                payload,
+                dup_flag,
+                properties,
+            } => {
+                let (topic_name, topic_alias) = topic_aliases.assign(topic_name);
+                let mut properties = properties.clone();
+                properties.topic_properties.topic_alias = topic_alias;
+
+                Some(BrokerMessage::PublishDelivery {
+                    packet_id: *packet_id,
+                    topic_name,
+                    qos: *qos,
+                    retain_flag: *retain_flag,
+                    payload: payload.clone(),
+                    dup_flag: *dup_flag,
+                    properties,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Reemplaza el topic name vacio de un Publish recibido por el topic asociado a su alias en la conexion.
+    fn resolve_topic_alias(
+        message: ClientMessage,
+        topic_aliases: &mut TopicAliases,
+    ) -> Result<ClientMessage, ProtocolError> {
+        match message {
+            ClientMessage::Publish {
+                packet_id,
+                topic_name,
+                qos,
+                retain_flag,
+                payload,
+                dup_flag,
+                properties,
+            } => Ok(ClientMessage::Publish {
+                packet_id,
+                topic_name: topic_aliases
+                    .resolve(&topic_name, properties.topic_properties.topic_alias)?,
+                qos,
+                retain_flag,
+                payload,
+                dup_flag,
+                properties,
+            }),
+            message => Ok(message),
+        }
+    }
+
+    /// Obtiene los subscriptores que deben recibir un publish hecho en topic_name:
+    /// los subscriptos al topic exacto, y los que tengan un wildcard filter que coincida con el topic.
+    ///
+    /// Si un cliente coincide por mas de una subscripcion, se lo devuelve una unica vez.
+    ///
+    /// Ademas, se agrega un miembro de cada shared subscription que coincida con el topic.
+    fn get_matching_subscribers(&self, topic: &Topic, topic_name: &str) -> Vec<Subscription> {
+        let mut users = topic.get_users_from_topic();
+
+        for subscription in self.wildcard_subscriptions.get_users_from_topic() {
+            if Topic::filter_matches(&subscription.topic, topic_name)
+                && !users.iter().any(|u| u.client_id == subscription.client_id)
+            {
+                users.push(subscription);
+            }
+        }
+
+        users.extend(self.get_shared_subscribers(topic_name));
+
+        users
+    }
+
+    /// Elige, por cada shared subscription que coincida con el topic, el miembro del grupo que recibe el publish.
+    ///
+    /// Los miembros se eligen en round-robin, priorizando a los que estan conectados. Si ninguno lo esta,
+    /// el mensaje queda encolado para el miembro que corresponda.
+    fn get_shared_subscribers(&self, topic_name: &str) -> Vec<Subscription> {
+        let mut groups: HashMap<String, Vec<Subscription>> = HashMap::new();
+        for subscription in self.shared_subscriptions.get_users_from_topic() {
+            if let Some((_, filter)) = Topic::parse_shared_filter(&subscription.topic) {
+                if Topic::filter_matches(filter, topic_name) {
+                    groups
+                        .entry(subscription.topic.clone())
+                        .or_default()
+                        .push(subscription);
+                }
+            }
+        }
+
+        let mut cursors = match self.shared_subscription_cursors.write() {
+            Ok(cursors) => cursors,
+            Err(_) => return Vec::new(),
+        };
+
+        groups
+            .into_iter()
+            .map(|(share, members)| {
+                let connected: Vec<Subscription> = members
+                    .iter()
+                    .filter(|member| self.is_connected(&member.client_id))
+                    .cloned()
+                    .collect();
+                let candidates = if connected.is_empty() {
+                    members
+                } else {
+                    connected
+                };
+
+                let cursor = cursors.entry(share).or_insert(0);
+                let member = candidates[*cursor % candidates.len()].clone();
+                *cursor = cursor.wrapping_add(1);
+                member
+            })
+            .collect()
+    }
+
+    fn is_connected(&self, client_id: &str) -> bool {
+        match self.clients_ids.read() {
+            Ok(clients) => matches!(clients.get(client_id), Some((Some(_), _))),
+            Err(_) => false,
+        }
+    }
+
+    /// Maneja el envio de un mensaje a un topic.
+    ///
+    /// Retorna el reason code correspondiente a si el envio fue exitoso o no.
+    ///
+    /// Si el topic no existe se lo crea, siempre que la politica de topics lo permita.
+    /// Los topic names con wildcards son invalidos.
+    ///
+    /// Las subscripciones con No Local no reciben los mensajes publicados por su propio cliente(publisher_id).
+    fn handle_publish(
+        &self,
+        message: ClientMessage,
+        topic_name: String,
+        publisher_id: Option<&str>,
+    ) -> Result<u8, ProtocolError> {
+        if topic_name.is_empty() || Topic::is_wildcard_filter(&topic_name) {
+            return Ok(TOPIC_NAME_INVALID_HEX);
+        }
+
+        if let Some(publisher_id) = publisher_id {
+            if !self.is_authorized(publisher_id, AclAction::Publish, &topic_name)? {
+                return Ok(NOT_AUTHORIZED_HEX);
+            }
+        }
+
+        let topic = match self.get_or_create_topic(&topic_name)? {
+            Some(topic) => topic,
+            None => return Ok(NOT_AUTHORIZED_HEX),
+        };
+        Broker::handle_retained_message(&topic, &message);
+        let users = self.get_matching_subscribers(&topic, &topic_name);
+        for user in users {
+            if user.options.no_local && Some(user.client_id.as_str()) == publisher_id {
+                continue;
+            }
+
+            let mut user_message = Broker::message_for_subscriber(&message, &user);
+            let mut mensaje = Broker::convert_to_delivery(&user_message, &user)?;
+            if !self.fits_in_packet_size(&user.client_id, &mensaje)? {
+                println!(
+                    "Publish descartado para {}: supera su maximum packet size",
+                    user.client_id
+                );
+                continue;
+            }
+            if self.must_wait_for_quota(&user.client_id, &user_message)? {
+                self.queue_delivery(&user.client_id, user_message, mensaje)?;
+                continue;
+            }
+
+            self.assign_delivery_packet_id(&user.client_id, &mut user_message, &mut mensaje)?;
+            match self.send_message_to_user(&user, &mensaje) {
+                Ok(_) => self.add_in_flight_message(&user.client_id, &user_message)?,
                 Err(_) => {
-                    if ClientConfig::client_is_online(user.client_id.clone()) {
+                    let is_online = self.with_session_lock(&user.client_id, || {
+                        if ClientConfig::client_is_online(
+                            &self.sessions_directory,
+                            user.client_id.clone(),
+                        ) {
+                            return true;
+                        }
+                        if ClientConfig::session_is_valid(
+                            &self.sessions_directory,
+                            user.client_id.clone(),
+                        ) {
+                            let _ = ClientConfig::add_offline_message(
+                                &self.sessions_directory,
+                                user.client_id.clone(),
+                                user_message,
+                            );
+                        }
+                        false
+                    })?;
+                    if is_online {
                         return Err(ProtocolError::UnspecifiedError(
                             "Error while sending the message: the client is online and not receiving messages".to_string(),
                         ));
-                    } else {
-                        let _ = ClientConfig::add_offline_message(
-                            user.client_id.clone(),
-                            message.clone(),
-                        );
                     }
                 }
             }
@@ -579,611 +1455,3182 @@ impl Broker {
         Ok(0x00_u8) // Unspecified Error reason code
     }
 
-    /// Maneja la subscripcion de un cliente a un topic.
-    /// Devuelve el reason code correspondiente a si la subscripcion fue exitosa o no.
-    /// Si el reason code es 0, el cliente se ha suscrito exitosamente.
-    fn handle_subscribe(
-        mut topics: HashMap<String, Topic>,
+    /// Maneja un Publish con QoS 2 recibido de un cliente.
+    ///
+    /// El mensaje se distribuye solo la primera vez que llega: si el packet_id del publisher todavia espera
+    /// el Pubrel, se trata de un reenvio y unicamente se vuelve a responder con el Pubrec.
+    fn handle_qos2_publish(
+        &self,
+        message: ClientMessage,
         topic_name: String,
-        subscription: Subscription,
+        packet_id: u16,
+        publisher_id: Option<&str>,
     ) -> Result<u8, ProtocolError> {
-        let reason_code;
-        if let Some(topic) = topics.get_mut(&topic_name) {
-            match topic.add_user_to_topic(subscription.clone()) {
-                0 => {
-                    reason_code = SUCCESS_HEX;
-                }
-                0x92 => {
-                    reason_code = SUB_ID_DUP_HEX;
-                }
-                _ => {
-                    reason_code = UNSPECIFIED_ERROR_HEX;
-                }
+        let pending_release = (publisher_id.unwrap_or_default().to_string(), packet_id);
+        {
+            let pending_releases = self
+                .qos2_pending_releases
+                .read()
+                .map_err(|_| ProtocolError::LockError)?;
+            if pending_releases.contains(&pending_release) {
+                return Ok(SUCCESS_HEX);
             }
-        } else {
-            reason_code = UNSPECIFIED_ERROR_HEX;
+        }
+
+        let reason_code = self.handle_publish(message, topic_name, publisher_id)?;
+        if reason_code < UNSPECIFIED_ERROR_HEX {
+            let mut pending_releases = self
+                .qos2_pending_releases
+                .write()
+                .map_err(|_| ProtocolError::LockError)?;
+            pending_releases.insert(pending_release);
         }
 
         Ok(reason_code)
     }
 
-    /// Maneja la desubscripcion de un cliente a un topic
-    /// Devuelve el reason code correspondiente a si la desubscripcion fue exitosa o no
-    /// Si el reason code es 0, el cliente se ha desuscrito exitosamente.
-    fn handle_unsubscribe(
-        mut topics: HashMap<String, Topic>,
-        topic_name: String,
-        subscription: Subscription,
-    ) -> Result<u8, ProtocolError> {
-        let reason_code;
+    /// Indica si el Publish recibido supera el receive maximum que el broker le informo al cliente en el Connack.
+    ///
+    /// Los Publish con QoS 1 se confirman al procesarlos, asi que solo pueden acumularse los de QoS 2 cuyo
+    /// Pubrel todavia no llego. El reenvio de uno de ellos no cuenta como un Publish nuevo.
+    fn exceeds_receive_maximum(
+        &self,
+        client_id: &str,
+        message: &ClientMessage,
+    ) -> Result<bool, ProtocolError> {
+        let packet_id = match message {
+            ClientMessage::Publish {
+                packet_id, qos: 2, ..
+            } => *packet_id,
+            _ => return Ok(false),
+        };
+        if self.receive_maximum == 0 {
+            return Ok(false);
+        }
 
-        if let Some(topic) = topics.get_mut(&topic_name) {
-            match topic.remove_user_from_topic(subscription.clone()) {
-                0 => {
-                    println!("Unsubscribe successfull");
-                    reason_code = SUCCESS_HEX;
-                }
-                _ => {
-                    println!("Non specified error");
-                    reason_code = UNSPECIFIED_ERROR_HEX;
-                }
-            }
+        let pending_releases = self
+            .qos2_pending_releases
+            .read()
+            .map_err(|_| ProtocolError::LockError)?;
+        if pending_releases.contains(&(client_id.to_string(), packet_id)) {
+            return Ok(false);
+        }
+        let pending = pending_releases
+            .iter()
+            .filter(|(pending_client_id, _)| pending_client_id == client_id)
+            .count();
+
+        Ok(pending >= self.receive_maximum as usize)
+    }
+
+    /// Libera el packet_id de un Publish con QoS 2 del cliente al recibir su Pubrel.
+    /// Devuelve el reason code que debe llevar el Pubcomp.
+    fn release_qos2_publish(&self, client_id: &str, packet_id: u16) -> Result<u8, ProtocolError> {
+        let mut pending_releases = self
+            .qos2_pending_releases
+            .write()
+            .map_err(|_| ProtocolError::LockError)?;
+
+        if pending_releases.remove(&(client_id.to_string(), packet_id)) {
+            Ok(SUCCESS_HEX)
         } else {
-            println!("Non specified error");
-            reason_code = UNSPECIFIED_ERROR_HEX;
+            Ok(PACKET_ID_NOT_FOUND_HEX)
         }
-        println!("Reason Code {:?}", reason_code);
+    }
 
-        Ok(reason_code)
+    /// Si el Publish tiene QoS > 0, se lo guarda como in-flight para el cliente hasta recibir su Puback o Pubcomp.
+    fn add_in_flight_message(
+        &self,
+        client_id: &str,
+        message: &ClientMessage,
+    ) -> Result<(), ProtocolError> {
+        if let ClientMessage::Publish { packet_id, qos, .. } = message {
+            if *qos == 0 {
+                return Ok(());
+            }
+
+            let mut in_flight_messages = self
+                .in_flight_messages
+                .write()
+                .map_err(|_| ProtocolError::LockError)?;
+            in_flight_messages
+                .entry(client_id.to_string())
+                .or_default()
+                .insert(*packet_id, (message.clone(), Instant::now()));
+        }
+
+        Ok(())
     }
 
-    ///Se toma un packet con su respectivo ID y se lo guarda en el hashmap de mensajes que tiene el Broker.
-    fn save_packet(
-        packets: Arc<RwLock<HashMap<u16, ClientMessage>>>,
-        message: ClientMessage,
-        packet_id: u16,
-    ) {
-        let mut lock = match packets.write() {
-            Ok(lock) => lock,
-            Err(_) => return,
-        };
+    /// Asigna al Publish que se entrega a un cliente un packet id propio de ese cliente.
+    fn assign_delivery_packet_id(
+        &self,
+        client_id: &str,
+        message: &mut ClientMessage,
+        delivery: &mut BrokerMessage,
+    ) -> Result<(), ProtocolError> {
+        if let ClientMessage::Publish { packet_id, qos, .. } = message {
+            *packet_id = self.next_delivery_packet_id(client_id, *qos)?;
+            if let BrokerMessage::PublishDelivery {
+                packet_id: delivery_packet_id,
+                ..
+            } = delivery
+            {
+                *delivery_packet_id = *packet_id;
+            }
+        }
 
-        lock.insert(packet_id, message);
+        Ok(())
     }
 
-    /// Envia el mensaje de Last Will al cliente.
-    ///
-    /// Se encarga de la logica necesaria segun los parametros del Last Will y sus properties
-    ///
-    /// Si hay un delay en el envio del mensaje (delay_interval), se encarga de esperar el tiempo correspondiente.
-    ///
-    /// Convierte el mensaje en un Publish y lo envia al broker.
-    fn get_last_will_message(will_message: &LastWill) -> ClientMessage {
-        let properties = will_message.get_properties();
-        let interval = properties.get_last_will_delay_interval();
-        thread::sleep(std::time::Duration::from_secs(interval as u64));
-        let will_topic = will_message.get_topic();
-        let message = will_message.get_message();
-        let will_qos = will_message.get_qos();
-        let will_retain = will_message.get_retain();
+    /// Devuelve el siguiente packet id del cliente que no este en uso por uno de sus mensajes in-flight.
+    /// Los Publish con QoS 0 no llevan packet id.
+    fn next_delivery_packet_id(&self, client_id: &str, qos: usize) -> Result<u16, ProtocolError> {
+        if qos == 0 {
+            return Ok(0);
+        }
 
-        let will_payload = PayloadTypes::WillPayload(message.to_string());
+        let in_flight_messages = self
+            .in_flight_messages
+            .read()
+            .map_err(|_| ProtocolError::LockError)?;
+        let in_use = in_flight_messages.get(client_id);
+        let mut delivery_packet_ids = self
+            .delivery_packet_ids
+            .write()
+            .map_err(|_| ProtocolError::LockError)?;
+        let last = delivery_packet_ids
+            .entry(client_id.to_string())
+            .or_insert(0);
 
-        ClientMessage::Publish {
-            packet_id: 0,
-            topic_name: will_topic.to_string(),
-            qos: will_qos as usize,
-            retain_flag: will_retain as usize,
-            payload: will_payload,
-            dup_flag: 0,
-            properties: will_message
-                .get_properties()
-                .clone()
-                .to_publish_properties(),
+        loop {
+            *last = last.checked_add(1).unwrap_or(1);
+            if !in_use.is_some_and(|messages| messages.contains_key(last)) {
+                return Ok(*last);
+            }
         }
     }
 
-    /// Dado un mensaje proveniente de un Client, se conforma y se envia el packet en respuesta.
-    pub fn handle_message(
+    /// Indica si el packet respeta el maximum packet size que el cliente indico en su Connect.
+    fn fits_in_packet_size(
         &self,
-        message: ClientMessage,
-        message_to_write_sender: &Sender<BrokerMessage>,
-        client_stream_ref: Arc<StreamOwned<ServerConnection, TcpStream>>,
-        client_id_sender: Sender<String>,
-    ) -> Result<ProtocolReturn, ProtocolError> {
-        let topics = self.topics.clone();
-        let packets = self.packets.clone();
-        let clients_auth_info = self.clients_auth_info.clone();
+        client_id: &str,
+        message: &BrokerMessage,
+    ) -> Result<bool, ProtocolError> {
+        let flow_control = self
+            .flow_control
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .get(client_id)
+            .copied()
+            .unwrap_or_default();
 
-        match message {
-            ClientMessage::Connect { 0: connect } => {
-                println!("Connect received");
+        Ok(flow_control.fits(FlowControl::broker_packet_size(message)?))
+    }
 
-                let will_message = connect.clone().give_will_message();
-
-                if ClientConfig::client_exists(connect.client_id.clone()) {
-                    // si el path del client existe, eliminarlo
-                    ClientConfig::delete_client_file(connect.client_id.clone())?;
-
-                    // println!("Loading client from file");
-                    // if let Err(e) =
-                    //     ClientConfig::change_client_state(connect.client_id.clone(), true)
-                    // {
-                    //     return Err(ProtocolError::UnspecifiedError(e.to_string()));
-                    // }
-
-                    // if let Ok(mut clients) = self.clients_ids.write() {
-                    //     clients.remove(&connect.client_id);
-                    //     clients.insert(
-                    //         connect.client_id.clone(),
-                    //         (Some(cloned_stream), will_message),
-                    //     );
-                    // } else {
-                    //     return Err(ProtocolError::WriteError);
-                    // }
-                }
-                println!("Creating new client");
-                if let Err(e) = ClientConfig::create_client_log_in_json(connect.client_id.clone()) {
-                    return Err(ProtocolError::UnspecifiedError(e.to_string()));
-                }
+    /// Indica si un Publish con QoS > 0 debe esperar para enviarse a un cliente conectado: ya sea porque el
+    /// cliente tiene su receive maximum completo, o porque hay mensajes anteriores esperando.
+    fn must_wait_for_quota(
+        &self,
+        client_id: &str,
+        message: &ClientMessage,
+    ) -> Result<bool, ProtocolError> {
+        if !matches!(message, ClientMessage::Publish { qos: 1 | 2, .. })
+            || !self.is_connected(client_id)
+        {
+            return Ok(false);
+        }
 
-                let connect_clone = connect.clone();
-                let reason_code = match Broker::authenticate_client(
-                    connect_clone.properties.authentication_method,
-                    connect_clone.client_id,
-                    connect_clone.username,
-                    connect_clone.password,
-                    clients_auth_info,
-                ) {
-                    Ok(r) => r,
-                    Err(e) => return Err(e),
-                };
+        let has_queued = self
+            .queued_deliveries
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .get(client_id)
+            .is_some_and(|queued| !queued.is_empty());
 
-                if reason_code == 0x00_u8 {
-                    if let Ok(mut clients) = self.clients_ids.write() {
-                        clients.insert(
-                            connect.client_id.clone(),
-                            (Some(client_stream_ref), will_message),
-                        );
-                    } else {
-                        return Err(ProtocolError::WriteError);
-                    }
+        Ok(has_queued || !self.has_send_quota(client_id)?)
+    }
 
-                    match client_id_sender.send(connect.client_id.clone()) {
-                        Ok(_) => (),
-                        Err(e) => return Err(ProtocolError::SendError(e.to_string())),
-                    };
-                }
+    /// Indica si el cliente tiene lugar en su receive maximum para otro Publish in-flight.
+    fn has_send_quota(&self, client_id: &str) -> Result<bool, ProtocolError> {
+        let in_flight = self
+            .in_flight_messages
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .get(client_id)
+            .map_or(0, |messages| messages.len());
+        let flow_control = self
+            .flow_control
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .get(client_id)
+            .copied()
+            .unwrap_or_default();
 
-                let properties = ConnackProperties {
-                    session_expiry_interval: connect.properties.session_expiry_interval,
-                    receive_maximum: connect.properties.receive_maximum,
-                    maximum_packet_size: connect.properties.maximum_packet_size,
-                    topic_alias_maximum: connect.properties.topic_alias_maximum,
-                    user_properties: connect.properties.user_properties,
-                    authentication_method: connect.properties.authentication_method,
-                    authentication_data: connect.properties.authentication_data,
-                    assigned_client_identifier: "none".to_string(),
-                    maximum_qos: true,
-                    reason_string: "none".to_string(),
-                    wildcard_subscription_available: false,
-                    subscription_identifier_available: false,
-                    shared_subscription_available: false,
-                    server_keep_alive: 0,
-                    response_information: "none".to_string(),
-                    server_reference: "none".to_string(),
-                    retain_available: false,
-                };
-                let connack = BrokerMessage::Connack {
-                    session_present: false,
-                    reason_code,
-                    properties,
-                };
-                println!("Sending Connack");
-                match message_to_write_sender.send(connack) {
-                    Ok(_) => return Ok(ProtocolReturn::ConnackSent),
-                    Err(err) => {
-                        println!("{:?}", err);
-                    }
-                }
+        Ok(flow_control.can_send(in_flight))
+    }
+
+    fn queue_delivery(
+        &self,
+        client_id: &str,
+        message: ClientMessage,
+        delivery: BrokerMessage,
+    ) -> Result<(), ProtocolError> {
+        self.queued_deliveries
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .entry(client_id.to_string())
+            .or_default()
+            .push_back((message, delivery, Instant::now()));
+
+        Ok(())
+    }
+
+    /// Envia, en orden, los Publish que esperaban lugar en el receive maximum del cliente.
+    ///
+    /// Se envian con el message expiry interval que les queda, y los que expiraron mientras esperaban se descartan.
+    fn send_queued_deliveries(&self, client_id: &str) -> Result<(), ProtocolError> {
+        while self.has_send_quota(client_id)? {
+            let next = self
+                .queued_deliveries
+                .write()
+                .map_err(|_| ProtocolError::LockError)?
+                .get_mut(client_id)
+                .and_then(|queued| queued.pop_front());
+            let (message, delivery, queued_at) = match next {
+                Some(next) => next,
+                None => return Ok(()),
+            };
+            let (mut message, mut delivery) = match Broker::with_remaining_expiry(
+                message,
+                delivery,
+                queued_at.elapsed().as_secs(),
+            ) {
+                Some(remaining) => remaining,
+                None => continue,
+            };
+
+            let user = Subscription::new(String::new(), client_id.to_string());
+            self.assign_delivery_packet_id(client_id, &mut message, &mut delivery)?;
+            if self.send_message_to_user(&user, &delivery).is_err() {
+                self.queued_deliveries
+                    .write()
+                    .map_err(|_| ProtocolError::LockError)?
+                    .entry(client_id.to_string())
+                    .or_default()
+                    .push_front((message, delivery, Instant::now()));
+                return Ok(());
             }
-            ClientMessage::Publish {
-                packet_id,
-                topic_name,
-                qos,
-                retain_flag,
-                payload,
-                dup_flag,
-                properties,
-            } => {
-                println!("Publish received");
-                let msg = ClientMessage::Publish {
+            self.add_in_flight_message(client_id, &message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Descuenta del message expiry interval de un Publish y del packet con el que se entrega los segundos que
+    /// estuvieron esperando. Devuelve None si el mensaje expiro.
+    fn with_remaining_expiry(
+        message: ClientMessage,
+        mut delivery: BrokerMessage,
+        elapsed_secs: u64,
+    ) -> Option<(ClientMessage, BrokerMessage)> {
+        let message = message.with_remaining_expiry(elapsed_secs)?;
+        if let (
+            ClientMessage::Publish { properties, .. },
+            BrokerMessage::PublishDelivery {
+                properties: delivery_properties,
+                ..
+            },
+        ) = (&message, &mut delivery)
+        {
+            delivery_properties.message_expiry_interval = properties.message_expiry_interval;
+        }
+
+        Some((message, delivery))
+    }
+
+    /// Al recibir el Puback o el Pubcomp de un cliente, el Publish deja de estar in-flight.
+    ///
+    /// Devuelve true si el packet_id correspondia a un mensaje in-flight de ese cliente.
+    fn acknowledge_in_flight_message(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+    ) -> Result<bool, ProtocolError> {
+        let mut in_flight_messages = self
+            .in_flight_messages
+            .write()
+            .map_err(|_| ProtocolError::LockError)?;
+
+        Ok(match in_flight_messages.get_mut(client_id) {
+            Some(messages) => messages.remove(&packet_id).is_some(),
+            None => false,
+        })
+    }
+
+    /// Al recibir el Pubrec de un Publish con QoS 2, en la ventana in-flight se lo reemplaza por su Pubrel,
+    /// que queda esperando el Pubcomp.
+    fn release_in_flight_message(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+    ) -> Result<(), ProtocolError> {
+        let mut in_flight_messages = self
+            .in_flight_messages
+            .write()
+            .map_err(|_| ProtocolError::LockError)?;
+
+        if let Some(messages) = in_flight_messages.get_mut(client_id) {
+            if messages.contains_key(&packet_id) {
+                let pubrel = ClientMessage::Pubrel {
                     packet_id,
-                    topic_name: topic_name.clone(),
-                    qos,
-                    retain_flag,
-                    payload: payload.clone(),
-                    dup_flag,
-                    properties,
+                    reason_code: SUCCESS_HEX,
                 };
-                Broker::save_packet(packets.clone(), msg.clone(), packet_id);
+                messages.insert(packet_id, (pubrel, Instant::now()));
+            }
+        }
 
-                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+        Ok(())
+    }
 
-                let reason_code = self.handle_publish(msg, topics.clone(), topic_name)?;
-                if qos == 1 {
-                    let puback = BrokerMessage::Puback {
-                        packet_id_msb: packet_id_bytes[0],
-                        packet_id_lsb: packet_id_bytes[1],
-                        reason_code,
-                    };
-                    println!("Sending Puback");
-                    match message_to_write_sender.send(puback) {
-                        Ok(_) => {
-                            println!("Puback sent");
-                            return Ok(ProtocolReturn::PubackSent);
-                        }
-                        Err(err) => println!("Error while sending Puback: {:?}", err),
-                    }
-                } else {
-                    return Ok(ProtocolReturn::NoAckSent);
-                }
-            }
-            ClientMessage::Subscribe {
+    /// Arma el packet que se reenvia por un mensaje in-flight: el Publish, o su Pubrel si ya llego el Pubrec.
+    fn convert_in_flight_message(message: &ClientMessage) -> Result<BrokerMessage, ProtocolError> {
+        match message {
+            ClientMessage::Pubrel {
                 packet_id,
-                properties,
-                payload,
+                reason_code,
             } => {
-                println!("Subscribe received");
-                let msg = ClientMessage::Subscribe {
-                    packet_id,
-                    properties: properties.clone(),
-                    payload: payload.clone(),
-                };
-
-                Broker::save_packet(packets.clone(), msg, packet_id);
+                let packet_id_bytes = packet_id.to_be_bytes();
+                Ok(BrokerMessage::Pubrel {
+                    packet_id_msb: packet_id_bytes[0],
+                    packet_id_lsb: packet_id_bytes[1],
+                    reason_code: *reason_code,
+                })
+            }
+            message => Broker::convert_to_broker_message(message),
+        }
+    }
 
-                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+    /// Devuelve los mensajes in-flight que llevan mas de timeout sin ser confirmados, junto al
+    /// client_id al que deben reenviarse. Si se indica un client_id, solo se buscan los de ese cliente.
+    ///
+    /// Los mensajes devueltos quedan marcados como reenvio(dup_flag = 1), y su tiempo de espera vuelve a comenzar.
+    /// Los Publish se reenvian con el message expiry interval que les queda; los que expiraron dejan de estar
+    /// in-flight y no se reenvian.
+    fn take_in_flight_messages_to_resend(
+        &self,
+        client_id: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Vec<(String, ClientMessage)>, ProtocolError> {
+        let mut in_flight_messages = self
+            .in_flight_messages
+            .write()
+            .map_err(|_| ProtocolError::LockError)?;
+        let mut to_resend = Vec::new();
+
+        for (id, messages) in in_flight_messages.iter_mut() {
+            if client_id.is_some_and(|client_id| client_id != id) {
+                continue;
+            }
 
-                let reason_code = Broker::handle_subscribe(
-                    topics.clone(),
-                    payload.topic.clone(),
-                    payload.clone(),
-                )?;
+            messages.retain(|_, (message, sent_at)| {
+                if sent_at.elapsed() < timeout {
+                    return true;
+                }
 
-                let suback = BrokerMessage::Suback {
-                    packet_id_msb: packet_id_bytes[0],
-                    packet_id_lsb: packet_id_bytes[1],
-                    reason_code,
-                };
-                println!("Sending Suback");
-                match message_to_write_sender.send(suback) {
-                    Ok(_) => {
-                        println!("Suback sent");
-                        let _ = ClientConfig::add_new_subscription(
-                            payload.client_id.clone(),
-                            payload.topic.clone(),
-                        );
-                        return Ok(ProtocolReturn::SubackSent);
+                match message
+                    .clone()
+                    .with_remaining_expiry(sent_at.elapsed().as_secs())
+                {
+                    Some(remaining) => {
+                        *message = remaining;
+                        message.mark_as_duplicate();
+                        *sent_at = Instant::now();
+                        to_resend.push((id.clone(), message.clone()));
+                        true
                     }
-                    Err(err) => println!("Error al enviar suback: {:?}", err),
+                    None => false,
                 }
+            });
+        }
 
-                return Ok(ProtocolReturn::SubackSent);
+        Ok(to_resend)
+    }
+
+    /// Reenvia los mensajes in-flight que no fueron confirmados dentro de IN_FLIGHT_TIMEOUT.
+    /// Si el cliente no esta conectado, el mensaje se mantiene in-flight hasta que se reconecte.
+    ///
+    /// Los mensajes in-flight que expiraron liberan su lugar, por lo que luego se envian los que esperaban.
+    fn retransmit_in_flight_messages(&self) -> Result<(), ProtocolError> {
+        for (client_id, message) in
+            self.take_in_flight_messages_to_resend(None, IN_FLIGHT_TIMEOUT)?
+        {
+            let broker_message = Broker::convert_in_flight_message(&message)?;
+            let user = Subscription::new(String::new(), client_id);
+
+            if self.send_message_to_user(&user, &broker_message).is_ok() {
+                println!("Publish in-flight reenviado a {}", user.client_id);
             }
-            ClientMessage::Unsubscribe {
-                packet_id,
-                properties,
-                payload,
-            } => {
-                println!("Recibí un Unsubscribe");
-                let msg = ClientMessage::Unsubscribe {
-                    packet_id,
-                    properties: properties.clone(),
-                    payload: payload.clone(),
-                };
-                Broker::save_packet(packets.clone(), msg, packet_id);
+        }
 
-                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+        let waiting_clients: Vec<String> = self
+            .queued_deliveries
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .keys()
+            .cloned()
+            .collect();
+        for client_id in waiting_clients {
+            if self.is_connected(&client_id) {
+                self.send_queued_deliveries(&client_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Cuando un cliente se reconecta, se le reenvian todos sus Publish in-flight.
+    fn resend_in_flight_messages(
+        &self,
+        client_id: &str,
+        message_to_write_sender: &Sender<BrokerMessage>,
+    ) -> Result<(), ProtocolError> {
+        for (_, message) in
+            self.take_in_flight_messages_to_resend(Some(client_id), Duration::ZERO)?
+        {
+            let broker_message = Broker::convert_in_flight_message(&message)?;
+            message_to_write_sender
+                .send(broker_message)
+                .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Cuando un cliente se reconecta, se le envian en orden los mensajes que recibio mientras estaba desconectado.
+    ///
+    /// Los que no entran en su receive maximum quedan esperando a que confirme los anteriores.
+    fn send_offline_messages(
+        &self,
+        client_id: &str,
+        message_to_write_sender: &Sender<BrokerMessage>,
+    ) -> Result<(), ProtocolError> {
+        let messages = self
+            .with_session_lock(client_id, || {
+                ClientConfig::take_offline_messages(&self.sessions_directory, client_id.to_string())
+            })?
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+
+        for mut message in messages {
+            let mut broker_message = Broker::convert_to_broker_message(&message)?;
+            if !self.fits_in_packet_size(client_id, &broker_message)? {
+                continue;
+            }
+            if self.must_wait_for_quota(client_id, &message)? {
+                self.queue_delivery(client_id, message, broker_message)?;
+                continue;
+            }
+
+            self.assign_delivery_packet_id(client_id, &mut message, &mut broker_message)?;
+            message_to_write_sender
+                .send(broker_message)
+                .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+            self.add_in_flight_message(client_id, &message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Prepara la sesion del cliente que se conecta.
+    ///
+    /// Si clean_start es false y existe una sesion previa que no expiro, se la retoma: se restauran sus
+    /// subscripciones y se devuelve true(session_present). En caso contrario, se descarta la sesion previa
+    /// y se crea una nueva.
+    fn prepare_session(&self, connect: &Connect) -> Result<bool, ProtocolError> {
+        let client_id = connect.client_id.clone();
+        let session_present = !connect.clean_start
+            && ClientConfig::session_is_valid(&self.sessions_directory, client_id.clone());
+
+        // Si la sesion anterior se retoma, su last will pendiente se descarta; si termina, se lo publica.
+        if let Some(will_message) = self.cancel_pending_will(&client_id)? {
+            if !session_present {
+                self.publish_last_will(will_message)?;
+            }
+        }
+
+        if session_present {
+            println!("Resuming session of client {}", client_id);
+            let subscriptions =
+                ClientConfig::get_client_subscriptions(&self.sessions_directory, client_id.clone())
+                    .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+
+            for subscription in subscriptions {
+                self.handle_subscribe(subscription.topic.clone(), subscription)?;
+            }
+        } else {
+            println!("Creating new client");
+            self.clean_session(&client_id)?;
+        }
 
-                let reason_code = Broker::handle_unsubscribe(
-                    topics.clone(),
-                    payload.topic.clone(),
-                    payload.clone(),
+        self.with_session_lock(&client_id, || {
+            if !session_present {
+                ClientConfig::create_client_log_in_json(
+                    &self.sessions_directory,
+                    client_id.clone(),
                 )?;
+            }
+            ClientConfig::start_session(
+                &self.sessions_directory,
+                client_id.clone(),
+                connect.properties.session_expiry_interval,
+            )
+        })?
+        .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
 
-                let unsuback = BrokerMessage::Unsuback {
-                    packet_id_msb: packet_id_bytes[0],
-                    packet_id_lsb: packet_id_bytes[1],
-                    reason_code,
-                };
+        Ok(session_present)
+    }
 
-                println!("Enviando un Unsuback");
-                match message_to_write_sender.send(unsuback) {
-                    Ok(_) => {
-                        println!("Unsuback enviado");
-                        let _ = ClientConfig::remove_subscription(
-                            payload.client_id.clone(),
-                            payload.topic.clone(),
-                        );
-                        return Ok(ProtocolReturn::UnsubackSent);
-                    }
-                    Err(err) => println!("Error al enviar Unsuback: {:?}", err),
-                }
+    /// Descarta las sesiones de los clientes desconectados cuyo session expiry interval vencio: sus
+    /// subscripciones dejan de recibir mensajes.
+    fn remove_expired_sessions(&self) -> Result<(), ProtocolError> {
+        let mut subscriptions = self.wildcard_subscriptions.get_users_from_topic();
+        subscriptions.extend(self.shared_subscriptions.get_users_from_topic());
+        for topic in self
+            .topics
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .values()
+        {
+            subscriptions.extend(topic.get_users_from_topic());
+        }
+
+        let mut expired_clients = HashSet::new();
+        for subscription in subscriptions {
+            if self.is_connected(&subscription.client_id)
+                || ClientConfig::session_is_valid(
+                    &self.sessions_directory,
+                    subscription.client_id.clone(),
+                )
+            {
+                continue;
             }
-            ClientMessage::Disconnect {
-                reason_code: _,
-                session_expiry_interval: _,
-                reason_string,
-                client_id,
-            } => {
-                if let Some(value) = self.handle_disconnect(reason_string, client_id) {
-                    return value;
-                }
 
-                return Ok(ProtocolReturn::DisconnectRecieved);
+            expired_clients.insert(subscription.client_id.clone());
+            self.handle_unsubscribe(subscription.topic.clone(), subscription)?;
+        }
+
+        for client_id in expired_clients {
+            println!("Session of client {} expired", client_id);
+            self.clean_session(&client_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Descarta la sesion previa de un cliente: sus subscripciones, sus mensajes in-flight y su archivo de sesion.
+    fn clean_session(&self, client_id: &str) -> Result<(), ProtocolError> {
+        if let Ok(subscriptions) =
+            ClientConfig::get_client_subscriptions(&self.sessions_directory, client_id.to_string())
+        {
+            for subscription in subscriptions {
+                self.handle_unsubscribe(subscription.topic.clone(), subscription)?;
             }
-            ClientMessage::Pingreq => {
-                println!("Pingreq received");
-                let pingresp = BrokerMessage::Pingresp;
-                println!("Sending Pingresp");
-                match message_to_write_sender.send(pingresp) {
-                    Ok(_) => {
-                        println!("Pingresp sent");
-                        return Ok(ProtocolReturn::PingrespSent);
-                    }
-                    Err(err) => println!("Error sending Pingresp: {:?}", err),
+        }
+
+        self.in_flight_messages
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id);
+        self.delivery_packet_ids
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .remove(client_id);
+
+        self.with_session_lock(client_id, || {
+            ClientConfig::delete_client_file(&self.sessions_directory, client_id.to_string())
+        })?
+    }
+
+    /// Busca el client_id del cliente conectado a traves del stream.
+    fn get_client_id_from_stream(
+        &self,
+        stream: &Arc<BrokerStream>,
+    ) -> Result<Option<String>, ProtocolError> {
+        let clients = self
+            .clients_ids
+            .read()
+            .map_err(|_| ProtocolError::LockError)?;
+
+        for (client_id, (client_stream, _)) in clients.iter() {
+            if let Some(client_stream) = client_stream {
+                if Arc::ptr_eq(client_stream, stream) {
+                    return Ok(Some(client_id.clone()));
                 }
             }
-            ClientMessage::Auth {
-                reason_code: _,
-                authentication_method,
-                authentication_data,
-                reason_string,
-                user_properties,
-            } => {
-                if let Some(value) = Broker::handle_auth(
-                    authentication_method,
-                    user_properties,
-                    authentication_data,
-                    reason_string,
-                    message_to_write_sender,
-                ) {
-                    return value;
-                }
+        }
+
+        Ok(None)
+    }
+
+    /// Si el publish tiene el retain_flag seteado, se reemplaza el retained message del topic.
+    /// Un publish retenido con payload vacio elimina el retained message.
+    fn handle_retained_message(topic: &Topic, message: &ClientMessage) {
+        if let ClientMessage::Publish {
+            retain_flag,
+            payload,
+            ..
+        } = message
+        {
+            if *retain_flag != 1 {
+                return;
+            }
+
+            if payload.is_empty() {
+                topic.set_retained_message(None);
+            } else {
+                topic.set_retained_message(Some(message.clone()));
+            }
+        }
+    }
+
+    /// Devuelve los retained messages de todos los topics que coinciden con el topic filter.
+    fn get_retained_messages(
+        &self,
+        topic_filter: &str,
+    ) -> Result<Vec<ClientMessage>, ProtocolError> {
+        let topics = self.topics.read().map_err(|_| ProtocolError::LockError)?;
+        let mut retained_messages = Vec::new();
+
+        for (topic_name, topic) in topics.iter() {
+            if !Topic::filter_matches(topic_filter, topic_name) {
+                continue;
+            }
+
+            if let Some(message) = topic.get_retained_message() {
+                retained_messages.push(message);
+            }
+        }
+
+        Ok(retained_messages)
+    }
+
+    /// Entrega un retained message a un cliente que acaba de subscribirse, igual que cualquier otro Publish:
+    /// se descarta si supera su maximum packet size, espera si su receive maximum esta completo, y queda
+    /// in-flight hasta que lo confirme.
+    ///
+    /// Los retained messages se envian con el retain_flag seteado, sin importar las opciones de la subscripcion.
+    fn deliver_retained_message(
+        &self,
+        subscription: &Subscription,
+        retained: &ClientMessage,
+        message_to_write_sender: &Sender<BrokerMessage>,
+    ) -> Result<(), ProtocolError> {
+        let client_id = &subscription.client_id;
+        let mut message = Broker::message_for_subscriber(retained, subscription);
+        let mut delivery = Broker::convert_to_delivery(&message, subscription)?;
+        if let BrokerMessage::PublishDelivery { retain_flag, .. } = &mut delivery {
+            *retain_flag = 1;
+        }
+
+        if !self.fits_in_packet_size(client_id, &delivery)? {
+            println!(
+                "Retained message descartado para {}: supera su maximum packet size",
+                client_id
+            );
+            return Ok(());
+        }
+        if self.must_wait_for_quota(client_id, &message)? {
+            return self.queue_delivery(client_id, message, delivery);
+        }
+
+        self.assign_delivery_packet_id(client_id, &mut message, &mut delivery)?;
+        match message_to_write_sender.send(delivery) {
+            Ok(_) => self.add_in_flight_message(client_id, &message),
+            Err(err) => {
+                println!("Error al enviar retained message: {:?}", err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Maneja la subscripcion de un cliente a un topic.
+    /// Devuelve el reason code correspondiente a si la subscripcion fue exitosa o no.
+    /// Si el reason code es 0, el cliente se ha suscrito exitosamente.
+    ///
+    /// Si el topic filter contiene wildcards, la subscripcion se guarda en wildcard_subscriptions.
+    /// Un topic filter invalido devuelve el reason code 0x8F(Topic Filter invalid).
+    ///
+    /// Si el topic no existe se lo crea, siempre que la politica de topics lo permita.
+    fn handle_subscribe(
+        &self,
+        topic_name: String,
+        subscription: Subscription,
+    ) -> Result<u8, ProtocolError> {
+        if !self.is_authorized(&subscription.client_id, AclAction::Subscribe, &topic_name)? {
+            return Ok(NOT_AUTHORIZED_HEX);
+        }
+
+        if Topic::is_shared_filter(&topic_name) {
+            return self.handle_shared_subscribe(&topic_name, subscription);
+        }
+
+        if !Topic::is_valid_filter(&topic_name) {
+            return Ok(TOPIC_FILTER_INVALID_HEX);
+        }
+
+        if !self.topic_policy.is_allowed(&topic_name) {
+            return Ok(NOT_AUTHORIZED_HEX);
+        }
+
+        let reason_code;
+        if Topic::is_wildcard_filter(&topic_name) {
+            let mut wildcard_subscriptions = self.wildcard_subscriptions.clone();
+            reason_code = wildcard_subscriptions.add_user_to_topic(subscription);
+        } else if let Some(mut topic) = self.get_or_create_topic(&topic_name)? {
+            match topic.add_user_to_topic(subscription.clone()) {
+                0 => {
+                    reason_code = SUCCESS_HEX;
+                }
+                0x92 => {
+                    reason_code = SUB_ID_DUP_HEX;
+                }
+                _ => {
+                    reason_code = UNSPECIFIED_ERROR_HEX;
+                }
+            }
+        } else {
+            reason_code = UNSPECIFIED_ERROR_HEX;
+        }
+
+        Ok(reason_code)
+    }
+
+    /// Indica si el cliente ya tiene una subscripcion al mismo topic filter.
+    fn has_subscription(&self, subscription: &Subscription) -> Result<bool, ProtocolError> {
+        if Topic::is_shared_filter(&subscription.topic) {
+            return Ok(self.shared_subscriptions.has_subscription(subscription));
+        }
+        if Topic::is_wildcard_filter(&subscription.topic) {
+            return Ok(self.wildcard_subscriptions.has_subscription(subscription));
+        }
+
+        let topics = self.topics.read().map_err(|_| ProtocolError::LockError)?;
+        Ok(topics
+            .get(&subscription.topic)
+            .is_some_and(|topic| topic.has_subscription(subscription)))
+    }
+
+    /// Decide si al subscribirse se deben enviar los retained messages, segun el Retain Handling de la subscripcion:
+    /// 0 siempre, 1 solo si la subscripcion es nueva, y 2 nunca.
+    /// Las shared subscriptions no reciben retained messages.
+    fn should_send_retained_messages(
+        subscription: &Subscription,
+        is_new_subscription: bool,
+    ) -> bool {
+        if Topic::is_shared_filter(&subscription.topic) {
+            return false;
+        }
+
+        match subscription.options.retain_handling {
+            0 => true,
+            1 => is_new_subscription,
+            _ => false,
+        }
+    }
+
+    /// Subscribe a un cliente a una shared subscription. La politica de topics se aplica sobre su topic filter.
+    fn handle_shared_subscribe(
+        &self,
+        topic_name: &str,
+        subscription: Subscription,
+    ) -> Result<u8, ProtocolError> {
+        let filter = match Topic::parse_shared_filter(topic_name) {
+            Some((_, filter)) => filter,
+            None => return Ok(TOPIC_FILTER_INVALID_HEX),
+        };
+
+        if !self.topic_policy.is_allowed(filter) {
+            return Ok(NOT_AUTHORIZED_HEX);
+        }
+
+        let mut shared_subscriptions = self.shared_subscriptions.clone();
+        Ok(shared_subscriptions.add_user_to_topic(subscription))
+    }
+
+    /// Maneja la desubscripcion de un cliente a un topic
+    /// Devuelve el reason code correspondiente a si la desubscripcion fue exitosa o no
+    /// Si el reason code es 0, el cliente se ha desuscrito exitosamente.
+    /// Si el cliente no estaba subscripto al topic filter, devuelve 0x11(No subscription existed).
+    fn handle_unsubscribe(
+        &self,
+        topic_name: String,
+        subscription: Subscription,
+    ) -> Result<u8, ProtocolError> {
+        let reason_code;
+        let mut topics = self.topics.write().map_err(|_| ProtocolError::LockError)?;
+
+        if Topic::is_shared_filter(&topic_name) {
+            let mut shared_subscriptions = self.shared_subscriptions.clone();
+            reason_code = shared_subscriptions.remove_user_from_topic(subscription);
+        } else if Topic::is_wildcard_filter(&topic_name) {
+            let mut wildcard_subscriptions = self.wildcard_subscriptions.clone();
+            reason_code = wildcard_subscriptions.remove_user_from_topic(subscription);
+        } else if let Some(topic) = topics.get_mut(&topic_name) {
+            match topic.remove_user_from_topic(subscription.clone()) {
+                SUCCESS_HEX => {
+                    println!("Unsubscribe successfull");
+                    reason_code = SUCCESS_HEX;
+                }
+                NO_SUBSCRIPTION_EXISTED_HEX => reason_code = NO_SUBSCRIPTION_EXISTED_HEX,
+                _ => {
+                    println!("Non specified error");
+                    reason_code = UNSPECIFIED_ERROR_HEX;
+                }
+            }
+        } else {
+            reason_code = NO_SUBSCRIPTION_EXISTED_HEX;
+        }
+        println!("Reason Code {:?}", reason_code);
+
+        Ok(reason_code)
+    }
+
+    ///Se toma un packet con su respectivo ID y se lo guarda en el hashmap de mensajes que tiene el Broker.
+    fn save_packet(
+        packets: Arc<RwLock<HashMap<u16, ClientMessage>>>,
+        message: ClientMessage,
+        packet_id: u16,
+    ) {
+        let mut lock = match packets.write() {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+
+        lock.insert(packet_id, message);
+    }
+
+    /// Envia el mensaje de Last Will al cliente.
+    ///
+    /// Se encarga de la logica necesaria segun los parametros del Last Will y sus properties
+    ///
+    /// Si hay un delay en el envio del mensaje (delay_interval), se encarga de esperar el tiempo correspondiente.
+    ///
+    /// Convierte el mensaje en un Publish y lo envia al broker.
+    fn get_last_will_message(will_message: &LastWill) -> ClientMessage {
+        let will_topic = will_message.get_topic();
+        let message = will_message.get_message();
+        let will_qos = will_message.get_qos();
+        let will_retain = will_message.get_retain();
+
+        let will_payload = PayloadTypes::WillPayload(message.to_string());
+
+        ClientMessage::Publish {
+            packet_id: 0,
+            topic_name: will_topic.to_string(),
+            qos: will_qos as usize,
+            retain_flag: will_retain as usize,
+            payload: will_payload,
+            dup_flag: 0,
+            properties: will_message
+                .get_properties()
+                .clone()
+                .to_publish_properties(),
+        }
+    }
+
+    /// Termina de procesar un Connect, una vez que se conoce el resultado de su autenticacion: si fue exitosa(y el
+    /// cliente esta autorizado), se prepara su sesion. En cualquier caso se le envia el Connack con el reason code.
+    fn handle_connect(
+        &self,
+        mut connect: Connect,
+        mut reason_code: u8,
+        message_to_write_sender: &Sender<BrokerMessage>,
+        client_stream_ref: Arc<BrokerStream>,
+        client_id_sender: &Sender<String>,
+    ) -> Result<ProtocolReturn, ProtocolError> {
+        let will_message = connect.clone().give_will_message();
+
+        if reason_code == SUCCESS_HEX {
+            reason_code = self.apply_certificate_identity(&mut connect, &client_stream_ref);
+        }
+        if reason_code == SUCCESS_HEX {
+            reason_code = self.authorize_connection(&connect, &will_message)?;
+        }
+
+        let mut session_present = false;
+        if reason_code == 0x00_u8 {
+            session_present = self.prepare_session(&connect)?;
+
+            if let Ok(mut clients) = self.clients_ids.write() {
+                clients.insert(
+                    connect.client_id.clone(),
+                    (Some(client_stream_ref), will_message),
+                );
+            } else {
+                return Err(ProtocolError::WriteError);
+            }
+            self.topic_aliases
+                .write()
+                .map_err(|_| ProtocolError::LockError)?
+                .insert(
+                    connect.client_id.clone(),
+                    Arc::new(Mutex::new(TopicAliases::new(
+                        connect.properties.topic_alias_maximum,
+                    ))),
+                );
+            self.flow_control
+                .write()
+                .map_err(|_| ProtocolError::LockError)?
+                .insert(
+                    connect.client_id.clone(),
+                    FlowControl::new(
+                        connect.properties.receive_maximum,
+                        connect.properties.maximum_packet_size,
+                    ),
+                );
+
+            match client_id_sender.send(connect.client_id.clone()) {
+                Ok(_) => (),
+                Err(e) => return Err(ProtocolError::SendError(e.to_string())),
+            };
+        }
+
+        let properties = ConnackProperties {
+            session_expiry_interval: connect.properties.session_expiry_interval,
+            receive_maximum: self.receive_maximum,
+            maximum_packet_size: self.maximum_packet_size,
+            topic_alias_maximum: self.topic_alias_maximum,
+            user_properties: connect.properties.user_properties,
+            authentication_method: connect.properties.authentication_method,
+            authentication_data: connect.properties.authentication_data,
+            assigned_client_identifier: String::new(),
+            maximum_qos: true,
+            reason_string: String::new(),
+            wildcard_subscription_available: true,
+            subscription_identifier_available: true,
+            shared_subscription_available: true,
+            server_keep_alive: connect.keep_alive,
+            response_information: String::new(),
+            server_reference: String::new(),
+            retain_available: true,
+        };
+        let connack = BrokerMessage::Connack {
+            session_present,
+            reason_code,
+            properties,
+        };
+        println!("Sending Connack");
+        match message_to_write_sender.send(connack) {
+            Ok(_) => {
+                if reason_code == SUCCESS_HEX {
+                    self.resend_in_flight_messages(&connect.client_id, message_to_write_sender)?;
+                    self.send_offline_messages(&connect.client_id, message_to_write_sender)?;
+                }
+                Ok(ProtocolReturn::ConnackSent)
+            }
+            Err(err) => Err(ProtocolError::SendError(err.to_string())),
+        }
+    }
+
+    /// Lleva a cabo los metodos de autenticacion extendida(como SCRAM-SHA-256) de una conexion.
+    ///
+    /// Un Connect con uno de estos metodos inicia el intercambio, y los Auth siguientes lo continuan: cada paso se
+    /// responde con un Auth(reason code 0x18), hasta que el Authenticator lo da por terminado y se envia el Connack.
+    /// Devuelve None si el mensaje formaba parte del intercambio, o el mismo mensaje si debe procesarse normalmente.
+    fn handle_enhanced_auth(
+        &self,
+        message: ClientMessage,
+        enhanced_auth: &mut Option<(Connect, Box<dyn Authenticator>)>,
+        message_to_write_sender: &Sender<BrokerMessage>,
+        client_stream_ref: &Arc<BrokerStream>,
+        client_id_sender: &Sender<String>,
+    ) -> Result<Option<ClientMessage>, ProtocolError> {
+        let (connect, mut authenticator, authentication_data) = match message {
+            ClientMessage::Connect(connect) => {
+                let factory = match self
+                    .authenticators
+                    .get(&connect.properties.authentication_method)
+                {
+                    Some(factory) => factory,
+                    None => return Ok(Some(ClientMessage::Connect(connect))),
+                };
+                println!("Connect received");
+
+                let authenticator = factory(&connect.client_id);
+                let authentication_data = connect.properties.authentication_data.clone();
+                (connect, authenticator, authentication_data)
+            }
+            ClientMessage::Auth {
+                reason_code: CONTINUE_AUTHENTICATION_HEX,
+                authentication_method,
+                authentication_data,
+                ..
+            } if enhanced_auth.is_some() => {
+                let (connect, authenticator) =
+                    enhanced_auth.take().ok_or(ProtocolError::AuthError)?;
+                if authenticator.method() != authentication_method {
+                    self.handle_connect(
+                        connect,
+                        BAD_AUTHENTICATION_METHOD_HEX,
+                        message_to_write_sender,
+                        Arc::clone(client_stream_ref),
+                        client_id_sender,
+                    )?;
+                    return Ok(None);
+                }
+                (connect, authenticator, authentication_data)
+            }
+            message => return Ok(Some(message)),
+        };
+
+        match authenticator.step(&authentication_data) {
+            AuthStep::Continue(data) => {
+                let auth = BrokerMessage::Auth {
+                    reason_code: CONTINUE_AUTHENTICATION_HEX,
+                    authentication_method: authenticator.method(),
+                    authentication_data: data,
+                    reason_string: String::new(),
+                    user_properties: Vec::new(),
+                };
+                message_to_write_sender
+                    .send(auth)
+                    .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+                *enhanced_auth = Some((connect, authenticator));
+            }
+            AuthStep::Success(data) => {
+                let mut connect = connect;
+                connect.properties.authentication_data = data;
+                self.handle_connect(
+                    connect,
+                    SUCCESS_HEX,
+                    message_to_write_sender,
+                    Arc::clone(client_stream_ref),
+                    client_id_sender,
+                )?;
+            }
+            AuthStep::Failure(reason_code) => {
+                let mut connect = connect;
+                connect.properties.authentication_data = Vec::new();
+                self.handle_connect(
+                    connect,
+                    reason_code,
+                    message_to_write_sender,
+                    Arc::clone(client_stream_ref),
+                    client_id_sender,
+                )?;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Dado un mensaje proveniente de un Client, se conforma y se envia el packet en respuesta.
+    pub fn handle_message(
+        &self,
+        message: ClientMessage,
+        message_to_write_sender: &Sender<BrokerMessage>,
+        client_stream_ref: Arc<BrokerStream>,
+        client_id_sender: Sender<String>,
+    ) -> Result<ProtocolReturn, ProtocolError> {
+        let packets = self.packets.clone();
+
+        match message {
+            ClientMessage::Connect { 0: connect } => {
+                println!("Connect received");
+
+                // Un cliente que presento un certificado valido ya quedo autenticado en el handshake TLS.
+                let connect_clone = connect.clone();
+                let reason_code = match self.client_certificate_name(&client_stream_ref) {
+                    Some(_) => SUCCESS_HEX,
+                    None => Broker::authenticate_client(
+                        connect_clone.properties.authentication_method,
+                        connect_clone.client_id,
+                        connect_clone.username,
+                        connect_clone.password,
+                        &self.clients_auth_info,
+                    )?,
+                };
+                return self.handle_connect(
+                    connect,
+                    reason_code,
+                    message_to_write_sender,
+                    client_stream_ref,
+                    &client_id_sender,
+                );
+            }
+            ClientMessage::Publish {
+                packet_id,
+                topic_name,
+                qos,
+                retain_flag,
+                payload,
+                dup_flag,
+                properties,
+            } => {
+                println!("Publish received");
+                let msg = ClientMessage::Publish {
+                    packet_id,
+                    topic_name: topic_name.clone(),
+                    qos,
+                    retain_flag,
+                    payload: payload.clone(),
+                    dup_flag,
+                    properties,
+                };
+                Broker::save_packet(packets.clone(), msg.clone(), packet_id);
+
+                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+
+                let publisher_id = self.get_client_id_from_stream(&client_stream_ref)?;
+                let reason_code = if qos == 2 {
+                    self.handle_qos2_publish(msg, topic_name, packet_id, publisher_id.as_deref())?
+                } else {
+                    self.handle_publish(msg, topic_name, publisher_id.as_deref())?
+                };
+
+                if qos == 1 {
+                    let puback = BrokerMessage::Puback {
+                        packet_id_msb: packet_id_bytes[0],
+                        packet_id_lsb: packet_id_bytes[1],
+                        reason_code,
+                    };
+                    println!("Sending Puback");
+                    match message_to_write_sender.send(puback) {
+                        Ok(_) => {
+                            println!("Puback sent");
+                            return Ok(ProtocolReturn::PubackSent);
+                        }
+                        Err(err) => println!("Error while sending Puback: {:?}", err),
+                    }
+                } else if qos == 2 {
+                    let pubrec = BrokerMessage::Pubrec {
+                        packet_id_msb: packet_id_bytes[0],
+                        packet_id_lsb: packet_id_bytes[1],
+                        reason_code,
+                    };
+                    println!("Sending Pubrec");
+                    match message_to_write_sender.send(pubrec) {
+                        Ok(_) => return Ok(ProtocolReturn::PubrecSent),
+                        Err(err) => println!("Error while sending Pubrec: {:?}", err),
+                    }
+                } else {
+                    return Ok(ProtocolReturn::NoAckSent);
+                }
+            }
+            ClientMessage::Puback {
+                packet_id,
+                reason_code: _,
+            } => {
+                println!("Puback received");
+                if let Some(client_id) = self.get_client_id_from_stream(&client_stream_ref)? {
+                    self.acknowledge_in_flight_message(&client_id, packet_id)?;
+                    self.send_queued_deliveries(&client_id)?;
+                }
+                return Ok(ProtocolReturn::PubackRecieved);
+            }
+            ClientMessage::Pubrel {
+                packet_id,
+                reason_code: _,
+            } => {
+                println!("Pubrel received");
+                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+                let client_id = self
+                    .get_client_id_from_stream(&client_stream_ref)?
+                    .unwrap_or_default();
+                let pubcomp = BrokerMessage::Pubcomp {
+                    packet_id_msb: packet_id_bytes[0],
+                    packet_id_lsb: packet_id_bytes[1],
+                    reason_code: self.release_qos2_publish(&client_id, packet_id)?,
+                };
+                match message_to_write_sender.send(pubcomp) {
+                    Ok(_) => return Ok(ProtocolReturn::PubcompSent),
+                    Err(err) => println!("Error while sending Pubcomp: {:?}", err),
+                }
+            }
+            ClientMessage::Pubrec {
+                packet_id,
+                reason_code,
+            } => {
+                println!("Pubrec received");
+                let client_id = self.get_client_id_from_stream(&client_stream_ref)?;
+                // Si el subscriptor rechazo el Publish, el flujo termina sin enviar el Pubrel.
+                if reason_code >= UNSPECIFIED_ERROR_HEX {
+                    if let Some(client_id) = client_id {
+                        self.acknowledge_in_flight_message(&client_id, packet_id)?;
+                        self.send_queued_deliveries(&client_id)?;
+                    }
+                    return Ok(ProtocolReturn::NoAckSent);
+                }
+                if let Some(client_id) = &client_id {
+                    self.release_in_flight_message(client_id, packet_id)?;
+                }
+
+                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+                let pubrel = BrokerMessage::Pubrel {
+                    packet_id_msb: packet_id_bytes[0],
+                    packet_id_lsb: packet_id_bytes[1],
+                    reason_code: SUCCESS_HEX,
+                };
+                match message_to_write_sender.send(pubrel) {
+                    Ok(_) => return Ok(ProtocolReturn::PubrelSent),
+                    Err(err) => println!("Error while sending Pubrel: {:?}", err),
+                }
+            }
+            ClientMessage::Pubcomp {
+                packet_id,
+                reason_code: _,
+            } => {
+                println!("Pubcomp received for packet {}", packet_id);
+                if let Some(client_id) = self.get_client_id_from_stream(&client_stream_ref)? {
+                    self.acknowledge_in_flight_message(&client_id, packet_id)?;
+                    self.send_queued_deliveries(&client_id)?;
+                }
+                return Ok(ProtocolReturn::PubcompRecieved);
+            }
+            ClientMessage::Subscribe {
+                packet_id,
+                properties,
+                payload,
+            } => {
+                println!("Subscribe received");
+                let msg = ClientMessage::Subscribe {
+                    packet_id,
+                    properties: properties.clone(),
+                    payload: payload.clone(),
+                };
+
+                Broker::save_packet(packets.clone(), msg, packet_id);
+
+                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+
+                // Cada topic filter se procesa por separado, y el Suback lleva un reason code por cada uno.
+                let mut results = Vec::new();
+                for subscription in payload {
+                    let mut subscription = subscription.clone();
+                    subscription.subscription_identifier = properties.sub_id;
+                    let is_new_subscription = !self.has_subscription(&subscription)?;
+                    // Si la subscripcion se acepta, el reason code es la QoS otorgada.
+                    let reason_code = match self
+                        .handle_subscribe(subscription.topic.clone(), subscription.clone())?
+                    {
+                        SUCCESS_HEX => subscription.options.maximum_qos,
+                        reason_code => reason_code,
+                    };
+                    results.push((subscription, is_new_subscription, reason_code));
+                }
+
+                let suback = BrokerMessage::Suback {
+                    packet_id_msb: packet_id_bytes[0],
+                    packet_id_lsb: packet_id_bytes[1],
+                    reason_codes: results
+                        .iter()
+                        .map(|(_, _, reason_code)| *reason_code)
+                        .collect(),
+                };
+                println!("Sending Suback");
+                match message_to_write_sender.send(suback) {
+                    Ok(_) => {
+                        println!("Suback sent");
+                        for (subscription, is_new_subscription, reason_code) in results {
+                            if reason_code >= UNSPECIFIED_ERROR_HEX {
+                                continue;
+                            }
+
+                            let _ = self.with_session_lock(&subscription.client_id, || {
+                                ClientConfig::add_new_subscription(
+                                    &self.sessions_directory,
+                                    &subscription,
+                                )
+                            });
+
+                            if !Broker::should_send_retained_messages(
+                                &subscription,
+                                is_new_subscription,
+                            ) {
+                                continue;
+                            }
+
+                            for retained in self.get_retained_messages(&subscription.topic)? {
+                                self.deliver_retained_message(
+                                    &subscription,
+                                    &retained,
+                                    &message_to_write_sender,
+                                )?;
+                            }
+                        }
+                        return Ok(ProtocolReturn::SubackSent);
+                    }
+                    Err(err) => println!("Error al enviar suback: {:?}", err),
+                }
+
+                return Ok(ProtocolReturn::SubackSent);
+            }
+            ClientMessage::Unsubscribe {
+                packet_id,
+                properties,
+                payload,
+            } => {
+                println!("Recibí un Unsubscribe");
+                let msg = ClientMessage::Unsubscribe {
+                    packet_id,
+                    properties: properties.clone(),
+                    payload: payload.clone(),
+                };
+                Broker::save_packet(packets.clone(), msg, packet_id);
+
+                let packet_id_bytes: [u8; 2] = packet_id.to_be_bytes();
+
+                let mut reason_codes = Vec::new();
+                for subscription in &payload {
+                    reason_codes.push(
+                        self.handle_unsubscribe(subscription.topic.clone(), subscription.clone())?,
+                    );
+                }
+
+                let unsuback = BrokerMessage::Unsuback {
+                    packet_id_msb: packet_id_bytes[0],
+                    packet_id_lsb: packet_id_bytes[1],
+                    reason_codes,
+                };
+
+                println!("Enviando un Unsuback");
+                match message_to_write_sender.send(unsuback) {
+                    Ok(_) => {
+                        println!("Unsuback enviado");
+                        for subscription in payload {
+                            let _ = self.with_session_lock(&subscription.client_id, || {
+                                ClientConfig::remove_subscription(
+                                    &self.sessions_directory,
+                                    subscription.client_id.clone(),
+                                    subscription.topic,
+                                )
+                            });
+                        }
+                        return Ok(ProtocolReturn::UnsubackSent);
+                    }
+                    Err(err) => println!("Error al enviar Unsuback: {:?}", err),
+                }
+            }
+            ClientMessage::Disconnect {
+                reason_code,
+                session_expiry_interval,
+                reason_string,
+                client_id,
+            } => {
+                if let Some(value) = self.handle_disconnect(
+                    reason_code,
+                    reason_string,
+                    client_id,
+                    session_expiry_interval,
+                ) {
+                    return value;
+                }
+
+                return Ok(ProtocolReturn::DisconnectRecieved);
+            }
+            ClientMessage::Pingreq => {
+                println!("Pingreq received");
+                let pingresp = BrokerMessage::Pingresp;
+                println!("Sending Pingresp");
+                match message_to_write_sender.send(pingresp) {
+                    Ok(_) => {
+                        println!("Pingresp sent");
+                        return Ok(ProtocolReturn::PingrespSent);
+                    }
+                    Err(err) => println!("Error sending Pingresp: {:?}", err),
+                }
+            }
+            ClientMessage::Auth {
+                reason_code: _,
+                authentication_method,
+                authentication_data,
+                reason_string,
+                user_properties,
+            } => {
+                if let Some(value) = self.handle_auth(
+                    authentication_method,
+                    user_properties,
+                    authentication_data,
+                    reason_string,
+                    message_to_write_sender,
+                ) {
+                    return value;
+                }
+            }
+        }
+        Err(ProtocolError::UnspecifiedError(
+            "Error: Unkown packet".to_string(),
+        ))
+    }
+
+    /// Al desconectarse el cliente, su sesion se mantiene durante el session expiry interval indicado en el Disconnect.
+    ///
+    /// El last will solo se publica si el cliente lo pide con el reason code DISCONNECT_WITH_WILL_HEX; en cualquier
+    /// otro caso, una desconexion normal lo descarta.
+    fn handle_disconnect(
+        &self,
+        reason_code: u8,
+        reason_string: String,
+        client_id: String,
+        session_expiry_interval: Option<u32>,
+    ) -> Option<Result<ProtocolReturn, ProtocolError>> {
+        println!(
+            "Disconnect received from Client {:?} with reason: {:?}",
+            client_id, reason_string
+        );
+        let client = if let Ok(mut lock) = self.clients_ids.write() {
+            lock.remove(&client_id)
+        } else {
+            return Some(Err(ProtocolError::WriteError));
+        };
+        _ = self.with_session_lock(&client_id, || {
+            ClientConfig::end_session(
+                &self.sessions_directory,
+                client_id.clone(),
+                session_expiry_interval,
+            )
+        });
+        if let Err(e) = self.remove_connection_state(&client_id) {
+            return Some(Err(e));
+        }
+
+        if let Some((_, Some(will_message))) = client {
+            if reason_code == DISCONNECT_WITH_WILL_HEX {
+                if let Err(e) = self.schedule_last_will(&client_id, will_message) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn broker_exit(&self) -> Result<(), ProtocolError> {
+        let clients = self
+            .clients_ids
+            .read()
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        for (client_id, (stream, _)) in clients.iter() {
+            if let Some(stream) = stream {
+                let disconnect = BrokerMessage::Disconnect {
+                    reason_code: 0,
+                    session_expiry_interval: 0,
+                    reason_string: "SERVER_SHUTDOWN".to_string(),
+                    user_properties: Vec::new(),
+                };
+
+                match disconnect.write_to(stream.as_ref()) {
+                    Ok(_) => {
+                        println!("Disconnect sent to {}", client_id);
+                    }
+                    Err(e) => return Err(ProtocolError::UnspecifiedError(e.to_string())),
+                }
+
+                match stream.shutdown() {
+                    Ok(_) => {
+                        println!("Stream closed");
+                    }
+                    Err(e) => return Err(ProtocolError::UnspecifiedError(e.to_string())),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Se encarga de los Auth que no forman parte de un intercambio de autenticacion extendida(ver handle_enhanced_auth).
+    /// Si el metodo es password-based, se devuelve un Ok con un ProtocolReturn::AuthRecieved.
+    /// Si el metodo no es soportado, se devuelve un connack con el reason code 0x8C.(bad auth method)
+    fn handle_auth(
+        &self,
+        authentication_method: String,
+        user_properties: Vec<(String, String)>,
+        authentication_data: Vec<u8>,
+        reason_string: String,
+        message_to_write_sender: &Sender<BrokerMessage>,
+    ) -> Option<Result<ProtocolReturn, ProtocolError>> {
+        println!("Recibi un auth");
+
+        match authentication_method.as_str() {
+            "password-based" => return Some(Ok(ProtocolReturn::AuthRecieved)),
+            _ => {
+                let properties = ConnackProperties {
+                    session_expiry_interval: 0,
+                    receive_maximum: self.receive_maximum,
+                    maximum_packet_size: self.maximum_packet_size,
+                    topic_alias_maximum: 0,
+                    user_properties,
+                    authentication_method,
+                    authentication_data,
+                    assigned_client_identifier: String::new(),
+                    maximum_qos: true,
+                    reason_string,
+                    wildcard_subscription_available: true,
+                    subscription_identifier_available: true,
+                    shared_subscription_available: true,
+                    server_keep_alive: 0,
+                    response_information: String::new(),
+                    server_reference: String::new(),
+                    retain_available: true,
+                };
+
+                let connack = BrokerMessage::Connack {
+                    session_present: false,
+                    reason_code: 0x8C, //Bad auth method
+                    properties,
+                };
+
+                match message_to_write_sender.send(connack) {
+                    Ok(_) => return Some(Ok(ProtocolReturn::ConnackSent)),
+                    Err(err) => {
+                        println!("{:?}", err);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Aca se realiza la autenticacion del cliente. Solo se debe llamar apenas llega un packet del tipo
+    /// Connect.
+    ///
+    /// Le ingresan como parametros el auth_method(solo vamos a soportar el metodo password-based),
+    /// el username, client_id y password que vienen definidos en el packet Connect que envia el usuario.
+    ///
+    /// Devuele en caso exitoso un u8 que representa el reason code del packet Connack que el Broker va a
+    /// enviarle al Client.
+    pub fn authenticate_client(
+        authentication_method: String,
+        client_id: String,
+        username: Option<String>,
+        password: Option<Vec<u8>>,
+        clients_auth_info: &CredentialStore,
+    ) -> Result<u8, ProtocolError> {
+        let mut connack_reason_code = 0x00_u8; //success :D
+
+        match authentication_method.as_str() {
+            "no-auth" => {}
+            "password-based" => {
+                match clients_auth_info.get(&client_id) {
+                    Some(credential) => {
+                        if let (Some(username), Some(password)) = (username, password) {
+                            if credential.verify(&username, &password) {
+                                return Ok(connack_reason_code);
+                            }
+                            connack_reason_code = 0x86_u8; //bad username or password
+                        } else {
+                            connack_reason_code = 0x86_u8;
+                        }
+                    }
+                    None => connack_reason_code = 0x85_u8, //client_id not valid
+                }
+            }
+            _ => connack_reason_code = BAD_AUTHENTICATION_METHOD_HEX,
+        }
+        Ok(connack_reason_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::mqtt::{
+        client_certificate::test_certificates,
+        connect::{connect_properties::ConnectProperties, will_properties::WillProperties},
+        credentials::Credential,
+        publish::publish_properties::{PublishProperties, TopicProperties},
+        reason_code::BAD_USERNAME_OR_PASSWORD_HEX,
+        scram::ScramClient,
+        subscription::SubscriptionOptions,
+    };
+    use std::io::Cursor;
+
+    #[test]
+    fn test_01_topics_are_created_on_demand() -> Result<(), ProtocolError> {
+        let mut broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+
+        assert!(broker.get_or_create_topic("topic_nuevo")?.is_some());
+        assert!(broker.topics.read().unwrap().contains_key("topic_nuevo"));
+
+        broker.set_topic_policy(TopicPolicy::read_policy_file(
+            "./src/monitoring/topics.txt",
+        )?);
+        assert!(broker.get_or_create_topic("incident")?.is_some());
+        assert!(broker.get_or_create_topic("otro_topic_nuevo")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_02_reading_config_files_err() {
+        let topics = Broker::read_topic_policy(&Some("./aca/estan/los/topics".to_string()));
+        let clients_auth_info = Broker::process_clients_file("./ahperoacavanlosclientesno");
+
+        assert!(topics.is_err());
+        assert!(clients_auth_info.is_err());
+    }
+
+    #[test]
+    fn test_03_processing_set_of_args() -> Result<(), ProtocolError> {
+        let args_ok = vec!["0.0.0.0".to_string(), "5000".to_string()];
+        let args_err = vec!["este_port_abrira_tu_corazon".to_string()];
+
+        assert!(Broker::new(args_err).is_err());
+
+        let broker = Broker::new(args_ok)?;
+        assert_eq!(
+            broker.listeners,
+            vec![Listener::new(ListenerProtocol::Tls, "0.0.0.0:5000")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_04_processing_clients_auth_info_ok() -> Result<(), ProtocolError> {
+        let file_path = "./src/monitoring/clients.txt";
+        let clients_auth_info = Broker::process_clients_file(file_path)?;
+
+        let credential = clients_auth_info.get("monitoring_app").unwrap();
+        assert_eq!(credential.get_username(), "monitoreo");
+        assert!(!credential.verify("monitoreo", b"un password cualquiera"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_05_processing_input_commands() -> Result<(), ProtocolError> {
+        let mut broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let good_command = b"shutdown\n";
+        let cursor_one = Cursor::new(good_command);
+
+        let bad_command = b"apagate\n";
+        let cursor_two = Cursor::new(bad_command);
+
+        assert!(broker.process_input_command(cursor_one).is_ok());
+        assert!(broker.process_input_command(cursor_two).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_06_subscribing_with_wildcard_filters() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+
+        let valid_filter = "drones/+/location".to_string();
+        let reason_code = broker.handle_subscribe(
+            valid_filter.clone(),
+            Subscription::new(valid_filter, "monitoring_app".to_string()),
+        )?;
+        assert_eq!(reason_code, SUCCESS_HEX);
+
+        let invalid_filter = "incident/#/resolved".to_string();
+        let reason_code = broker.handle_subscribe(
+            invalid_filter.clone(),
+            Subscription::new(invalid_filter, "monitoring_app".to_string()),
+        )?;
+        assert_eq!(reason_code, TOPIC_FILTER_INVALID_HEX);
+
+        assert_eq!(
+            broker.wildcard_subscriptions.get_users_from_topic().len(),
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_07_retained_messages() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = |payload: PayloadTypes| ClientMessage::Publish {
+            packet_id: 1,
+            topic_name: "drones/7/status".to_string(),
+            qos: 0,
+            retain_flag: 1,
+            payload,
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+
+        let reason_code = broker.handle_publish(
+            publish(PayloadTypes::WillPayload("online".to_string())),
+            "drones/7/status".to_string(),
+            None,
+        )?;
+        assert_eq!(reason_code, SUCCESS_HEX);
+
+        let retained = broker.get_retained_messages("drones/+/status")?;
+        assert_eq!(retained.len(), 1);
+        match &retained[0] {
+            ClientMessage::Publish {
+                retain_flag,
+                payload,
+                ..
+            } => {
+                assert_eq!(*retain_flag, 1);
+                assert_eq!(*payload, PayloadTypes::WillPayload("online".to_string()));
+            }
+            _ => panic!("Se esperaba un Publish"),
+        }
+
+        // Una lista de camaras vacia es un payload con datos: se retiene como cualquier otro.
+        broker.handle_publish(
+            publish(PayloadTypes::CamerasUpdatePayload(Vec::new())),
+            "drones/7/status".to_string(),
+            None,
+        )?;
+        assert_eq!(broker.get_retained_messages("drones/7/status")?.len(), 1);
+
+        // Solo un payload de largo cero elimina el retained message.
+        broker.handle_publish(
+            publish(PayloadTypes::Binary(Vec::new())),
+            "drones/7/status".to_string(),
+            None,
+        )?;
+        assert!(broker.get_retained_messages("drones/7/status")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_08_qos_2_publish_is_distributed_once() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = |payload: PayloadTypes| ClientMessage::Publish {
+            packet_id: 9,
+            topic_name: "incident_resolved".to_string(),
+            qos: 2,
+            retain_flag: 1,
+            payload,
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+        let first = PayloadTypes::WillPayload("primero".to_string());
+        let duplicate = PayloadTypes::WillPayload("duplicado".to_string());
+
+        let reason_code = broker.handle_qos2_publish(
+            publish(first.clone()),
+            "incident_resolved".to_string(),
+            9,
+            None,
+        )?;
+        assert_eq!(reason_code, SUCCESS_HEX);
+
+        // El reenvio con el mismo packet_id no se vuelve a procesar.
+        let reason_code = broker.handle_qos2_publish(
+            publish(duplicate),
+            "incident_resolved".to_string(),
+            9,
+            None,
+        )?;
+        assert_eq!(reason_code, SUCCESS_HEX);
+        assert_eq!(
+            broker.get_retained_messages("incident_resolved")?,
+            vec![publish(first.clone())]
+        );
+
+        assert_eq!(broker.release_qos2_publish("", 9)?, SUCCESS_HEX);
+        assert_eq!(broker.release_qos2_publish("", 9)?, PACKET_ID_NOT_FOUND_HEX);
+
+        // Cada cliente tiene sus propios packet ids: el mismo id de otro publisher es un Publish nuevo.
+        broker.handle_qos2_publish(
+            publish(first),
+            "incident_resolved".to_string(),
+            9,
+            Some("camera_system"),
+        )?;
+        assert!(broker
+            .qos2_pending_releases
+            .read()
+            .unwrap()
+            .contains(&("camera_system".to_string(), 9)));
+        assert_eq!(
+            broker.release_qos2_publish("monitoring_app", 9)?,
+            PACKET_ID_NOT_FOUND_HEX
+        );
+
+        // Al desconectarse el cliente, se descartan sus ids pendientes.
+        broker.remove_connection_state("camera_system")?;
+        assert_eq!(
+            broker.release_qos2_publish("camera_system", 9)?,
+            PACKET_ID_NOT_FOUND_HEX
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_09_in_flight_messages_are_resent_until_acknowledged() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = ClientMessage::Publish {
+            packet_id: 4,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("incendio".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+
+        broker.add_in_flight_message("drone_1", &publish)?;
+        assert!(broker
+            .take_in_flight_messages_to_resend(None, Duration::from_secs(60))?
+            .is_empty());
+
+        let to_resend =
+            broker.take_in_flight_messages_to_resend(Some("drone_1"), Duration::ZERO)?;
+        assert_eq!(to_resend.len(), 1);
+        assert_eq!(to_resend[0].0, "drone_1");
+        assert!(matches!(
+            to_resend[0].1,
+            ClientMessage::Publish { dup_flag: 1, .. }
+        ));
+
+        assert!(!broker.acknowledge_in_flight_message("camera_system", 4)?);
+        assert!(broker.acknowledge_in_flight_message("drone_1", 4)?);
+        assert!(broker
+            .take_in_flight_messages_to_resend(None, Duration::ZERO)?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_10_persistent_sessions() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let client_id = "test_persistent_session".to_string();
+        let connect = |clean_start: bool| {
+            Connect::new(
+                clean_start,
+                false,
+                0,
+                false,
+                35,
+                ConnectProperties::new(
+                    30,
+                    1,
+                    20,
+                    20,
+                    true,
+                    true,
+                    vec![],
+                    "password-based".to_string(),
+                    vec![],
+                ),
+                client_id.clone(),
+                WillProperties::new(0, 0, 0, String::new(), String::new(), vec![], vec![]),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+            )
+        };
+
+        let mut subscription = Subscription::new("incident".to_string(), client_id.clone())
+            .with_options(SubscriptionOptions::new(true, true, 1).with_maximum_qos(1));
+        subscription.subscription_identifier = 5;
+        let topic_users = |broker: &Broker| -> Result<Vec<Subscription>, ProtocolError> {
+            Ok(broker
+                .topics
+                .read()
+                .map_err(|_| ProtocolError::LockError)?
+                .get("incident")
+                .map(|topic| topic.get_users_from_topic())
+                .unwrap_or_default())
+        };
+
+        assert!(!broker.prepare_session(&connect(false))?);
+        broker.handle_subscribe("incident".to_string(), subscription.clone())?;
+        ClientConfig::add_new_subscription(&broker.sessions_directory, &subscription)
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        ClientConfig::end_session(&broker.sessions_directory, client_id.clone(), None)
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+
+        // La sesion se retoma con la subscripcion completa: su QoS, sus opciones y su subscription identifier.
+        let restarted = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        assert!(restarted.prepare_session(&connect(false))?);
+        assert_eq!(topic_users(&restarted)?, vec![subscription.clone()]);
+        assert_eq!(
+            ClientConfig::get_client_subscriptions(&broker.sessions_directory, client_id.clone())
+                .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?,
+            vec![subscription]
+        );
+
+        // Mientras la sesion no expire, sus subscripciones se mantienen aunque el cliente este desconectado.
+        ClientConfig::end_session(&broker.sessions_directory, client_id.clone(), None)
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        restarted.remove_expired_sessions()?;
+        assert_eq!(topic_users(&restarted)?.len(), 1);
+
+        ClientConfig::end_session(&broker.sessions_directory, client_id.clone(), Some(0))
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        restarted.remove_expired_sessions()?;
+        assert!(topic_users(&restarted)?.is_empty());
+        assert!(!ClientConfig::client_exists(
+            &broker.sessions_directory,
+            client_id.clone()
+        ));
+
+        assert!(!broker.prepare_session(&connect(true))?);
+        assert!(ClientConfig::get_client_subscriptions(
+            &broker.sessions_directory,
+            client_id.clone()
+        )
+        .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?
+        .is_empty());
+
+        ClientConfig::delete_client_file(&broker.sessions_directory, client_id)
+    }
+
+    #[test]
+    fn test_11_last_will_is_published_on_abnormal_disconnection() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let client_id = "test_keep_alive".to_string();
+        let will = LastWill::new(
+            "drones/3/status".to_string(),
+            "offline".to_string(),
+            0,
+            true,
+            WillProperties::new(0, 1, 0, String::new(), String::new(), vec![], vec![]),
+        );
+        broker
+            .clients_ids
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .insert(client_id.clone(), (None, Some(will)));
+
+        broker.handle_abnormal_disconnection(&client_id)?;
+
+        assert!(!broker
+            .clients_ids
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .contains_key(&client_id));
+        let retained = broker.get_retained_messages("drones/3/status")?;
+        assert!(matches!(
+            &retained[0],
+            ClientMessage::Publish { payload, .. }
+                if *payload == PayloadTypes::WillPayload("offline".to_string())
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_12_last_will_is_delayed_and_only_sent_on_abnormal_disconnection(
+    ) -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let will = |delay: u32| {
+            LastWill::new(
+                "cameras/status".to_string(),
+                "offline".to_string(),
+                0,
+                true,
+                WillProperties::new(delay, 1, 0, String::new(), String::new(), vec![], vec![]),
+            )
+        };
+        let connect_client = |client_id: &str, will: LastWill| -> Result<(), ProtocolError> {
+            broker
+                .clients_ids
+                .write()
+                .map_err(|_| ProtocolError::LockError)?
+                .insert(client_id.to_string(), (None, Some(will)));
+            ClientConfig::start_session(&broker.sessions_directory, client_id.to_string(), 120)
+                .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))
+        };
+
+        ClientConfig::create_client_log_in_json(
+            &broker.sessions_directory,
+            "camera_system".to_string(),
+        )
+        .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+
+        connect_client("camera_system", will(0))?;
+        broker.handle_disconnect(
+            SUCCESS_HEX,
+            "normal".to_string(),
+            "camera_system".to_string(),
+            Some(0),
+        );
+        assert!(broker.get_retained_messages("cameras/status")?.is_empty());
+
+        connect_client("camera_system", will(60))?;
+        broker.handle_abnormal_disconnection("camera_system")?;
+        broker.publish_expired_wills()?;
+        assert!(broker.get_retained_messages("cameras/status")?.is_empty());
+        assert!(broker.cancel_pending_will("camera_system")?.is_some());
+
+        broker.schedule_last_will("camera_system", will(60))?;
+        if let Some((_, publish_at)) = broker
+            .pending_wills
+            .write()
+            .map_err(|_| ProtocolError::LockError)?
+            .get_mut("camera_system")
+        {
+            *publish_at = Instant::now();
+        }
+        broker.publish_expired_wills()?;
+        assert_eq!(broker.get_retained_messages("cameras/status")?.len(), 1);
+        assert!(broker.cancel_pending_will("camera_system")?.is_none());
+
+        // Si la sesion termina antes que el will delay interval, el last will se publica al terminar la sesion.
+        ClientConfig::end_session(
+            &broker.sessions_directory,
+            "camera_system".to_string(),
+            Some(10),
+        )
+        .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        broker.schedule_last_will("camera_system", will(60))?;
+        let publish_at = broker
+            .pending_wills
+            .read()
+            .map_err(|_| ProtocolError::LockError)?
+            .get("camera_system")
+            .map(|(_, publish_at)| *publish_at);
+        assert!(publish_at
+            .is_some_and(|publish_at| publish_at <= Instant::now() + Duration::from_secs(10)));
+
+        ClientConfig::delete_client_file(&broker.sessions_directory, "camera_system".to_string())
+    }
+
+    #[test]
+    fn test_13_topic_aliases() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = |topic_name: &str, topic_alias: u16| ClientMessage::Publish {
+            packet_id: 5,
+            topic_name: topic_name.to_string(),
+            qos: 0,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("location".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+
+        let mut topic_aliases = TopicAliases::new(broker.topic_alias_maximum);
+        Broker::resolve_topic_alias(publish("drone_locations", 10), &mut topic_aliases)?;
+        let resolved = Broker::resolve_topic_alias(publish("", 10), &mut topic_aliases)?;
+        assert!(matches!(
+            resolved,
+            ClientMessage::Publish { ref topic_name, .. } if topic_name == "drone_locations"
+        ));
+        assert_eq!(
+            Broker::resolve_topic_alias(publish("", 11), &mut topic_aliases),
+            Err(ProtocolError::TopicAliasInvalid)
+        );
+
+        let mut monitoring_app_aliases = TopicAliases::new(5);
+        let delivery = Broker::convert_to_broker_message(&resolved)?;
+        let topic_and_alias = |message: Option<BrokerMessage>| match message {
+            Some(BrokerMessage::PublishDelivery {
+                topic_name,
+                properties,
+                ..
+            }) => (topic_name, properties.topic_properties.topic_alias),
+            _ => panic!("Se esperaba un PublishDelivery"),
+        };
+
+        assert_eq!(
+            topic_and_alias(Broker::apply_topic_alias(
+                &mut monitoring_app_aliases,
+                &delivery
+            )),
+            ("drone_locations".to_string(), 1)
+        );
+        assert_eq!(
+            topic_and_alias(Broker::apply_topic_alias(
+                &mut monitoring_app_aliases,
+                &delivery
+            )),
+            (String::new(), 1)
+        );
+        assert_eq!(
+            topic_and_alias(Broker::apply_topic_alias(
+                &mut TopicAliases::new(0),
+                &delivery
+            )),
+            ("drone_locations".to_string(), 0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_14_shared_subscriptions_are_load_balanced() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let subscribe = |filter: &str, client_id: &str| {
+            broker.handle_subscribe(
+                filter.to_string(),
+                Subscription::new(filter.to_string(), client_id.to_string()),
+            )
+        };
+
+        assert_eq!(
+            subscribe("$share/workers/incident", "worker_1")?,
+            SUCCESS_HEX
+        );
+        assert_eq!(
+            subscribe("$share/workers/incident", "worker_2")?,
+            SUCCESS_HEX
+        );
+        assert_eq!(subscribe("$share/analytics/#", "analytics")?, SUCCESS_HEX);
+        assert_eq!(
+            subscribe("$share//incident", "worker_3")?,
+            TOPIC_FILTER_INVALID_HEX
+        );
+
+        let topic = Topic::new();
+        let receivers = |broker: &Broker| {
+            let mut receivers: Vec<String> = broker
+                .get_matching_subscribers(&topic, "incident")
+                .into_iter()
+                .map(|subscription| subscription.client_id)
+                .collect();
+            receivers.sort();
+            receivers
+        };
+
+        assert_eq!(receivers(&broker), vec!["analytics", "worker_1"]);
+        assert_eq!(receivers(&broker), vec!["analytics", "worker_2"]);
+        assert_eq!(receivers(&broker), vec!["analytics", "worker_1"]);
+
+        broker.handle_unsubscribe(
+            "$share/workers/incident".to_string(),
+            Subscription::new(
+                "$share/workers/incident".to_string(),
+                "worker_1".to_string(),
+            ),
+        )?;
+        assert_eq!(receivers(&broker), vec!["analytics", "worker_2"]);
+        assert_eq!(receivers(&broker), vec!["analytics", "worker_2"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_15_subscription_options_and_identifiers() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = ClientMessage::Publish {
+            packet_id: 3,
+            topic_name: "incident".to_string(),
+            qos: 0,
+            retain_flag: 1,
+            payload: PayloadTypes::WillPayload("incidente".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+        let mut subscription =
+            Subscription::new("incident".to_string(), "monitoring_app".to_string());
+        subscription.subscription_identifier = 7;
+
+        let retain_and_identifier = |message: BrokerMessage| match message {
+            BrokerMessage::PublishDelivery {
+                retain_flag,
+                properties,
+                ..
+            } => (retain_flag, properties.subscription_identifier),
+            _ => panic!("Se esperaba un PublishDelivery"),
+        };
+        assert_eq!(
+            retain_and_identifier(Broker::convert_to_delivery(&publish, &subscription)?),
+            (0, 7)
+        );
+        let retain_as_published = subscription
+            .clone()
+            .with_options(SubscriptionOptions::new(false, true, 0));
+        assert_eq!(
+            retain_and_identifier(Broker::convert_to_delivery(&publish, &retain_as_published)?),
+            (1, 7)
+        );
+
+        assert!(!broker.has_subscription(&subscription)?);
+        broker.handle_subscribe(subscription.topic.clone(), subscription.clone())?;
+        assert!(broker.has_subscription(&subscription)?);
+
+        let with_retain_handling = |retain_handling: u8| {
+            subscription.clone().with_options(SubscriptionOptions::new(
+                false,
+                false,
+                retain_handling,
+            ))
+        };
+        assert!(Broker::should_send_retained_messages(
+            &with_retain_handling(0),
+            false
+        ));
+        assert!(Broker::should_send_retained_messages(
+            &with_retain_handling(1),
+            true
+        ));
+        assert!(!Broker::should_send_retained_messages(
+            &with_retain_handling(1),
+            false
+        ));
+        assert!(!Broker::should_send_retained_messages(
+            &with_retain_handling(2),
+            true
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_16_unsubscribing_from_filters_without_subscription() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let unsubscribe = |filter: &str| {
+            broker.handle_unsubscribe(
+                filter.to_string(),
+                Subscription::new(filter.to_string(), "drone_1".to_string()),
+            )
+        };
+
+        for filter in ["incident", "drones/+/status"] {
+            broker.handle_subscribe(
+                filter.to_string(),
+                Subscription::new(filter.to_string(), "drone_1".to_string()),
+            )?;
+        }
+
+        assert_eq!(unsubscribe("incident")?, SUCCESS_HEX);
+        assert_eq!(unsubscribe("incident")?, NO_SUBSCRIPTION_EXISTED_HEX);
+        assert_eq!(unsubscribe("drones/+/status")?, SUCCESS_HEX);
+        assert_eq!(
+            unsubscribe("attending_incident")?,
+            NO_SUBSCRIPTION_EXISTED_HEX
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_17_flow_control_of_each_client() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = |payload: &str| ClientMessage::Publish {
+            packet_id: 4,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload(payload.to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+        broker
+            .flow_control
+            .write()
+            .unwrap()
+            .insert("drone_1".to_string(), FlowControl::new(1, 128));
+
+        assert!(broker.has_send_quota("drone_1")?);
+        broker.add_in_flight_message("drone_1", &publish("incendio"))?;
+        assert!(!broker.has_send_quota("drone_1")?);
+        assert!(broker.has_send_quota("camera_system")?);
+
+        let small = Broker::convert_to_broker_message(&publish("incendio"))?;
+        let large = Broker::convert_to_broker_message(&publish(&"incendio".repeat(20)))?;
+        assert!(broker.fits_in_packet_size("drone_1", &small)?);
+        assert!(!broker.fits_in_packet_size("drone_1", &large)?);
+        assert!(broker.fits_in_packet_size("camera_system", &large)?);
+
+        // Si el cliente no esta conectado, los mensajes encolados siguen esperando.
+        broker.queue_delivery("drone_1", publish("incendio"), small)?;
+        assert!(broker.acknowledge_in_flight_message("drone_1", 4)?);
+        broker.send_queued_deliveries("drone_1")?;
+        assert_eq!(broker.queued_deliveries.read().unwrap()["drone_1"].len(), 1);
+
+        // Los Publish con QoS 2 ocupan el receive maximum hasta recibir el Pubcomp.
+        let qos_2 = match publish("incendio") {
+            ClientMessage::Publish {
+                topic_name,
+                retain_flag,
+                payload,
+                dup_flag,
+                properties,
+                ..
+            } => ClientMessage::Publish {
+                packet_id: 5,
+                topic_name,
+                qos: 2,
+                retain_flag,
+                payload,
+                dup_flag,
+                properties,
+            },
+            _ => unreachable!(),
+        };
+        broker.add_in_flight_message("drone_1", &qos_2)?;
+        assert!(!broker.has_send_quota("drone_1")?);
+
+        broker.release_in_flight_message("drone_1", 5)?;
+        assert!(!broker.has_send_quota("drone_1")?);
+        let to_resend =
+            broker.take_in_flight_messages_to_resend(Some("drone_1"), Duration::ZERO)?;
+        assert!(matches!(
+            Broker::convert_in_flight_message(&to_resend[0].1)?,
+            BrokerMessage::Pubrel {
+                packet_id_lsb: 5,
+                ..
             }
-        }
-        Err(ProtocolError::UnspecifiedError(
-            "Error: Unkown packet".to_string(),
-        ))
-    }
+        ));
 
-    fn handle_disconnect(
-        &self,
-        reason_string: String,
-        client_id: String,
-    ) -> Option<Result<ProtocolReturn, ProtocolError>> {
-        println!(
-            "Disconnect received from Client {:?} with reason: {:?}",
-            client_id, reason_string
-        );
-        if let Ok(mut lock) = self.clients_ids.write() {
-            lock.remove(&client_id);
-        } else {
-            return Some(Err(ProtocolError::WriteError));
-        }
-        _ = ClientConfig::change_client_state(client_id.clone(), false);
-        None
+        assert!(broker.acknowledge_in_flight_message("drone_1", 5)?);
+        assert!(broker.has_send_quota("drone_1")?);
+
+        Ok(())
     }
 
-    pub fn broker_exit(&self) -> Result<(), ProtocolError> {
-        let clients = self
-            .clients_ids
-            .read()
-            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
-        for (client_id, (stream, _)) in clients.iter() {
-            if let Some(stream) = stream {
-                let disconnect = BrokerMessage::Disconnect {
-                    reason_code: 0,
-                    session_expiry_interval: 0,
-                    reason_string: "SERVER_SHUTDOWN".to_string(),
-                    user_properties: Vec::new(),
-                };
+    #[test]
+    fn test_18_acl_is_enforced_on_publish_and_subscribe() -> Result<(), ProtocolError> {
+        let mut broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        broker.set_acl(Acl::read_acl_file("./src/monitoring/acl.txt")?);
+        broker
+            .client_usernames
+            .write()
+            .unwrap()
+            .insert("7".to_string(), "drone".to_string());
+        let publish = |topic_name: &str| ClientMessage::Publish {
+            packet_id: 1,
+            topic_name: topic_name.to_string(),
+            qos: 0,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("7".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                0,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+        let subscribe = |topic_name: &str| {
+            broker.handle_subscribe(
+                topic_name.to_string(),
+                Subscription::new(topic_name.to_string(), "7".to_string()),
+            )
+        };
 
-                match disconnect.write_to(stream.get_ref()) {
-                    Ok(_) => {
-                        println!("Disconnect sent to {}", client_id);
-                    }
-                    Err(e) => return Err(ProtocolError::UnspecifiedError(e.to_string())),
-                }
+        assert_eq!(
+            broker.handle_publish(
+                publish("single_camera_disconnect"),
+                "single_camera_disconnect".to_string(),
+                Some("7"),
+            )?,
+            NOT_AUTHORIZED_HEX
+        );
+        assert_eq!(
+            broker.handle_publish(
+                publish("drone_locations"),
+                "drone_locations".to_string(),
+                Some("7"),
+            )?,
+            SUCCESS_HEX
+        );
 
-                match stream.get_ref().shutdown(Shutdown::Both) {
-                    Ok(_) => {
-                        println!("Stream closed");
-                    }
-                    Err(e) => return Err(ProtocolError::UnspecifiedError(e.to_string())),
-                }
-            }
-        }
+        assert_eq!(subscribe("drone_locations")?, NOT_AUTHORIZED_HEX);
+        assert_eq!(subscribe("incident")?, SUCCESS_HEX);
+        assert_eq!(subscribe("responses/7")?, SUCCESS_HEX);
 
         Ok(())
     }
 
-    ///Se encarga de la autenticacion del cliente.
-    /// Si el metodo es password-based, se devuelve un Ok con un ProtocolReturn::AuthRecieved.
-    /// Si el metodo no es soportado, se devuelve un connack con el reason code 0x8C.(bad auth method)
-    fn handle_auth(
-        authentication_method: String,
-        user_properties: Vec<(String, String)>,
-        authentication_data: Vec<u8>,
-        reason_string: String,
-        message_to_write_sender: &Sender<BrokerMessage>,
-    ) -> Option<Result<ProtocolReturn, ProtocolError>> {
-        println!("Recibi un auth");
+    #[test]
+    fn test_19_scram_authentication_over_auth_packets() -> Result<(), ProtocolError> {
+        let mut broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let credential = Credential::new("monitoreo".to_string(), b"un password");
+        broker.register_authenticator(
+            SCRAM_SHA_256,
+            Arc::new(move |_: &str| -> Box<dyn Authenticator> {
+                Box::new(ScramServer::new(Some(credential.clone())))
+            }),
+        );
 
-        match authentication_method.as_str() {
-            "password-based" => return Some(Ok(ProtocolReturn::AuthRecieved)),
-            _ => {
-                let properties = ConnackProperties {
-                    session_expiry_interval: 0,
-                    receive_maximum: 0,
-                    maximum_packet_size: 0,
-                    topic_alias_maximum: 0,
-                    user_properties,
-                    authentication_method,
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|_| ProtocolError::StreamError)?;
+        let address = listener
+            .local_addr()
+            .map_err(|_| ProtocolError::StreamError)?;
+        let tcp_stream = TcpStream::connect(address).map_err(|_| ProtocolError::StreamError)?;
+        let server_connection = ServerConnection::new(broker.server_config.clone())
+            .map_err(|_| ProtocolError::StreamError)?;
+        let stream = Arc::new(BrokerStream::Tls(Box::new(StreamOwned::new(
+            server_connection,
+            tcp_stream,
+        ))));
+        let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
+        let (client_id_sender, _client_id_receiver) = mpsc::channel();
+        let client_id = "test_scram_authentication".to_string();
+
+        let authenticate = |password: &[u8]| -> Result<u8, ProtocolError> {
+            let mut client = ScramClient::new("monitoreo".to_string(), password.to_vec());
+            let connect = Connect::new(
+                true,
+                false,
+                0,
+                false,
+                35,
+                ConnectProperties::new(
+                    0,
+                    20,
+                    0,
+                    20,
+                    true,
+                    true,
+                    vec![],
+                    SCRAM_SHA_256.to_string(),
+                    client.initial_data(),
+                ),
+                client_id.clone(),
+                WillProperties::new(0, 0, 0, String::new(), String::new(), vec![], vec![]),
+                String::new(),
+                String::new(),
+                "monitoreo".to_string(),
+                String::new(),
+            );
+            let mut enhanced_auth = None;
+            let mut handle = |message: ClientMessage| {
+                broker.handle_enhanced_auth(
+                    message,
+                    &mut enhanced_auth,
+                    &message_to_write_sender,
+                    &stream,
+                    &client_id_sender,
+                )
+            };
+
+            assert!(handle(ClientMessage::Connect(connect))?.is_none());
+            let server_first = match message_to_write_receiver.try_recv() {
+                Ok(BrokerMessage::Auth {
+                    reason_code: CONTINUE_AUTHENTICATION_HEX,
                     authentication_data,
-                    assigned_client_identifier: "none".to_string(),
-                    maximum_qos: true,
-                    reason_string,
-                    wildcard_subscription_available: false,
-                    subscription_identifier_available: false,
-                    shared_subscription_available: false,
-                    server_keep_alive: 0,
-                    response_information: "none".to_string(),
-                    server_reference: "none".to_string(),
-                    retain_available: false,
-                };
+                    ..
+                }) => authentication_data,
+                other => panic!("Se esperaba un Auth y llego {:?}", other),
+            };
+            let client_final = match client.step(&server_first) {
+                AuthStep::Continue(data) => data,
+                other => panic!("Se esperaba continuar y llego {:?}", other),
+            };
 
-                let connack = BrokerMessage::Connack {
-                    session_present: false,
-                    reason_code: 0x8C, //Bad auth method
+            assert!(handle(ClientMessage::Auth {
+                reason_code: CONTINUE_AUTHENTICATION_HEX,
+                authentication_method: SCRAM_SHA_256.to_string(),
+                authentication_data: client_final,
+                reason_string: String::new(),
+                user_properties: vec![],
+            })?
+            .is_none());
+            match message_to_write_receiver.try_recv() {
+                Ok(BrokerMessage::Connack {
+                    reason_code,
                     properties,
-                };
-
-                match message_to_write_sender.send(connack) {
-                    Ok(_) => return Some(Ok(ProtocolReturn::ConnackSent)),
-                    Err(err) => {
-                        println!("{:?}", err);
+                    ..
+                }) => {
+                    if reason_code == SUCCESS_HEX {
+                        assert_eq!(
+                            client.step(&properties.authentication_data),
+                            AuthStep::Success(vec![])
+                        );
                     }
+                    Ok(reason_code)
                 }
+                other => panic!("Se esperaba un Connack y llego {:?}", other),
             }
+        };
+
+        assert_eq!(
+            authenticate(b"otro password")?,
+            BAD_USERNAME_OR_PASSWORD_HEX
+        );
+        assert_eq!(authenticate(b"un password")?, SUCCESS_HEX);
+
+        ClientConfig::delete_client_file(&broker.sessions_directory, client_id.clone())
+    }
+
+    /// Verificador del certificado del Broker que no controla su vencimiento, para que el test solo dependa de
+    /// la verificacion de los certificados de clientes.
+    #[derive(Debug)]
+    struct AnyServerCertificate(Arc<rustls::crypto::CryptoProvider>);
+
+    impl rustls::client::danger::ServerCertVerifier for AnyServerCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &rustls::pki_types::ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: rustls::pki_types::UnixTime,
+        ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &rustls::DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &rustls::DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
         }
-        None
     }
 
-    /// Aca se realiza la autenticacion del cliente. Solo se debe llamar apenas llega un packet del tipo
-    /// Connect.
-    ///
-    /// Le ingresan como parametros el auth_method(solo vamos a soportar el metodo password-based),
-    /// el username, client_id y password que vienen definidos en el packet Connect que envia el usuario.
-    ///
-    /// Devuele en caso exitoso un u8 que representa el reason code del packet Connack que el Broker va a
-    /// enviarle al Client.
-    pub fn authenticate_client(
-        authentication_method: String,
-        client_id: String,
-        username: Option<String>,
-        password: Option<Vec<u8>>,
-        clients_auth_info: HashMap<String, (String, Vec<u8>)>,
-    ) -> Result<u8, ProtocolError> {
-        let mut connack_reason_code = 0x00_u8; //success :D
+    /// Hace el handshake TLS contra el Broker desde un cliente que presenta el certificado indicado, y devuelve
+    /// el stream del lado del Broker(o None si el handshake fallo).
+    fn mutual_tls_handshake(
+        broker: &Broker,
+        client_certificate: Option<ClientCertificate>,
+    ) -> Result<Option<Arc<BrokerStream>>, ProtocolError> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|_| ProtocolError::StreamError)?;
+        let address = listener
+            .local_addr()
+            .map_err(|_| ProtocolError::StreamError)?;
+
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let builder = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyServerCertificate(provider)));
+        let client_config = match client_certificate {
+            Some(client_certificate) => {
+                let (certificates, private_key) = client_certificate.load()?;
+                builder
+                    .with_client_auth_cert(certificates, private_key)
+                    .map_err(|e| ProtocolError::ClientConnectionError(e.to_string()))?
+            }
+            None => builder.with_no_client_auth(),
+        };
 
-        match authentication_method.as_str() {
-            "no-auth" => {}
-            "password-based" => {
-                match clients_auth_info.get(&client_id) {
-                    Some(value) => {
-                        if let (Some(username), Some(password)) = (username, password) {
-                            if value == &(username, password) {
-                                return Ok(connack_reason_code);
-                            }
-                            connack_reason_code = 0x86_u8; //bad username or password
-                        } else {
-                            connack_reason_code = 0x86_u8;
-                        }
-                    }
-                    None => connack_reason_code = 0x85_u8, //client_id not valid
-                }
+        let client = thread::spawn(move || {
+            let mut tcp_stream = TcpStream::connect(address).ok()?;
+            let server_name = "rustic_city_eye".try_into().ok()?;
+            let mut connection =
+                rustls::ClientConnection::new(Arc::new(client_config), server_name).ok()?;
+            while connection.is_handshaking() {
+                connection.complete_io(&mut tcp_stream).ok()?;
+            }
+            // Se espera a que el Broker lea el final del handshake antes de cerrar la conexion.
+            connection.complete_io(&mut tcp_stream).ok()
+        });
+
+        let (mut tcp_stream, _) = listener.accept().map_err(|_| ProtocolError::StreamError)?;
+        let mut connection = ServerConnection::new(broker.server_config.clone())
+            .map_err(|_| ProtocolError::StreamError)?;
+        let mut handshake_ok = true;
+        while connection.is_handshaking() {
+            if connection.complete_io(&mut tcp_stream).is_err() {
+                handshake_ok = false;
+                break;
             }
-            _ => connack_reason_code = 0x8C_u8,
         }
-        Ok(connack_reason_code)
+        if handshake_ok {
+            connection.send_close_notify();
+            let _ = connection.complete_io(&mut tcp_stream);
+        }
+        let _ = client.join();
+
+        if !handshake_ok {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(BrokerStream::Tls(Box::new(
+            StreamOwned::new(connection, tcp_stream),
+        )))))
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn test_20_mutual_tls_maps_the_certificate_common_name() -> Result<(), ProtocolError> {
+        let directory =
+            tempfile::tempdir().map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        let (ca_file_path, drone_certificate) =
+            test_certificates::generate(directory.path(), "drone");
 
-    use super::*;
-    use std::io::Cursor;
+        let mut broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        broker.require_client_certificates(&ca_file_path, CertificateIdentity::ClientId)?;
 
-    #[test]
-    fn test_01_getting_starting_topics_ok() -> Result<(), ProtocolError> {
-        let file_path = "./src/monitoring/topics.txt";
-        let topics = Broker::get_broker_starting_topics(file_path)?;
+        assert!(mutual_tls_handshake(&broker, None)?.is_none());
 
-        let mut expected_topics = HashMap::new();
-        let topic_readings = Broker::process_topic_config_file(file_path)?;
+        // El certificado del Broker no esta firmado por la CA de los clientes.
+        let broker_certificate = ClientCertificate::new(
+            "./src/mqtt/certs/cert.pem",
+            "./src/mqtt/certs/private_key.pem",
+        );
+        assert!(mutual_tls_handshake(&broker, Some(broker_certificate))?.is_none());
 
-        for topic in topic_readings {
-            expected_topics.insert(topic, Topic::new());
-        }
+        let stream = match mutual_tls_handshake(&broker, Some(drone_certificate.clone()))? {
+            Some(stream) => stream,
+            None => panic!("El handshake con el certificado del drone deberia funcionar"),
+        };
+        assert_eq!(
+            broker.client_certificate_name(&stream),
+            Some("drone".to_string())
+        );
+
+        let mut connect = Connect::read_connect_config("./src/drones/connect_config.json")?;
+        assert_eq!(
+            broker.apply_certificate_identity(&mut connect, &stream),
+            SUCCESS_HEX
+        );
+        connect.client_id = "otro_drone".to_string();
+        assert_eq!(
+            broker.apply_certificate_identity(&mut connect, &stream),
+            NOT_AUTHORIZED_HEX
+        );
+
+        broker.client_certificate_identity = Some(CertificateIdentity::Username);
+        connect.username = Some("monitoreo".to_string());
+        assert_eq!(
+            broker.apply_certificate_identity(&mut connect, &stream),
+            SUCCESS_HEX
+        );
+        assert_eq!(connect.username, Some("drone".to_string()));
+
+        Ok(())
+    }
 
-        for (topic_name, _topic) in topics {
-            assert!(expected_topics.contains_key(&topic_name));
+    /// Levanta un listener del protocolo indicado en un puerto libre, y devuelve su address.
+    fn spawn_listener(
+        broker: &Broker,
+        protocol: ListenerProtocol,
+    ) -> Result<std::net::SocketAddr, ProtocolError> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|_| ProtocolError::StreamError)?;
+        let address = listener
+            .local_addr()
+            .map_err(|_| ProtocolError::StreamError)?;
+
+        let mut broker = broker.clone();
+        thread::spawn(move || {
+            let threadpool = Arc::new(ThreadPool::new(8));
+            broker.listen_for_connections(&listener, protocol, &threadpool)
+        });
+
+        Ok(address)
+    }
+
+    #[test]
+    fn test_21_plain_tcp_and_websocket_listeners() -> Result<(), ProtocolError> {
+        use std::io::{Read, Write};
+
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let io_error = |_| ProtocolError::StreamError;
+
+        // Por TCP los packets viajan tal cual.
+        let address = spawn_listener(&broker, ListenerProtocol::Tcp)?;
+        let mut tcp_stream = TcpStream::connect(address).map_err(io_error)?;
+        tcp_stream.write_all(&[0xC0, 0x00]).map_err(io_error)?;
+        let mut pingresp = [0u8; 2];
+        tcp_stream.read_exact(&mut pingresp).map_err(io_error)?;
+        assert_eq!(pingresp, [0xD0, 0x00]);
+
+        // Ni un cliente que no envia el handshake ni uno que no pide el subprotocolo mqtt detienen al
+        // listener de WebSocket.
+        let address = spawn_listener(&broker, ListenerProtocol::WebSocket)?;
+        let _idle = TcpStream::connect(address).map_err(io_error)?;
+        let mut rejected = TcpStream::connect(address).map_err(io_error)?;
+        rejected
+            .write_all(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\n\r\n")
+            .map_err(io_error)?;
+        let mut response = String::new();
+        let _ = rejected.read_to_string(&mut response);
+        assert!(response.starts_with("HTTP/1.1 400"));
+
+        let mut ws_stream = TcpStream::connect(address).map_err(io_error)?;
+        ws_stream
+            .write_all(
+                b"GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\nSec-WebSocket-Protocol: mqtt\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .map_err(io_error)?;
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            ws_stream.read_exact(&mut byte).map_err(io_error)?;
+            response.push(byte[0]);
         }
+        let response = String::from_utf8_lossy(&response).to_string();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: HSmrc0sMlYUkAGmm5OPpG2HaGWk="));
+
+        // Pingreq en un frame binario enmascarado.
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x82, 0x82];
+        frame.extend_from_slice(&mask);
+        frame.extend_from_slice(&[0xC0 ^ 1, 2]);
+        ws_stream.write_all(&frame).map_err(io_error)?;
+
+        let mut pingresp = [0u8; 4];
+        ws_stream.read_exact(&mut pingresp).map_err(io_error)?;
+        assert_eq!(pingresp, [0x82, 0x02, 0xD0, 0x00]);
 
         Ok(())
     }
 
     #[test]
-    fn test_02_reading_config_files_err() {
-        let topics: Result<HashMap<String, Topic>, ProtocolError> =
-            Broker::get_broker_starting_topics("./aca/estan/los/topics");
-        let clients_auth_info = Broker::process_clients_file("./ahperoacavanlosclientesno");
+    fn test_22_client_certificates_require_tls_listeners() -> Result<(), ProtocolError> {
+        let directory =
+            tempfile::tempdir().map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        let (ca_file_path, _) = test_certificates::generate(directory.path(), "drone");
 
-        assert!(topics.is_err());
-        assert!(clients_auth_info.is_err());
+        let mut broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        broker.require_client_certificates(&ca_file_path, CertificateIdentity::ClientId)?;
+        broker.set_listeners(vec![
+            Listener::new(ListenerProtocol::Tls, "127.0.0.1:0"),
+            Listener::new(ListenerProtocol::Tcp, "127.0.0.1:0"),
+        ]);
+        assert!(matches!(
+            broker.server_run(),
+            Err(ProtocolError::ServerConfigError(_))
+        ));
+
+        // Un cliente sin certificado no puede tomar ningun client_id.
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|_| ProtocolError::StreamError)?;
+        let address = listener
+            .local_addr()
+            .map_err(|_| ProtocolError::StreamError)?;
+        let _client = TcpStream::connect(address).map_err(|_| ProtocolError::StreamError)?;
+        let (stream, _) = listener.accept().map_err(|_| ProtocolError::StreamError)?;
+        let stream = Arc::new(BrokerStream::Tcp(stream));
+
+        let mut connect = Connect::read_connect_config("./src/drones/connect_config.json")?;
+        assert_eq!(
+            broker.apply_certificate_identity(&mut connect, &stream),
+            NOT_AUTHORIZED_HEX
+        );
+
+        Ok(())
     }
 
     #[test]
-    fn test_03_processing_set_of_args() -> Result<(), ProtocolError> {
-        let args_ok = vec!["0.0.0.0".to_string(), "5000".to_string()];
-        let args_err = vec!["este_port_abrira_tu_corazon".to_string()];
+    fn test_23_deliveries_use_subscriber_packet_ids_and_granted_qos() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = ClientMessage::Publish {
+            packet_id: 40,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("incendio".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+
+        // Cada subscriptor recibe el Publish con la menor QoS entre la publicada y la otorgada.
+        let qos_0 = Subscription::new("incident".to_string(), "drone_1".to_string())
+            .with_options(SubscriptionOptions::default().with_maximum_qos(0));
+        let mut message = Broker::message_for_subscriber(&publish, &qos_0);
+        let mut delivery = Broker::convert_to_delivery(&message, &qos_0)?;
+        broker.assign_delivery_packet_id("drone_1", &mut message, &mut delivery)?;
+        assert!(matches!(
+            message,
+            ClientMessage::Publish {
+                packet_id: 0,
+                qos: 0,
+                ..
+            }
+        ));
+        assert!(matches!(
+            delivery,
+            BrokerMessage::PublishDelivery {
+                packet_id: 0,
+                qos: 0,
+                ..
+            }
+        ));
+
+        // Los packet ids son propios de cada subscriptor, y no se repiten mientras esten in-flight.
+        let qos_2 = Subscription::new("incident".to_string(), "drone_2".to_string());
+        let mut message = Broker::message_for_subscriber(&publish, &qos_2);
+        let mut delivery = Broker::convert_to_delivery(&message, &qos_2)?;
+        broker.assign_delivery_packet_id("drone_2", &mut message, &mut delivery)?;
+        assert!(matches!(
+            message,
+            ClientMessage::Publish {
+                packet_id: 1,
+                qos: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            delivery,
+            BrokerMessage::PublishDelivery { packet_id: 1, .. }
+        ));
+        broker.add_in_flight_message("drone_2", &message)?;
+        assert_eq!(broker.next_delivery_packet_id("camera_system", 1)?, 1);
+
+        broker
+            .delivery_packet_ids
+            .write()
+            .unwrap()
+            .insert("drone_2".to_string(), u16::MAX);
+        assert_eq!(broker.next_delivery_packet_id("drone_2", 1)?, 2);
+        assert_eq!(broker.next_delivery_packet_id("drone_2", 0)?, 0);
+
+        Ok(())
+    }
 
-        let processing_good_args_result = Broker::process_starting_args(args_ok);
-        let processing_bad_args_result = Broker::process_starting_args(args_err);
+    #[test]
+    fn test_24_inbound_qos2_publishes_respect_the_receive_maximum() -> Result<(), ProtocolError> {
+        let broker = Broker::from_config(BrokerConfig {
+            receive_maximum: 2,
+            ..BrokerConfig::default()
+        })?;
+        let publish = |packet_id: u16, qos: usize| ClientMessage::Publish {
+            packet_id,
+            topic_name: "incident".to_string(),
+            qos,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("incendio".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
 
-        assert!(processing_bad_args_result.is_err());
-        assert!(processing_good_args_result.is_ok());
+        for packet_id in [1, 2] {
+            assert!(!broker.exceeds_receive_maximum("drone_1", &publish(packet_id, 2))?);
+            broker.handle_qos2_publish(
+                publish(packet_id, 2),
+                "incident".to_string(),
+                packet_id,
+                Some("drone_1"),
+            )?;
+        }
 
-        let resulting_address = processing_good_args_result.unwrap();
+        assert!(broker.exceeds_receive_maximum("drone_1", &publish(3, 2))?);
+        // Los reenvios, los Publish con QoS 1 y los de otros clientes no cuentan.
+        assert!(!broker.exceeds_receive_maximum("drone_1", &publish(2, 2))?);
+        assert!(!broker.exceeds_receive_maximum("drone_1", &publish(3, 1))?);
+        assert!(!broker.exceeds_receive_maximum("drone_2", &publish(3, 2))?);
 
-        assert_eq!(resulting_address, "0.0.0.0:5000".to_string());
+        broker.release_qos2_publish("drone_1", 1)?;
+        assert!(!broker.exceeds_receive_maximum("drone_1", &publish(3, 2))?);
 
         Ok(())
     }
 
     #[test]
-    fn test_04_processing_clients_auth_info_ok() -> Result<(), ProtocolError> {
-        let file_path = "./src/monitoring/clients.txt";
-        let clients_auth_info = Broker::process_clients_file(file_path)?;
+    fn test_25_expired_messages_are_not_resent_nor_released() -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = |packet_id: u16, message_expiry_interval: u32| ClientMessage::Publish {
+            packet_id,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("incendio".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                message_expiry_interval,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+        let twenty_seconds_ago = Instant::now() - Duration::from_secs(20);
+
+        broker.in_flight_messages.write().unwrap().insert(
+            "drone_1".to_string(),
+            HashMap::from([
+                (1, (publish(1, 10), twenty_seconds_ago)),
+                (2, (publish(2, 60), twenty_seconds_ago)),
+                (3, (publish(3, 0), twenty_seconds_ago)),
+            ]),
+        );
 
-        let file = match File::open(file_path) {
-            Ok(file) => file,
-            Err(_) => return Err(ProtocolError::ReadingClientsFileError),
+        // El que expiro deja la ventana in-flight, y el resto se reenvia con el tiempo de vida que le queda.
+        let mut to_resend =
+            broker.take_in_flight_messages_to_resend(Some("drone_1"), Duration::ZERO)?;
+        to_resend.sort_by_key(|(_, message)| match message {
+            ClientMessage::Publish { packet_id, .. } => *packet_id,
+            _ => 0,
+        });
+        assert_eq!(to_resend.len(), 2);
+        assert!(matches!(
+            &to_resend[0].1,
+            ClientMessage::Publish { packet_id: 2, properties, .. }
+                if properties.message_expiry_interval == 40
+        ));
+        assert!(matches!(
+            &to_resend[1].1,
+            ClientMessage::Publish { packet_id: 3, properties, .. }
+                if properties.message_expiry_interval == 0
+        ));
+        assert!(!broker.acknowledge_in_flight_message("drone_1", 1)?);
+
+        // Los que expiran mientras esperan lugar en el receive maximum se descartan.
+        for (packet_id, message_expiry_interval) in [(4, 10), (5, 60)] {
+            let message = publish(packet_id, message_expiry_interval);
+            let delivery = Broker::convert_to_broker_message(&message)?;
+            broker
+                .queued_deliveries
+                .write()
+                .unwrap()
+                .entry("camera_system".to_string())
+                .or_default()
+                .push_back((message, delivery, twenty_seconds_ago));
+        }
+        broker.send_queued_deliveries("camera_system")?;
+
+        let queued = &broker.queued_deliveries.read().unwrap()["camera_system"];
+        assert_eq!(queued.len(), 1);
+        assert!(matches!(
+            &queued[0].1,
+            BrokerMessage::PublishDelivery { properties, .. }
+                if properties.message_expiry_interval == 40
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_26_retained_messages_respect_the_flow_control_of_the_subscriber(
+    ) -> Result<(), ProtocolError> {
+        let broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
+        let publish = |topic_name: &str, payload: &str| ClientMessage::Publish {
+            packet_id: 6,
+            topic_name: topic_name.to_string(),
+            qos: 1,
+            retain_flag: 1,
+            payload: PayloadTypes::WillPayload(payload.to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
         };
+        for (topic_name, payload) in [
+            ("drones/1/status", "activo".to_string()),
+            ("drones/2/status", "activo".to_string()),
+            ("drones/3/status", "activo".repeat(30)),
+        ] {
+            broker.handle_publish(publish(topic_name, &payload), topic_name.to_string(), None)?;
+        }
 
-        let expected_clients = Broker::read_clients_file(&file)?;
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|_| ProtocolError::StreamError)?;
+        let address = listener
+            .local_addr()
+            .map_err(|_| ProtocolError::StreamError)?;
+        let tcp_stream = TcpStream::connect(address).map_err(|_| ProtocolError::StreamError)?;
+        broker.clients_ids.write().unwrap().insert(
+            "drone_1".to_string(),
+            (Some(Arc::new(BrokerStream::Tcp(tcp_stream))), None),
+        );
+        broker
+            .flow_control
+            .write()
+            .unwrap()
+            .insert("drone_1".to_string(), FlowControl::new(1, 128));
 
-        for (client_id, _auth_info) in clients_auth_info {
-            assert!(expected_clients.contains_key(&client_id));
+        let subscription = Subscription::new("drones/+/status".to_string(), "drone_1".to_string());
+        let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
+        for retained in broker.get_retained_messages(&subscription.topic)? {
+            broker.deliver_retained_message(&subscription, &retained, &message_to_write_sender)?;
         }
 
+        // Se envia uno solo, que queda in-flight; el otro espera lugar y el que supera el maximum packet size se descarta.
+        let sent: Vec<BrokerMessage> = message_to_write_receiver.try_iter().collect();
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(
+            sent[0],
+            BrokerMessage::PublishDelivery {
+                packet_id: 1,
+                retain_flag: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            broker.in_flight_messages.read().unwrap()["drone_1"].len(),
+            1
+        );
+        assert_eq!(broker.queued_deliveries.read().unwrap()["drone_1"].len(), 1);
+
         Ok(())
     }
 
     #[test]
-    fn test_05_processing_input_commands() -> Result<(), ProtocolError> {
-        let mut broker = Broker::new(vec!["127.0.0.1".to_string(), "5000".to_string()])?;
-        let good_command = b"shutdown\n";
-        let cursor_one = Cursor::new(good_command);
+    fn test_27_concurrent_offline_messages_are_not_lost() -> Result<(), ProtocolError> {
+        let directory =
+            tempfile::tempdir().map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        let broker = Broker::from_config(BrokerConfig {
+            sessions_directory: directory.path().to_string_lossy().to_string(),
+            ..BrokerConfig::default()
+        })?;
+        let publish = |payload: String| ClientMessage::Publish {
+            packet_id: 7,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload(payload),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                0,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
 
-        let bad_command = b"apagate\n";
-        let cursor_two = Cursor::new(bad_command);
+        let session_error =
+            |e: Box<dyn std::error::Error>| ProtocolError::UnspecifiedError(e.to_string());
+        ClientConfig::create_client_log_in_json(&broker.sessions_directory, "drone_1".to_string())
+            .map_err(session_error)?;
+        ClientConfig::start_session(&broker.sessions_directory, "drone_1".to_string(), 120)
+            .map_err(session_error)?;
+        ClientConfig::end_session(&broker.sessions_directory, "drone_1".to_string(), None)
+            .map_err(session_error)?;
+        broker.handle_subscribe(
+            "incident".to_string(),
+            Subscription::new("incident".to_string(), "drone_1".to_string()),
+        )?;
 
-        assert!(broker.process_input_command(cursor_one).is_ok());
-        assert!(broker.process_input_command(cursor_two).is_err());
+        // Cada Publish para el cliente desconectado se guarda en su sesion, aunque lleguen a la vez.
+        let publishers: Vec<_> = (0..4)
+            .map(|publisher| {
+                let broker = broker.clone();
+                thread::spawn(move || {
+                    for message in 0..5 {
+                        let payload = format!("{}-{}", publisher, message);
+                        broker.handle_publish(publish(payload), "incident".to_string(), None)?;
+                    }
+                    Ok::<(), ProtocolError>(())
+                })
+            })
+            .collect();
+        for publisher in publishers {
+            publisher
+                .join()
+                .map_err(|_| ProtocolError::UnspecifiedError("Publisher fallo".to_string()))??;
+        }
+
+        let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
+        broker.send_offline_messages("drone_1", &message_to_write_sender)?;
+        assert_eq!(message_to_write_receiver.try_iter().count(), 20);
 
         Ok(())
     }
@@ -0,0 +1,445 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::utils::payload_types::PayloadWriteVersion;
+
+use super::{
+    broker_stream::{Listener, ListenerProtocol},
+    client_certificate::CertificateIdentity,
+    protocol_error::ProtocolError,
+};
+
+/// Cantidad de argumentos posicionales: el nombre del programa y el puerto.
+const SERVER_ARGS: usize = 2;
+
+/// Cantidad de argumentos cuando ademas del puerto se indica un archivo con la politica de topics.
+const SERVER_ARGS_WITH_TOPIC_POLICY: usize = 3;
+
+/// Cantidad de argumentos cuando ademas de la politica de topics se indica un archivo de ACL.
+const SERVER_ARGS_WITH_ACL: usize = 4;
+
+/// Cantidad de argumentos cuando ademas de la ACL se indica la CA que firma los certificados de los clientes.
+const SERVER_ARGS_WITH_CLIENT_CA: usize = 5;
+
+/// Directorio del crate: los paths por defecto se resuelven desde aca, y no desde el directorio en el que se
+/// inicia el Broker.
+const CRATE_DIRECTORY: &str = env!("CARGO_MANIFEST_DIR");
+
+/// Archivo de configuracion que se lee, si existe, cuando no se indica uno con --config.
+const DEFAULT_CONFIG_FILE: &str = "broker_config.json";
+
+const USAGE: &str = "<puerto> [archivo de politica de topics] [archivo de ACL] [CA de certificados de clientes]
+    o bien
+    [--config <archivo json>] [--listener <protocolo>://<direccion>]... [--cert <archivo>] [--private-key <archivo>]
+    [--client-ca <archivo>] [--certificate-identity client_id|username] [--clients-file <archivo>]
+    [--topic-policy <archivo>] [--acl <archivo>] [--sessions-dir <directorio>] [--threads <cantidad>]
+    [--receive-maximum <cantidad>] [--maximum-packet-size <bytes>] [--topic-alias-maximum <cantidad>]
+    [--payload-write-version legacy|versioned]";
+
+/// Configuracion con la que se inicia el Broker.
+///
+/// Se lee de un archivo JSON, en el que los campos que no aparecen toman su valor por defecto. Los paths
+/// relativos del archivo se resuelven desde el directorio en el que esta el archivo, por lo que el Broker
+/// puede iniciarse desde cualquier directorio. Si no se indica un archivo, se lee el broker_config.json del crate.
+/// Los argumentos de la linea de comandos pisan los valores del archivo.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    /// Direcciones en las que se escuchan conexiones, cada una con su protocolo.
+    pub listeners: Vec<Listener>,
+
+    /// Certificado y clave privada con los que el Broker se identifica en las conexiones TLS.
+    pub certificate_file: String,
+    pub private_key_file: String,
+
+    /// CA que firma los certificados de los clientes. Si se indica, se pasa a exigir TLS mutuo.
+    pub client_ca_file: Option<String>,
+
+    /// Campo del Connect al que se mapea el common name del certificado de cada cliente.
+    pub certificate_identity: CertificateIdentity,
+
+    /// Archivo con las credenciales de los clientes que se autentican con password.
+    pub clients_file: String,
+
+    /// Archivo con la politica de topics. Si no se indica, se permite crear cualquier topic.
+    pub topic_policy_file: Option<String>,
+
+    /// Archivo de ACL. Si no se indica, los clientes autenticados pueden usar cualquier topic.
+    pub acl_file: Option<String>,
+
+    /// Directorio en el que se persisten las sesiones de los clientes.
+    pub sessions_directory: String,
+
+    /// Cantidad de threads del threadpool con el que se atienden las conexiones.
+    pub threadpool_size: usize,
+
+    /// Limites que el Broker informa en el Connack.
+    pub receive_maximum: u16,
+    pub maximum_packet_size: u32,
+    pub topic_alias_maximum: u16,
+
+    /// Encoding con el que se escriben los payloads de la aplicacion. Por defecto el anterior, que entienden
+    /// todas las versiones.
+    pub payload_write_version: PayloadWriteVersion,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> BrokerConfig {
+        let crate_path = |path: &str| {
+            Path::new(CRATE_DIRECTORY)
+                .join(path)
+                .to_string_lossy()
+                .to_string()
+        };
+
+        BrokerConfig {
+            listeners: vec![Listener::new(ListenerProtocol::Tls, "0.0.0.0:5000")],
+            certificate_file: crate_path("src/mqtt/certs/cert.pem"),
+            private_key_file: crate_path("src/mqtt/certs/private_key.pem"),
+            client_ca_file: None,
+            certificate_identity: CertificateIdentity::Username,
+            clients_file: crate_path("src/monitoring/clients.txt"),
+            topic_policy_file: None,
+            acl_file: None,
+            sessions_directory: crate_path("src/mqtt/clients"),
+            threadpool_size: 30,
+            receive_maximum: 100,
+            maximum_packet_size: 1024 * 1024,
+            topic_alias_maximum: 100,
+            payload_write_version: PayloadWriteVersion::Legacy,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Lee la configuracion de un archivo JSON, resolviendo sus paths relativos desde el directorio del archivo.
+    pub fn read_config_file(file_path: &str) -> Result<BrokerConfig, ProtocolError> {
+        let file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(e) => return Err(ProtocolError::OpenFileError(e.to_string())),
+        };
+
+        let mut config: BrokerConfig = match serde_json::from_reader(BufReader::new(file)) {
+            Ok(config) => config,
+            Err(e) => {
+                return Err(ProtocolError::ServerConfigError(format!(
+                    "{}: {}",
+                    file_path, e
+                )))
+            }
+        };
+
+        let base_directory = Path::new(file_path).parent().unwrap_or(Path::new(""));
+        config.resolve_paths(base_directory);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Configuracion sobre la que se aplican los argumentos: la del broker_config.json del crate si existe, o
+    /// la configuracion por defecto.
+    fn base_config() -> Result<BrokerConfig, ProtocolError> {
+        let file_path = Path::new(CRATE_DIRECTORY).join(DEFAULT_CONFIG_FILE);
+        if file_path.exists() {
+            BrokerConfig::read_config_file(&file_path.to_string_lossy())
+        } else {
+            Ok(BrokerConfig::default())
+        }
+    }
+
+    /// Arma la configuracion a partir de los argumentos con los que se inicio el Broker(el primero es el
+    /// nombre del programa).
+    ///
+    /// Se aceptan los argumentos posicionales de siempre(puerto, politica de topics, ACL y CA de clientes), o
+    /// bien opciones: --config indica el archivo de configuracion, y el resto de las opciones lo pisan.
+    pub fn from_args(args: &[String]) -> Result<BrokerConfig, ProtocolError> {
+        let result = if args.iter().skip(1).any(|arg| arg.starts_with("--")) {
+            BrokerConfig::from_options(&args[1..])
+        } else {
+            BrokerConfig::from_positional_args(args)
+        };
+
+        if let Err(ProtocolError::InvalidNumberOfArguments) = result {
+            let app_name = args.first().map(String::as_str).unwrap_or("broker");
+            println!("Usage:\n{:?} {}", app_name, USAGE);
+        }
+
+        result
+    }
+
+    fn from_positional_args(args: &[String]) -> Result<BrokerConfig, ProtocolError> {
+        if args.len() != SERVER_ARGS
+            && args.len() != SERVER_ARGS_WITH_TOPIC_POLICY
+            && args.len() != SERVER_ARGS_WITH_ACL
+            && args.len() != SERVER_ARGS_WITH_CLIENT_CA
+        {
+            return Err(ProtocolError::InvalidNumberOfArguments);
+        }
+
+        let address = "0.0.0.0:".to_owned() + &args[1];
+        let config = BrokerConfig {
+            listeners: vec![Listener::new(ListenerProtocol::Tls, &address)],
+            topic_policy_file: args.get(SERVER_ARGS).cloned(),
+            acl_file: args.get(SERVER_ARGS_WITH_TOPIC_POLICY).cloned(),
+            client_ca_file: args.get(SERVER_ARGS_WITH_ACL).cloned(),
+            ..BrokerConfig::base_config()?
+        };
+
+        Ok(config)
+    }
+
+    fn from_options(options: &[String]) -> Result<BrokerConfig, ProtocolError> {
+        if !options.len().is_multiple_of(2) {
+            return Err(ProtocolError::InvalidNumberOfArguments);
+        }
+        let options: Vec<(&str, &str)> = options
+            .chunks(2)
+            .map(|pair| (pair[0].as_str(), pair[1].as_str()))
+            .collect();
+
+        // El archivo se lee primero, sin importar en que posicion se lo indique, para que el resto de las
+        // opciones lo pisen.
+        let mut config = match options.iter().rev().find(|(name, _)| *name == "--config") {
+            Some((_, file_path)) => BrokerConfig::read_config_file(file_path)?,
+            None => BrokerConfig::base_config()?,
+        };
+
+        let mut listeners = Vec::new();
+        for (name, value) in options {
+            match name {
+                "--config" => {}
+                "--listener" => listeners.push(BrokerConfig::parse_listener(value)?),
+                "--cert" => config.certificate_file = value.to_string(),
+                "--private-key" => config.private_key_file = value.to_string(),
+                "--client-ca" => config.client_ca_file = Some(value.to_string()),
+                "--certificate-identity" => {
+                    config.certificate_identity = CertificateIdentity::from_str(value)?
+                }
+                "--clients-file" => config.clients_file = value.to_string(),
+                "--topic-policy" => config.topic_policy_file = Some(value.to_string()),
+                "--acl" => config.acl_file = Some(value.to_string()),
+                "--sessions-dir" => config.sessions_directory = value.to_string(),
+                "--threads" => config.threadpool_size = BrokerConfig::parse_number(name, value)?,
+                "--receive-maximum" => {
+                    config.receive_maximum = BrokerConfig::parse_number(name, value)?
+                }
+                "--maximum-packet-size" => {
+                    config.maximum_packet_size = BrokerConfig::parse_number(name, value)?
+                }
+                "--topic-alias-maximum" => {
+                    config.topic_alias_maximum = BrokerConfig::parse_number(name, value)?
+                }
+                "--payload-write-version" => {
+                    config.payload_write_version = PayloadWriteVersion::from_str(value)?
+                }
+                _ => {
+                    return Err(ProtocolError::ServerConfigError(format!(
+                        "Opcion desconocida: {}",
+                        name
+                    )))
+                }
+            }
+        }
+
+        if !listeners.is_empty() {
+            config.listeners = listeners;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Lee un listener con el formato <protocolo>://<direccion>, por ejemplo tcp://0.0.0.0:1883.
+    fn parse_listener(value: &str) -> Result<Listener, ProtocolError> {
+        match value.split_once("://") {
+            Some((protocol, address)) => Ok(Listener::new(
+                ListenerProtocol::from_str(protocol)?,
+                address,
+            )),
+            None => Err(ProtocolError::ServerConfigError(format!(
+                "Listener invalido: {}",
+                value
+            ))),
+        }
+    }
+
+    fn parse_number<T: FromStr>(name: &str, value: &str) -> Result<T, ProtocolError> {
+        value.parse().map_err(|_| {
+            ProtocolError::ServerConfigError(format!("Valor invalido para {}: {}", name, value))
+        })
+    }
+
+    fn resolve_paths(&mut self, base_directory: &Path) {
+        let resolve = |path: &mut String| {
+            let resolved: PathBuf = base_directory.join(&*path);
+            *path = resolved.to_string_lossy().to_string();
+        };
+
+        resolve(&mut self.certificate_file);
+        resolve(&mut self.private_key_file);
+        resolve(&mut self.clients_file);
+        resolve(&mut self.sessions_directory);
+        for path in [
+            &mut self.client_ca_file,
+            &mut self.topic_policy_file,
+            &mut self.acl_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            resolve(path);
+        }
+    }
+
+    fn validate(&self) -> Result<(), ProtocolError> {
+        if self.listeners.is_empty() {
+            return Err(ProtocolError::ServerConfigError(
+                "Se debe indicar al menos un listener".to_string(),
+            ));
+        }
+        if self.threadpool_size == 0 {
+            return Err(ProtocolError::ServerConfigError(
+                "El threadpool debe tener al menos un thread".to_string(),
+            ));
+        }
+        if self.receive_maximum == 0 || self.maximum_packet_size == 0 {
+            return Err(ProtocolError::ServerConfigError(
+                "El receive maximum y el maximum packet size deben ser mayores a 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_01_processing_positional_args() -> Result<(), ProtocolError> {
+        let config = BrokerConfig::from_args(&args(&["broker", "5000"]))?;
+        assert_eq!(
+            config.listeners,
+            vec![Listener::new(ListenerProtocol::Tls, "0.0.0.0:5000")]
+        );
+        assert_eq!(config.topic_policy_file, None);
+
+        let config = BrokerConfig::from_args(&args(&["broker", "5000", "topics.txt", "acl.txt"]))?;
+        assert_eq!(config.topic_policy_file, Some("topics.txt".to_string()));
+        assert_eq!(config.acl_file, Some("acl.txt".to_string()));
+        assert_eq!(config.client_ca_file, None);
+
+        assert!(BrokerConfig::from_args(&args(&["broker"])).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_02_reading_config_file_resolves_relative_paths() -> Result<(), ProtocolError> {
+        let directory =
+            tempfile::tempdir().map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        let file_path = directory.path().join("broker.json");
+        std::fs::write(
+            &file_path,
+            r#"{
+                "listeners": [
+                    {"protocol": "tls", "address": "0.0.0.0:8883"},
+                    {"protocol": "websocket", "address": "127.0.0.1:8080"}
+                ],
+                "certificate_file": "certs/cert.pem",
+                "acl_file": "/etc/broker/acl.txt",
+                "certificate_identity": "client_id",
+                "threadpool_size": 8
+            }"#,
+        )
+        .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+
+        let config = BrokerConfig::read_config_file(&file_path.to_string_lossy())?;
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[1].protocol, ListenerProtocol::WebSocket);
+        assert_eq!(
+            Path::new(&config.certificate_file),
+            directory.path().join("certs/cert.pem")
+        );
+        assert_eq!(config.acl_file, Some("/etc/broker/acl.txt".to_string()));
+        assert_eq!(config.certificate_identity, CertificateIdentity::ClientId);
+        assert_eq!(config.threadpool_size, 8);
+        assert_eq!(
+            config.receive_maximum,
+            BrokerConfig::default().receive_maximum
+        );
+
+        let config = BrokerConfig::from_args(&args(&[
+            "broker",
+            "--threads",
+            "4",
+            "--config",
+            &file_path.to_string_lossy(),
+            "--listener",
+            "tcp://127.0.0.1:1883",
+        ]))?;
+        assert_eq!(config.threadpool_size, 4);
+        assert_eq!(
+            config.listeners,
+            vec![Listener::new(ListenerProtocol::Tcp, "127.0.0.1:1883")]
+        );
+        assert_eq!(config.certificate_identity, CertificateIdentity::ClientId);
+        assert_eq!(config.payload_write_version, PayloadWriteVersion::Legacy);
+
+        let config =
+            BrokerConfig::from_args(&args(&["broker", "--payload-write-version", "versioned"]))?;
+        assert_eq!(config.payload_write_version, PayloadWriteVersion::Versioned);
+
+        directory
+            .close()
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))
+    }
+
+    #[test]
+    fn test_04_default_paths_do_not_depend_on_the_working_directory() -> Result<(), ProtocolError> {
+        let config = BrokerConfig::default();
+        for path in [
+            &config.certificate_file,
+            &config.private_key_file,
+            &config.clients_file,
+        ] {
+            assert!(Path::new(path).is_absolute());
+            assert!(Path::new(path).exists());
+        }
+
+        // Sin --config se usa el broker_config.json del crate, con sus paths resueltos desde el crate.
+        let config = BrokerConfig::from_args(&args(&["broker", "--threads", "4"]))?;
+        assert!(Path::new(&config.certificate_file).exists());
+        assert!(Path::new(&config.sessions_directory).starts_with(CRATE_DIRECTORY));
+        assert_eq!(config.threadpool_size, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_03_invalid_options_are_rejected() {
+        assert!(BrokerConfig::from_args(&args(&["broker", "--threads", "0"])).is_err());
+        assert!(BrokerConfig::from_args(&args(&["broker", "--threads"])).is_err());
+        assert!(BrokerConfig::from_args(&args(&["broker", "--puerto", "5000"])).is_err());
+        assert!(
+            BrokerConfig::from_args(&args(&["broker", "--listener", "udp://0.0.0.0:1"])).is_err()
+        );
+        assert!(
+            BrokerConfig::from_args(&args(&["broker", "--config", "./no/existe.json"])).is_err()
+        );
+        assert!(
+            BrokerConfig::from_args(&args(&["broker", "--payload-write-version", "v2"])).is_err()
+        );
+    }
+}
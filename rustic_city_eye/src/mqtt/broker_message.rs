@@ -1,21 +1,20 @@
-use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::io::{BufWriter, Error, Read, Write};
 
 use crate::{
     utils::payload_types::PayloadTypes,
     utils::{reader::*, writer::*},
 };
 
-const SESSION_EXPIRY_INTERVAL_ID: u8 = 0x11;
-const REASON_STRING_ID: u8 = 0x1F;
-const USER_PROPERTY_ID: u8 = 0x26;
 use super::{
-    connack_properties::ConnackProperties, payload::Payload, protocol_error::ProtocolError,
+    client_message::{
+        publish_first_byte, publish_flags, read_acknowledgement, read_auth_body,
+        read_disconnect_body, write_auth_body, write_disconnect_body,
+    },
+    connack_properties::ConnackProperties,
+    payload::Payload,
+    protocol_error::ProtocolError,
     publish::publish_properties::PublishProperties,
 };
-use crate::utils::{
-    reader::{read_string, read_u8},
-    writer::{write_string, write_u8},
-};
 
 #[derive(Debug, PartialEq)]
 pub enum BrokerMessage {
@@ -31,17 +30,38 @@ pub enum BrokerMessage {
         packet_id_lsb: u8,
         reason_code: u8,
     },
-    /// El Suback se utiliza para confirmar la suscripción a un topic
+    /// Pubrec es la respuesta a un Publish packet con QoS 2, y el primer paso del flujo exactly-once.
+    Pubrec {
+        packet_id_msb: u8,
+        packet_id_lsb: u8,
+        reason_code: u8,
+    },
+
+    /// Pubrel es la respuesta a un Pubrec: le indica al Client que puede liberar el packet_id.
+    Pubrel {
+        packet_id_msb: u8,
+        packet_id_lsb: u8,
+        reason_code: u8,
+    },
+
+    /// Pubcomp es la respuesta a un Pubrel, y completa el flujo de un Publish con QoS 2.
+    Pubcomp {
+        packet_id_msb: u8,
+        packet_id_lsb: u8,
+        reason_code: u8,
+    },
+
+    /// El Suback se utiliza para confirmar la suscripción a los topic filters de un Subscribe
     ///
-    /// reason_code es el código de razón de la confirmación
+    /// reason_codes contiene un código de razón por cada topic filter, en el mismo orden del Subscribe
     /// packet_id_msb y packet_id_lsb son los bytes más significativos y menos significativos del packet_id
     Suback {
         /// packet_id_msb es el byte más significativo del packet_id
         packet_id_msb: u8,
         /// packet_id_lsb es el byte menos significativo del packet_id
         packet_id_lsb: u8,
-        /// reason_code es el código de razón de la confirmación
-        reason_code: u8,
+        /// reason_codes son los códigos de razón de cada topic filter
+        reason_codes: Vec<u8>,
     },
     PublishDelivery {
         packet_id: u16,
@@ -52,10 +72,11 @@ pub enum BrokerMessage {
         dup_flag: usize,
         properties: PublishProperties,
     },
+    /// El Unsuback confirma la desuscripción, con un código de razón por cada topic filter del Unsubscribe.
     Unsuback {
         packet_id_msb: u8,
         packet_id_lsb: u8,
-        reason_code: u8,
+        reason_codes: Vec<u8>,
     },
     Disconnect {
         reason_code: u8,
@@ -89,234 +110,165 @@ pub enum BrokerMessage {
 }
 #[allow(dead_code)]
 impl BrokerMessage {
+    /// Escribe el packet completo: fixed header, remaining length, variable header y payload.
     pub fn write_to(&self, stream: impl Write) -> Result<(), ProtocolError> {
         let mut writer = BufWriter::new(stream);
+
+        let mut body = Vec::new();
+        self.write_body(&mut body)?;
+
+        write_packet(&mut writer, self.first_packet_byte()?, &body)?;
+        writer.flush().map_err(|_e| ProtocolError::WriteError)
+    }
+
+    /// Escribe todo lo que sigue al fixed header. Su largo es el remaining length del packet.
+    fn write_body(&self, body: &mut Vec<u8>) -> Result<(), ProtocolError> {
         match self {
             BrokerMessage::Connack {
                 session_present,
                 reason_code,
                 properties,
             } => {
-                let byte_1: u8 = 0x20_u8.to_le(); //00100000
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_| ProtocolError::WriteError)?;
-
-                write_bool(&mut writer, session_present)?;
-                write_u8(&mut writer, reason_code)?;
-                properties.write_to(&mut writer)?;
-                writer.flush().map_err(|_| ProtocolError::WriteError)?;
-
-                Ok(())
+                //connect acknowledge flags: solo el bit 0 indica si habia una sesion
+                write_bool(body, session_present)?;
+                write_u8(body, reason_code)?;
+                properties.write_to(body)
             }
             BrokerMessage::Puback {
                 packet_id_msb,
                 packet_id_lsb,
                 reason_code,
+            }
+            | BrokerMessage::Pubrec {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code,
+            }
+            | BrokerMessage::Pubrel {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code,
+            }
+            | BrokerMessage::Pubcomp {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code,
             } => {
-                //fixed header
-                let byte_1: u8 = 0x40_u8.to_le(); //01000000
-
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_| ProtocolError::WriteError)?;
-
-                //variable header
-                //packet_id
-                write_u8(&mut writer, packet_id_msb)?;
-                write_u8(&mut writer, packet_id_lsb)?;
-
-                //reason code
-                write_u8(&mut writer, reason_code)?;
-
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-
-                Ok(())
+                write_u8(body, packet_id_msb)?;
+                write_u8(body, packet_id_lsb)?;
+                write_u8(body, reason_code)
             }
             BrokerMessage::Suback {
                 packet_id_msb,
                 packet_id_lsb,
-                reason_code,
+                reason_codes,
+            }
+            | BrokerMessage::Unsuback {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_codes,
             } => {
-                //fixed header
-                let byte_1: u8 = 0x90_u8.to_le(); //10010000
-
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-
-                write_u8(&mut writer, packet_id_msb)?;
-                write_u8(&mut writer, packet_id_lsb)?;
-
-                //reason code
-                write_u8(&mut writer, reason_code)?;
-
-                //sub_id
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
+                write_u8(body, packet_id_msb)?;
+                write_u8(body, packet_id_lsb)?;
+                write_properties_block(body, &[])?;
 
+                //payload: un reason code por cada topic filter
+                body.extend_from_slice(reason_codes);
                 Ok(())
             }
             BrokerMessage::PublishDelivery {
                 packet_id,
                 topic_name,
                 qos,
-                retain_flag,
+                retain_flag: _,
                 payload,
-                dup_flag,
+                dup_flag: _,
                 properties,
             } => {
-                //fixed header -> es uno de juguete, hay que pensarlo mejor
-                let byte_1: u8 = 0x00_u8.to_le();
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-
-                //variable header
-                //packet_id
-                write_u8(&mut writer, &packet_id.to_be_bytes()[0])?;
-                write_u8(&mut writer, &packet_id.to_be_bytes()[1])?;
-
-                //topic_name
-                write_string(&mut writer, topic_name)?;
-
-                //qos
-                write_u8(&mut writer, &qos.to_be_bytes()[0])?;
-
-                //retain_flag
-                write_u8(&mut writer, &retain_flag.to_be_bytes()[0])?;
-
-                //payload
-                payload.write_to(&mut writer)?;
-                // write_string(&mut writer, payload)?;
-
-                //dup_flag
-                write_u8(&mut writer, &dup_flag.to_be_bytes()[0])?;
-
-                //properties
-                properties.write_properties(&mut writer)?;
-
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-
-                Ok(())
-            }
-            BrokerMessage::Unsuback {
-                packet_id_msb,
-                packet_id_lsb,
-                reason_code,
-            } => {
-                //fixed header
-                let byte_1: u8 = 0xB0_u8.to_le(); //10110000
-
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
+                write_string(body, topic_name)?;
 
-                //variable header
-                //packet_id
-                write_u8(&mut writer, packet_id_msb)?;
-                write_u8(&mut writer, packet_id_lsb)?;
-                write_u8(&mut writer, reason_code)?;
-
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
+                // Los Publish con QoS 0 no llevan packet_id.
+                if *qos > 0 {
+                    write_u16(body, packet_id)?;
+                }
 
-                Ok(())
+                properties.for_payload(payload).write_properties(body)?;
+                payload.write_to(body)
             }
             BrokerMessage::Disconnect {
                 reason_code,
                 session_expiry_interval,
                 reason_string,
                 user_properties,
-            } => {
-                //fixed header
-                let header: u8 = 0xE0_u8.to_le(); //11100000
-                write_u8(&mut writer, &header)?;
-                //variable_header
-                write_u8(&mut writer, reason_code)?;
-
-                write_u8(&mut writer, &SESSION_EXPIRY_INTERVAL_ID)?;
-                write_u32(&mut writer, session_expiry_interval)?;
-
-                write_u8(&mut writer, &REASON_STRING_ID)?;
-                write_string(&mut writer, reason_string)?;
-
-                write_u8(&mut writer, &USER_PROPERTY_ID)?;
-                write_string_pairs(&mut writer, user_properties)?;
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-                Ok(())
-            }
-            BrokerMessage::Pingresp => {
-                let byte_1: u8 = 0xD0_u8.to_le();
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-
-                Ok(())
-            }
+            } => write_disconnect_body(
+                body,
+                reason_code,
+                &Some(*session_expiry_interval).filter(|interval| *interval != 0),
+                reason_string,
+                user_properties,
+            ),
+            BrokerMessage::Pingresp => Ok(()),
             BrokerMessage::Auth {
                 reason_code,
                 authentication_method,
                 authentication_data,
                 reason_string,
                 user_properties,
-            } => {
-                let byte_1 = 0xF0_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-
-                write_u8(&mut writer, reason_code)?;
-
-                let authentication_method_id: u8 = 0x15_u8;
-                writer
-                    .write_all(&[authentication_method_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_string(&mut writer, authentication_method)?;
-
-                let authentication_data_id: u8 = 0x16_u8;
-                writer
-                    .write_all(&[authentication_data_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_bin_vec(&mut writer, authentication_data)?;
-
-                let reason_string_id: u8 = 0x1F_u8;
-                writer
-                    .write_all(&[reason_string_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_string(&mut writer, reason_string)?;
-
-                let user_properties_id: u8 = 0x26_u8; // 38
-                writer
-                    .write_all(&[user_properties_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_tuple_vec(&mut writer, user_properties)?;
-
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
+            } => write_auth_body(
+                body,
+                reason_code,
+                authentication_method,
+                authentication_data,
+                reason_string,
+                user_properties,
+            ),
+        }
+    }
 
-                Ok(())
-            }
+    fn first_packet_byte(&self) -> Result<u8, ProtocolError> {
+        match self {
+            BrokerMessage::Connack { .. } => Ok(0x20), //00100000
+            BrokerMessage::Puback { .. } => Ok(0x40),  //01000000
+            BrokerMessage::Pubrec { .. } => Ok(0x50),  //01010000
+            BrokerMessage::Pubrel { .. } => Ok(0x62),  //01100010
+            BrokerMessage::Pubcomp { .. } => Ok(0x70), //01110000
+            BrokerMessage::Suback { .. } => Ok(0x90),  //10010000
+            BrokerMessage::PublishDelivery {
+                qos,
+                retain_flag,
+                dup_flag,
+                ..
+            } => publish_first_byte(*qos, *retain_flag, *dup_flag),
+            BrokerMessage::Unsuback { .. } => Ok(0xB0), //10110000
+            BrokerMessage::Disconnect { .. } => Ok(0xE0), //11100000
+            BrokerMessage::Pingresp => Ok(0xD0),
+            BrokerMessage::Auth { .. } => Ok(0xF0),
         }
     }
 
-    pub fn read_from(mut stream: impl Read) -> Result<BrokerMessage, Error> {
-        let mut header = [0u8; 1];
-        stream.read_exact(&mut header)?;
-        let header = u8::from_le_bytes(header);
+    pub fn read_from(stream: impl Read) -> Result<BrokerMessage, Error> {
+        BrokerMessage::read_with_limit(stream, 0)
+    }
+
+    /// Lee un packet, rechazandolo sin leer su contenido si supera maximum_packet_size(0 indica que no hay limite).
+    pub fn read_with_limit(
+        mut stream: impl Read,
+        maximum_packet_size: u32,
+    ) -> Result<BrokerMessage, Error> {
+        let (header, body) = read_packet(&mut stream, maximum_packet_size)?;
+        let mut body = body.as_slice();
 
         match header {
-            0x00 => {
-                let packet_id_msb = read_u8(&mut stream)?;
-                let packet_id_lsb = read_u8(&mut stream)?;
-                let topic_name = read_string(&mut stream)?;
-                let qos = read_u8(&mut stream)? as usize;
-                let retain_flag = read_u8(&mut stream)? as usize;
-                let payload = PayloadTypes::read_from(&mut stream)?;
+            header if header >> 4 == 0x3 => {
+                let (dup_flag, qos, retain_flag) = publish_flags(header)?;
 
-                let dup_flag = read_u8(&mut stream)? as usize;
-                let properties = PublishProperties::read_from(&mut stream)?;
+                let topic_name = read_string(&mut body)?;
+                let packet_id = if qos > 0 { read_u16(&mut body)? } else { 0 };
+                let properties = PublishProperties::read_from(&mut body)?;
+                let payload = PayloadTypes::read_with_properties(&mut body, &properties)?;
 
                 Ok(BrokerMessage::PublishDelivery {
-                    packet_id: u16::from_be_bytes([packet_id_msb, packet_id_lsb]),
+                    packet_id,
                     topic_name,
                     qos,
                     retain_flag,
@@ -326,138 +278,89 @@ impl BrokerMessage {
                 })
             }
             0x20 => {
-                let session_present = read_bool(&mut stream)?;
-                let reason_code = read_u8(&mut stream)?;
-                let properties = ConnackProperties::read_from(&mut stream)?;
+                let session_present = read_u8(&mut body)? & 1 == 1;
+                let reason_code = read_u8(&mut body)?;
+                let properties = ConnackProperties::read_from(&mut body)?;
                 Ok(BrokerMessage::Connack {
                     session_present,
                     reason_code,
                     properties,
                 })
             }
-            0x40 => {
-                let packet_id_msb = read_u8(&mut stream)?;
-                let packet_id_lsb = read_u8(&mut stream)?;
-                let reason_code = read_u8(&mut stream)?;
-
-                Ok(BrokerMessage::Puback {
-                    packet_id_msb,
-                    packet_id_lsb,
-                    reason_code,
+            0x40 | 0x50 | 0x62 | 0x70 => {
+                let (packet_id, reason_code) = read_acknowledgement(&mut body)?;
+                let [packet_id_msb, packet_id_lsb] = packet_id.to_be_bytes();
+
+                Ok(match header {
+                    0x40 => BrokerMessage::Puback {
+                        packet_id_msb,
+                        packet_id_lsb,
+                        reason_code,
+                    },
+                    0x50 => BrokerMessage::Pubrec {
+                        packet_id_msb,
+                        packet_id_lsb,
+                        reason_code,
+                    },
+                    0x62 => BrokerMessage::Pubrel {
+                        packet_id_msb,
+                        packet_id_lsb,
+                        reason_code,
+                    },
+                    _ => BrokerMessage::Pubcomp {
+                        packet_id_msb,
+                        packet_id_lsb,
+                        reason_code,
+                    },
                 })
             }
-            0x90 => {
-                let packet_id_msb = read_u8(&mut stream)?;
-                let packet_id_lsb = read_u8(&mut stream)?;
-                let reason_code = read_u8(&mut stream)?;
-                Ok(BrokerMessage::Suback {
-                    packet_id_msb,
-                    packet_id_lsb,
-                    reason_code,
-                })
-            }
-            0xB0 => {
-                let packet_id_msb = read_u8(&mut stream)?;
-                let packet_id_lsb = read_u8(&mut stream)?;
-                let reason_code = read_u8(&mut stream)?;
-
-                Ok(BrokerMessage::Unsuback {
-                    packet_id_msb,
-                    packet_id_lsb,
-                    reason_code,
-                })
+            0x90 | 0xB0 => {
+                let packet_id_msb = read_u8(&mut body)?;
+                let packet_id_lsb = read_u8(&mut body)?;
+                read_properties_block(&mut body)?;
+                let reason_codes = body.to_vec();
+
+                if header == 0x90 {
+                    Ok(BrokerMessage::Suback {
+                        packet_id_msb,
+                        packet_id_lsb,
+                        reason_codes,
+                    })
+                } else {
+                    Ok(BrokerMessage::Unsuback {
+                        packet_id_msb,
+                        packet_id_lsb,
+                        reason_codes,
+                    })
+                }
             }
             0xE0 => {
-                let reason_code = read_u8(&mut stream)?;
-                let session_expiry_interval_id = read_u8(&mut stream)?;
-                if session_expiry_interval_id != SESSION_EXPIRY_INTERVAL_ID {
-                    return Err(Error::new(
-                        std::io::ErrorKind::Other,
-                        "Invalid session expiry interval id",
-                    ));
-                }
-                let session_expiry_interval = read_u32(&mut stream)?;
-
-                let reason_string_id = read_u8(&mut stream)?;
-                if reason_string_id != REASON_STRING_ID {
-                    return Err(Error::new(
-                        std::io::ErrorKind::Other,
-                        "Invalid reason string id",
-                    ));
-                }
-                let reason_string = read_string(&mut stream)?;
-
-                let user_property_id = read_u8(&mut stream)?;
-                if user_property_id != USER_PROPERTY_ID {
-                    return Err(Error::new(
-                        std::io::ErrorKind::Other,
-                        "Invalid user property id",
-                    ));
-                }
-                let user_properties = read_string_pairs(&mut stream)?;
+                let (reason_code, session_expiry_interval, reason_string, user_properties) =
+                    read_disconnect_body(&mut body)?;
 
                 Ok(BrokerMessage::Disconnect {
                     reason_code,
-                    session_expiry_interval,
+                    session_expiry_interval: session_expiry_interval.unwrap_or(0),
                     reason_string,
                     user_properties,
                 })
             }
             0xD0 => Ok(BrokerMessage::Pingresp),
             0xF0 => {
-                let reason_code = read_u8(&mut stream)?;
-                let mut authentication_method: Option<String> = None;
-                let mut authentication_data: Option<Vec<u8>> = None;
-                let mut reason_string: Option<String> = None;
-                let mut user_properties: Option<Vec<(String, String)>> = None;
-
-                let mut count = 0;
-                while let Ok(property_id) = read_u8(&mut stream) {
-                    match property_id {
-                        0x15 => {
-                            let value = read_string(&mut stream)?;
-                            authentication_method = Some(value);
-                        }
-                        0x16 => {
-                            let value = read_bin_vec(&mut stream)?;
-                            authentication_data = Some(value);
-                        }
-                        0x26 => {
-                            let value = read_tuple_vec(&mut stream)?;
-                            user_properties = Some(value);
-                        }
-                        0x1F => {
-                            let value = read_string(&mut stream)?;
-                            reason_string = Some(value);
-                        }
-                        _ => {
-                            return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido"));
-                        }
-                    }
-                    count += 1;
-                    if count == 4 {
-                        break;
-                    }
-                }
+                let (
+                    reason_code,
+                    authentication_method,
+                    authentication_data,
+                    reason_string,
+                    user_properties,
+                ) = read_auth_body(&mut body)?;
 
                 Ok(BrokerMessage::Auth {
                     reason_code,
-                    user_properties: user_properties.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing user_properties property",
-                    ))?,
-                    authentication_method: authentication_method.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing authentication_method property",
-                    ))?,
-                    authentication_data: authentication_data.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing authentication_data property",
-                    ))?,
-                    reason_string: reason_string.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing reason_string property",
-                    ))?,
+                    authentication_method,
+                    authentication_data,
+                    reason_string,
+                    user_properties,
                 })
             }
             _ => Err(Error::new(std::io::ErrorKind::Other, "Invalid header")),
@@ -475,6 +378,21 @@ impl BrokerMessage {
                 packet_id_msb,
                 packet_id_lsb,
                 reason_code: _,
+            }
+            | BrokerMessage::Pubrec {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code: _,
+            }
+            | BrokerMessage::Pubrel {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code: _,
+            }
+            | BrokerMessage::Pubcomp {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code: _,
             } => {
                 let bytes = packet_id.to_be_bytes();
 
@@ -483,7 +401,7 @@ impl BrokerMessage {
             BrokerMessage::Suback {
                 packet_id_msb,
                 packet_id_lsb,
-                reason_code: _,
+                reason_codes: _,
             } => {
                 let bytes = packet_id.to_be_bytes();
 
@@ -501,7 +419,7 @@ impl BrokerMessage {
             BrokerMessage::Unsuback {
                 packet_id_msb,
                 packet_id_lsb,
-                reason_code: _,
+                reason_codes: _,
             } => {
                 let bytes = packet_id.to_be_bytes();
 
@@ -535,7 +453,7 @@ mod tests {
     #[test]
     fn test_02_analizing_packet_ids_ok() {
         let suback = BrokerMessage::Suback {
-            reason_code: 1,
+            reason_codes: vec![1],
             packet_id_msb: 2,
             packet_id_lsb: 1,
         };
@@ -555,7 +473,7 @@ mod tests {
         let unsuback = BrokerMessage::Unsuback {
             packet_id_msb: 1,
             packet_id_lsb: 1,
-            reason_code: 1,
+            reason_codes: vec![0x00, 0x11],
         };
 
         let mut cursor = Cursor::new(Vec::<u8>::new());
@@ -577,4 +495,36 @@ mod tests {
         };
         assert_eq!(unsuback, read_unsuback);
     }
+
+    #[test]
+    fn test_04_qos_2_packets_ok() -> Result<(), ProtocolError> {
+        let pubrec = BrokerMessage::Pubrec {
+            packet_id_msb: 1,
+            packet_id_lsb: 5,
+            reason_code: 0x00,
+        };
+        let pubrel = BrokerMessage::Pubrel {
+            packet_id_msb: 1,
+            packet_id_lsb: 5,
+            reason_code: 0x00,
+        };
+        let pubcomp = BrokerMessage::Pubcomp {
+            packet_id_msb: 1,
+            packet_id_lsb: 5,
+            reason_code: 0x92,
+        };
+
+        for message in [pubrec, pubrel, pubcomp] {
+            let mut cursor = Cursor::new(Vec::<u8>::new());
+            message.write_to(&mut cursor)?;
+            cursor.set_position(0);
+
+            let read_message = BrokerMessage::read_from(&mut cursor)
+                .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+            assert!(read_message.analize_packet_id(261));
+            assert_eq!(message, read_message);
+        }
+
+        Ok(())
+    }
 }
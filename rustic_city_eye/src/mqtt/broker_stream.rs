@@ -0,0 +1,119 @@
+use std::{
+    io::{Error, Read, Write},
+    net::{Shutdown, TcpStream},
+    time::Duration,
+};
+
+use rustls::{pki_types::CertificateDer, ServerConnection, StreamOwned};
+use serde::Deserialize;
+
+use super::{protocol_error::ProtocolError, websocket::WebSocketStream};
+
+/// Protocolo con el que los clientes se conectan a un listener del Broker.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerProtocol {
+    /// MQTT sobre TCP, sin cifrar. Pensado para desarrollo local.
+    Tcp,
+
+    /// MQTT sobre TLS.
+    Tls,
+
+    /// MQTT sobre WebSocket(subprotocolo "mqtt"), para los dashboards que corren en un browser.
+    WebSocket,
+}
+
+impl std::str::FromStr for ListenerProtocol {
+    type Err = ProtocolError;
+
+    fn from_str(protocol: &str) -> Result<ListenerProtocol, ProtocolError> {
+        match protocol {
+            "tcp" => Ok(ListenerProtocol::Tcp),
+            "tls" => Ok(ListenerProtocol::Tls),
+            "websocket" => Ok(ListenerProtocol::WebSocket),
+            _ => Err(ProtocolError::ServerConfigError(format!(
+                "Protocolo de listener desconocido: {}",
+                protocol
+            ))),
+        }
+    }
+}
+
+/// Direccion en la que el Broker escucha conexiones, y el protocolo que usan los clientes que se conectan a ella.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Listener {
+    pub protocol: ListenerProtocol,
+    pub address: String,
+}
+
+impl Listener {
+    pub fn new(protocol: ListenerProtocol, address: &str) -> Listener {
+        Listener {
+            protocol,
+            address: address.to_string(),
+        }
+    }
+}
+
+/// Conexion del Broker con un cliente, segun el listener por el que se conecto.
+///
+/// Se lee y se escribe a traves de una referencia compartida, como con un TcpStream: el thread de lectura y
+/// el de escritura del Broker usan la misma conexion. En las conexiones TLS, los packets se leen y escriben
+/// sobre el TcpStream, como el resto de la aplicacion.
+pub enum BrokerStream {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    WebSocket(WebSocketStream),
+}
+
+impl BrokerStream {
+    /// El TcpStream sobre el que se establecio la conexion.
+    fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            BrokerStream::Tcp(stream) => stream,
+            BrokerStream::Tls(stream) => stream.get_ref(),
+            BrokerStream::WebSocket(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Certificados que presento el cliente en el handshake TLS. Solo las conexiones TLS pueden tenerlos.
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        match self {
+            BrokerStream::Tls(stream) => stream.conn.peer_certificates(),
+            _ => None,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.tcp_stream().set_read_timeout(timeout)
+    }
+
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.tcp_stream().shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for &BrokerStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            BrokerStream::WebSocket(stream) => (&*stream).read(buf),
+            _ => self.tcp_stream().read(buf),
+        }
+    }
+}
+
+impl Write for &BrokerStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            BrokerStream::WebSocket(stream) => (&*stream).write(buf),
+            _ => self.tcp_stream().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            BrokerStream::WebSocket(stream) => (&*stream).flush(),
+            _ => self.tcp_stream().flush(),
+        }
+    }
+}
@@ -1,26 +1,59 @@
 use rand::Rng;
 use rustls::{ClientConfig, ClientConnection, KeyLogFile, RootCertStore, StreamOwned};
 use std::{
+    collections::{HashMap, VecDeque},
     fs::File,
     io::BufReader,
     net::{Shutdown, TcpStream},
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{self, Receiver, Sender},
         Arc, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
     mqtt::{
-        broker_message::BrokerMessage, client_message::ClientMessage,
-        messages_config::MessagesConfig, protocol_error::ProtocolError,
+        authenticator::{AuthStep, Authenticator},
+        broker_message::BrokerMessage,
+        client_certificate::ClientCertificate,
+        client_message::ClientMessage,
+        flow_control::FlowControl,
+        messages_config::MessagesConfig,
+        protocol_error::ProtocolError,
+        publish::{
+            publish_config::PublishConfig,
+            publish_properties::{PublishProperties, TopicProperties},
+            topic_aliases::TopicAliases,
+        },
+        subscribe_config::SubscribeConfig,
+        subscribe_properties::SubscribeProperties,
+    },
+    utils::{payload_types::PayloadTypes, reader::is_packet_too_large, threadpool::ThreadPool},
+};
+
+use super::{
+    client_message,
+    client_return::ClientReturn,
+    reason_code::{
+        CONTINUE_AUTHENTICATION_HEX, PACKET_ID_NOT_FOUND_HEX, PACKET_TOO_LARGE_HEX, SUCCESS_HEX,
+        UNSPECIFIED_ERROR_HEX,
     },
-    utils::threadpool::ThreadPool,
 };
 
-use super::{client_message, client_return::ClientReturn};
+/// Tiempo que se espera el Puback de un Publish con QoS 1 antes de reenviarlo.
+const IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Cada cuanto el thread de escritura revisa si hay mensajes in-flight para reenviar.
+const IN_FLIGHT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Prefijo del topic en el que cada cliente recibe las respuestas a sus requests.
+const RESPONSE_TOPIC_PREFIX: &str = "responses";
+
+/// Requests enviados cuya respuesta todavia no llego, indexados por su correlation data.
+type PendingRequests = Arc<Mutex<HashMap<Vec<u8>, Sender<ClientMessage>>>>;
 
 pub trait ClientTrait {
     fn client_run(&mut self) -> Result<(), ProtocolError>;
@@ -50,6 +83,24 @@ pub struct Client {
     packets_ids: Arc<Vec<u16>>,
 
     sender_channel: Option<Sender<ClientMessage>>,
+
+    // keep_alive es el tiempo maximo sin enviarle packets al broker; al cumplirse se le envia un Pingreq
+    keep_alive: Duration,
+
+    // topic_alias_maximum es el maximo topic alias que acepta el broker en los Publish que se le envian
+    topic_alias_maximum: u16,
+
+    // broker_flow_control son el receive maximum y el maximum packet size que indico el broker en el Connack
+    broker_flow_control: FlowControl,
+
+    // maximum_packet_size es el tamaño maximo de los packets que el cliente acepta recibir, indicado en el Connect
+    maximum_packet_size: u32,
+
+    // pending_requests son los requests enviados que esperan su respuesta
+    pending_requests: PendingRequests,
+
+    // response_topic_subscribed indica si ya se envio el Subscribe al topic de respuestas del cliente
+    response_topic_subscribed: Arc<AtomicBool>,
 }
 
 impl Client {
@@ -68,6 +119,71 @@ impl Client {
         address: String,
         connect: client_message::Connect,
         sender_channel: Sender<ClientMessage>,
+    ) -> Result<Client, ProtocolError> {
+        Client::connect(
+            receiver_channel,
+            address,
+            connect,
+            sender_channel,
+            None,
+            None,
+        )
+    }
+
+    /// Igual que new, pero el cliente presenta un certificado en el handshake TLS(TLS mutuo).
+    ///
+    /// Ante un Broker que pide certificados de clientes, el certificado reemplaza al password: el Broker toma
+    /// la identidad del cliente del common name del certificado.
+    pub fn with_client_certificate(
+        receiver_channel: Receiver<Box<dyn MessagesConfig + Send>>,
+        address: String,
+        connect: client_message::Connect,
+        sender_channel: Sender<ClientMessage>,
+        client_certificate: ClientCertificate,
+    ) -> Result<Client, ProtocolError> {
+        Client::connect(
+            receiver_channel,
+            address,
+            connect,
+            sender_channel,
+            None,
+            Some(client_certificate),
+        )
+    }
+
+    /// Igual que new, pero el cliente se autentica con un metodo de autenticacion extendida(como SCRAM-SHA-256).
+    ///
+    /// El Connect lleva el metodo y los datos iniciales del Authenticator(y no el password), y cada Auth que envie
+    /// el Broker se responde con el siguiente paso del intercambio. El Connack trae los datos finales del Broker, con
+    /// los que el Authenticator termina de verificarlo.
+    pub fn with_authenticator(
+        receiver_channel: Receiver<Box<dyn MessagesConfig + Send>>,
+        address: String,
+        mut connect: client_message::Connect,
+        sender_channel: Sender<ClientMessage>,
+        mut authenticator: Box<dyn Authenticator>,
+    ) -> Result<Client, ProtocolError> {
+        connect.properties.authentication_method = authenticator.method();
+        connect.properties.authentication_data = authenticator.initial_data();
+        connect.password = None;
+
+        Client::connect(
+            receiver_channel,
+            address,
+            connect,
+            sender_channel,
+            Some(authenticator),
+            None,
+        )
+    }
+
+    fn connect(
+        receiver_channel: Receiver<Box<dyn MessagesConfig + Send>>,
+        address: String,
+        connect: client_message::Connect,
+        sender_channel: Sender<ClientMessage>,
+        mut authenticator: Option<Box<dyn Authenticator>>,
+        client_certificate: Option<ClientCertificate>,
     ) -> Result<Client, ProtocolError> {
         let stream = match TcpStream::connect(address) {
             Ok(stream) => stream,
@@ -75,9 +191,11 @@ impl Client {
         };
 
         let client_id = connect.get_client_id().to_string();
+        let keep_alive = connect.keep_alive;
+        let maximum_packet_size = connect.properties.maximum_packet_size;
         let connect_message = ClientMessage::Connect(connect);
 
-        let tls_stream = Client::build_tls_stream(stream)?;
+        let tls_stream = Client::build_tls_stream(stream, client_certificate)?;
 
         println!("Sending Connect message to Broker");
 
@@ -86,43 +204,98 @@ impl Client {
             Err(e) => return Err(e),
         }
 
-        if let Ok(message) = BrokerMessage::read_from(tls_stream.get_ref()) {
+        loop {
+            let message = match BrokerMessage::read_from(tls_stream.get_ref()) {
+                Ok(message) => message,
+                Err(_) => return Err(ProtocolError::NotReceivedMessageError),
+            };
+
             match message {
+                BrokerMessage::Auth {
+                    reason_code: CONTINUE_AUTHENTICATION_HEX,
+                    authentication_method,
+                    authentication_data,
+                    reason_string: _,
+                    user_properties: _,
+                } => {
+                    let authenticator = authenticator
+                        .as_mut()
+                        .ok_or(ProtocolError::ExpectedConnack)?;
+                    let response = match authenticator.step(&authentication_data) {
+                        AuthStep::Continue(data) => data,
+                        _ => return Err(ProtocolError::AuthError),
+                    };
+
+                    let auth = ClientMessage::Auth {
+                        reason_code: CONTINUE_AUTHENTICATION_HEX,
+                        authentication_method,
+                        authentication_data: response,
+                        reason_string: String::new(),
+                        user_properties: Vec::new(),
+                    };
+                    auth.write_to(tls_stream.get_ref())?;
+                }
                 BrokerMessage::Connack {
                     session_present: _,
                     reason_code,
-                    properties: _,
+                    properties,
                 } => {
                     println!("Connack received");
-                    match reason_code {
-                        0x00_u8 => {
-                            println!("Successful connection!");
-
-                            Ok(Client {
-                                receiver_channel: Arc::new(Mutex::new(receiver_channel)),
-                                stream: tls_stream,
-                                packets_ids: Arc::new(Vec::new()),
-                                sender_channel: Some(sender_channel),
-                                client_id,
-                            })
-                        }
-                        _ => {
-                            println!("Authentication failed: reason code {}", reason_code);
-                            Err(ProtocolError::AuthError)
+                    if reason_code != SUCCESS_HEX {
+                        println!("Authentication failed: reason code {}", reason_code);
+                        return Err(ProtocolError::AuthError);
+                    }
+                    if let Some(authenticator) = authenticator.as_mut() {
+                        if !matches!(
+                            authenticator.step(&properties.authentication_data),
+                            AuthStep::Success(_)
+                        ) {
+                            println!("Authentication failed: the broker could not be verified");
+                            return Err(ProtocolError::AuthError);
                         }
                     }
+                    println!("Successful connection!");
+
+                    return Ok(Client {
+                        receiver_channel: Arc::new(Mutex::new(receiver_channel)),
+                        stream: tls_stream,
+                        packets_ids: Arc::new(Vec::new()),
+                        sender_channel: Some(sender_channel),
+                        client_id,
+                        keep_alive: Client::negotiate_keep_alive(
+                            keep_alive,
+                            properties.server_keep_alive,
+                        ),
+                        topic_alias_maximum: properties.topic_alias_maximum,
+                        broker_flow_control: FlowControl::new(
+                            properties.receive_maximum,
+                            properties.maximum_packet_size,
+                        ),
+                        maximum_packet_size,
+                        pending_requests: Arc::new(Mutex::new(HashMap::new())),
+                        response_topic_subscribed: Arc::new(AtomicBool::new(false)),
+                    });
                 }
-                _ => Err(ProtocolError::ExpectedConnack),
+                _ => return Err(ProtocolError::ExpectedConnack),
             }
-        } else {
-            Err(ProtocolError::NotReceivedMessageError)
+        }
+    }
+
+    /// Si el broker indica un server keep alive, este reemplaza al keep alive enviado en el Connect.
+    fn negotiate_keep_alive(keep_alive: u16, server_keep_alive: u16) -> Duration {
+        match server_keep_alive {
+            0 => Duration::from_secs(keep_alive as u64),
+            seconds => Duration::from_secs(seconds as u64),
         }
     }
 
     /// A partir de un TcpStream y de las certificaciones del Servidor, se conforma un TLS Stream del crate de
     /// rustls, que nos permite encriptar el Stream con TLS.
+    ///
+    /// Si se indica un certificado de cliente, se lo presenta cuando el Broker lo pide.
     fn build_tls_stream(
         stream: TcpStream,
+        client_certificate: Option<ClientCertificate>,
     ) -> Result<Arc<StreamOwned<ClientConnection, TcpStream>>, ProtocolError> {
         let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
         let mut root_store = RootCertStore::empty();
@@ -138,9 +311,17 @@ impl Client {
 
         root_store.add_parsable_certificates(certs);
 
-        let mut config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+        let builder = ClientConfig::builder().with_root_certificates(root_store);
+        let mut config = match client_certificate {
+            Some(client_certificate) => {
+                let (certificates, private_key) = client_certificate.load()?;
+                match builder.with_client_auth_cert(certificates, private_key) {
+                    Ok(config) => config,
+                    Err(e) => return Err(ProtocolError::ClientConnectionError(e.to_string())),
+                }
+            }
+            None => builder.with_no_client_auth(),
+        };
 
         config.key_log = Arc::new(KeyLogFile::new());
 
@@ -171,7 +352,7 @@ impl Client {
     pub fn handle_disconnect(
         client_id: String,
         reason: &str,
-        session_expiry_interval: u32,
+        session_expiry_interval: Option<u32>,
     ) -> ClientMessage {
         let reason_code: u8;
         let reason_string: String;
@@ -232,7 +413,11 @@ impl Client {
 
         let (disconnect_sender, disconnect_receiver) = mpsc::channel();
         let client_id_clone = self.client_id.clone();
-        let packet_ids_ref = Arc::clone(&self.packets_ids);
+        let keep_alive = self.keep_alive;
+        let topic_aliases = TopicAliases::new(self.topic_alias_maximum);
+        let pending_requests = Arc::clone(&self.pending_requests);
+        let broker_flow_control = self.broker_flow_control;
+        let flow_control = FlowControl::new(0, self.maximum_packet_size);
 
         let _write_messages = threadpool.execute(move || {
             Client::write_messages(
@@ -241,7 +426,9 @@ impl Client {
                 pending_id_messages_sender,
                 puback_notify_receiver,
                 disconnect_receiver,
-                packet_ids_ref,
+                keep_alive,
+                topic_aliases,
+                broker_flow_control,
             )
         });
 
@@ -255,6 +442,8 @@ impl Client {
                     sender_channel,
                     disconnect_sender,
                     client_id_clone,
+                    pending_requests,
+                    flow_control,
                 ) {
                     Ok(_) => {
                         stream_ref.get_ref().shutdown(Shutdown::Both).map_err(|_| {
@@ -270,15 +459,30 @@ impl Client {
         Ok(())
     }
 
+    /// Lee los mensajes que llegan del broker y los procesa.
+    ///
+    /// Lleva registro de los packet ids de los Publish con QoS 2 recibidos cuyo Pubrel todavia no llego,
+    /// para no entregarle al sistema dos veces el mismo mensaje.
+    ///
+    /// Las respuestas a los requests pendientes se entregan a quien hizo el request, y no al sistema.
+    ///
+    /// Si el broker envia un packet que supera el maximum packet size del cliente, se lo descarta sin leer su
+    /// contenido y se cierra la conexion con un Disconnect(0x95).
+    #[allow(clippy::too_many_arguments)]
     pub fn receive_messages(
         stream: Arc<StreamOwned<ClientConnection, TcpStream>>,
         pending_id_messages_receiver: Receiver<u16>,
-        puback_notify_sender: Sender<bool>,
+        puback_notify_sender: Sender<u16>,
         sender_channel: Sender<ClientMessage>,
         disconnect_sender: Sender<bool>,
         client_id: String,
+        pending_requests: PendingRequests,
+        flow_control: FlowControl,
     ) -> Result<(), ProtocolError> {
         let mut pending_messages = Vec::new();
+        let mut qos2_received = Vec::new();
+        // El broker ya respeta el topic alias maximum indicado en el Connect.
+        let mut topic_aliases = TopicAliases::new(u16::MAX);
 
         loop {
             if let Ok(packet) = pending_id_messages_receiver.try_recv() {
@@ -287,22 +491,46 @@ impl Client {
                 }
             }
 
-            if let Ok(message) = BrokerMessage::read_from(stream.get_ref()) {
-                match Client::handle_message(
-                    message,
-                    pending_messages.clone(),
-                    puback_notify_sender.clone(),
-                    sender_channel.clone(),
-                    client_id.clone(),
-                ) {
-                    Ok(return_value) => {
-                        if return_value == ClientReturn::DisconnectRecieved {
-                            disconnect_sender.send(true).expect("Error al desconectar");
-                            return Ok(());
-                        }
+            let message = match BrokerMessage::read_with_limit(
+                stream.get_ref(),
+                flow_control.maximum_packet_size(),
+            ) {
+                Ok(message) => message,
+                Err(e) if is_packet_too_large(&e) => {
+                    reject_packet_too_large(&stream, &sender_channel, client_id)?;
+                    disconnect_sender.send(true).expect("Error al desconectar");
+                    return Ok(());
+                }
+                Err(_) => continue,
+            };
+
+            let message = match resolve_topic_alias(message, &mut topic_aliases) {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            };
+
+            let delivery_channel = take_request_channel(&message, &pending_requests)
+                .unwrap_or_else(|| sender_channel.clone());
+
+            match Client::handle_message(
+                message,
+                &stream,
+                pending_messages.clone(),
+                &mut qos2_received,
+                puback_notify_sender.clone(),
+                delivery_channel,
+                client_id.clone(),
+            ) {
+                Ok(return_value) => {
+                    if return_value == ClientReturn::DisconnectRecieved {
+                        disconnect_sender.send(true).expect("Error al desconectar");
+                        return Ok(());
                     }
-                    Err(err) => return Err(err),
                 }
+                Err(err) => return Err(err),
             }
         }
     }
@@ -310,10 +538,14 @@ impl Client {
     /// Lee del stream un mensaje y lo procesa
     /// Devuelve un ClientReturn con informacion del mensaje recibido
     /// O ProtocolError en caso de error
+    ///
+    /// Los acks del flujo de QoS 2(Pubrec, Pubrel y Pubcomp) se escriben directamente en el stream.
     pub fn handle_message(
         message: BrokerMessage,
+        stream: &Arc<StreamOwned<ClientConnection, TcpStream>>,
         pending_messages: Vec<u16>,
-        puback_notify_sender: Sender<bool>,
+        qos2_received: &mut Vec<u16>,
+        puback_notify_sender: Sender<u16>,
         sender_channel: Sender<ClientMessage>,
         client_id: String,
     ) -> Result<ClientReturn, ProtocolError> {
@@ -330,11 +562,49 @@ impl Client {
                 packet_id_msb,
                 packet_id_lsb,
                 reason_code: _,
-            } => handle_puback(
-                &pending_messages,
+            } => handle_puback(packet_id_msb, packet_id_lsb, puback_notify_sender),
+            BrokerMessage::Pubrec {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code,
+            } => {
+                // Si el broker rechazo el Publish, el flujo termina sin enviar el Pubrel.
+                if reason_code >= UNSPECIFIED_ERROR_HEX {
+                    return handle_pubcomp(
+                        puback_notify_sender,
+                        u16::from_be_bytes([packet_id_msb, packet_id_lsb]),
+                    );
+                }
+
+                let pubrel = ClientMessage::Pubrel {
+                    packet_id: u16::from_be_bytes([packet_id_msb, packet_id_lsb]),
+                    reason_code: SUCCESS_HEX,
+                };
+                pubrel.write_to(stream.get_ref())?;
+
+                Ok(ClientReturn::PubrecRecieved)
+            }
+            BrokerMessage::Pubrel {
+                packet_id_msb,
+                packet_id_lsb,
+                reason_code: _,
+            } => {
+                let packet_id = u16::from_be_bytes([packet_id_msb, packet_id_lsb]);
+                let pubcomp = ClientMessage::Pubcomp {
+                    packet_id,
+                    reason_code: release_qos2_delivery(qos2_received, packet_id),
+                };
+                pubcomp.write_to(stream.get_ref())?;
+
+                Ok(ClientReturn::PubrelRecieved)
+            }
+            BrokerMessage::Pubcomp {
                 packet_id_msb,
                 packet_id_lsb,
+                reason_code: _,
+            } => handle_pubcomp(
                 puback_notify_sender,
+                u16::from_be_bytes([packet_id_msb, packet_id_lsb]),
             ),
             BrokerMessage::Disconnect {
                 reason_code,
@@ -346,7 +616,7 @@ impl Client {
                     reason_string,
                     &sender_channel,
                     reason_code,
-                    session_expiry_interval,
+                    Some(session_expiry_interval).filter(|interval| *interval != 0),
                     client_id,
                 );
 
@@ -355,7 +625,7 @@ impl Client {
             BrokerMessage::Suback {
                 packet_id_msb,
                 packet_id_lsb,
-                reason_code: _,
+                reason_codes: _,
             } => {
                 handle_suback(&pending_messages, packet_id_msb, packet_id_lsb);
 
@@ -380,14 +650,40 @@ impl Client {
                     properties,
                 };
 
-                handle_publish_delivery(sender_channel, publish);
+                if qos == 1 {
+                    handle_publish_delivery(sender_channel, publish);
+
+                    // Con el Puback el broker deja de considerar al mensaje como in-flight.
+                    let puback = ClientMessage::Puback {
+                        packet_id,
+                        reason_code: SUCCESS_HEX,
+                    };
+                    puback.write_to(stream.get_ref())?;
+                    return Ok(ClientReturn::PublishDeliveryRecieved);
+                }
+
+                if qos != 2 {
+                    handle_publish_delivery(sender_channel, publish);
+                    return Ok(ClientReturn::PublishDeliveryRecieved);
+                }
+
+                // Un Publish con QoS 2 se entrega al sistema solo la primera vez que llega.
+                if register_qos2_delivery(qos2_received, packet_id) {
+                    handle_publish_delivery(sender_channel, publish);
+                }
+
+                let pubrec = ClientMessage::Pubrec {
+                    packet_id,
+                    reason_code: SUCCESS_HEX,
+                };
+                pubrec.write_to(stream.get_ref())?;
 
                 Ok(ClientReturn::PublishDeliveryRecieved)
             }
             BrokerMessage::Unsuback {
                 packet_id_msb,
                 packet_id_lsb,
-                reason_code: _,
+                reason_codes: _,
             } => {
                 handle_unsuback(pending_messages, packet_id_msb, packet_id_lsb);
                 Ok(ClientReturn::UnsubackRecieved)
@@ -417,17 +713,33 @@ impl Client {
 
     /// Esta funcion se encarga de la escritura de mensajes que recibe mediante el channel.
     ///
+    /// Los publish con qos 1 se envian y quedan en una ventana de mensajes in-flight hasta recibir su puback.
+    /// Si el puback no llega dentro de IN_FLIGHT_TIMEOUT, se reenvia el publish con el dup_flag en 1, indicando que no es el primer envio.
+    ///
+    /// Si el mensaje es un publish con qos 2, se espera a que termine el flujo Pubrec/Pubrel/Pubcomp(el Pubrel lo envia el thread de lectura).
+    ///
+    /// Si durante el keep_alive no se envio ningun packet, se envia un Pingreq para que el broker no cierre la conexion.
     ///
-    /// Si el mensaje es un publish con qos 1, se envia el mensaje y se espera un puback. Si no se recibe, espera 0.5 segundos y reenvia el mensaje. aumentando en 1 el dup_flag, indicando que es al vez numero n que se envia el publish.
+    /// Los publish se envian con el topic alias que indique su configuracion, siempre que el broker lo acepte.
+    ///
+    /// Mientras la ventana in-flight ocupa todo el receive maximum del broker, los Publish con QoS > 0 esperan, en
+    /// orden, a que se libere lugar. El resto de los mensajes se envia igual.
+    /// Los mensajes que superan el maximum packet size del broker se descartan.
     #[allow(clippy::too_many_arguments)]
     fn write_messages(
         stream: Arc<StreamOwned<ClientConnection, TcpStream>>,
         receiver_channel: Arc<Mutex<Receiver<Box<dyn MessagesConfig + Send>>>>,
         pending_id_messages_sender: Sender<u16>,
-        puback_notify_receiver: Receiver<bool>,
+        puback_notify_receiver: Receiver<u16>,
         disconnect_receiver: Receiver<bool>,
-        packet_ids: Arc<Vec<u16>>,
+        keep_alive: Duration,
+        mut topic_aliases: TopicAliases,
+        broker_flow_control: FlowControl,
     ) -> Result<(), ProtocolError> {
+        let mut in_flight = HashMap::new();
+        let mut waiting_for_quota = VecDeque::new();
+        let mut last_sent = Instant::now();
+
         loop {
             let stream_ref = Arc::clone(&stream);
             if let Ok(disconnect_status) = disconnect_receiver.try_recv() {
@@ -436,43 +748,69 @@ impl Client {
                 }
             }
 
+            remove_acknowledged_messages(&puback_notify_receiver, &mut in_flight);
+            for publish in get_expired_in_flight_messages(&mut in_flight, IN_FLIGHT_TIMEOUT) {
+                println!("Reenviando publish sin puback");
+                apply_topic_alias(&publish, &mut topic_aliases)
+                    .write_to(stream.get_ref())
+                    .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+                last_sent = Instant::now();
+            }
+
+            if ping_is_due(keep_alive, last_sent) {
+                ClientMessage::Pingreq
+                    .write_to(stream.get_ref())
+                    .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+                last_sent = Instant::now();
+            }
+
+            while broker_flow_control.can_send(in_flight.len()) {
+                let publish = match waiting_for_quota.pop_front() {
+                    Some(publish) => publish,
+                    None => break,
+                };
+                write_publish(
+                    publish,
+                    &stream,
+                    &pending_id_messages_sender,
+                    &puback_notify_receiver,
+                    &mut in_flight,
+                    &mut topic_aliases,
+                )?;
+                last_sent = Instant::now();
+            }
+
             let lock = match receiver_channel.lock() {
                 Ok(lock) => lock,
                 Err(_) => return Err(ProtocolError::StreamError),
             };
-            if let Ok(message_config) = lock.recv() {
-                let packet_id = Client::get_packet_id(packet_ids.to_vec());
+            if let Ok(message_config) = lock.recv_timeout(IN_FLIGHT_CHECK_INTERVAL) {
+                let packet_id =
+                    Client::get_packet_id(packet_ids_in_use(&in_flight, &waiting_for_quota));
                 let message = message_config.parse_message(packet_id);
+                if !broker_flow_control.fits(FlowControl::client_packet_size(&message)?) {
+                    eprintln!("{}", ProtocolError::PacketTooLarge);
+                    continue;
+                }
+                last_sent = Instant::now();
 
                 match message {
-                    ClientMessage::Publish {
-                        packet_id,
-                        topic_name,
-                        qos,
-                        retain_flag,
-                        payload,
-                        dup_flag,
-                        properties,
-                    } => {
-                        let publish = ClientMessage::Publish {
-                            packet_id,
-                            topic_name: topic_name.clone(),
-                            qos,
-                            retain_flag,
-                            payload: payload.clone(),
-                            dup_flag,
-                            properties: properties.clone(),
-                        };
-                        if let Some(value) = write_publish(
-                            publish,
-                            packet_id,
-                            qos,
+                    ClientMessage::Publish { qos, .. }
+                        if qos > 0
+                            && (!waiting_for_quota.is_empty()
+                                || !broker_flow_control.can_send(in_flight.len())) =>
+                    {
+                        waiting_for_quota.push_back(message);
+                    }
+                    ClientMessage::Publish { .. } => {
+                        write_publish(
+                            message,
                             &stream,
                             &pending_id_messages_sender,
                             &puback_notify_receiver,
-                        ) {
-                            return value;
-                        }
+                            &mut in_flight,
+                            &mut topic_aliases,
+                        )?;
                     }
                     ClientMessage::Disconnect {
                         reason_code: _,
@@ -504,25 +842,23 @@ impl Client {
         }
     }
 
+    /// Escribe el mensaje. Su packet id se registra como pendiente antes de escribirlo, para que el thread de
+    /// lectura ya lo conozca cuando llegue la respuesta.
     fn write_message_to_stream(
         stream: Arc<StreamOwned<ClientConnection, TcpStream>>,
         pending_id_messages_sender: Sender<u16>,
         packet_id: Option<u16>,
         message: ClientMessage,
     ) -> Result<(), ProtocolError> {
-        match message.write_to(stream.get_ref()) {
-            Ok(_) => {
-                if let Some(id) = packet_id {
-                    match pending_id_messages_sender.send(id) {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(ProtocolError::SendError(e.to_string())),
-                    }
-                } else {
-                    Ok(())
-                }
-            }
-            Err(e) => Err(ProtocolError::SendError(e.to_string())),
+        if let Some(id) = packet_id {
+            pending_id_messages_sender
+                .send(id)
+                .map_err(|e| ProtocolError::SendError(e.to_string()))?;
         }
+
+        message
+            .write_to(stream.get_ref())
+            .map_err(|e| ProtocolError::SendError(e.to_string()))
     }
 
     pub fn get_publish_end_channel(
@@ -545,55 +881,266 @@ impl Client {
         }
         packet_id
     }
+
+    /// Publica un request en el topic recibido y bloquea hasta que llegue su respuesta, o hasta que se cumpla el timeout.
+    ///
+    /// El request lleva como response topic al topic de respuestas del cliente, y una correlation data unica con la
+    /// que se reconoce a su respuesta. La primera vez que se hace un request, el cliente se subscribe a ese topic.
+    ///
+    /// El request se envia con QoS 0, ya que es la respuesta la que confirma que el responder lo recibio; y expira junto
+    /// con el timeout, para que no se ejecute si quien lo envio ya dejo de esperar.
+    ///
+    /// Tanto el request como el Subscribe se envian por message_channel, el channel con el que se le pasan al
+    /// cliente los mensajes a enviar, para que los escriba el thread de escritura con su packet id.
+    pub fn request(
+        &self,
+        message_channel: &Sender<Box<dyn MessagesConfig + Send>>,
+        topic: String,
+        payload: PayloadTypes,
+        timeout: Duration,
+    ) -> Result<ClientMessage, ProtocolError> {
+        self.subscribe_to_response_topic(message_channel)?;
+
+        let correlation_data = rand::thread_rng().gen::<u128>().to_be_bytes().to_vec();
+        let (response_sender, response_receiver) = mpsc::channel();
+        self.pending_requests
+            .lock()
+            .map_err(|_| ProtocolError::LockError)?
+            .insert(correlation_data.clone(), response_sender);
+
+        let properties = PublishProperties::new(
+            1,
+            timeout.as_secs_f64().ceil() as u32,
+            TopicProperties {
+                topic_alias: 0,
+                response_topic: self.response_topic(),
+            },
+            correlation_data.clone(),
+            String::new(),
+            0,
+            String::new(),
+        );
+        let publish = PublishConfig::new(0, 0, 0, topic, payload, properties);
+
+        let response = match message_channel.send(Box::new(publish)) {
+            Ok(()) => response_receiver
+                .recv_timeout(timeout)
+                .map_err(|_| ProtocolError::RequestTimeout),
+            Err(e) => Err(ProtocolError::SendError(e.to_string())),
+        };
+
+        if let Ok(mut pending_requests) = self.pending_requests.lock() {
+            pending_requests.remove(&correlation_data);
+        }
+
+        response
+    }
+
+    /// Topic en el que el cliente recibe las respuestas a sus requests.
+    pub fn response_topic(&self) -> String {
+        format!("{}/{}", RESPONSE_TOPIC_PREFIX, self.client_id)
+    }
+
+    /// Se subscribe al topic de respuestas del cliente, si todavia no lo habia hecho.
+    ///
+    /// Como el thread de escritura envia los mensajes en orden, y el broker procesa en orden los packets de una
+    /// conexion, el Subscribe queda registrado antes de que llegue el request al responder.
+    fn subscribe_to_response_topic(
+        &self,
+        message_channel: &Sender<Box<dyn MessagesConfig + Send>>,
+    ) -> Result<(), ProtocolError> {
+        if self.response_topic_subscribed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let subscribe = SubscribeConfig::new(
+            vec![self.response_topic()],
+            SubscribeProperties::new(0, Vec::new()),
+            self.client_id.clone(),
+        );
+
+        if let Err(e) = message_channel.send(Box::new(subscribe)) {
+            self.response_topic_subscribed
+                .store(false, Ordering::SeqCst);
+            return Err(ProtocolError::SendError(e.to_string()));
+        }
+
+        Ok(())
+    }
 }
+
+/// Si el mensaje es la respuesta a un request pendiente, devuelve el channel de quien hizo el request
+/// y lo saca de los requests pendientes.
+fn take_request_channel(
+    message: &BrokerMessage,
+    pending_requests: &PendingRequests,
+) -> Option<Sender<ClientMessage>> {
+    let correlation_data = match message {
+        BrokerMessage::PublishDelivery { properties, .. } => &properties.correlation_data,
+        _ => return None,
+    };
+
+    if correlation_data.is_empty() {
+        return None;
+    }
+
+    match pending_requests.lock() {
+        Ok(mut pending_requests) => pending_requests.remove(correlation_data),
+        Err(_) => None,
+    }
+}
+
 /// Recibe todos los campos necesarios para la escritura por stream de un mensaje Publish.
-/// En caso de que este tenga una QoS == 1 y no se reiba un Puback, se reenvia el mensaje hasta recibirlo
+/// En caso de que este tenga una QoS == 1, se lo agrega a la ventana in-flight hasta recibir su Puback.
+/// Con QoS == 2 se espera hasta recibir el Pubcomp.
 fn write_publish(
     publish: ClientMessage,
-    packet_id: u16,
-    qos: usize,
     stream: &Arc<StreamOwned<ClientConnection, TcpStream>>,
     pending_id_messages_sender: &Sender<u16>,
-    puback_notify_receiver: &Receiver<bool>,
-) -> Option<Result<(), ProtocolError>> {
-    match publish.write_to(stream.get_ref()) {
-        Ok(_) => match pending_id_messages_sender.send(packet_id) {
-            Ok(_) => {
-                if qos == 1 {
-                    loop {
-                        thread::sleep(Duration::from_millis(20));
-
-                        if let Ok(puback) = puback_notify_receiver.try_recv() {
-                            if !puback {
-                                match publish.write_to(stream.get_ref()) {
-                                    Ok(_) => match pending_id_messages_sender.send(packet_id) {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            return Some(Err(ProtocolError::SendError(
-                                                e.to_string(),
-                                            )))
-                                        }
-                                    },
-                                    Err(e) => {
-                                        return Some(Err(ProtocolError::SendError(e.to_string())))
-                                    }
-                                }
-                            } else {
-                                break;
-                            }
-                        }
-                    }
+    puback_notify_receiver: &Receiver<u16>,
+    in_flight: &mut HashMap<u16, (ClientMessage, Instant)>,
+    topic_aliases: &mut TopicAliases,
+) -> Result<(), ProtocolError> {
+    let (packet_id, qos) = match &publish {
+        ClientMessage::Publish { packet_id, qos, .. } => (*packet_id, *qos),
+        _ => return Ok(()),
+    };
+    pending_id_messages_sender
+        .send(packet_id)
+        .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+    apply_topic_alias(&publish, topic_aliases)
+        .write_to(stream.get_ref())
+        .map_err(|e| ProtocolError::SendError(e.to_string()))?;
+
+    if qos == 1 {
+        in_flight.insert(packet_id, (publish, Instant::now()));
+    } else if qos == 2 {
+        loop {
+            thread::sleep(Duration::from_millis(20));
+
+            if let Ok(acknowledged_id) = puback_notify_receiver.try_recv() {
+                if acknowledged_id == packet_id {
+                    break;
                 }
+                in_flight.remove(&acknowledged_id);
             }
-            Err(e) => {
-                return Some(Err(ProtocolError::SendError(e.to_string())));
-            }
-        },
-        Err(e) => {
-            return Some(Err(ProtocolError::SendError(e.to_string())));
         }
     }
-    None
+
+    Ok(())
+}
+
+/// Packet ids de los Publish que todavia no terminaron su flujo: los de la ventana in-flight y los que esperan
+/// lugar en ella. No se pueden asignar a un mensaje nuevo.
+fn packet_ids_in_use(
+    in_flight: &HashMap<u16, (ClientMessage, Instant)>,
+    waiting_for_quota: &VecDeque<ClientMessage>,
+) -> Vec<u16> {
+    let waiting_ids = waiting_for_quota
+        .iter()
+        .filter_map(|message| match message {
+            ClientMessage::Publish { packet_id, .. } => Some(*packet_id),
+            _ => None,
+        });
+
+    in_flight.keys().copied().chain(waiting_ids).collect()
+}
+
+/// Si el topic alias del publish ya esta asociado a su topic, se lo envia con el topic name vacio.
+///
+/// En la ventana in-flight se guarda el publish original, con su topic name. Los reenvios tambien pasan por
+/// aca: el alias ya establecido sigue valido durante toda la conexion, y en una nueva se vuelve a establecer.
+fn apply_topic_alias(publish: &ClientMessage, topic_aliases: &mut TopicAliases) -> ClientMessage {
+    let mut publish = publish.clone();
+    if let ClientMessage::Publish {
+        topic_name,
+        properties,
+        ..
+    } = &mut publish
+    {
+        let (aliased_topic_name, topic_alias) =
+            topic_aliases.apply(topic_name, properties.topic_properties.topic_alias);
+        *topic_name = aliased_topic_name;
+        properties.topic_properties.topic_alias = topic_alias;
+    }
+
+    publish
+}
+
+/// Reemplaza el topic name vacio de un publish recibido por el topic asociado a su alias.
+fn resolve_topic_alias(
+    message: BrokerMessage,
+    topic_aliases: &mut TopicAliases,
+) -> Result<BrokerMessage, ProtocolError> {
+    match message {
+        BrokerMessage::PublishDelivery {
+            packet_id,
+            topic_name,
+            qos,
+            retain_flag,
+            payload,
+            dup_flag,
+            properties,
+        } => Ok(BrokerMessage::PublishDelivery {
+            packet_id,
+            topic_name: topic_aliases
+                .resolve(&topic_name, properties.topic_properties.topic_alias)?,
+            qos,
+            retain_flag,
+            payload,
+            dup_flag,
+            properties,
+        }),
+        message => Ok(message),
+    }
+}
+
+/// Un keep alive de 0 indica que no se deben enviar Pingreqs.
+fn ping_is_due(keep_alive: Duration, last_sent: Instant) -> bool {
+    !keep_alive.is_zero() && last_sent.elapsed() >= keep_alive
+}
+
+/// Saca de la ventana in-flight los Publish cuyo Puback ya fue recibido.
+fn remove_acknowledged_messages(
+    puback_notify_receiver: &Receiver<u16>,
+    in_flight: &mut HashMap<u16, (ClientMessage, Instant)>,
+) {
+    while let Ok(packet_id) = puback_notify_receiver.try_recv() {
+        in_flight.remove(&packet_id);
+    }
+}
+
+/// Devuelve los Publish de la ventana in-flight que no fueron confirmados dentro del timeout,
+/// marcados como reenvio(dup_flag = 1). Su tiempo de espera vuelve a comenzar.
+///
+/// Los Publish se reenvian con el message expiry interval que les queda. Los que expiraron se sacan de la ventana.
+fn get_expired_in_flight_messages(
+    in_flight: &mut HashMap<u16, (ClientMessage, Instant)>,
+    timeout: Duration,
+) -> Vec<ClientMessage> {
+    let mut expired = Vec::new();
+
+    in_flight.retain(|_, (publish, sent_at)| {
+        if sent_at.elapsed() < timeout {
+            return true;
+        }
+
+        match publish
+            .clone()
+            .with_remaining_expiry(sent_at.elapsed().as_secs())
+        {
+            Some(remaining) => {
+                *publish = remaining;
+                publish.mark_as_duplicate();
+                *sent_at = Instant::now();
+                expired.push(publish.clone());
+                true
+            }
+            None => false,
+        }
+    });
+
+    expired
 }
 
 fn handle_unsuback(pending_messages: Vec<u16>, packet_id_msb: u8, packet_id_lsb: u8) {
@@ -625,27 +1172,86 @@ fn handle_suback(pending_messages: &Vec<u16>, packet_id_msb: u8, packet_id_lsb:
     }
 }
 
+/// Notifica al thread de escritura que el Publish con QoS 1 fue confirmado,
+/// para que lo saque de su ventana de mensajes in-flight.
 fn handle_puback(
-    pending_messages: &Vec<u16>,
     packet_id_msb: u8,
     packet_id_lsb: u8,
-    puback_notify_sender: Sender<bool>,
+    puback_notify_sender: Sender<u16>,
 ) -> Result<ClientReturn, ProtocolError> {
-    for pending_message in pending_messages {
-        let packet_id_bytes: [u8; 2] = pending_message.to_be_bytes();
-        if packet_id_bytes[0] == packet_id_msb && packet_id_bytes[1] == packet_id_lsb {}
-    }
-    match puback_notify_sender.send(true) {
+    match puback_notify_sender.send(u16::from_be_bytes([packet_id_msb, packet_id_lsb])) {
         Ok(_) => Ok(ClientReturn::PubackRecieved),
         Err(e) => Err(ProtocolError::ChanellError(e.to_string())),
     }
 }
 
+/// Notifica al thread de escritura que termino el flujo de un Publish con QoS 2.
+fn handle_pubcomp(
+    puback_notify_sender: Sender<u16>,
+    packet_id: u16,
+) -> Result<ClientReturn, ProtocolError> {
+    match puback_notify_sender.send(packet_id) {
+        Ok(_) => Ok(ClientReturn::PubcompRecieved),
+        Err(e) => Err(ProtocolError::ChanellError(e.to_string())),
+    }
+}
+
+/// Registra el packet id de un Publish con QoS 2 recibido.
+///
+/// Devuelve false si ya estaba registrado: en ese caso el Publish es un duplicado que ya
+/// fue entregado al sistema, y no debe volver a procesarse.
+fn register_qos2_delivery(qos2_received: &mut Vec<u16>, packet_id: u16) -> bool {
+    if qos2_received.contains(&packet_id) {
+        return false;
+    }
+
+    qos2_received.push(packet_id);
+    true
+}
+
+/// Libera el packet id de un Publish con QoS 2 al recibir su Pubrel.
+/// Devuelve el reason code que debe llevar el Pubcomp.
+fn release_qos2_delivery(qos2_received: &mut Vec<u16>, packet_id: u16) -> u8 {
+    match qos2_received.iter().position(|id| *id == packet_id) {
+        Some(index) => {
+            qos2_received.remove(index);
+            SUCCESS_HEX
+        }
+        None => PACKET_ID_NOT_FOUND_HEX,
+    }
+}
+
+/// Cierra la conexion con el broker por haber recibido un packet que supera el maximum packet size del cliente,
+/// y se lo informa al sistema.
+fn reject_packet_too_large(
+    stream: &Arc<StreamOwned<ClientConnection, TcpStream>>,
+    sender_channel: &Sender<ClientMessage>,
+    client_id: String,
+) -> Result<(), ProtocolError> {
+    let reason_string = ProtocolError::PacketTooLarge.to_string();
+    ClientMessage::Disconnect {
+        reason_code: PACKET_TOO_LARGE_HEX,
+        session_expiry_interval: None,
+        reason_string: reason_string.clone(),
+        client_id: client_id.clone(),
+    }
+    .write_to(stream.get_ref())?;
+
+    handle_disconnect(
+        reason_string,
+        sender_channel,
+        PACKET_TOO_LARGE_HEX,
+        None,
+        client_id,
+    );
+    Ok(())
+}
+
 fn handle_disconnect(
     reason_string: String,
     sender_channel: &Sender<ClientMessage>,
     reason_code: u8,
-    session_expiry_interval: u32,
+    session_expiry_interval: Option<u32>,
     client_id: String,
 ) {
     println!(
@@ -731,6 +1337,18 @@ pub fn handle_message(
                     Err(e) => Err(ProtocolError::ChanellError(e.to_string())),
                 }
             }
+            BrokerMessage::Pubrec { .. } => {
+                println!("Recibi un mensaje {:?}", message);
+                Ok(ClientReturn::PubrecRecieved)
+            }
+            BrokerMessage::Pubrel { .. } => {
+                println!("Recibi un mensaje {:?}", message);
+                Ok(ClientReturn::PubrelRecieved)
+            }
+            BrokerMessage::Pubcomp { .. } => {
+                println!("Recibi un mensaje {:?}", message);
+                Ok(ClientReturn::PubcompRecieved)
+            }
             BrokerMessage::Disconnect {
                 reason_code,
                 session_expiry_interval,
@@ -744,7 +1362,8 @@ pub fn handle_message(
 
                 match sender_chanell.send(ClientMessage::Disconnect {
                     reason_code,
-                    session_expiry_interval,
+                    session_expiry_interval: Some(session_expiry_interval)
+                        .filter(|interval| *interval != 0),
                     reason_string,
                     client_id,
                 }) {
@@ -757,7 +1376,7 @@ pub fn handle_message(
             BrokerMessage::Suback {
                 packet_id_msb,
                 packet_id_lsb,
-                reason_code: _,
+                reason_codes: _,
             } => {
                 for pending_message in &pending_messages {
                     let packet_id_bytes: [u8; 2] = pending_message.to_be_bytes();
@@ -796,7 +1415,7 @@ pub fn handle_message(
             BrokerMessage::Unsuback {
                 packet_id_msb,
                 packet_id_lsb,
-                reason_code: _,
+                reason_codes: _,
             } => {
                 for pending_message in &pending_messages {
                     let packet_id_bytes: [u8; 2] = pending_message.to_be_bytes();
@@ -966,4 +1585,160 @@ mod tests {
         });
         handle.join().unwrap();
     }
+
+    #[test]
+    fn test_qos_2_delivery_is_processed_once() {
+        let mut qos2_received = Vec::new();
+
+        assert!(register_qos2_delivery(&mut qos2_received, 7));
+        assert!(!register_qos2_delivery(&mut qos2_received, 7));
+
+        assert_eq!(release_qos2_delivery(&mut qos2_received, 7), SUCCESS_HEX);
+        assert_eq!(
+            release_qos2_delivery(&mut qos2_received, 7),
+            PACKET_ID_NOT_FOUND_HEX
+        );
+
+        assert!(register_qos2_delivery(&mut qos2_received, 7));
+    }
+
+    #[test]
+    fn test_responses_are_delivered_to_their_request() {
+        let pending_requests: PendingRequests = Arc::new(Mutex::new(HashMap::new()));
+        let (response_sender, response_receiver) = mpsc::channel();
+        pending_requests
+            .lock()
+            .unwrap()
+            .insert(vec![1, 2, 3], response_sender);
+
+        let delivery = |correlation_data: Vec<u8>| BrokerMessage::PublishDelivery {
+            packet_id: 1,
+            topic_name: "responses/cliente".to_string(),
+            qos: 0,
+            retain_flag: 0,
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                0,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                correlation_data,
+                String::new(),
+                0,
+                String::new(),
+            ),
+            payload: PayloadTypes::WillPayload("ok".to_string()),
+        };
+
+        assert!(take_request_channel(&delivery(vec![9]), &pending_requests).is_none());
+        assert!(take_request_channel(&BrokerMessage::Pingresp, &pending_requests).is_none());
+
+        let channel = take_request_channel(&delivery(vec![1, 2, 3]), &pending_requests).unwrap();
+        channel
+            .send(ClientMessage::Pingreq)
+            .expect("Error al enviar la respuesta");
+        assert_eq!(response_receiver.recv().unwrap(), ClientMessage::Pingreq);
+
+        // Una vez entregada la respuesta, el request deja de estar pendiente.
+        assert!(take_request_channel(&delivery(vec![1, 2, 3]), &pending_requests).is_none());
+    }
+
+    #[test]
+    fn test_unacknowledged_qos_1_publish_is_retransmitted_with_dup() {
+        let publish = ClientMessage::Publish {
+            packet_id: 3,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::CamerasUpdatePayload(vec![]),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+        let mut in_flight = HashMap::new();
+        in_flight.insert(3, (publish, Instant::now()));
+
+        assert!(get_expired_in_flight_messages(&mut in_flight, Duration::from_secs(60)).is_empty());
+
+        let expired = get_expired_in_flight_messages(&mut in_flight, Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert!(matches!(
+            expired[0],
+            ClientMessage::Publish { dup_flag: 1, .. }
+        ));
+
+        let (puback_notify_sender, puback_notify_receiver) = mpsc::channel();
+        puback_notify_sender.send(3).unwrap();
+        remove_acknowledged_messages(&puback_notify_receiver, &mut in_flight);
+        assert!(in_flight.is_empty());
+
+        // Si expiro mientras esperaba el Puback, no se reenvia y sale de la ventana.
+        in_flight.insert(
+            3,
+            (expired[0].clone(), Instant::now() - Duration::from_secs(20)),
+        );
+        assert!(get_expired_in_flight_messages(&mut in_flight, Duration::ZERO).is_empty());
+        assert!(in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_pingreq_is_sent_after_keep_alive() {
+        let long_ago = Instant::now() - Duration::from_secs(40);
+
+        assert!(ping_is_due(Duration::from_secs(35), long_ago));
+        assert!(!ping_is_due(Duration::from_secs(35), Instant::now()));
+        assert!(!ping_is_due(Duration::ZERO, long_ago));
+        assert_eq!(Client::negotiate_keep_alive(35, 0), Duration::from_secs(35));
+        assert_eq!(
+            Client::negotiate_keep_alive(35, 10),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn test_packet_ids_of_unfinished_publishes_are_not_reused() {
+        let publish = |packet_id: u16| ClientMessage::Publish {
+            packet_id,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("incendio".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                0,
+                0,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+        let mut in_flight = HashMap::new();
+        in_flight.insert(3, (publish(3), Instant::now()));
+        let waiting_for_quota = VecDeque::from([publish(7)]);
+
+        let mut in_use = packet_ids_in_use(&in_flight, &waiting_for_quota);
+        in_use.sort();
+        assert_eq!(in_use, vec![3, 7]);
+
+        for _ in 0..100 {
+            assert!(!in_use.contains(&Client::get_packet_id(in_use.clone())));
+        }
+    }
 }
@@ -0,0 +1,197 @@
+use std::{fs::File, io::BufReader};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_pemfile::{certs, private_key};
+use serde::Deserialize;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::protocol_error::ProtocolError;
+
+/// Indica a que campo del Connect se mapea el common name(CN) del certificado que presenta un cliente
+/// cuando el Broker pide certificados de clientes(TLS mutuo).
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertificateIdentity {
+    /// El client_id del Connect debe coincidir con el CN del certificado.
+    ClientId,
+
+    /// El CN del certificado se usa como username del cliente, sin importar el que venga en el Connect.
+    Username,
+}
+
+impl std::str::FromStr for CertificateIdentity {
+    type Err = ProtocolError;
+
+    fn from_str(identity: &str) -> Result<CertificateIdentity, ProtocolError> {
+        match identity {
+            "client_id" => Ok(CertificateIdentity::ClientId),
+            "username" => Ok(CertificateIdentity::Username),
+            _ => Err(ProtocolError::ServerConfigError(format!(
+                "Identidad de certificado desconocida: {}",
+                identity
+            ))),
+        }
+    }
+}
+
+/// Certificado(y su clave privada) con el que un Client se identifica ante el Broker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCertificate {
+    certificate_file_path: String,
+    private_key_file_path: String,
+}
+
+impl ClientCertificate {
+    pub fn new(certificate_file_path: &str, private_key_file_path: &str) -> ClientCertificate {
+        ClientCertificate {
+            certificate_file_path: certificate_file_path.to_string(),
+            private_key_file_path: private_key_file_path.to_string(),
+        }
+    }
+
+    /// Lee la cadena de certificados del archivo PEM.
+    pub fn read_certificates(
+        file_path: &str,
+    ) -> Result<Vec<CertificateDer<'static>>, ProtocolError> {
+        let mut file = ClientCertificate::open_file(file_path)?;
+
+        match certs(&mut file).collect::<Result<Vec<_>, _>>() {
+            Ok(certificates) if !certificates.is_empty() => Ok(certificates),
+            Ok(_) => Err(ProtocolError::ReadingCertificateError(format!(
+                "No hay certificados en {}",
+                file_path
+            ))),
+            Err(e) => Err(ProtocolError::ReadingCertificateError(e.to_string())),
+        }
+    }
+
+    /// Devuelve la cadena de certificados y la clave privada, listas para la configuracion de rustls.
+    pub fn load(
+        &self,
+    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), ProtocolError> {
+        let certificates = ClientCertificate::read_certificates(&self.certificate_file_path)?;
+
+        let mut file = ClientCertificate::open_file(&self.private_key_file_path)?;
+        match private_key(&mut file) {
+            Ok(Some(key)) => Ok((certificates, key)),
+            Ok(None) => Err(ProtocolError::ReadingPrivateKeyError),
+            Err(e) => Err(ProtocolError::ReadingCertificateError(e.to_string())),
+        }
+    }
+
+    fn open_file(file_path: &str) -> Result<BufReader<File>, ProtocolError> {
+        match File::open(file_path) {
+            Ok(file) => Ok(BufReader::new(file)),
+            Err(e) => Err(ProtocolError::OpenFileError(e.to_string())),
+        }
+    }
+}
+
+/// Devuelve el common name(CN) del subject de un certificado X.509 codificado en DER.
+///
+/// Si el subject no tiene un CN, o tiene mas de uno, no se puede saber a quien identifica el certificado y se
+/// devuelve None.
+pub fn common_name(certificate: &[u8]) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    let mut common_names = certificate.subject().iter_common_name();
+
+    let common_name = common_names.next()?.as_str().ok()?.to_string();
+    if common_names.next().is_some() {
+        return None;
+    }
+    Some(common_name)
+}
+
+/// Certificados que generan los tests, para no tener claves privadas en el repositorio.
+#[cfg(test)]
+pub(crate) mod test_certificates {
+    use std::path::Path;
+
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    use super::ClientCertificate;
+
+    /// Genera en el directorio una CA de clientes y un certificado firmado por ella con el common name indicado.
+    /// Devuelve el path del certificado de la CA y el certificado del cliente.
+    pub fn generate(directory: &Path, common_name: &str) -> (String, ClientCertificate) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "rustic_city_eye clients CA");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_certificate = ca_params.self_signed(&ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(Vec::new()).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let client_certificate = client_params
+            .signed_by(&client_key, &ca_certificate, &ca_key)
+            .unwrap();
+
+        let path = |file_name: &str| directory.join(file_name).to_string_lossy().to_string();
+        std::fs::write(path("clients_ca.pem"), ca_certificate.pem()).unwrap();
+        std::fs::write(path("client_cert.pem"), client_certificate.pem()).unwrap();
+        std::fs::write(path("client_key.pem"), client_key.serialize_pem()).unwrap();
+
+        (
+            path("clients_ca.pem"),
+            ClientCertificate::new(&path("client_cert.pem"), &path("client_key.pem")),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_01_reading_common_names() -> Result<(), ProtocolError> {
+        let directory =
+            tempfile::tempdir().map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        let (ca_file_path, drone_certificate) =
+            test_certificates::generate(directory.path(), "drone");
+
+        let (drone_certificates, _) = drone_certificate.load()?;
+        assert_eq!(
+            common_name(drone_certificates[0].as_ref()),
+            Some("drone".to_string())
+        );
+
+        let ca_certificates = ClientCertificate::read_certificates(&ca_file_path)?;
+        assert_eq!(
+            common_name(ca_certificates[0].as_ref()),
+            Some("rustic_city_eye clients CA".to_string())
+        );
+
+        let broker_certificates =
+            ClientCertificate::read_certificates("./src/mqtt/certs/cert.pem")?;
+        assert_eq!(
+            common_name(broker_certificates[0].as_ref()),
+            Some("rustic_city_eye".to_string())
+        );
+
+        assert_eq!(common_name(&[0x30, 0x05, 0x01]), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_02_loading_a_client_certificate() -> Result<(), ProtocolError> {
+        let directory =
+            tempfile::tempdir().map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        let (_, certificate) = test_certificates::generate(directory.path(), "drone");
+        assert!(certificate.load().is_ok());
+
+        let certificate =
+            ClientCertificate::new(&certificate.certificate_file_path, "./no_existe.pem");
+        assert!(matches!(
+            certificate.load(),
+            Err(ProtocolError::OpenFileError(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -1,8 +1,14 @@
-use std::fs::File;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use super::{client_message::ClientMessage, protocol_error::ProtocolError};
+use super::{
+    client_message::ClientMessage, protocol_error::ProtocolError, subscription::Subscription,
+};
 
 /// Estructura que representa la configuración de un cliente
 #[derive(Serialize, Deserialize)]
@@ -11,12 +17,60 @@ pub(crate) struct ClientConfig {
     pub client_id: String,
     /// Estado del cliente
     pub state: bool,
-    /// Lista de suscripciones del cliente
-    pub subscriptions: Vec<String>,
-    /// Lista de mensajes pendientes
-    pub pending_messages: Vec<ClientMessage>,
+    /// Lista de suscripciones del cliente, con sus opciones y subscription identifier
+    #[serde(deserialize_with = "read_subscriptions")]
+    pub subscriptions: Vec<Subscription>,
+    /// Cola de mensajes recibidos mientras el cliente estaba desconectado, en orden de llegada
+    pub pending_messages: Vec<PendingMessage>,
+    /// Segundos que se mantiene la sesion luego de que el cliente se desconecta
+    #[serde(default)]
+    pub session_expiry_interval: u32,
+    /// Momento de la desconexion(en segundos desde UNIX_EPOCH). Vale None mientras el cliente esta conectado
+    #[serde(default)]
+    pub disconnected_at: Option<u64>,
 }
 
+/// Mensaje encolado para un cliente desconectado.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct PendingMessage {
+    pub message: ClientMessage,
+    /// Momento en que se encolo el mensaje(en segundos desde UNIX_EPOCH)
+    pub queued_at: u64,
+}
+
+/// Subscripcion guardada en una sesion. Las sesiones guardadas por versiones anteriores solo tienen el topic.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredSubscription {
+    Subscription(Subscription),
+    Topic(String),
+}
+
+/// Lee las subscripciones de una sesion. Las que solo tienen el topic toman las opciones por defecto; su
+/// client_id se completa al cargar la sesion.
+fn read_subscriptions<'de, D>(deserializer: D) -> Result<Vec<Subscription>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let stored: Vec<StoredSubscription> = Vec::deserialize(deserializer)?;
+    Ok(stored
+        .into_iter()
+        .map(|subscription| match subscription {
+            StoredSubscription::Subscription(subscription) => subscription,
+            StoredSubscription::Topic(topic) => Subscription::new(topic, String::new()),
+        })
+        .collect())
+}
+
+/// Con este session expiry interval la sesion no expira nunca.
+const SESSION_NEVER_EXPIRES: u32 = u32::MAX;
+
+/// Cantidad maxima de mensajes encolados por cliente. Al superarla se descartan los mas viejos.
+const MAX_PENDING_MESSAGES: usize = 1000;
+
+/// Segundos que un mensaje puede permanecer encolado antes de descartarse.
+const PENDING_MESSAGE_EXPIRY: u64 = 24 * 60 * 60;
+
 /// Implementación de métodos para la estructura ClientConfig
 /// Métodos para guardar, modificar y obtener la configuración de un cliente
 impl ClientConfig {
@@ -28,69 +82,145 @@ impl ClientConfig {
             state,
             subscriptions: Vec::new(),
             pending_messages: Vec::new(),
+            session_expiry_interval: 0,
+            disconnected_at: None,
+        }
+    }
+
+    /// Archivo en el que se guarda la sesion del cliente, dentro del directorio de sesiones del Broker.
+    fn path(sessions_directory: &str, client_id: &str) -> PathBuf {
+        Path::new(sessions_directory).join(format!("{}.json", client_id))
+    }
+
+    fn load(
+        sessions_directory: &str,
+        client_id: &str,
+    ) -> Result<ClientConfig, Box<dyn std::error::Error>> {
+        let path = ClientConfig::path(sessions_directory, client_id);
+        let file = File::open(path)?;
+        let mut client_config: ClientConfig = serde_json::from_reader(file)?;
+        for subscription in &mut client_config.subscriptions {
+            subscription.client_id = client_config.client_id.clone();
         }
+        Ok(client_config)
     }
 
-    /// Cambia el estado de un cliente en el archivo json
-    pub fn change_client_state(
+    fn save(&self, sessions_directory: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = ClientConfig::path(sessions_directory, &self.client_id);
+        let json = serde_json::to_string(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Marca al cliente como conectado y guarda el session expiry interval que pidio en el Connect.
+    pub fn start_session(
+        sessions_directory: &str,
         client_id: String,
-        state: bool,
+        session_expiry_interval: u32,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
-        let file = File::open(&path)?;
-        let client_config: ClientConfig = serde_json::from_reader(file)?;
-        let new_client_config = ClientConfig {
-            client_id: client_config.client_id,
-            state,
-            subscriptions: client_config.subscriptions,
-            pending_messages: client_config.pending_messages,
+        let mut client_config = ClientConfig::load(sessions_directory, &client_id)?;
+        client_config.state = true;
+        client_config.session_expiry_interval = session_expiry_interval;
+        client_config.disconnected_at = None;
+        client_config.save(sessions_directory)
+    }
+
+    /// Marca al cliente como desconectado, guardando el momento de la desconexion.
+    /// Si el Disconnect indica un session expiry interval, reemplaza al del Connect.
+    pub fn end_session(
+        sessions_directory: &str,
+        client_id: String,
+        session_expiry_interval: Option<u32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut client_config = ClientConfig::load(sessions_directory, &client_id)?;
+        client_config.state = false;
+        client_config.disconnected_at = Some(ClientConfig::now());
+        if let Some(interval) = session_expiry_interval {
+            client_config.session_expiry_interval = interval;
+        }
+        client_config.save(sessions_directory)
+    }
+
+    /// Devuelve el session expiry interval vigente de la sesion del cliente.
+    pub fn get_session_expiry_interval(
+        sessions_directory: &str,
+        client_id: &str,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        Ok(ClientConfig::load(sessions_directory, client_id)?.session_expiry_interval)
+    }
+
+    /// Indica si existe una sesion previa del cliente que todavia no expiro.
+    ///
+    /// Con un session expiry interval de 0 la sesion termina al desconectarse el cliente.
+    pub fn session_is_valid(sessions_directory: &str, client_id: String) -> bool {
+        let client_config = match ClientConfig::load(sessions_directory, &client_id) {
+            Ok(client_config) => client_config,
+            Err(_) => return false,
         };
-        let json = serde_json::to_string(&new_client_config)?;
-        std::fs::write(&path, json)?;
-        Ok(())
+
+        match client_config.disconnected_at {
+            None => true,
+            Some(disconnected_at) => match client_config.session_expiry_interval {
+                SESSION_NEVER_EXPIRES => true,
+                0 => false,
+                interval => ClientConfig::now().saturating_sub(disconnected_at) <= interval as u64,
+            },
+        }
     }
 
     /// Guarda la configuración de un cliente en un archivo json
-    pub fn create_client_log_in_json(client_id: String) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn create_client_log_in_json(
+        sessions_directory: &str,
+        client_id: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let client_config = ClientConfig::new(client_id.clone());
         let json = serde_json::to_string(&client_config)?;
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
+        let path = ClientConfig::path(sessions_directory, &client_id);
 
         std::fs::write(path, json)?;
         Ok(())
     }
 
-    /// Agrega una nueva suscripción a un cliente en el archivo json
+    /// Agrega una nueva suscripción a un cliente en el archivo json, con sus opciones y subscription identifier.
+    /// Si el cliente ya estaba subscripto a ese topic filter, la reemplaza.
     pub fn add_new_subscription(
-        client_id: String,
-        topic: String,
+        sessions_directory: &str,
+        subscription: &Subscription,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
-        if !ClientConfig::client_exists(client_id.clone()) {
-            let _ = ClientConfig::create_client_log_in_json(client_id.clone());
+        let client_id = subscription.client_id.clone();
+        if !ClientConfig::client_exists(sessions_directory, client_id.clone()) {
+            let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
         }
 
-        let file = std::fs::File::open(path.clone())?;
-        let mut client_config: ClientConfig = serde_json::from_reader(file)?;
-        if client_config.subscriptions.contains(&topic) {
-            return Ok(());
-        }
-
-        client_config.subscriptions.push(topic);
-        let json = serde_json::to_string(&client_config)?;
-        std::fs::write(path, json)?;
-        Ok(())
+        let mut client_config = ClientConfig::load(sessions_directory, &client_id)?;
+        client_config
+            .subscriptions
+            .retain(|stored| !stored.same_subscription(subscription));
+        client_config.subscriptions.push(subscription.clone());
+        client_config.save(sessions_directory)
     }
 
     /// Remueve una suscripción de un cliente en el archivo json
     pub fn remove_subscription(
+        sessions_directory: &str,
         client_id: String,
         topic: String,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
+        let path = ClientConfig::path(sessions_directory, &client_id);
         let file = std::fs::File::open(path.clone())?;
         let mut client_config: ClientConfig = serde_json::from_reader(file)?;
-        if let Some(index) = client_config.subscriptions.iter().position(|x| x == &topic) {
+        if let Some(index) = client_config
+            .subscriptions
+            .iter()
+            .position(|subscription| subscription.topic == topic)
+        {
             client_config.subscriptions.remove(index);
             let json = serde_json::to_string(&client_config)?;
             std::fs::write(path, json)?;
@@ -101,16 +231,19 @@ impl ClientConfig {
     }
 
     /// Verifica si un cliente existe en el archivo json
-    pub fn client_exists(client_id: String) -> bool {
+    pub fn client_exists(sessions_directory: &str, client_id: String) -> bool {
         // verifica si un cliente existe en el archivo json
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
+        let path = ClientConfig::path(sessions_directory, &client_id);
         std::fs::metadata(path).is_ok()
     }
 
     /// Remueve un cliente del archivo json
-    pub fn delete_client_file(client_id: String) -> Result<(), ProtocolError> {
+    pub fn delete_client_file(
+        sessions_directory: &str,
+        client_id: String,
+    ) -> Result<(), ProtocolError> {
         // remueve un cliente del archivo json
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
+        let path = ClientConfig::path(sessions_directory, &client_id);
         if std::fs::metadata(&path).is_err() {
             return Ok(());
         }
@@ -121,8 +254,8 @@ impl ClientConfig {
         }
     }
 
-    pub fn client_is_online(client_id: String) -> bool {
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
+    pub fn client_is_online(sessions_directory: &str, client_id: String) -> bool {
+        let path = ClientConfig::path(sessions_directory, &client_id);
         if std::fs::metadata(&path).is_ok() {
             let file = match std::fs::File::open(path) {
                 Ok(file) => file,
@@ -139,9 +272,10 @@ impl ClientConfig {
     }
 
     pub fn _remove_all_subscriptions_from_file(
+        sessions_directory: &str,
         client_id: String,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
+        let path = ClientConfig::path(sessions_directory, &client_id);
         let file = std::fs::File::open(path.clone())?;
         let mut client_config: ClientConfig = serde_json::from_reader(file)?;
         client_config.subscriptions = Vec::new();
@@ -150,42 +284,82 @@ impl ClientConfig {
         Ok(())
     }
 
-    pub fn _get_client_subscriptions(
+    pub fn get_client_subscriptions(
+        sessions_directory: &str,
         client_id: String,
-    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
-        let file = std::fs::File::open(path.clone())?;
-        let client_config: ClientConfig = serde_json::from_reader(file)?;
-        Ok(client_config.subscriptions)
+    ) -> Result<Vec<Subscription>, Box<dyn std::error::Error>> {
+        Ok(ClientConfig::load(sessions_directory, &client_id)?.subscriptions)
     }
 
+    /// Encola un mensaje para un cliente desconectado, para enviarselo cuando se reconecte.
+    ///
+    /// Si la cola esta llena se descarta el mensaje mas viejo.
     pub fn add_offline_message(
+        sessions_directory: &str,
         client_id: String,
         message: ClientMessage,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
-        if !ClientConfig::client_exists(client_id.clone()) {
-            let _ = ClientConfig::create_client_log_in_json(client_id.clone());
+        if !ClientConfig::client_exists(sessions_directory, client_id.clone()) {
+            let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
         }
-        let file = std::fs::File::open(path.clone())?;
-        let mut client_config: ClientConfig = serde_json::from_reader(file)?;
-        let json = serde_json::to_string(&message)?;
-        client_config.subscriptions.push(json);
-        let json = serde_json::to_string(&client_config)?;
-        std::fs::write(path, json)?;
+        let mut client_config = ClientConfig::load(sessions_directory, &client_id)?;
+        client_config.pending_messages.push(PendingMessage {
+            message,
+            queued_at: ClientConfig::now(),
+        });
 
-        Ok(())
+        let pending = client_config.pending_messages.len();
+        if pending > MAX_PENDING_MESSAGES {
+            client_config
+                .pending_messages
+                .drain(..pending - MAX_PENDING_MESSAGES);
+        }
+
+        client_config.save(sessions_directory)
+    }
+
+    /// Vacia la cola de mensajes pendientes del cliente, devolviendo en orden de llegada los que no expiraron.
+    ///
+    /// Ademas de PENDING_MESSAGE_EXPIRY, se respeta el message expiry interval de cada Publish, que se
+    /// devuelve descontando el tiempo que estuvo encolado.
+    pub fn take_offline_messages(
+        sessions_directory: &str,
+        client_id: String,
+    ) -> Result<Vec<ClientMessage>, Box<dyn std::error::Error>> {
+        let mut client_config = ClientConfig::load(sessions_directory, &client_id)?;
+        let now = ClientConfig::now();
+        let messages = std::mem::take(&mut client_config.pending_messages)
+            .into_iter()
+            .filter_map(|pending| {
+                let queued_for = now.saturating_sub(pending.queued_at);
+                if queued_for > PENDING_MESSAGE_EXPIRY {
+                    return None;
+                }
+                pending.message.with_remaining_expiry(queued_for)
+            })
+            .collect();
+        client_config.save(sessions_directory)?;
+
+        Ok(messages)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        mqtt::{
+            publish::publish_properties::{PublishProperties, TopicProperties},
+            subscription::SubscriptionOptions,
+        },
+        utils::payload_types::PayloadTypes,
+    };
+
     impl ClientConfig {
         /// Obtiene un cliente del archivo json
-        pub fn get_client(client_id: String) -> ClientConfig {
+        pub fn get_client(sessions_directory: &str, client_id: String) -> ClientConfig {
             // obtiene un cliente del archivo json
-            let path = format!("./src/mqtt/clients/{}.json", client_id);
+            let path = ClientConfig::path(sessions_directory, &client_id);
             let file = std::fs::File::open(path).unwrap();
             serde_json::from_reader(file).unwrap()
         }
@@ -202,43 +376,227 @@ mod tests {
 
     #[test]
     fn test_change_client_state() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
         let client_id = "test".to_string();
-        let _ = ClientConfig::create_client_log_in_json(client_id.clone());
-        let _ = ClientConfig::change_client_state(client_id.clone(), false);
-        let client_config = ClientConfig::get_client(client_id.clone());
+        let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
+        let _ = ClientConfig::end_session(sessions_directory, client_id.clone(), None);
+        let client_config = ClientConfig::get_client(sessions_directory, client_id.clone());
         assert!(!client_config.state);
-        ClientConfig::delete_client_file(client_id.clone()).unwrap();
+        ClientConfig::delete_client_file(sessions_directory, client_id.clone()).unwrap();
     }
 
     #[test]
     fn test_create_client_log_in_json() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
         let client_id = "test".to_string();
-        let _ = ClientConfig::create_client_log_in_json(client_id.clone());
-        let path = format!("./src/mqtt/clients/{}.json", client_id);
+        let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
+        let path = ClientConfig::path(sessions_directory, &client_id);
         assert!(std::fs::metadata(path).is_ok());
-        ClientConfig::delete_client_file(client_id.clone()).unwrap();
+        ClientConfig::delete_client_file(sessions_directory, client_id.clone()).unwrap();
     }
 
     #[test]
     fn test_add_new_subscription() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
         let client_id = "test".to_string();
         let topic = "test".to_string();
-        let _ = ClientConfig::create_client_log_in_json(client_id.clone());
-        let _ = ClientConfig::add_new_subscription(client_id.clone(), topic.clone());
-        let client_config = ClientConfig::get_client(client_id.clone());
-        assert_eq!(client_config.subscriptions[0], topic);
-        ClientConfig::delete_client_file(client_id.clone()).unwrap();
+        let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
+        let mut subscription = Subscription::new(topic.clone(), client_id.clone())
+            .with_options(SubscriptionOptions::new(true, false, 1).with_maximum_qos(1));
+        subscription.subscription_identifier = 7;
+        let _ = ClientConfig::add_new_subscription(sessions_directory, &subscription);
+        let client_config = ClientConfig::get_client(sessions_directory, client_id.clone());
+        assert_eq!(client_config.subscriptions, vec![subscription.clone()]);
+
+        // Al volver a subscribirse al mismo topic filter se reemplazan sus opciones.
+        let subscription = Subscription::new(topic, client_id.clone());
+        let _ = ClientConfig::add_new_subscription(sessions_directory, &subscription);
+        assert_eq!(
+            ClientConfig::get_client_subscriptions(sessions_directory, client_id.clone()).unwrap(),
+            vec![subscription]
+        );
+        ClientConfig::delete_client_file(sessions_directory, client_id.clone()).unwrap();
+    }
+
+    #[test]
+    fn test_sessions_with_only_the_topic_of_each_subscription_are_read() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
+        let client_id = "test_previous_session".to_string();
+        std::fs::write(
+            ClientConfig::path(sessions_directory, &client_id),
+            r#"{"client_id":"test_previous_session","state":false,"subscriptions":["incident"],"pending_messages":[]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ClientConfig::get_client_subscriptions(sessions_directory, client_id.clone()).unwrap(),
+            vec![Subscription::new("incident".to_string(), client_id.clone())]
+        );
+        ClientConfig::delete_client_file(sessions_directory, client_id).unwrap();
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
+        let client_id = "test_session".to_string();
+        let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
+        let _ = ClientConfig::start_session(sessions_directory, client_id.clone(), 30);
+        assert!(ClientConfig::session_is_valid(
+            sessions_directory,
+            client_id.clone()
+        ));
+
+        let _ = ClientConfig::end_session(sessions_directory, client_id.clone(), None);
+        assert!(ClientConfig::session_is_valid(
+            sessions_directory,
+            client_id.clone()
+        ));
+        assert!(!ClientConfig::client_is_online(
+            sessions_directory,
+            client_id.clone()
+        ));
+
+        let _ = ClientConfig::end_session(sessions_directory, client_id.clone(), Some(0));
+        assert!(!ClientConfig::session_is_valid(
+            sessions_directory,
+            client_id.clone()
+        ));
+
+        ClientConfig::delete_client_file(sessions_directory, client_id.clone()).unwrap();
+        assert!(!ClientConfig::session_is_valid(
+            sessions_directory,
+            client_id
+        ));
     }
 
     #[test]
     fn test_remove_subscription() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
         let client_id = "test".to_string();
         let topic = "test".to_string();
-        let _ = ClientConfig::create_client_log_in_json(client_id.clone());
-        let _ = ClientConfig::add_new_subscription(client_id.clone(), topic.clone());
-        let _ = ClientConfig::remove_subscription(client_id.clone(), topic.clone());
-        let client_config = ClientConfig::get_client(client_id.clone());
+        let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
+        let _ = ClientConfig::add_new_subscription(
+            sessions_directory,
+            &Subscription::new(topic.clone(), client_id.clone()),
+        );
+        let _ =
+            ClientConfig::remove_subscription(sessions_directory, client_id.clone(), topic.clone());
+        let client_config = ClientConfig::get_client(sessions_directory, client_id.clone());
         assert_eq!(client_config.subscriptions.len(), 0);
-        ClientConfig::delete_client_file(client_id.clone()).unwrap();
+        ClientConfig::delete_client_file(sessions_directory, client_id.clone()).unwrap();
+    }
+
+    fn offline_publish(topic_name: &str, message_expiry_interval: u32) -> ClientMessage {
+        ClientMessage::Publish {
+            packet_id: 1,
+            topic_name: topic_name.to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("location".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                message_expiry_interval,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_offline_messages_are_delivered_in_order() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
+        let client_id = "test_offline_messages".to_string();
+        let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
+        let publish = |topic_name: &str| offline_publish(topic_name, 0);
+        let _ = ClientConfig::add_offline_message(
+            sessions_directory,
+            client_id.clone(),
+            publish("expired"),
+        );
+        let mut client_config = ClientConfig::get_client(sessions_directory, client_id.clone());
+        client_config.pending_messages[0].queued_at = 0;
+        client_config.save(sessions_directory).unwrap();
+
+        let _ = ClientConfig::add_offline_message(
+            sessions_directory,
+            client_id.clone(),
+            publish("first"),
+        );
+        let _ = ClientConfig::add_offline_message(
+            sessions_directory,
+            client_id.clone(),
+            publish("second"),
+        );
+
+        let messages =
+            ClientConfig::take_offline_messages(sessions_directory, client_id.clone()).unwrap();
+        assert_eq!(messages, vec![publish("first"), publish("second")]);
+        assert!(
+            ClientConfig::take_offline_messages(sessions_directory, client_id.clone())
+                .unwrap()
+                .is_empty()
+        );
+
+        ClientConfig::delete_client_file(sessions_directory, client_id).unwrap();
+    }
+
+    #[test]
+    fn test_offline_messages_respect_message_expiry() {
+        let directory = tempfile::tempdir().unwrap();
+        let sessions_directory = directory.path().to_str().unwrap();
+        let client_id = "test_offline_message_expiry".to_string();
+        let _ = ClientConfig::create_client_log_in_json(sessions_directory, client_id.clone());
+        let _ = ClientConfig::add_offline_message(
+            sessions_directory,
+            client_id.clone(),
+            offline_publish("stale", 10),
+        );
+        let _ = ClientConfig::add_offline_message(
+            sessions_directory,
+            client_id.clone(),
+            offline_publish("fresh", 60),
+        );
+        let _ = ClientConfig::add_offline_message(
+            sessions_directory,
+            client_id.clone(),
+            offline_publish("no_expiry", 0),
+        );
+        let mut client_config = ClientConfig::get_client(sessions_directory, client_id.clone());
+        for pending in client_config.pending_messages.iter_mut() {
+            pending.queued_at -= 20;
+        }
+        client_config.save(sessions_directory).unwrap();
+
+        let messages =
+            ClientConfig::take_offline_messages(sessions_directory, client_id.clone()).unwrap();
+        assert_eq!(messages.len(), 2);
+        match &messages[0] {
+            ClientMessage::Publish {
+                topic_name,
+                properties,
+                ..
+            } => {
+                assert_eq!(topic_name, "fresh");
+                assert!(properties.message_expiry_interval <= 40);
+            }
+            _ => panic!("Se esperaba un Publish"),
+        }
+        assert_eq!(messages[1], offline_publish("no_expiry", 0));
+
+        ClientConfig::delete_client_file(sessions_directory, client_id).unwrap();
     }
 }
@@ -12,13 +12,19 @@ use super::connect::connect_properties::ConnectProperties;
 use super::connect::will_properties::WillProperties;
 use super::messages_config::MessagesConfig;
 use super::payload::Payload;
-use super::subscription::Subscription;
+use super::subscription::{Subscription, SubscriptionOptions};
 
 use super::protocol_error::ProtocolError;
 use crate::mqtt::connect::last_will::LastWill;
+const PROTOCOL_NAME: &str = "MQTT";
 const PROTOCOL_VERSION: u8 = 5;
+const CONNECT_HEADER: u8 = 0x10;
 const SESSION_EXPIRY_INTERVAL_ID: u8 = 0x11;
+const AUTHENTICATION_METHOD_ID: u8 = 0x15;
+const AUTHENTICATION_DATA_ID: u8 = 0x16;
+const SERVER_REFERENCE_ID: u8 = 0x1C;
 const REASON_STRING_ID: u8 = 0x1F;
+const USER_PROPERTY_ID: u8 = 0x26;
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 ///El Connect Message es el primer mensaje que el cliente envia cuando se conecta al broker. Este contiene toda la informacion necesaria para que el broker identifique al cliente y pueda establecer una sesion con los parametros establecidos.
@@ -33,11 +39,11 @@ const REASON_STRING_ID: u8 = 0x1F;
 ///
 /// finalmente, si el cliente envia un username y un password, estos se escriben en el payload.
 pub struct Connect {
-    clean_start: bool,
+    pub(crate) clean_start: bool,
     last_will_flag: bool,
     last_will_qos: u8,
     last_will_retain: bool,
-    keep_alive: u16,
+    pub(crate) keep_alive: u16,
     pub(crate) properties: ConnectProperties,
 
     /// Connect Payload
@@ -83,6 +89,40 @@ pub enum ClientMessage {
         properties: PublishProperties,
     },
 
+    /// Respuesta a un Publish con QoS 1 que el broker le entrego al cliente.
+    /// Al recibirlo, el broker deja de considerar al mensaje como in-flight.
+    Puback {
+        /// packet_id del Publish al que se responde.
+        packet_id: u16,
+        /// reason_code indica si el Publish fue aceptado(valores menores a 0x80) o no.
+        reason_code: u8,
+    },
+
+    /// Respuesta a un Publish con QoS 2. Es el primer paso del flujo de entrega exactly-once:
+    /// quien lo envia ya recibio el Publish, y no debe volver a procesarlo aunque le llegue duplicado.
+    Pubrec {
+        /// packet_id del Publish al que se responde.
+        packet_id: u16,
+        /// reason_code indica si el Publish fue aceptado(valores menores a 0x80) o no.
+        reason_code: u8,
+    },
+
+    /// Respuesta a un Pubrec. Le indica al receptor del Publish que puede liberar el packet_id.
+    Pubrel {
+        /// packet_id del Publish al que se responde.
+        packet_id: u16,
+        /// reason_code indica si el packet_id fue encontrado(0x00) o no(0x92).
+        reason_code: u8,
+    },
+
+    /// Respuesta a un Pubrel. Es el ultimo paso del flujo de entrega de un Publish con QoS 2.
+    Pubcomp {
+        /// packet_id del Publish al que se responde.
+        packet_id: u16,
+        /// reason_code indica si el packet_id fue encontrado(0x00) o no(0x92).
+        reason_code: u8,
+    },
+
     /// El Subscribe Message se utiliza para suscribirse a uno o más topics. El cliente puede enviar un mensaje de subscribe con un packet id y una lista de topics a los que se quiere suscribir. El broker responde con un mensaje de suback con el mismo packet id y una lista de return codes que indican si la suscripcion fue exitosa o no.
     Subscribe {
         /// packet_id es un identificador unico que el cliente asigna a cada mensaje que envia.
@@ -90,7 +130,7 @@ pub enum ClientMessage {
         /// properties es un struct que contiene las propiedades del mensaje de subscribe.
         properties: SubscribeProperties,
         /// Vector de subscription es un struct que contiene la informacion de la suscripcion.
-        payload: Subscription,
+        payload: Vec<Subscription>,
     },
 
     /// El Unsubscribe Message se utiliza para cancelar una o más suscripciones. El cliente envia un mensaje de unsubscribe con un packet id y una lista de topics de los que quiere desuscribirse. El broker responde con un mensaje de unsuback con el mismo packet id y una lista de return codes que indican si la desuscripcion fue exitosa o no.
@@ -100,7 +140,7 @@ pub enum ClientMessage {
         /// properties es un struct que contiene las propiedades del mensaje de unsubscribe.
         properties: SubscribeProperties,
         /// Vector de subscription es un struct que contiene la informacion de la suscripcion.
-        payload: Subscription,
+        payload: Vec<Subscription>,
     },
 
     /// Es el ultimo mensaje que el cliente envia antes de desconectarse, este mensaje contiene informacion sobre la razon de la desconexión y propiedades adicionales.
@@ -108,7 +148,8 @@ pub enum ClientMessage {
         /// reason_code es el codigo de la razon de la desconexión.
         reason_code: u8,
         /// session_expiry_interval es el tiempo en segundos que el broker debe mantener la sesion del cliente activa despues de que este se desconecte.
-        session_expiry_interval: u32,
+        /// Si es None se mantiene el que se indico en el Connect.
+        session_expiry_interval: Option<u32>,
         /// reason_string es un mensaje de texto que describe la razon de la desconexión.
         reason_string: String,
         /// client_id es el identificador unico del cliente.
@@ -262,37 +303,38 @@ impl Connect {
     }
 
     pub fn write_to(&self, mut writer: impl Write) -> Result<(), ProtocolError> {
-        //fixed header
-        let byte_1: u8 = 0x10_u8.to_le(); //00010000
-        writer
-            .write_all(&[byte_1])
-            .map_err(|_e| ProtocolError::WriteError)?;
+        let mut body = Vec::new();
+        self.write_body(&mut body)?;
+
+        write_packet(&mut writer, CONNECT_HEADER, &body)?;
+        writer.flush().map_err(|_e| ProtocolError::WriteError)
+    }
 
+    /// Escribe el variable header y el payload del Connect, en el orden que define el protocolo.
+    fn write_body(&self, body: &mut Vec<u8>) -> Result<(), ProtocolError> {
         //protocol name
-        let protocol_name = "MQTT";
-        write_string(&mut writer, protocol_name)?;
+        write_string(body, PROTOCOL_NAME)?;
 
         //protocol version
-        let protocol_version: u8 = 0x05;
-        writer
-            .write_all(&[protocol_version])
-            .map_err(|_e| ProtocolError::WriteError)?;
+        write_u8(body, &PROTOCOL_VERSION)?;
+
         //connection flags
         let mut connect_flags: u8 = 0x00;
         if self.clean_start {
             connect_flags |= 1 << 1; //set bit 1 to 1
         }
 
-        if self.last_will_flag {
-            connect_flags |= 1 << 2;
-        }
         if self.last_will_qos > 1 {
             return Err(ProtocolError::InvalidQOS);
         }
-        connect_flags |= (self.last_will_qos & 0b11) << 3;
+        // Si no hay will message, la QoS y el retain del will deben valer 0.
+        if self.last_will_flag {
+            connect_flags |= 1 << 2;
+            connect_flags |= (self.last_will_qos & 0b11) << 3;
 
-        if self.last_will_retain {
-            connect_flags |= 1 << 5;
+            if self.last_will_retain {
+                connect_flags |= 1 << 5;
+            }
         }
 
         if let Some(password) = self.password.as_ref() {
@@ -307,47 +349,54 @@ impl Connect {
             }
         }
 
-        writer
-            .write_all(&[connect_flags])
-            .map_err(|_e| ProtocolError::WriteError)?;
+        write_u8(body, &connect_flags)?;
 
         //keep alive
-        write_u16(&mut writer, &self.keep_alive)?;
-        write_string(&mut writer, &self.client_id)?;
+        write_u16(body, &self.keep_alive)?;
 
-        if let (Some(will_properties), Some(last_will_topic), Some(last_will_message)) = (
-            self.will_properties.clone(),
-            self.last_will_topic.clone(),
-            self.last_will_message.clone(),
-        ) {
-            if self.last_will_flag {
-                will_properties.write_to(&mut writer)?;
-                write_string(&mut writer, &last_will_topic)?;
-                write_string(&mut writer, &last_will_message)?;
+        //properties
+        self.properties.write_to(body)?;
+
+        //payload
+        write_string(body, &self.client_id)?;
+
+        if self.last_will_flag {
+            match (
+                &self.will_properties,
+                &self.last_will_topic,
+                &self.last_will_message,
+            ) {
+                (Some(will_properties), Some(last_will_topic), Some(last_will_message)) => {
+                    will_properties.write_to(body)?;
+                    write_string(body, last_will_topic)?;
+                    write_bin_vec(body, &last_will_message.as_bytes().to_vec())?;
+                }
+                _ => return Err(ProtocolError::MissingWillMessageProperties),
             }
         }
 
         if let Some(username) = self.username.as_ref() {
             if !username.is_empty() {
-                write_string(&mut writer, username)?;
+                write_string(body, username)?;
             }
         }
 
         if let Some(password) = self.password.as_ref() {
             if !password.is_empty() {
-                write_bin_vec(&mut writer, password)?;
+                write_bin_vec(body, password)?;
             }
         }
 
-        self.properties.write_to(&mut writer)?;
-
         Ok(())
     }
 
+    /// Lee un Connect a partir de lo que sigue al fixed header.
+    ///
+    /// El will message, el username y el password quedan en None si sus flags no estan seteados.
     pub fn read_from(mut stream: impl Read) -> Result<Connect, Error> {
         let protocol_name = read_string(&mut stream)?;
 
-        if protocol_name != "MQTT" {
+        if protocol_name != PROTOCOL_NAME {
             return Err(Error::new(
                 std::io::ErrorKind::Other,
                 "Nombre de protocolo inválido",
@@ -364,6 +413,12 @@ impl Connect {
         }
         //connect flags
         let connect_flags = read_u8(&mut stream)?;
+        if connect_flags & 1 != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "El bit reservado de los connect flags debe valer 0",
+            ));
+        }
         let clean_start = (connect_flags & (1 << 1)) != 0;
         let last_will_flag = (connect_flags & (1 << 2)) != 0;
         let last_will_qos = (connect_flags >> 3) & 0b11;
@@ -371,34 +426,32 @@ impl Connect {
 
         //keep alive
         let keep_alive = read_u16(&mut stream)?;
+
+        //properties
+        let properties = ConnectProperties::read_from(&mut stream)?;
+
         //payload
         //client ID
         let client_id = read_string(&mut stream)?;
 
-        let mut last_will_topic = String::new();
-        let mut will_message = String::new();
-
-        let will_properties = WillProperties::read_from(&mut stream)?;
+        let (mut will_properties, mut last_will_topic, mut last_will_message) = (None, None, None);
         if last_will_flag {
-            last_will_topic = read_string(&mut stream)?;
-            will_message = read_string(&mut stream)?;
+            will_properties = Some(WillProperties::read_from(&mut stream)?);
+            last_will_topic = Some(read_string(&mut stream)?);
+            last_will_message =
+                Some(String::from_utf8_lossy(&read_bin_vec(&mut stream)?).to_string());
         }
 
-        let hay_user = (connect_flags & (1 << 7)) != 0;
-        let mut user = String::new();
-        if hay_user {
-            user = read_string(&mut stream)?;
+        let mut username = None;
+        if (connect_flags & (1 << 7)) != 0 {
+            username = Some(read_string(&mut stream)?);
         }
 
-        let hay_pass = (connect_flags & (1 << 6)) != 0;
-        let mut pass = Vec::new();
-        if hay_pass {
-            pass = read_bin_vec(&mut stream)?;
+        let mut password = None;
+        if (connect_flags & (1 << 6)) != 0 {
+            password = Some(read_bin_vec(&mut stream)?);
         }
 
-        //properties
-        let properties: ConnectProperties = ConnectProperties::read_from(&mut stream)?;
-
         Ok(Connect {
             clean_start,
             last_will_flag,
@@ -407,313 +460,143 @@ impl Connect {
             keep_alive,
             properties,
             client_id,
-            will_properties: Some(will_properties),
-            last_will_topic: Some(last_will_topic),
-            last_will_message: Some(will_message),
-            username: Some(user),
-            password: Some(pass),
+            will_properties,
+            last_will_topic,
+            last_will_message,
+            username,
+            password,
         })
     }
 }
 
 impl ClientMessage {
-    pub fn write_to(&self, stream: impl Write) -> Result<(), ProtocolError> {
-        let mut writer = BufWriter::new(stream);
-        match self {
-            ClientMessage::Connect(connect) => {
-                connect.write_to(&mut writer)?;
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-
-                Ok(())
-            }
-            ClientMessage::Publish {
-                packet_id,
-                topic_name,
-                qos,
-                retain_flag,
-                payload,
-                dup_flag,
-                properties,
-            } => {
-                //fixed header
-                let mut byte_1 = 0x30_u8;
-
-                if *retain_flag == 1 {
-                    //we must replace any existing retained message for this topic and store
-                    //the app message.
-                    byte_1 |= 1 << 0;
-                }
-
-                if *qos == 1 {
-                    byte_1 |= 1 << 1;
-                    byte_1 |= 0 << 2;
-                } else if *qos != 0x00 && *qos != 0x01 {
-                    return Err(ProtocolError::InvalidQOS);
-                }
-
-                if *dup_flag == 1 {
-                    byte_1 |= 1 << 3;
-                }
+    /// Marca un Publish como reenvio, seteando su dup_flag en 1.
+    /// Sobre el resto de los packets no tiene efecto.
+    pub fn mark_as_duplicate(&mut self) {
+        if let ClientMessage::Publish { dup_flag, .. } = self {
+            *dup_flag = 1;
+        }
+    }
 
-                //Dup flag must be set to 0 for all QoS 0 messages.
-                if *qos == 0x00 {
-                    byte_1 |= 0 << 3;
+    /// Descuenta del message expiry interval de un Publish los segundos que estuvo almacenado,
+    /// para que el subscriptor reciba el tiempo de vida restante.
+    ///
+    /// Devuelve None si el mensaje expiro. Un message expiry interval de 0 indica que el mensaje no expira.
+    /// Sobre el resto de los packets no tiene efecto.
+    pub fn with_remaining_expiry(mut self, elapsed_secs: u64) -> Option<ClientMessage> {
+        if let ClientMessage::Publish { properties, .. } = &mut self {
+            let interval = properties.message_expiry_interval as u64;
+            if interval != 0 {
+                if elapsed_secs >= interval {
+                    return None;
                 }
-
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-
-                //Remaining Length
-                write_u16(&mut writer, packet_id)?;
-
-                write_string(&mut writer, topic_name)?;
-
-                //Properties
-                properties
-                    .write_properties(&mut writer)
-                    .map_err(|_e| ProtocolError::WriteError)?;
-
-                //Payload
-                payload.write_to(&mut writer)?;
-
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-                Ok(())
+                properties.message_expiry_interval = (interval - elapsed_secs) as u32;
             }
-            ClientMessage::Subscribe {
-                packet_id,
-                properties,
-                payload,
-            } => {
-                // fixed header
-                self.write_first_packet_byte(&mut writer)?;
-
-                // variable header
-                write_u16(&mut writer, packet_id)?;
-
-                //Properties
-                properties.write_properties(&mut writer)?;
+        }
 
-                // payload
-                write_string(&mut writer, &payload.topic)?;
-                write_string(&mut writer, &payload.client_id)?;
+        Some(self)
+    }
 
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-                Ok(())
+    /// Indica si el mensaje es un request, es decir, un Publish que espera una respuesta en su response topic.
+    pub fn is_request(&self) -> bool {
+        match self {
+            ClientMessage::Publish { properties, .. } => {
+                !properties.topic_properties.response_topic.is_empty()
             }
-            ClientMessage::Unsubscribe {
-                packet_id,
-                properties,
-                payload,
-            } => {
-                // fixed header
-                self.write_first_packet_byte(&mut writer)?;
-
-                // variable header
-                write_u16(&mut writer, packet_id)?;
-
-                // variable header
-                properties.write_properties(&mut writer)?;
-
-                // escribir payload
-                write_string(&mut writer, &payload.topic)?;
-                write_string(&mut writer, &payload.client_id)?;
+            _ => false,
+        }
+    }
 
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-                Ok(())
+    /// Los packets que envia el Client no identifican a quien los envia: el Broker lo conoce por la conexion.
+    ///
+    /// Completa el client_id de las subscripciones y del Disconnect con el del Client de la conexion.
+    pub fn with_client_id(mut self, client_id: &str) -> ClientMessage {
+        match &mut self {
+            ClientMessage::Subscribe { payload, .. }
+            | ClientMessage::Unsubscribe { payload, .. } => {
+                for subscription in payload {
+                    subscription.client_id = client_id.to_string();
+                }
             }
             ClientMessage::Disconnect {
-                reason_code,
-                session_expiry_interval,
-                reason_string,
-                client_id,
-            } => {
-                //fixed header
-                self.write_first_packet_byte(&mut writer)?;
-
-                //variable_header
-                write_u8(&mut writer, reason_code)?;
-
-                write_u8(&mut writer, &SESSION_EXPIRY_INTERVAL_ID)?;
-                write_u32(&mut writer, session_expiry_interval)?;
-
-                write_u8(&mut writer, &REASON_STRING_ID)?;
-                write_string(&mut writer, reason_string)?;
+                client_id: disconnect_client_id,
+                ..
+            } => *disconnect_client_id = client_id.to_string(),
+            _ => {}
+        }
 
-                write_string(&mut writer, client_id)?;
+        self
+    }
 
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-                Ok(())
-            }
-            ClientMessage::Pingreq => {
-                let byte_1: u8 = 0xC0_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-                Ok(())
-            }
-            ClientMessage::Auth {
-                reason_code,
-                authentication_method: _,
-                authentication_data: _,
-                reason_string: _,
-                user_properties: _,
-            } => {
-                println!("authenticating user...");
-                self.write_first_packet_byte(&mut writer)?;
+    /// Escribe el packet completo: fixed header, remaining length, variable header y payload.
+    pub fn write_to(&self, stream: impl Write) -> Result<(), ProtocolError> {
+        let mut writer = BufWriter::new(stream);
 
-                write_u8(&mut writer, reason_code)?;
-                self.write_packet_properties(&mut writer)?;
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
+        let mut body = Vec::new();
+        self.write_body(&mut body)?;
 
-                Ok(())
-            }
-        }
+        write_packet(&mut writer, self.first_packet_byte()?, &body)?;
+        writer.flush().map_err(|_e| ProtocolError::WriteError)
     }
 
-    fn write_first_packet_byte(
-        &self,
-        writer: &mut BufWriter<impl Write>,
-    ) -> Result<(), ProtocolError> {
+    /// Escribe todo lo que sigue al fixed header. Su largo es el remaining length del packet.
+    fn write_body(&self, body: &mut Vec<u8>) -> Result<(), ProtocolError> {
         match self {
-            ClientMessage::Connect { .. } => {
-                let byte_1: u8 = 0x10_u8.to_le(); //00010000
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-            }
+            ClientMessage::Connect(connect) => connect.write_body(body),
             ClientMessage::Publish {
-                packet_id: _,
-                topic_name: _,
+                packet_id,
+                topic_name,
                 qos,
-                retain_flag,
-                payload: _,
-                dup_flag,
-                properties: _,
+                retain_flag: _,
+                payload,
+                dup_flag: _,
+                properties,
             } => {
-                let mut byte_1 = 0x30_u8;
+                write_string(body, topic_name)?;
 
-                if *retain_flag == 1 {
-                    //we must replace any existing retained message for this topic and store
-                    //the app message.
-                    byte_1 |= 1 << 0;
+                // Los Publish con QoS 0 no llevan packet_id.
+                if *qos > 0 {
+                    write_u16(body, packet_id)?;
                 }
 
-                if *qos == 1 {
-                    byte_1 |= 1 << 1;
-                    byte_1 |= 0 << 2;
-                } else if *qos != 0x00 && *qos != 0x01 {
-                    //we should throw a DISCONNECT with reason code 0x81(Malformed packet).
-                    println!("Qos inválido");
-                }
-
-                if *dup_flag == 1 {
-                    byte_1 |= 1 << 3;
-                }
-
-                //Dup flag must be set to 0 for all QoS 0 messages.
-                if *qos == 0x00 {
-                    byte_1 |= 0 << 3;
-                }
+                //Properties
+                properties.for_payload(payload).write_properties(body)?;
 
-                match writer.write_all(&[byte_1]) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        return Err(ProtocolError::WriteError);
-                    }
-                }
-            }
-            ClientMessage::Subscribe {
-                packet_id: _,
-                properties: _,
-                payload: _,
-            } => {
-                let byte_1: u8 = 0x82_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-            }
-            ClientMessage::Unsubscribe {
-                packet_id: _,
-                properties: _,
-                payload: _,
-            } => {
-                let byte_1: u8 = 0xA2_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
+                //Payload
+                payload.write_to(body)
             }
-            ClientMessage::Disconnect {
-                reason_code: _,
-                session_expiry_interval: _,
-                reason_string: _,
-                client_id: _,
-            } => {
-                let byte_1: u8 = 0xE0_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
+            ClientMessage::Puback {
+                packet_id,
+                reason_code,
             }
-            ClientMessage::Pingreq => {
-                let byte_1: u8 = 0xC0_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
+            | ClientMessage::Pubrec {
+                packet_id,
+                reason_code,
             }
-            ClientMessage::Auth {
-                reason_code: _,
-                authentication_method: _,
-                authentication_data: _,
-                reason_string: _,
-                user_properties: _,
-            } => {
-                let byte_1 = 0xF0_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
+            | ClientMessage::Pubrel {
+                packet_id,
+                reason_code,
             }
-        }
-        Ok(())
-    }
-
-    fn write_packet_properties(
-        &self,
-        mut writer: &mut BufWriter<impl Write>,
-    ) -> Result<(), ProtocolError> {
-        match self {
-            ClientMessage::Connect { .. } => Ok(()),
-            ClientMessage::Publish {
+            | ClientMessage::Pubcomp {
                 packet_id,
-                topic_name,
-                qos: _,
-                retain_flag: _,
-                payload: _,
-                dup_flag: _,
-                properties,
+                reason_code,
             } => {
-                write_u16(writer, packet_id)?;
-
-                write_string(writer, topic_name)?;
-
-                //Properties
-                properties.write_properties(writer)?;
-                Ok(())
+                write_u16(body, packet_id)?;
+                write_u8(body, reason_code)
             }
             ClientMessage::Subscribe {
                 packet_id,
                 properties,
                 payload,
             } => {
-                write_u16(writer, packet_id)?;
-
-                //Properties
-                properties.write_properties(writer)?;
-
-                //payload
-                write_string(writer, &payload.topic)?;
-                write_string(writer, &payload.client_id)?;
+                write_u16(body, packet_id)?;
+                properties.write_properties(body)?;
 
+                // payload: cada topic filter seguido de sus opciones
+                for subscription in payload {
+                    write_string(body, &subscription.topic)?;
+                    write_u8(body, &subscription.options.to_byte())?;
+                }
                 Ok(())
             }
             ClientMessage::Unsubscribe {
@@ -721,135 +604,87 @@ impl ClientMessage {
                 properties,
                 payload,
             } => {
-                write_u16(writer, packet_id)?;
-
-                // variable header
-                properties.write_properties(writer)?;
-
-                //payload
-                write_string(&mut writer, &payload.topic)?;
-                write_string(&mut writer, &payload.client_id)?;
+                write_u16(body, packet_id)?;
+                properties.write_unsubscribe_properties(body)?;
 
+                for subscription in payload {
+                    write_string(body, &subscription.topic)?;
+                }
                 Ok(())
             }
             ClientMessage::Disconnect {
                 reason_code,
                 session_expiry_interval,
                 reason_string,
-                client_id,
-            } => {
-                //fixed header
-                let header: u8 = 0xE0_u8.to_le(); //11100000
-                write_u8(writer, &header)?;
-                //variable_header
-                write_u8(writer, reason_code)?;
-
-                write_u8(writer, &SESSION_EXPIRY_INTERVAL_ID)?;
-                write_u32(writer, session_expiry_interval)?;
-
-                write_u8(writer, &REASON_STRING_ID)?;
-                write_string(writer, reason_string)?;
-
-                write_string(writer, client_id)?;
-
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-
-                Ok(())
-            }
-            ClientMessage::Pingreq => {
-                let byte_1: u8 = 0xC0_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-                Ok(())
-            }
+                client_id: _,
+            } => write_disconnect_body(
+                body,
+                reason_code,
+                session_expiry_interval,
+                reason_string,
+                &[],
+            ),
+            ClientMessage::Pingreq => Ok(()),
             ClientMessage::Auth {
                 reason_code,
                 authentication_method,
                 authentication_data,
                 reason_string,
                 user_properties,
-            } => {
-                let byte_1 = 0xF0_u8;
-                writer
-                    .write_all(&[byte_1])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-
-                write_u8(&mut writer, reason_code).map_err(|_e| ProtocolError::WriteError)?;
-
-                let authentication_method_id: u8 = 0x15_u8;
-                writer
-                    .write_all(&[authentication_method_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_string(&mut writer, authentication_method)?;
-
-                let authentication_data_id: u8 = 0x16_u8;
-                writer
-                    .write_all(&[authentication_data_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_bin_vec(&mut writer, authentication_data)?;
-
-                let reason_string_id: u8 = 0x1F_u8;
-                writer
-                    .write_all(&[reason_string_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_string(&mut writer, reason_string)?;
-
-                let user_properties_id: u8 = 0x26_u8; // 38
-                writer
-                    .write_all(&[user_properties_id])
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                write_tuple_vec(&mut writer, user_properties)?;
-
-                writer.flush().map_err(|_e| ProtocolError::WriteError)?;
-
-                Ok(())
-            }
+            } => write_auth_body(
+                body,
+                reason_code,
+                authentication_method,
+                authentication_data,
+                reason_string,
+                user_properties,
+            ),
         }
     }
 
-    pub fn read_from(mut stream: impl Read) -> Result<ClientMessage, Error> {
-        let mut header = [0u8; 1];
-        stream.read_exact(&mut header)?;
-
-        let mut header = u8::from_le_bytes(header);
-        let (mut dup_flag, mut qos, mut retain_flag) = (0, 0, 0);
-
-        let first_header_digits = header >> 4;
-        if first_header_digits == 0x3 {
-            let mask = 0b00001111;
-            let last_header_digits = header & mask;
-
-            header = 0x30_u8.to_le();
-
-            if last_header_digits & 0b00000001 == 0b00000001 {
-                retain_flag = 1;
-            }
-            if last_header_digits & 0b00000010 == 0b00000010 {
-                qos = 1;
-            }
-            if (last_header_digits >> 3) == 1 {
-                dup_flag = 1;
-            }
+    fn first_packet_byte(&self) -> Result<u8, ProtocolError> {
+        match self {
+            ClientMessage::Connect(_) => Ok(CONNECT_HEADER),
+            ClientMessage::Publish {
+                qos,
+                retain_flag,
+                dup_flag,
+                ..
+            } => publish_first_byte(*qos, *retain_flag, *dup_flag),
+            ClientMessage::Puback { .. } => Ok(0x40),
+            ClientMessage::Pubrec { .. } => Ok(0x50),
+            //los bits reservados del Pubrel deben valer 0010
+            ClientMessage::Pubrel { .. } => Ok(0x62),
+            ClientMessage::Pubcomp { .. } => Ok(0x70),
+            ClientMessage::Subscribe { .. } => Ok(0x82),
+            ClientMessage::Unsubscribe { .. } => Ok(0xA2),
+            ClientMessage::Disconnect { .. } => Ok(0xE0),
+            ClientMessage::Pingreq => Ok(0xC0),
+            ClientMessage::Auth { .. } => Ok(0xF0),
         }
+    }
 
-        match header {
-            0x10 => {
-                //leo el protocol name
+    pub fn read_from(stream: impl Read) -> Result<ClientMessage, Error> {
+        ClientMessage::read_with_limit(stream, 0)
+    }
 
-                let connect = Connect::read_from(&mut stream)?;
+    /// Lee un packet, rechazandolo sin leer su contenido si supera maximum_packet_size(0 indica que no hay limite).
+    pub fn read_with_limit(
+        mut stream: impl Read,
+        maximum_packet_size: u32,
+    ) -> Result<ClientMessage, Error> {
+        let (header, body) = read_packet(&mut stream, maximum_packet_size)?;
+        let mut body = body.as_slice();
 
-                Ok(ClientMessage::Connect(connect))
-            }
-            0x30 => {
-                println!("Reading publish message");
-
-                let packet_id = read_u16(&mut stream)?;
-                let topic_name = read_string(&mut stream)?;
-                let properties = PublishProperties::read_from(&mut stream)?;
+        match header {
+            CONNECT_HEADER => Ok(ClientMessage::Connect(Connect::read_from(&mut body)?)),
+            header if header >> 4 == 0x3 => {
+                let (dup_flag, qos, retain_flag) = publish_flags(header)?;
 
-                let payload = PayloadTypes::read_from(&mut stream)?;
+                let topic_name = read_string(&mut body)?;
+                let packet_id = if qos > 0 { read_u16(&mut body)? } else { 0 };
+                let properties = PublishProperties::read_from(&mut body)?;
+                let payload = PayloadTypes::read_with_properties(&mut body, &properties)?;
 
                 Ok(ClientMessage::Publish {
                     packet_id,
@@ -861,15 +696,47 @@ impl ClientMessage {
                     properties,
                 })
             }
+            0x40 => {
+                let (packet_id, reason_code) = read_acknowledgement(&mut body)?;
+                Ok(ClientMessage::Puback {
+                    packet_id,
+                    reason_code,
+                })
+            }
+            0x50 => {
+                let (packet_id, reason_code) = read_acknowledgement(&mut body)?;
+                Ok(ClientMessage::Pubrec {
+                    packet_id,
+                    reason_code,
+                })
+            }
+            0x62 => {
+                let (packet_id, reason_code) = read_acknowledgement(&mut body)?;
+                Ok(ClientMessage::Pubrel {
+                    packet_id,
+                    reason_code,
+                })
+            }
+            0x70 => {
+                let (packet_id, reason_code) = read_acknowledgement(&mut body)?;
+                Ok(ClientMessage::Pubcomp {
+                    packet_id,
+                    reason_code,
+                })
+            }
             0x82 => {
-                let packet_id = read_u16(&mut stream)?;
+                let packet_id = read_u16(&mut body)?;
 
-                let properties = SubscribeProperties::read_properties(&mut stream)?;
+                let properties = SubscribeProperties::read_properties(&mut body)?;
 
-                let payload = Subscription {
-                    topic: read_string(&mut stream)?,
-                    client_id: read_string(&mut stream)?,
-                };
+                // El client_id no viaja en el packet: lo completa el Broker(ver with_client_id).
+                let mut payload = Vec::new();
+                while !body.is_empty() {
+                    let topic = read_string(&mut body)?;
+                    let options = SubscriptionOptions::from_byte(read_u8(&mut body)?)
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+                    payload.push(Subscription::new(topic, String::new()).with_options(options));
+                }
 
                 Ok(ClientMessage::Subscribe {
                     packet_id,
@@ -878,14 +745,14 @@ impl ClientMessage {
                 })
             }
             0xA2 => {
-                let packet_id = read_u16(&mut stream)?;
+                let packet_id = read_u16(&mut body)?;
 
-                let properties = SubscribeProperties::read_properties(&mut stream)?;
+                let properties = SubscribeProperties::read_properties(&mut body)?;
 
-                let payload = Subscription {
-                    topic: read_string(&mut stream)?,
-                    client_id: read_string(&mut stream)?,
-                };
+                let mut payload = Vec::new();
+                while !body.is_empty() {
+                    payload.push(Subscription::new(read_string(&mut body)?, String::new()));
+                }
 
                 Ok(ClientMessage::Unsubscribe {
                     packet_id,
@@ -894,89 +761,32 @@ impl ClientMessage {
                 })
             }
             0xE0 => {
-                let reason_code = read_u8(&mut stream)?;
-                let session_expiry_interval_id = read_u8(&mut stream)?;
-                if session_expiry_interval_id != SESSION_EXPIRY_INTERVAL_ID {
-                    return Err(Error::new(
-                        std::io::ErrorKind::Other,
-                        "Invalid session expiry interval id",
-                    ));
-                }
-                let session_expiry_interval = read_u32(&mut stream)?;
-
-                let reason_string_id = read_u8(&mut stream)?;
-                if reason_string_id != REASON_STRING_ID {
-                    return Err(Error::new(
-                        std::io::ErrorKind::Other,
-                        "Invalid reason string id",
-                    ));
-                }
-                let reason_string = read_string(&mut stream)?;
-
-                let client_id = read_string(&mut stream)?;
+                let (reason_code, session_expiry_interval, reason_string, _) =
+                    read_disconnect_body(&mut body)?;
 
                 Ok(ClientMessage::Disconnect {
                     reason_code,
                     session_expiry_interval,
                     reason_string,
-                    client_id,
+                    client_id: String::new(),
                 })
             }
             0xC0 => Ok(ClientMessage::Pingreq),
             0xF0 => {
-                let reason_code = read_u8(&mut stream)?;
-                let mut authentication_method: Option<String> = None;
-                let mut authentication_data: Option<Vec<u8>> = None;
-                let mut reason_string: Option<String> = None;
-                let mut user_properties: Option<Vec<(String, String)>> = None;
-
-                let mut count = 0;
-                while let Ok(property_id) = read_u8(&mut stream) {
-                    match property_id {
-                        0x15 => {
-                            let value = read_string(&mut stream)?;
-                            authentication_method = Some(value);
-                        }
-                        0x16 => {
-                            let value = read_bin_vec(&mut stream)?;
-                            authentication_data = Some(value);
-                        }
-                        0x26 => {
-                            let value = read_tuple_vec(&mut stream)?;
-                            user_properties = Some(value);
-                        }
-                        0x1F => {
-                            let value = read_string(&mut stream)?;
-                            reason_string = Some(value);
-                        }
-                        _ => {
-                            return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido"));
-                        }
-                    }
-                    count += 1;
-                    if count == 4 {
-                        break;
-                    }
-                }
+                let (
+                    reason_code,
+                    authentication_method,
+                    authentication_data,
+                    reason_string,
+                    user_properties,
+                ) = read_auth_body(&mut body)?;
 
                 Ok(ClientMessage::Auth {
                     reason_code,
-                    user_properties: user_properties.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing user_properties property",
-                    ))?,
-                    authentication_method: authentication_method.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing authentication_method property",
-                    ))?,
-                    authentication_data: authentication_data.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing authentication_data property",
-                    ))?,
-                    reason_string: reason_string.ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Missing reason_string property",
-                    ))?,
+                    authentication_method,
+                    authentication_data,
+                    reason_string,
+                    user_properties,
                 })
             }
             _ => Err(Error::new(std::io::ErrorKind::Other, "Invalid header")),
@@ -984,17 +794,218 @@ impl ClientMessage {
     }
 }
 
+/// Primer byte del fixed header de un Publish: el tipo de packet y los flags de dup, QoS y retain.
+pub(crate) fn publish_first_byte(
+    qos: usize,
+    retain_flag: usize,
+    dup_flag: usize,
+) -> Result<u8, ProtocolError> {
+    if qos > 2 {
+        return Err(ProtocolError::InvalidQOS);
+    }
+
+    let mut byte_1 = 0x30_u8 | ((qos as u8) << 1);
+
+    if retain_flag == 1 {
+        byte_1 |= 1 << 0;
+    }
+
+    //Dup flag must be set to 0 for all QoS 0 messages.
+    if dup_flag == 1 && qos != 0 {
+        byte_1 |= 1 << 3;
+    }
+
+    Ok(byte_1)
+}
+
+/// Devuelve el dup flag, la QoS y el retain flag del primer byte de un Publish.
+pub(crate) fn publish_flags(byte_1: u8) -> Result<(usize, usize, usize), Error> {
+    let qos = ((byte_1 >> 1) & 0b11) as usize;
+    if qos > 2 {
+        return Err(Error::new(ErrorKind::InvalidData, "QoS inválida"));
+    }
+
+    Ok((((byte_1 >> 3) & 1) as usize, qos, (byte_1 & 1) as usize))
+}
+
+/// Lee el packet_id y el reason code de un Puback, Pubrec, Pubrel o Pubcomp.
+///
+/// Si el packet termina despues del packet_id, el reason code es 0x00(Success). Las properties se ignoran.
+pub(crate) fn read_acknowledgement(body: &mut &[u8]) -> Result<(u16, u8), Error> {
+    let packet_id = read_u16(body)?;
+    let reason_code = if body.is_empty() {
+        0x00
+    } else {
+        read_u8(body)?
+    };
+
+    Ok((packet_id, reason_code))
+}
+
+/// Escribe el variable header de un Disconnect: el reason code y sus properties. El session expiry interval
+/// se escribe siempre que se indique, aunque valga 0.
+pub(crate) fn write_disconnect_body(
+    body: &mut Vec<u8>,
+    reason_code: &u8,
+    session_expiry_interval: &Option<u32>,
+    reason_string: &str,
+    user_properties: &[(String, String)],
+) -> Result<(), ProtocolError> {
+    write_u8(body, reason_code)?;
+
+    let mut properties = Vec::new();
+    if let Some(session_expiry_interval) = session_expiry_interval {
+        write_u8(&mut properties, &SESSION_EXPIRY_INTERVAL_ID)?;
+        write_u32(&mut properties, session_expiry_interval)?;
+    }
+    if !reason_string.is_empty() {
+        write_u8(&mut properties, &REASON_STRING_ID)?;
+        write_string(&mut properties, reason_string)?;
+    }
+    write_user_properties(&mut properties, user_properties)?;
+
+    write_properties_block(body, &properties)
+}
+
+/// Lee el reason code, el session expiry interval, el reason string y las user properties de un Disconnect.
+///
+/// Un Disconnect sin variable header equivale a una desconexion normal(0x00) sin properties.
+#[allow(clippy::type_complexity)]
+pub(crate) fn read_disconnect_body(
+    body: &mut &[u8],
+) -> Result<(u8, Option<u32>, String, Vec<(String, String)>), Error> {
+    let (mut session_expiry_interval, mut reason_string, mut user_properties) =
+        (None, String::new(), Vec::new());
+    if body.is_empty() {
+        return Ok((
+            0x00,
+            session_expiry_interval,
+            reason_string,
+            user_properties,
+        ));
+    }
+
+    let reason_code = read_u8(body)?;
+    if body.is_empty() {
+        return Ok((
+            reason_code,
+            session_expiry_interval,
+            reason_string,
+            user_properties,
+        ));
+    }
+
+    let block = read_properties_block(body)?;
+    let mut properties = block.as_slice();
+    while !properties.is_empty() {
+        match read_u8(&mut properties)? {
+            SESSION_EXPIRY_INTERVAL_ID => {
+                session_expiry_interval = Some(read_u32(&mut properties)?)
+            }
+            REASON_STRING_ID => reason_string = read_string(&mut properties)?,
+            SERVER_REFERENCE_ID => {
+                read_string(&mut properties)?;
+            }
+            USER_PROPERTY_ID => {
+                let key = read_string(&mut properties)?;
+                let value = read_string(&mut properties)?;
+                user_properties.push((key, value));
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido")),
+        }
+    }
+
+    Ok((
+        reason_code,
+        session_expiry_interval,
+        reason_string,
+        user_properties,
+    ))
+}
+
+/// Escribe el variable header de un Auth: el reason code y sus properties.
+pub(crate) fn write_auth_body(
+    body: &mut Vec<u8>,
+    reason_code: &u8,
+    authentication_method: &str,
+    authentication_data: &Vec<u8>,
+    reason_string: &str,
+    user_properties: &[(String, String)],
+) -> Result<(), ProtocolError> {
+    write_u8(body, reason_code)?;
+
+    let mut properties = Vec::new();
+    if !authentication_method.is_empty() {
+        write_u8(&mut properties, &AUTHENTICATION_METHOD_ID)?;
+        write_string(&mut properties, authentication_method)?;
+    }
+    if !authentication_data.is_empty() {
+        write_u8(&mut properties, &AUTHENTICATION_DATA_ID)?;
+        write_bin_vec(&mut properties, authentication_data)?;
+    }
+    if !reason_string.is_empty() {
+        write_u8(&mut properties, &REASON_STRING_ID)?;
+        write_string(&mut properties, reason_string)?;
+    }
+    write_user_properties(&mut properties, user_properties)?;
+
+    write_properties_block(body, &properties)
+}
+
+/// Lee el reason code, el authentication method, el authentication data, el reason string y las user
+/// properties de un Auth. Un Auth sin variable header equivale a un Success(0x00) sin properties.
+#[allow(clippy::type_complexity)]
+pub(crate) fn read_auth_body(
+    body: &mut &[u8],
+) -> Result<(u8, String, Vec<u8>, String, Vec<(String, String)>), Error> {
+    let (mut authentication_method, mut authentication_data) = (String::new(), Vec::new());
+    let (mut reason_string, mut user_properties) = (String::new(), Vec::new());
+    if body.is_empty() {
+        return Ok((
+            0x00,
+            authentication_method,
+            authentication_data,
+            reason_string,
+            user_properties,
+        ));
+    }
+
+    let reason_code = read_u8(body)?;
+    let block = read_properties_block(body)?;
+    let mut properties = block.as_slice();
+    while !properties.is_empty() {
+        match read_u8(&mut properties)? {
+            AUTHENTICATION_METHOD_ID => authentication_method = read_string(&mut properties)?,
+            AUTHENTICATION_DATA_ID => authentication_data = read_bin_vec(&mut properties)?,
+            REASON_STRING_ID => reason_string = read_string(&mut properties)?,
+            USER_PROPERTY_ID => {
+                let key = read_string(&mut properties)?;
+                let value = read_string(&mut properties)?;
+                user_properties.push((key, value));
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido")),
+        }
+    }
+
+    Ok((
+        reason_code,
+        authentication_method,
+        authentication_data,
+        reason_string,
+        user_properties,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
     use crate::{
-        monitoring::incident::Incident,
         mqtt::{
             connect::{connect_properties::ConnectProperties, will_properties::WillProperties},
             publish::publish_properties::TopicProperties,
         },
-        utils::{incident_payload::IncidentPayload, location::Location},
+        utils::{location::Location, single_disconnect_payload::SingleDisconnectPayload},
     };
 
     use super::*;
@@ -1103,13 +1114,11 @@ mod tests {
             [1, 2, 3].to_vec(),
             "a".to_string(),
             1,
-            "a".to_string(),
+            String::new(),
         );
 
-        let location = Location::new(12.1, 25.0);
-        let incident = Incident::new(location);
-
-        let payload = PayloadTypes::IncidentLocation(IncidentPayload::new(incident));
+        // Con el encoding anterior el id de los incidentes no viaja, asi que se usa una ubicacion.
+        let payload = PayloadTypes::LocationPayload(Location::new(12.1, 25.0));
 
         let publish = ClientMessage::Publish {
             packet_id: 1,
@@ -1143,10 +1152,11 @@ mod tests {
 
     #[test]
     fn test_04_subscribe_ok() {
-        let payload = Subscription {
-            topic: "topic".to_string(),
-            client_id: "client".to_string(),
-        };
+        let payload = vec![
+            Subscription::new("topic".to_string(), "client".to_string())
+                .with_options(SubscriptionOptions::new(true, false, 1)),
+            Subscription::new("drones/+/status".to_string(), "client".to_string()),
+        ];
 
         let sub = ClientMessage::Subscribe {
             packet_id: 1,
@@ -1172,22 +1182,21 @@ mod tests {
             }
         };
 
-        println!("{:?}", read_sub);
-        println!("{:?}", sub);
-        assert_eq!(sub, read_sub);
+        // El client_id no viaja en el packet: lo completa el Broker con el de la conexion.
+        assert_eq!(sub, read_sub.with_client_id("client"));
     }
 
     #[test]
     fn test_05_unsubscribe_ok() {
-        let payload = Subscription {
-            topic: "topic".to_string(),
-            client_id: "client".to_string(),
-        };
+        let payload = vec![
+            Subscription::new("topic".to_string(), "client".to_string()),
+            Subscription::new("incident".to_string(), "client".to_string()),
+        ];
 
         let unsub = ClientMessage::Unsubscribe {
             packet_id: 1,
             properties: SubscribeProperties::new(
-                1,
+                0,
                 vec![("propiedad".to_string(), "valor".to_string())],
             ),
             payload,
@@ -1208,14 +1217,14 @@ mod tests {
             }
         };
 
-        assert_eq!(unsub, read_unsub);
+        assert_eq!(unsub, read_unsub.with_client_id("client"));
     }
 
     #[test]
     fn test_06_disconnect_ok() {
         let disconect = ClientMessage::Disconnect {
             reason_code: 1,
-            session_expiry_interval: 1,
+            session_expiry_interval: Some(1),
             reason_string: "hola".to_string(),
             client_id: "client".to_string(),
         };
@@ -1234,7 +1243,7 @@ mod tests {
                 panic!("no se pudo leer del cursor {:?}", e);
             }
         };
-        assert_eq!(disconect, read_disconect);
+        assert_eq!(disconect, read_disconect.with_client_id("client"));
     }
 
     // #
@@ -1310,6 +1319,100 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_08_qos_2_packets_ok() -> Result<(), ProtocolError> {
+        let publish = ClientMessage::Publish {
+            packet_id: 7,
+            topic_name: "incident_resolved".to_string(),
+            qos: 2,
+            retain_flag: 0,
+            payload: PayloadTypes::CamerasUpdatePayload(vec![]),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 10,
+                    response_topic: "String".to_string(),
+                },
+                [1, 2, 3].to_vec(),
+                "a".to_string(),
+                1,
+                String::new(),
+            ),
+        };
+        let pubrec = ClientMessage::Pubrec {
+            packet_id: 7,
+            reason_code: 0x00,
+        };
+        let pubrel = ClientMessage::Pubrel {
+            packet_id: 7,
+            reason_code: 0x00,
+        };
+        let pubcomp = ClientMessage::Pubcomp {
+            packet_id: 7,
+            reason_code: 0x92,
+        };
+
+        for message in [publish, pubrec, pubrel, pubcomp] {
+            let mut cursor = Cursor::new(Vec::<u8>::new());
+            message.write_to(&mut cursor)?;
+            cursor.set_position(0);
+
+            let read_message = ClientMessage::read_from(&mut cursor)
+                .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+            assert_eq!(message, read_message);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_09_puback_and_duplicated_publish_ok() -> Result<(), ProtocolError> {
+        let mut publish = ClientMessage::Publish {
+            packet_id: 3,
+            topic_name: "incident".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: PayloadTypes::CamerasUpdatePayload(vec![]),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                10,
+                TopicProperties {
+                    topic_alias: 10,
+                    response_topic: "String".to_string(),
+                },
+                [1, 2, 3].to_vec(),
+                "a".to_string(),
+                1,
+                String::new(),
+            ),
+        };
+        publish.mark_as_duplicate();
+        assert!(matches!(
+            publish,
+            ClientMessage::Publish { dup_flag: 1, .. }
+        ));
+
+        let puback = ClientMessage::Puback {
+            packet_id: 3,
+            reason_code: 0x00,
+        };
+
+        for message in [publish, puback] {
+            let mut cursor = Cursor::new(Vec::<u8>::new());
+            message.write_to(&mut cursor)?;
+            cursor.set_position(0);
+
+            let read_message = ClientMessage::read_from(&mut cursor)
+                .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+            assert_eq!(message, read_message);
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_01_config_creation_cases() {
         let config_ok = Connect::read_connect_config("./src/monitoring/connect_config.json");
@@ -1480,4 +1583,232 @@ mod tests {
 
         assert_eq!(pingreq_message, ClientMessage::Pingreq);
     }
+
+    #[test]
+    fn test_remaining_message_expiry() {
+        let publish = |message_expiry_interval: u32| ClientMessage::Publish {
+            packet_id: 1,
+            topic_name: "drone_locations".to_string(),
+            qos: 0,
+            retain_flag: 0,
+            payload: PayloadTypes::WillPayload("location".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                message_expiry_interval,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+
+        assert_eq!(publish(10).with_remaining_expiry(4), Some(publish(6)));
+        assert_eq!(publish(10).with_remaining_expiry(10), None);
+        assert_eq!(publish(0).with_remaining_expiry(1000), Some(publish(0)));
+        assert_eq!(
+            ClientMessage::Pingreq.with_remaining_expiry(1000),
+            Some(ClientMessage::Pingreq)
+        );
+    }
+
+    #[test]
+    fn test_10_fixed_header_and_remaining_length() -> Result<(), ProtocolError> {
+        let mut buffer = Vec::new();
+        ClientMessage::Pingreq.write_to(&mut buffer)?;
+        assert_eq!(buffer, vec![0xC0, 0x00]);
+
+        let mut buffer = Vec::new();
+        ClientMessage::Puback {
+            packet_id: 261,
+            reason_code: 0x10,
+        }
+        .write_to(&mut buffer)?;
+        assert_eq!(buffer, vec![0x40, 0x03, 0x01, 0x05, 0x10]);
+
+        // Un Puback sin reason code equivale a un Success.
+        let puback = ClientMessage::read_from(&mut [0x40_u8, 0x02, 0x01, 0x05].as_slice())
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        assert_eq!(
+            puback,
+            ClientMessage::Puback {
+                packet_id: 261,
+                reason_code: 0x00
+            }
+        );
+
+        // Un Disconnect sin variable header es una desconexion normal.
+        let disconnect = ClientMessage::read_from(&mut [0xE0_u8, 0x00].as_slice())
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        assert_eq!(
+            disconnect,
+            ClientMessage::Disconnect {
+                reason_code: 0x00,
+                session_expiry_interval: None,
+                reason_string: String::new(),
+                client_id: String::new(),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_11_qos_0_publish_has_no_packet_id() -> Result<(), ProtocolError> {
+        let publish = |packet_id: u16| ClientMessage::Publish {
+            packet_id,
+            topic_name: "a/b".to_string(),
+            qos: 0,
+            retain_flag: 1,
+            payload: PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(3)),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                0,
+                0,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+
+        let mut buffer = Vec::new();
+        publish(9).write_to(&mut buffer)?;
+
+        // fixed header, remaining length, topic name y properties vacias
+        assert_eq!(buffer[0], 0x31);
+        assert_eq!(buffer[1] as usize, buffer.len() - 2);
+        assert_eq!(buffer[2..8], [0x00, 0x03, b'a', b'/', b'b', 0x00]);
+
+        let read_publish = ClientMessage::read_from(&mut buffer.as_slice())
+            .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))?;
+        assert_eq!(read_publish, publish(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_12_reading_a_connect_from_a_standard_client() -> std::io::Result<()> {
+        // Connect de un cliente con clean start, keep alive de 60 segundos, sin properties ni credenciales.
+        let bytes = [
+            0x10, 0x11, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x3C, 0x00, 0x00,
+            0x04, b'p', b'u', b'b', b'1',
+        ];
+
+        let connect = ClientMessage::read_from(&mut bytes.as_slice())?;
+        let expected = Connect {
+            clean_start: true,
+            last_will_flag: false,
+            last_will_qos: 0,
+            last_will_retain: false,
+            keep_alive: 60,
+            properties: ConnectProperties::new(
+                0,
+                0,
+                0,
+                0,
+                false,
+                true,
+                vec![],
+                String::new(),
+                vec![],
+            ),
+            client_id: "pub1".to_string(),
+            will_properties: None,
+            last_will_topic: None,
+            last_will_message: None,
+            username: None,
+            password: None,
+        };
+        assert_eq!(connect, ClientMessage::Connect(expected.clone()));
+
+        let mut buffer = Vec::new();
+        expected
+            .write_to(&mut buffer)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        assert_eq!(buffer, bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_13_json_payload_sets_its_content_type() -> std::io::Result<()> {
+        let payload = PayloadTypes::Json(serde_json::json!({"estado": "ok"}));
+        let properties = PublishProperties::new(
+            0,
+            0,
+            TopicProperties {
+                topic_alias: 0,
+                response_topic: String::new(),
+            },
+            vec![],
+            String::new(),
+            0,
+            String::new(),
+        );
+        let publish = ClientMessage::Publish {
+            packet_id: 1,
+            topic_name: "estado".to_string(),
+            qos: 1,
+            retain_flag: 0,
+            payload: payload.clone(),
+            dup_flag: 0,
+            properties: properties.clone(),
+        };
+
+        let mut buffer = Vec::new();
+        publish
+            .write_to(&mut buffer)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+
+        match ClientMessage::read_from(&mut buffer.as_slice())? {
+            ClientMessage::Publish {
+                payload: read_payload,
+                properties: read_properties,
+                ..
+            } => {
+                assert_eq!(read_payload, payload);
+                assert_eq!(read_properties.payload_format_indicator, 1);
+                assert_eq!(read_properties.content_type, "application/json");
+                assert_eq!(read_properties, properties.for_payload(&payload));
+            }
+            other => panic!("Se esperaba un Publish, se leyo {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_14_disconnect_writes_an_explicit_session_expiry_of_zero() -> Result<(), ProtocolError> {
+        let read = |buffer: Vec<u8>| {
+            ClientMessage::read_from(&mut buffer.as_slice())
+                .map_err(|e| ProtocolError::UnspecifiedError(e.to_string()))
+        };
+        let disconnect = |session_expiry_interval: Option<u32>| ClientMessage::Disconnect {
+            reason_code: 0x00,
+            session_expiry_interval,
+            reason_string: String::new(),
+            client_id: String::new(),
+        };
+
+        // reason code, largo de las properties, id y valor del session expiry interval
+        let mut buffer = Vec::new();
+        disconnect(Some(0)).write_to(&mut buffer)?;
+        assert_eq!(buffer, vec![0xE0, 7, 0x00, 5, 0x11, 0, 0, 0, 0]);
+        assert_eq!(read(buffer)?, disconnect(Some(0)));
+
+        let mut buffer = Vec::new();
+        disconnect(None).write_to(&mut buffer)?;
+        assert_eq!(read(buffer)?, disconnect(None));
+
+        Ok(())
+    }
 }
@@ -2,6 +2,9 @@
 pub enum ClientReturn {
     ConnackReceived,
     PubackRecieved,
+    PubrecRecieved,
+    PubrelRecieved,
+    PubcompRecieved,
     DisconnectRecieved,
     DisconnectSent,
     SubackRecieved,
@@ -1,9 +1,27 @@
 use crate::utils::reader::*;
 use crate::utils::writer::*;
-use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
 
 use super::protocol_error::ProtocolError;
 
+const SESSION_EXPIRY_INTERVAL_ID: u8 = 0x11;
+const ASSIGNED_CLIENT_IDENTIFIER_ID: u8 = 0x12;
+const AUTHENTICATION_METHOD_ID: u8 = 0x15;
+const AUTHENTICATION_DATA_ID: u8 = 0x16;
+const RESPONSE_INFORMATION_ID: u8 = 0x1A;
+const SERVER_REFERENCE_ID: u8 = 0x1C;
+const REASON_STRING_ID: u8 = 0x1F;
+const RECEIVE_MAXIMUM_ID: u8 = 0x21;
+const TOPIC_ALIAS_MAXIMUM_ID: u8 = 0x22;
+const MAXIMUM_QOS_ID: u8 = 0x24;
+const RETAIN_AVAILABLE_ID: u8 = 0x25;
+const USER_PROPERTY_ID: u8 = 0x26;
+const MAXIMUM_PACKET_SIZE_ID: u8 = 0x27;
+const WILDCARD_SUBSCRIPTION_AVAILABLE_ID: u8 = 0x28;
+const SUBSCRIPTION_IDENTIFIER_AVAILABLE_ID: u8 = 0x29;
+const SHARED_SUBSCRIPTION_AVAILABLE_ID: u8 = 0x2A;
+const SERVER_KEEP_ALIVE_ID: u8 = 0x2D;
+
 #[derive(Debug, PartialEq)]
 pub struct ConnackProperties {
     pub session_expiry_interval: u32,
@@ -26,287 +44,163 @@ pub struct ConnackProperties {
 }
 
 impl ConnackProperties {
+    /// Escribe el bloque de properties precedido por su largo. Los valores en 0 y los strings vacios no se envian.
+    ///
+    /// maximum_qos en true indica que se soportan todas las QoS, por lo que la property se omite(equivale a
+    /// QoS 2). En false se envia un Maximum QoS de 1.
     pub fn write_to(&self, stream: &mut dyn Write) -> Result<(), ProtocolError> {
-        let mut writer = BufWriter::new(stream);
-
-        let session_expiry_interval_id: u8 = 0x11_u8; //17
-        writer
-            .write_all(&[session_expiry_interval_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_u32(&mut writer, &self.session_expiry_interval)?;
-
-        let assigned_client_identifier_id: u8 = 0x12_u8; //18
-        writer
-            .write_all(&[assigned_client_identifier_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_string(&mut writer, &self.assigned_client_identifier)?;
-
-        let authentication_method_id: u8 = 0x15_u8; //21
-        writer
-            .write_all(&[authentication_method_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_string(&mut writer, &self.authentication_method)?;
-
-        let authentication_data_id: u8 = 0x16_u8; //22
-        writer
-            .write_all(&[authentication_data_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_bin_vec(&mut writer, &self.authentication_data)?;
-
-        let response_information_id: u8 = 0x1A_u8; //26
-        writer
-            .write_all(&[response_information_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_string(&mut writer, &self.response_information)?;
-
-        let server_reference_id: u8 = 0x1C_u8; //28
-        writer
-            .write_all(&[server_reference_id])
-            .map_err(|_: Error| ProtocolError::WriteError)?;
-        write_string(&mut writer, &self.server_reference)?;
-
-        let reason_string_id: u8 = 0x1F_u8; //31
-        writer
-            .write_all(&[reason_string_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_string(&mut writer, &self.reason_string)?;
-
-        let receive_maximum_id: u8 = 0x21_u8; //33
-        writer
-            .write_all(&[receive_maximum_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_u16(&mut writer, &self.receive_maximum)?;
-
-        let topic_alias_maximum_id: u8 = 0x22_u8; //34
-        writer
-            .write_all(&[topic_alias_maximum_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_u16(&mut writer, &self.topic_alias_maximum)?;
-
-        let maximum_qos_id: u8 = 0x24_u8; //36
-        writer
-            .write_all(&[maximum_qos_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_bool(&mut writer, &self.maximum_qos)?;
-
-        let retain_available_id: u8 = 0x25_u8; //37
-        writer
-            .write_all(&[retain_available_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_bool(&mut writer, &self.retain_available)?;
-
-        let user_properties_id: u8 = 0x26_u8; //38
-        writer
-            .write_all(&[user_properties_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_tuple_vec(&mut writer, &self.user_properties)?;
-
-        let maximum_packet_size_id: u8 = 0x27_u8; //39
-        writer
-            .write_all(&[maximum_packet_size_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_u32(&mut writer, &self.maximum_packet_size)?;
-
-        let wildcard_subscription_available_id: u8 = 0x28_u8; //40
-        writer
-            .write_all(&[wildcard_subscription_available_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_bool(&mut writer, &self.wildcard_subscription_available)?;
-
-        let subscription_identifier_available_id: u8 = 0x29_u8; //41
-        writer
-            .write_all(&[subscription_identifier_available_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_bool(&mut writer, &self.subscription_identifier_available)?;
-
-        let shared_subscription_available_id: u8 = 0x2A_u8; //42
-        writer
-            .write_all(&[shared_subscription_available_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_bool(&mut writer, &self.shared_subscription_available)?;
-
-        let server_keep_alive_id: u8 = 0x2D_u8; //45
-        writer
-            .write_all(&[server_keep_alive_id])
-            .map_err(|_| ProtocolError::WriteError)?;
-        write_u16(&mut writer, &self.server_keep_alive)?;
-
-        Ok(())
-    }
+        let mut properties = Vec::new();
 
-    pub fn read_from(stream: &mut dyn Read) -> Result<ConnackProperties, Error> {
-        let session_expiry_interval_id = read_u8(stream)?;
-        if session_expiry_interval_id != 0x11 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid session expiry interval id",
-            ));
-        }
-        let session_expiry_interval = read_u32(stream)?;
-
-        let assigned_client_identifier_id = read_u8(stream)?;
-        if assigned_client_identifier_id != 0x12 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid assigned client identifier id",
-            ));
-        }
-        let assigned_client_identifier = read_string(stream)?;
-
-        let authentication_method_id = read_u8(stream)?;
-        if authentication_method_id != 0x15 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid authentication method id",
-            ));
-        }
-        let authentication_method = read_string(stream)?;
-
-        let authentication_data_id = read_u8(stream)?;
-        if authentication_data_id != 0x16 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid authentication data id",
-            ));
-        }
-        let authentication_data = read_bin_vec(stream)?;
-
-        let response_information_id = read_u8(stream)?;
-        if response_information_id != 0x1A {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid response information id",
-            ));
+        if self.session_expiry_interval != 0 {
+            write_u8(&mut properties, &SESSION_EXPIRY_INTERVAL_ID)?;
+            write_u32(&mut properties, &self.session_expiry_interval)?;
         }
-        let response_information = read_string(stream)?;
-
-        let server_reference_id = read_u8(stream)?;
-        if server_reference_id != 0x1C {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid server reference id",
-            ));
-        }
-        let server_reference = read_string(stream)?;
-
-        let reason_string_id = read_u8(stream)?;
-        if reason_string_id != 0x1F {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid reason string id",
-            ));
-        }
-        let reason_string = read_string(stream)?;
-
-        let receive_maximum_id = read_u8(stream)?;
-        if receive_maximum_id != 0x21 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid receive maximum id",
-            ));
-        }
-        let receive_maximum = read_u16(stream)?;
-
-        let topic_alias_maximum_id = read_u8(stream)?;
-        if topic_alias_maximum_id != 0x22 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid topic alias maximum id",
-            ));
+
+        let strings = [
+            (
+                ASSIGNED_CLIENT_IDENTIFIER_ID,
+                &self.assigned_client_identifier,
+            ),
+            (AUTHENTICATION_METHOD_ID, &self.authentication_method),
+            (RESPONSE_INFORMATION_ID, &self.response_information),
+            (SERVER_REFERENCE_ID, &self.server_reference),
+            (REASON_STRING_ID, &self.reason_string),
+        ];
+        for (id, value) in strings {
+            if !value.is_empty() {
+                write_u8(&mut properties, &id)?;
+                write_string(&mut properties, value)?;
+            }
         }
-        let topic_alias_maximum = read_u16(stream)?;
-
-        let maximum_qos_id = read_u8(stream)?;
-        if maximum_qos_id != 0x24 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid maximum qos id",
-            ));
+
+        if !self.authentication_data.is_empty() {
+            write_u8(&mut properties, &AUTHENTICATION_DATA_ID)?;
+            write_bin_vec(&mut properties, &self.authentication_data)?;
         }
-        let maximum_qos = read_bool(stream)?;
-
-        let retain_available_id = read_u8(stream)?;
-        if retain_available_id != 0x25 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid retain available id",
-            ));
+
+        if self.receive_maximum != 0 {
+            write_u8(&mut properties, &RECEIVE_MAXIMUM_ID)?;
+            write_u16(&mut properties, &self.receive_maximum)?;
         }
-        let retain_available = read_bool(stream)?;
-
-        let user_properties_id = read_u8(stream)?;
-        if user_properties_id != 0x26 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid user properties id",
-            ));
+
+        if self.topic_alias_maximum != 0 {
+            write_u8(&mut properties, &TOPIC_ALIAS_MAXIMUM_ID)?;
+            write_u16(&mut properties, &self.topic_alias_maximum)?;
         }
-        let user_properties = read_tuple_vec(stream)?;
-
-        let maximum_packet_size_id = read_u8(stream)?;
-        if maximum_packet_size_id != 0x27 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid maximum packet size id",
-            ));
+
+        if !self.maximum_qos {
+            write_u8(&mut properties, &MAXIMUM_QOS_ID)?;
+            write_u8(&mut properties, &1)?;
         }
-        let maximum_packet_size = read_u32(stream)?;
-
-        let wildcard_subscription_available_id = read_u8(stream)?;
-        if wildcard_subscription_available_id != 0x28 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid wildcard subscription available id",
-            ));
+
+        let flags = [
+            (RETAIN_AVAILABLE_ID, self.retain_available),
+            (
+                WILDCARD_SUBSCRIPTION_AVAILABLE_ID,
+                self.wildcard_subscription_available,
+            ),
+            (
+                SUBSCRIPTION_IDENTIFIER_AVAILABLE_ID,
+                self.subscription_identifier_available,
+            ),
+            (
+                SHARED_SUBSCRIPTION_AVAILABLE_ID,
+                self.shared_subscription_available,
+            ),
+        ];
+        for (id, value) in flags {
+            write_u8(&mut properties, &id)?;
+            write_bool(&mut properties, &value)?;
         }
-        let wildcard_subscription_available = read_bool(stream)?;
-
-        let subscription_identifier_available_id = read_u8(stream)?;
-        if subscription_identifier_available_id != 0x29 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid subscription identifier available id",
-            ));
+
+        write_user_properties(&mut properties, &self.user_properties)?;
+
+        if self.maximum_packet_size != 0 {
+            write_u8(&mut properties, &MAXIMUM_PACKET_SIZE_ID)?;
+            write_u32(&mut properties, &self.maximum_packet_size)?;
         }
-        let subscription_identifier_available = read_bool(stream)?;
-
-        let shared_subscription_available_id = read_u8(stream)?;
-        if shared_subscription_available_id != 0x2A {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid shared subscription available id",
-            ));
+
+        if self.server_keep_alive != 0 {
+            write_u8(&mut properties, &SERVER_KEEP_ALIVE_ID)?;
+            write_u16(&mut properties, &self.server_keep_alive)?;
         }
-        let shared_subscription_available = read_bool(stream)?;
-
-        let server_keep_alive_id = read_u8(stream)?;
-        if server_keep_alive_id != 0x2D {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid server keep alive id",
-            ));
+
+        write_properties_block(stream, &properties)
+    }
+
+    /// Lee el bloque de properties. Las que no vienen toman el valor que les da el protocolo: las
+    /// funcionalidades opcionales se consideran disponibles, y el resto queda en 0 o vacio.
+    pub fn read_from(stream: &mut dyn Read) -> Result<ConnackProperties, Error> {
+        let block = read_properties_block(stream)?;
+        let mut reader = block.as_slice();
+
+        let mut properties = ConnackProperties {
+            session_expiry_interval: 0,
+            receive_maximum: 0,
+            maximum_qos: true,
+            retain_available: true,
+            maximum_packet_size: 0,
+            assigned_client_identifier: String::new(),
+            topic_alias_maximum: 0,
+            reason_string: String::new(),
+            user_properties: Vec::new(),
+            wildcard_subscription_available: true,
+            subscription_identifier_available: true,
+            shared_subscription_available: true,
+            server_keep_alive: 0,
+            response_information: String::new(),
+            server_reference: String::new(),
+            authentication_method: String::new(),
+            authentication_data: Vec::new(),
+        };
+
+        while !reader.is_empty() {
+            match read_u8(&mut reader)? {
+                SESSION_EXPIRY_INTERVAL_ID => {
+                    properties.session_expiry_interval = read_u32(&mut reader)?
+                }
+                ASSIGNED_CLIENT_IDENTIFIER_ID => {
+                    properties.assigned_client_identifier = read_string(&mut reader)?
+                }
+                AUTHENTICATION_METHOD_ID => {
+                    properties.authentication_method = read_string(&mut reader)?
+                }
+                AUTHENTICATION_DATA_ID => {
+                    properties.authentication_data = read_bin_vec(&mut reader)?
+                }
+                RESPONSE_INFORMATION_ID => {
+                    properties.response_information = read_string(&mut reader)?
+                }
+                SERVER_REFERENCE_ID => properties.server_reference = read_string(&mut reader)?,
+                REASON_STRING_ID => properties.reason_string = read_string(&mut reader)?,
+                RECEIVE_MAXIMUM_ID => properties.receive_maximum = read_u16(&mut reader)?,
+                TOPIC_ALIAS_MAXIMUM_ID => properties.topic_alias_maximum = read_u16(&mut reader)?,
+                MAXIMUM_QOS_ID => {
+                    read_u8(&mut reader)?;
+                    properties.maximum_qos = false;
+                }
+                RETAIN_AVAILABLE_ID => properties.retain_available = read_bool(&mut reader)?,
+                USER_PROPERTY_ID => {
+                    let key = read_string(&mut reader)?;
+                    let value = read_string(&mut reader)?;
+                    properties.user_properties.push((key, value));
+                }
+                MAXIMUM_PACKET_SIZE_ID => properties.maximum_packet_size = read_u32(&mut reader)?,
+                WILDCARD_SUBSCRIPTION_AVAILABLE_ID => {
+                    properties.wildcard_subscription_available = read_bool(&mut reader)?
+                }
+                SUBSCRIPTION_IDENTIFIER_AVAILABLE_ID => {
+                    properties.subscription_identifier_available = read_bool(&mut reader)?
+                }
+                SHARED_SUBSCRIPTION_AVAILABLE_ID => {
+                    properties.shared_subscription_available = read_bool(&mut reader)?
+                }
+                SERVER_KEEP_ALIVE_ID => properties.server_keep_alive = read_u16(&mut reader)?,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido")),
+            }
         }
-        let server_keep_alive = read_u16(stream)?;
 
-        Ok(ConnackProperties {
-            session_expiry_interval,
-            assigned_client_identifier,
-            authentication_method,
-            authentication_data,
-            response_information,
-            server_reference,
-            reason_string,
-            receive_maximum,
-            topic_alias_maximum,
-            maximum_qos,
-            retain_available,
-            user_properties,
-            maximum_packet_size,
-            wildcard_subscription_available,
-            subscription_identifier_available,
-            shared_subscription_available,
-            server_keep_alive,
-        })
+        Ok(properties)
     }
 }
 
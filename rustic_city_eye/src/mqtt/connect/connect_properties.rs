@@ -1,10 +1,20 @@
 use serde::{Deserialize, Serialize};
 
-use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
 
 use crate::mqtt::protocol_error::ProtocolError;
 use crate::utils::{reader::*, writer::*};
 
+const SESSION_EXPIRY_INTERVAL_ID: u8 = 0x11;
+const AUTHENTICATION_METHOD_ID: u8 = 0x15;
+const AUTHENTICATION_DATA_ID: u8 = 0x16;
+const REQUEST_PROBLEM_INFORMATION_ID: u8 = 0x17;
+const REQUEST_RESPONSE_INFORMATION_ID: u8 = 0x19;
+const RECEIVE_MAXIMUM_ID: u8 = 0x21;
+const TOPIC_ALIAS_MAXIMUM_ID: u8 = 0x22;
+const USER_PROPERTY_ID: u8 = 0x26;
+const MAXIMUM_PACKET_SIZE_ID: u8 = 0x27;
+
 /// Contiene las propiedades de un Connect packet.
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct ConnectProperties {
@@ -45,167 +55,108 @@ impl ConnectProperties {
         }
     }
 
+    /// Escribe el bloque de properties precedido por su largo. Solo se escriben las que difieren de su
+    /// valor por defecto: request_problem_information vale true si no se envia, y el resto 0 o vacio.
     pub fn write_to(&self, stream: &mut dyn Write) -> Result<(), ProtocolError> {
-        let mut writer = BufWriter::new(stream);
-
-        let session_expiry_interval_id: u8 = 0x11_u8;
-
-        writer
-            .write_all(&[session_expiry_interval_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_u32(&mut writer, &self.session_expiry_interval)?;
-
-        let authentication_method_id: u8 = 0x15_u8;
-        writer
-            .write_all(&[authentication_method_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_string(&mut writer, &self.authentication_method)?;
-
-        let authentication_data_id: u8 = 0x16_u8;
-        writer
-            .write_all(&[authentication_data_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_bin_vec(&mut writer, &self.authentication_data)?;
-
-        let request_problem_information_id: u8 = 0x17_u8;
-        writer
-            .write_all(&[request_problem_information_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_bool(&mut writer, &self.request_problem_information)?;
-
-        let request_response_information_id: u8 = 0x19_u8; // 25
-
-        writer
-            .write_all(&[request_response_information_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_bool(&mut writer, &self.request_response_information)?;
-
-        let receive_maximum_id: u8 = 0x21_u8; // 33
-        writer
-            .write_all(&[receive_maximum_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_u16(&mut writer, &self.receive_maximum)?;
-
-        let topic_alias_maximum_id: u8 = 0x22_u8; // 34
-        writer
-            .write_all(&[topic_alias_maximum_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_u16(&mut writer, &self.topic_alias_maximum)?;
-
-        let user_properties_id: u8 = 0x26_u8; // 38
-        writer
-            .write_all(&[user_properties_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_tuple_vec(&mut writer, &self.user_properties)?;
-
-        let maximum_packet_size_id: u8 = 0x27_u8; // 39
-        writer
-            .write_all(&[maximum_packet_size_id])
-            .map_err(|_e| ProtocolError::WriteError)?;
-        write_u32(&mut writer, &self.maximum_packet_size)?;
-
-        Ok(())
+        let mut properties = Vec::new();
+
+        if self.session_expiry_interval != 0 {
+            write_u8(&mut properties, &SESSION_EXPIRY_INTERVAL_ID)?;
+            write_u32(&mut properties, &self.session_expiry_interval)?;
+        }
+
+        if !self.authentication_method.is_empty() {
+            write_u8(&mut properties, &AUTHENTICATION_METHOD_ID)?;
+            write_string(&mut properties, &self.authentication_method)?;
+
+            if !self.authentication_data.is_empty() {
+                write_u8(&mut properties, &AUTHENTICATION_DATA_ID)?;
+                write_bin_vec(&mut properties, &self.authentication_data)?;
+            }
+        }
+
+        if !self.request_problem_information {
+            write_u8(&mut properties, &REQUEST_PROBLEM_INFORMATION_ID)?;
+            write_bool(&mut properties, &self.request_problem_information)?;
+        }
+
+        if self.request_response_information {
+            write_u8(&mut properties, &REQUEST_RESPONSE_INFORMATION_ID)?;
+            write_bool(&mut properties, &self.request_response_information)?;
+        }
+
+        if self.receive_maximum != 0 {
+            write_u8(&mut properties, &RECEIVE_MAXIMUM_ID)?;
+            write_u16(&mut properties, &self.receive_maximum)?;
+        }
+
+        if self.topic_alias_maximum != 0 {
+            write_u8(&mut properties, &TOPIC_ALIAS_MAXIMUM_ID)?;
+            write_u16(&mut properties, &self.topic_alias_maximum)?;
+        }
+
+        write_user_properties(&mut properties, &self.user_properties)?;
+
+        if self.maximum_packet_size != 0 {
+            write_u8(&mut properties, &MAXIMUM_PACKET_SIZE_ID)?;
+            write_u32(&mut properties, &self.maximum_packet_size)?;
+        }
+
+        write_properties_block(stream, &properties)
     }
 
     pub fn read_from(stream: &mut dyn Read) -> Result<ConnectProperties, Error> {
-        let mut reader = BufReader::new(stream);
-
-        let mut session_expiry_interval: Option<u32> = None;
-        let mut receive_maximum: Option<u16> = None;
-        let mut maximum_packet_size: Option<u32> = None;
-        let mut topic_alias_maximum: Option<u16> = None;
-        let mut request_response_information: Option<bool> = None;
-        let mut request_problem_information: Option<bool> = None;
-        let mut user_properties: Option<Vec<(String, String)>> = None;
-        let mut authentication_method: Option<String> = None;
-        let mut authentication_data: Option<Vec<u8>> = None;
-        let mut count = 0;
-        while let Ok(property_id) = read_u8(&mut reader) {
-            match property_id {
-                0x11 => {
-                    let value = read_u32(&mut reader)?;
-                    session_expiry_interval = Some(value);
-                }
-                0x15 => {
-                    let value = read_string(&mut reader)?;
-                    authentication_method = Some(value);
+        let block = read_properties_block(stream)?;
+        let mut reader = block.as_slice();
+
+        let mut connect_properties = ConnectProperties::new(
+            0,
+            0,
+            0,
+            0,
+            false,
+            true,
+            Vec::new(),
+            String::new(),
+            Vec::new(),
+        );
+
+        while !reader.is_empty() {
+            match read_u8(&mut reader)? {
+                SESSION_EXPIRY_INTERVAL_ID => {
+                    connect_properties.session_expiry_interval = read_u32(&mut reader)?
                 }
-                0x16 => {
-                    let value = read_bin_vec(&mut reader)?;
-                    authentication_data = Some(value);
+                AUTHENTICATION_METHOD_ID => {
+                    connect_properties.authentication_method = read_string(&mut reader)?
                 }
-                0x17 => {
-                    let value = read_bool(&mut reader)?;
-                    request_problem_information = Some(value);
+                AUTHENTICATION_DATA_ID => {
+                    connect_properties.authentication_data = read_bin_vec(&mut reader)?
                 }
-                0x19 => {
-                    let value = read_bool(&mut reader)?;
-                    request_response_information = Some(value);
+                REQUEST_PROBLEM_INFORMATION_ID => {
+                    connect_properties.request_problem_information = read_bool(&mut reader)?
                 }
-                0x21 => {
-                    let value = read_u16(&mut reader)?;
-                    receive_maximum = Some(value);
+                REQUEST_RESPONSE_INFORMATION_ID => {
+                    connect_properties.request_response_information = read_bool(&mut reader)?
                 }
-                0x22 => {
-                    let value = read_u16(&mut reader)?;
-                    topic_alias_maximum = Some(value);
+                RECEIVE_MAXIMUM_ID => connect_properties.receive_maximum = read_u16(&mut reader)?,
+                TOPIC_ALIAS_MAXIMUM_ID => {
+                    connect_properties.topic_alias_maximum = read_u16(&mut reader)?
                 }
-                0x26 => {
-                    let value = read_tuple_vec(&mut reader)?;
-                    user_properties = Some(value);
+                USER_PROPERTY_ID => {
+                    let key = read_string(&mut reader)?;
+                    let value = read_string(&mut reader)?;
+                    connect_properties.user_properties.push((key, value));
                 }
-                0x27 => {
-                    let value = read_u32(&mut reader)?;
-                    maximum_packet_size = Some(value);
+                MAXIMUM_PACKET_SIZE_ID => {
+                    connect_properties.maximum_packet_size = read_u32(&mut reader)?
                 }
                 _ => {
                     return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido"));
                 }
             }
-            count += 1;
-            if count == 9 {
-                break;
-            }
         }
 
-        Ok(ConnectProperties {
-            session_expiry_interval: session_expiry_interval.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing session_expiry_interval property",
-            ))?,
-            receive_maximum: receive_maximum.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing receive_maximum property",
-            ))?,
-            maximum_packet_size: maximum_packet_size.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing maximum_packet_size property",
-            ))?,
-            topic_alias_maximum: topic_alias_maximum.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing topic_alias_maximum property",
-            ))?,
-            request_response_information: request_response_information.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing request_response_information property",
-            ))?,
-            request_problem_information: request_problem_information.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing request_problem_information property",
-            ))?,
-            user_properties: user_properties.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing user_properties property",
-            ))?,
-            authentication_method: authentication_method.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing authentication_method property",
-            ))?,
-            authentication_data: authentication_data.ok_or(Error::new(
-                ErrorKind::InvalidData,
-                "Missing authentication_data property",
-            ))?,
-        })
+        Ok(connect_properties)
     }
 }
 
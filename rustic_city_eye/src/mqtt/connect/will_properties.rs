@@ -1,6 +1,5 @@
 use serde::{Deserialize, Serialize};
 
-use std::io::BufWriter;
 use std::io::Error;
 use std::io::Read;
 use std::io::Write;
@@ -59,129 +58,93 @@ impl WillProperties {
             user_properties,
         }
     }
+    /// Escribe el bloque de will properties precedido por su largo, omitiendo las que estan vacias o en 0.
+    ///
+    /// El message expiry interval se envia en 4 bytes, como lo define el protocolo.
     pub fn write_to(&self, stream: &mut dyn Write) -> Result<(), ProtocolError> {
-        let mut writer = BufWriter::new(stream);
-        //will properties
-        write_u8(&mut writer, &WILL_DELAY_INTERVAL_ID)?;
+        let mut properties = Vec::new();
 
-        write_u32(&mut writer, &self.last_will_delay_interval)?;
+        if self.last_will_delay_interval != 0 {
+            write_u8(&mut properties, &WILL_DELAY_INTERVAL_ID)?;
+            write_u32(&mut properties, &self.last_will_delay_interval)?;
+        }
 
         let payload_format_indicator = 0x01_u8;
         //siempre 1 porque los strings en rust siempre son utf-8
-        write_u8(&mut writer, &PAYLOAD_FORMAT_INDICATOR_ID)?;
-        write_u8(&mut writer, &payload_format_indicator)?;
-
-        write_u8(&mut writer, &MESSAGE_EXPIRY_INTERVAL_ID)?;
-
-        write_u16(&mut writer, &self.message_expiry_interval)?;
+        write_u8(&mut properties, &PAYLOAD_FORMAT_INDICATOR_ID)?;
+        write_u8(&mut properties, &payload_format_indicator)?;
 
-        write_u8(&mut writer, &CONTENT_TYPE_ID)?;
-        write_string(&mut writer, &self.content_type)?;
-
-        write_u8(&mut writer, &RESPONSE_TOPIC_ID)?;
-        write_string(&mut writer, &self.response_topic)?;
-
-        write_u8(&mut writer, &CORRELATION_DATA_ID)?;
-        let correlation_data_length = self.correlation_data.len() as u16;
-        write_u16(&mut writer, &correlation_data_length)?;
-        for byte in &self.correlation_data {
-            write_u8(&mut writer, byte)?;
+        if self.message_expiry_interval != 0 {
+            write_u8(&mut properties, &MESSAGE_EXPIRY_INTERVAL_ID)?;
+            write_u32(&mut properties, &(self.message_expiry_interval as u32))?;
         }
-        //user property
 
-        write_u8(&mut writer, &USER_PROPERTIES_ID)?;
-        let user_properties_length = self.user_properties.len() as u16;
-        write_u16(&mut writer, &user_properties_length)?;
-        for (key, value) in &self.user_properties {
-            write_string(&mut writer, key)?;
-            write_string(&mut writer, value)?;
+        if !self.content_type.is_empty() {
+            write_u8(&mut properties, &CONTENT_TYPE_ID)?;
+            write_string(&mut properties, &self.content_type)?;
         }
 
-        Ok(())
-    }
-
-    pub fn read_from(stream: &mut dyn Read) -> Result<WillProperties, Error> {
-        //will properties
-        let will_delay_interval_id = read_u8(stream)?;
-        if will_delay_interval_id != WILL_DELAY_INTERVAL_ID {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid will delay interval id",
-            ));
+        if !self.response_topic.is_empty() {
+            write_u8(&mut properties, &RESPONSE_TOPIC_ID)?;
+            write_string(&mut properties, &self.response_topic)?;
         }
-        let will_delay_interval = read_u32(stream)?;
 
-        let payload_format_indicator_id = read_u8(stream)?;
-        if payload_format_indicator_id != PAYLOAD_FORMAT_INDICATOR_ID {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid payload format indicator id",
-            ));
+        if !self.correlation_data.is_empty() {
+            write_u8(&mut properties, &CORRELATION_DATA_ID)?;
+            write_bin_vec(&mut properties, &self.correlation_data)?;
         }
-        let payload_format_indicator = read_u8(stream)?;
 
-        let message_expiry_interval_id = read_u8(stream)?;
-        if message_expiry_interval_id != MESSAGE_EXPIRY_INTERVAL_ID {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid message expiry interval id",
-            ));
-        }
-        let message_expiry_interval = read_u16(stream)?;
-        let content_type_id = read_u8(stream)?;
-        if content_type_id != CONTENT_TYPE_ID {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid content type id",
-            ));
-        }
-        let content_type = read_string(stream)?;
+        write_user_properties(&mut properties, &self.user_properties)?;
 
-        let response_topic_id = read_u8(stream)?;
-        if response_topic_id != RESPONSE_TOPIC_ID {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid response topic id",
-            ));
-        }
-        let response_topic = read_string(stream)?;
+        write_properties_block(stream, &properties)
+    }
 
-        let correlation_data_id = read_u8(stream)?;
-        if correlation_data_id != CORRELATION_DATA_ID {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid correlation data id",
-            ));
-        }
-        let correlation_data_length = read_u16(stream)?;
-        let mut correlation_data = vec![0; correlation_data_length as usize];
-        stream.read_exact(&mut correlation_data)?;
+    pub fn read_from(stream: &mut dyn Read) -> Result<WillProperties, Error> {
+        let block = read_properties_block(stream)?;
+        let mut properties = block.as_slice();
 
-        //user property
-        let user_properties_id = read_u8(stream)?;
-        if user_properties_id != USER_PROPERTIES_ID {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid user properties id",
-            ));
-        }
-        let mut user_properties = Vec::new();
-        let user_properties_lenght = read_u16(stream)?;
-        for _ in 0..user_properties_lenght {
-            let user_properties_key = read_string(stream)?;
-            let user_properties_value = read_string(stream)?;
-            user_properties.push((user_properties_key, user_properties_value));
+        let mut will_properties = WillProperties::new(
+            0,
+            0,
+            0,
+            String::new(),
+            String::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+
+        while !properties.is_empty() {
+            match read_u8(&mut properties)? {
+                WILL_DELAY_INTERVAL_ID => {
+                    will_properties.last_will_delay_interval = read_u32(&mut properties)?
+                }
+                PAYLOAD_FORMAT_INDICATOR_ID => {
+                    will_properties.payload_format_indicator = read_u8(&mut properties)?
+                }
+                MESSAGE_EXPIRY_INTERVAL_ID => {
+                    let interval = read_u32(&mut properties)?;
+                    will_properties.message_expiry_interval = interval.min(u16::MAX as u32) as u16;
+                }
+                CONTENT_TYPE_ID => will_properties.content_type = read_string(&mut properties)?,
+                RESPONSE_TOPIC_ID => will_properties.response_topic = read_string(&mut properties)?,
+                CORRELATION_DATA_ID => {
+                    will_properties.correlation_data = read_bin_vec(&mut properties)?
+                }
+                USER_PROPERTIES_ID => {
+                    let key = read_string(&mut properties)?;
+                    let value = read_string(&mut properties)?;
+                    will_properties.user_properties.push((key, value));
+                }
+                _ => {
+                    return Err(Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Property ID inválido",
+                    ))
+                }
+            }
         }
 
-        Ok(WillProperties {
-            last_will_delay_interval: will_delay_interval,
-            payload_format_indicator,
-            message_expiry_interval,
-            content_type,
-            response_topic,
-            correlation_data,
-            user_properties,
-        })
+        Ok(will_properties)
     }
 
     pub fn get_last_will_delay_interval(&self) -> u32 {
@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use super::protocol_error::ProtocolError;
+
+/// Esquema con el que se guardan las credenciales en el archivo de clientes(RFC 5802).
+const SCRAM_SCHEME: &str = "scram-sha-256";
+
+/// Esquema anterior, en el que se guardaba el resultado de PBKDF2. Se sigue leyendo para no invalidar
+/// los archivos existentes, pero al escribir el archivo se lo reemplaza por el nuevo.
+const LEGACY_PBKDF2_SCHEME: &str = "pbkdf2-sha256";
+
+/// Cantidad de iteraciones de PBKDF2 con las que se hashean los passwords nuevos.
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+
+const SALT_LEN: usize = 16;
+const SHA256_BLOCK_LEN: usize = 64;
+
+/// Calcula el HMAC-SHA256 del mensaje con la key dada.
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block_key = if key.len() > SHA256_BLOCK_LEN {
+        Sha256::digest(key).to_vec()
+    } else {
+        key.to_vec()
+    };
+    block_key.resize(SHA256_BLOCK_LEN, 0);
+
+    let mut inner = Sha256::new();
+    inner.update(block_key.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+
+    let mut outer = Sha256::new();
+    outer.update(block_key.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+/// Deriva una clave de 32 bytes a partir del password y la salt con PBKDF2-HMAC-SHA256.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut first_block = salt.to_vec();
+    first_block.extend_from_slice(&1_u32.to_be_bytes());
+
+    let mut u = hmac_sha256(password, &first_block);
+    let mut derived = u.clone();
+    for _ in 1..iterations {
+        u = hmac_sha256(password, &u);
+        for (d, byte) in derived.iter_mut().zip(u.iter()) {
+            *d ^= byte;
+        }
+    }
+
+    derived
+}
+
+/// Claves que se derivan del SaltedPassword de SCRAM: (ClientKey, StoredKey, ServerKey).
+pub fn derive_scram_keys(salted_password: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let client_key = hmac_sha256(salted_password, b"Client Key");
+    let stored_key = Sha256::digest(&client_key).to_vec();
+    let server_key = hmac_sha256(salted_password, b"Server Key");
+    (client_key, stored_key, server_key)
+}
+
+/// Compara dos secuencias de bytes sin cortar en la primera diferencia, para no filtrar por
+/// el tiempo de respuesta cuantos bytes coinciden.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0_u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Credencial de un cliente: su username y las claves de SCRAM-SHA-256 derivadas de su password.
+///
+/// Ni el password ni el resultado de PBKDF2(el SaltedPassword) se guardan, ya que con cualquiera de los dos se
+/// puede completar SCRAM. Solo se guardan la salt, la cantidad de iteraciones, el StoredKey y el ServerKey.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credential {
+    username: String,
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl Credential {
+    /// Deriva las claves del password con una salt aleatoria.
+    pub fn new(username: String, password: &[u8]) -> Credential {
+        let mut salt = vec![0; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let salted_password = pbkdf2_sha256(password, &salt, DEFAULT_ITERATIONS);
+        Credential::from_salted_password(username, DEFAULT_ITERATIONS, salt, &salted_password)
+    }
+
+    fn from_salted_password(
+        username: String,
+        iterations: u32,
+        salt: Vec<u8>,
+        salted_password: &[u8],
+    ) -> Credential {
+        let (_, stored_key, server_key) = derive_scram_keys(salted_password);
+        Credential {
+            username,
+            iterations,
+            salt,
+            stored_key,
+            server_key,
+        }
+    }
+
+    /// Indica si el username y el password coinciden con los de la credencial.
+    pub fn verify(&self, username: &str, password: &[u8]) -> bool {
+        let salted_password = pbkdf2_sha256(password, &self.salt, self.iterations);
+        let (_, stored_key, _) = derive_scram_keys(&salted_password);
+        self.username == username && constant_time_eq(&stored_key, &self.stored_key)
+    }
+
+    pub fn get_username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn get_salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn get_iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    /// H(ClientKey): con el se verifica la prueba que envia el cliente en SCRAM.
+    pub fn get_stored_key(&self) -> &[u8] {
+        &self.stored_key
+    }
+
+    /// Con el ServerKey el Broker firma el intercambio SCRAM, para probarle al cliente que conoce la credencial.
+    pub fn get_server_key(&self) -> &[u8] {
+        &self.server_key
+    }
+
+    /// Lee la credencial con el formato
+    /// `scram-sha-256$<iteraciones>$<salt en base64>$<StoredKey en base64>$<ServerKey en base64>`.
+    ///
+    /// Tambien acepta el formato anterior, `pbkdf2-sha256$<iteraciones>$<salt en base64>$<hash en base64>`,
+    /// del que se derivan las claves.
+    fn parse(username: String, encoded: &str) -> Result<Credential, ProtocolError> {
+        let parts: Vec<&str> = encoded.split('$').collect();
+        let expected_parts = match parts[0] {
+            SCRAM_SCHEME => 5,
+            LEGACY_PBKDF2_SCHEME => 4,
+            _ => return Err(ProtocolError::ReadingClientsFileError),
+        };
+        if parts.len() != expected_parts {
+            return Err(ProtocolError::ReadingClientsFileError);
+        }
+
+        let iterations = match parts[1].parse::<u32>() {
+            Ok(iterations) if iterations > 0 => iterations,
+            _ => return Err(ProtocolError::ReadingClientsFileError),
+        };
+        let mut decoded = Vec::new();
+        for part in &parts[2..] {
+            match STANDARD.decode(part) {
+                Ok(bytes) => decoded.push(bytes),
+                Err(_) => return Err(ProtocolError::ReadingClientsFileError),
+            }
+        }
+
+        let salt = decoded.remove(0);
+        if parts[0] == LEGACY_PBKDF2_SCHEME {
+            return Ok(Credential::from_salted_password(
+                username,
+                iterations,
+                salt,
+                &decoded[0],
+            ));
+        }
+
+        Ok(Credential {
+            username,
+            iterations,
+            salt,
+            stored_key: decoded.remove(0),
+            server_key: decoded.remove(0),
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "{}${}${}${}${}",
+            SCRAM_SCHEME,
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(&self.stored_key),
+            STANDARD.encode(&self.server_key)
+        )
+    }
+}
+
+/// Credenciales de los clientes que se autentican con el metodo password-based, indexadas por client_id.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CredentialStore {
+    credentials: HashMap<String, Credential>,
+}
+
+impl CredentialStore {
+    /// Lee las credenciales desde un archivo.
+    ///
+    /// Cada linea tiene el formato `<client_id>, <username>, <hash del password>`. Las lineas vacias
+    /// y las que empiezan con '#' se ignoran.
+    pub fn read_from_file(file_path: &str) -> Result<CredentialStore, ProtocolError> {
+        let file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => return Err(ProtocolError::ReadingClientsFileError),
+        };
+
+        let mut credentials = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line.trim().to_string(),
+                Err(_) => return Err(ProtocolError::ReadingClientsFileError),
+            };
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split(',').map(|part| part.trim()).collect();
+            if parts.len() != 3 || parts[0].is_empty() {
+                return Err(ProtocolError::ReadingClientsFileError);
+            }
+            let credential = Credential::parse(parts[1].to_string(), parts[2])?;
+            credentials.insert(parts[0].to_string(), credential);
+        }
+
+        Ok(CredentialStore { credentials })
+    }
+
+    /// Escribe las credenciales en el archivo, ordenadas por client_id.
+    pub fn write_to_file(&self, file_path: &str) -> Result<(), ProtocolError> {
+        let mut client_ids: Vec<&String> = self.credentials.keys().collect();
+        client_ids.sort();
+
+        let mut content = String::new();
+        for client_id in client_ids {
+            let credential = &self.credentials[client_id];
+            content.push_str(&format!(
+                "{}, {}, {}\n",
+                client_id,
+                credential.username,
+                credential.encode()
+            ));
+        }
+
+        let mut file = match File::create(file_path) {
+            Ok(file) => file,
+            Err(_) => return Err(ProtocolError::WritingClientsFileError),
+        };
+        file.write_all(content.as_bytes())
+            .map_err(|_| ProtocolError::WritingClientsFileError)
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<&Credential> {
+        self.credentials.get(client_id)
+    }
+
+    /// Agrega un cliente nuevo. Falla si ya existe un cliente con ese client_id.
+    pub fn add_user(
+        &mut self,
+        client_id: &str,
+        username: &str,
+        password: &[u8],
+    ) -> Result<(), ProtocolError> {
+        if self.credentials.contains_key(client_id) {
+            return Err(ProtocolError::InvalidCommand(format!(
+                "El cliente {} ya existe",
+                client_id
+            )));
+        }
+
+        self.credentials.insert(
+            client_id.to_string(),
+            Credential::new(username.to_string(), password),
+        );
+        Ok(())
+    }
+
+    /// Elimina un cliente. Falla si no existe.
+    pub fn remove_user(&mut self, client_id: &str) -> Result<(), ProtocolError> {
+        match self.credentials.remove(client_id) {
+            Some(_) => Ok(()),
+            None => Err(ProtocolError::InvalidCommand(format!(
+                "El cliente {} no existe",
+                client_id
+            ))),
+        }
+    }
+
+    /// Reemplaza el password de un cliente, con una salt nueva. Falla si no existe.
+    pub fn rotate_password(
+        &mut self,
+        client_id: &str,
+        password: &[u8],
+    ) -> Result<(), ProtocolError> {
+        match self.credentials.get_mut(client_id) {
+            Some(credential) => {
+                *credential = Credential::new(credential.username.clone(), password);
+                Ok(())
+            }
+            None => Err(ProtocolError::InvalidCommand(format!(
+                "El cliente {} no existe",
+                client_id
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_01_pbkdf2_sha256_vectors() {
+        assert_eq!(
+            to_hex(&pbkdf2_sha256(b"password", b"salt", 1)),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"
+        );
+        assert_eq!(
+            to_hex(&pbkdf2_sha256(b"password", b"salt", 2)),
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"
+        );
+    }
+
+    #[test]
+    fn test_02_add_rotate_and_remove_users() -> Result<(), ProtocolError> {
+        let mut store = CredentialStore::default();
+        store.add_user("monitoring_app", "monitoreo", b"un password")?;
+        assert!(store.add_user("monitoring_app", "otro", b"otro").is_err());
+
+        let credential = store.get("monitoring_app").unwrap().clone();
+        assert!(credential.verify("monitoreo", b"un password"));
+        assert!(!credential.verify("monitoreo", b"otro password"));
+        assert!(!credential.verify("otro", b"un password"));
+
+        store.rotate_password("monitoring_app", b"un password")?;
+        let rotated = store.get("monitoring_app").unwrap();
+        assert_ne!(rotated.salt, credential.salt);
+        assert!(rotated.verify("monitoreo", b"un password"));
+
+        store.remove_user("monitoring_app")?;
+        assert!(store.get("monitoring_app").is_none());
+        assert!(store.remove_user("monitoring_app").is_err());
+        assert!(store.rotate_password("monitoring_app", b"x").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_03_writing_and_reading_the_file() -> Result<(), ProtocolError> {
+        let dir = tempfile::tempdir().map_err(|_| ProtocolError::WritingClientsFileError)?;
+        let path = dir.path().join("clients.txt");
+        let path = path.to_str().unwrap();
+
+        let mut store = CredentialStore::default();
+        store.add_user("monitoring_app", "monitoreo", b"un password")?;
+        store.write_to_file(path)?;
+
+        let content = std::fs::read_to_string(path).unwrap();
+        assert!(content.starts_with("monitoring_app, monitoreo, scram-sha-256$"));
+        assert!(!content.contains("un password"));
+        assert_eq!(CredentialStore::read_from_file(path)?, store);
+
+        let monitoring_store = CredentialStore::read_from_file("./src/monitoring/clients.txt")?;
+        assert_eq!(
+            monitoring_store
+                .get("monitoring_app")
+                .unwrap()
+                .get_username(),
+            "monitoreo"
+        );
+        assert!(CredentialStore::read_from_file("./este/archivo/no/existe").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_04_legacy_pbkdf2_credentials_are_converted() -> Result<(), ProtocolError> {
+        let hash = pbkdf2_sha256(b"un password", b"salt", 4096);
+        let legacy = format!(
+            "pbkdf2-sha256$4096${}${}",
+            STANDARD.encode(b"salt"),
+            STANDARD.encode(&hash)
+        );
+
+        let credential = Credential::parse("monitoreo".to_string(), &legacy)?;
+        assert!(credential.verify("monitoreo", b"un password"));
+        assert!(!credential.verify("monitoreo", b"otro password"));
+
+        let encoded = credential.encode();
+        assert!(encoded.starts_with("scram-sha-256$4096$"));
+        assert!(!encoded.contains(&STANDARD.encode(&hash)));
+        assert_eq!(
+            Credential::parse("monitoreo".to_string(), &encoded)?,
+            credential
+        );
+
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use super::{client_message::ClientMessage, messages_config::MessagesConfig};
 
 pub struct DisconnectConfig {
     pub(crate) reason_code: u8,
-    pub(crate) session_expiry_interval: u32,
+    pub(crate) session_expiry_interval: Option<u32>,
     pub(crate) reason_string: String,
     pub(crate) client_id: String,
 }
@@ -21,7 +21,7 @@ impl MessagesConfig for DisconnectConfig {
 impl DisconnectConfig {
     pub fn new(
         reason_code: u8,
-        session_expiry_interval: u32,
+        session_expiry_interval: Option<u32>,
         reason_string: String,
         client_id: String,
     ) -> DisconnectConfig {
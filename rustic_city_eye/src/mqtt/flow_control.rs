@@ -0,0 +1,89 @@
+use crate::mqtt::{
+    broker_message::BrokerMessage, client_message::ClientMessage, protocol_error::ProtocolError,
+};
+
+/// Limites de flujo que un extremo de la conexion le indica al otro en su Connect o Connack.
+///
+/// El receive maximum es la cantidad de Publish con QoS > 0 sin confirmar que acepta recibir a la vez, y el
+/// maximum packet size es el tamaño maximo(en bytes) de los packets que acepta recibir.
+/// Un valor de 0 indica que no se informo ese limite.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FlowControl {
+    receive_maximum: u16,
+    maximum_packet_size: u32,
+}
+
+impl FlowControl {
+    pub fn new(receive_maximum: u16, maximum_packet_size: u32) -> FlowControl {
+        FlowControl {
+            receive_maximum,
+            maximum_packet_size,
+        }
+    }
+
+    /// Indica si puede enviarse otro Publish con QoS > 0 cuando hay in_flight Publish sin confirmar.
+    pub fn can_send(&self, in_flight: usize) -> bool {
+        self.receive_maximum == 0 || in_flight < self.receive_maximum as usize
+    }
+
+    /// Maximum packet size(en bytes) informado. Vale 0 si no se informo.
+    pub fn maximum_packet_size(&self) -> u32 {
+        self.maximum_packet_size
+    }
+
+    /// Indica si un packet de packet_size bytes respeta el maximum packet size.
+    pub fn fits(&self, packet_size: usize) -> bool {
+        self.maximum_packet_size == 0 || packet_size <= self.maximum_packet_size as usize
+    }
+
+    /// Tamaño en bytes de un packet enviado por el cliente.
+    pub fn client_packet_size(message: &ClientMessage) -> Result<usize, ProtocolError> {
+        let mut bytes = Vec::new();
+        message.write_to(&mut bytes)?;
+        Ok(bytes.len())
+    }
+
+    /// Tamaño en bytes de un packet enviado por el broker.
+    pub fn broker_packet_size(message: &BrokerMessage) -> Result<usize, ProtocolError> {
+        let mut bytes = Vec::new();
+        message.write_to(&mut bytes)?;
+        Ok(bytes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receive_maximum_limits_in_flight_messages() {
+        let flow_control = FlowControl::new(2, 0);
+
+        assert!(flow_control.can_send(0));
+        assert!(flow_control.can_send(1));
+        assert!(!flow_control.can_send(2));
+
+        assert!(FlowControl::default().can_send(usize::MAX));
+    }
+
+    #[test]
+    fn test_maximum_packet_size() -> Result<(), ProtocolError> {
+        let flow_control = FlowControl::new(0, 4);
+
+        let pingreq_size = FlowControl::client_packet_size(&ClientMessage::Pingreq)?;
+        assert!(pingreq_size <= 4);
+        assert!(flow_control.fits(pingreq_size));
+        assert!(!flow_control.fits(5));
+
+        let disconnect = BrokerMessage::Disconnect {
+            reason_code: 0,
+            session_expiry_interval: 0,
+            reason_string: "un reason string largo".to_string(),
+            user_properties: Vec::new(),
+        };
+        assert!(!flow_control.fits(FlowControl::broker_packet_size(&disconnect)?));
+        assert!(FlowControl::default().fits(usize::MAX));
+
+        Ok(())
+    }
+}
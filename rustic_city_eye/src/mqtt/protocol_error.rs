@@ -20,10 +20,13 @@ pub enum ProtocolError {
     MissingWillMessageProperties,
     ChanellError(String),
     ReadingClientsFileError,
+    WritingClientsFileError,
+    ReadingAclFileError,
     NotReceivedMessageError,
     ExpectedConnack,
     AuthError,
     AbnormalDisconnection,
+    TopicAliasInvalid,
     DroneError(String),
     CameraError(String),
     SendError(String),
@@ -42,6 +45,11 @@ pub enum ProtocolError {
     OpenFileError(String),
     ReadingCertificateError(String),
     ReadingPrivateKeyError,
+    RequestTimeout,
+    PacketTooLarge,
+    MalformedPacket,
+    ReceiveMaximumExceeded,
+    WebSocketHandshakeError(String),
 }
 
 impl fmt::Display for ProtocolError {
@@ -54,6 +62,18 @@ impl fmt::Display for ProtocolError {
             ProtocolError::ReadingPrivateKeyError => {
                 write!(f, "Error: No private key found.")
             }
+            ProtocolError::PacketTooLarge => {
+                write!(f, "Error: el packet supera el maximum packet size.")
+            }
+            ProtocolError::MalformedPacket => {
+                write!(f, "Error: el packet esta mal formado.")
+            }
+            ProtocolError::ReceiveMaximumExceeded => {
+                write!(f, "Error: se supero el receive maximum del broker.")
+            }
+            ProtocolError::RequestTimeout => {
+                write!(f, "Error: no llego la respuesta del request a tiempo.")
+            }
             ProtocolError::NotReceivedMessageError => {
                 write!(f, "Error: no ha llegado ningun mensaje.")
             }
@@ -66,6 +86,12 @@ impl fmt::Display for ProtocolError {
             ProtocolError::ReadingClientsFileError => {
                 write!(f, "Error al leer el archivo de clientes.")
             }
+            ProtocolError::WritingClientsFileError => {
+                write!(f, "Error al escribir el archivo de clientes.")
+            }
+            ProtocolError::ReadingAclFileError => {
+                write!(f, "Error al leer el archivo de ACL.")
+            }
             ProtocolError::MissingWillMessageProperties => {
                 write!(f, "Error: faltan propiedades del will message.")
             }
@@ -104,6 +130,9 @@ impl fmt::Display for ProtocolError {
             ProtocolError::ReadingCertificateError(ref err) => {
                 write!(f, "Error while reading certificate: {}", err)
             }
+            ProtocolError::WebSocketHandshakeError(ref err) => {
+                write!(f, "Handshake de WebSocket invalido: {}", err)
+            }
 
             ProtocolError::ChanellError(ref err) => {
                 write!(
@@ -115,6 +144,9 @@ impl fmt::Display for ProtocolError {
             ProtocolError::AbnormalDisconnection => {
                 write!(f, "Error: desconexión anormal.")
             }
+            ProtocolError::TopicAliasInvalid => {
+                write!(f, "Error: topic alias inválido.")
+            }
             ProtocolError::DroneError(ref err) => {
                 write!(f, "Error de protocolo: {}", err)
             }
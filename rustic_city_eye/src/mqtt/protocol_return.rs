@@ -5,6 +5,11 @@ pub enum ProtocolReturn {
     ConnackSent,
     SubackSent,
     PubackSent,
+    PubackRecieved,
+    PubrecSent,
+    PubrelSent,
+    PubcompSent,
+    PubcompRecieved,
     PingrespSent,
     UnsubackSent,
     DisconnectRecieved,
@@ -20,6 +25,11 @@ impl fmt::Display for ProtocolReturn {
             ProtocolReturn::ConnackSent => write!(f, "Connack Enviado."),
             ProtocolReturn::SubackSent => write!(f, "Suback Enviado."),
             ProtocolReturn::PubackSent => write!(f, "Puback Enviado."),
+            ProtocolReturn::PubackRecieved => write!(f, "Puback Recibido."),
+            ProtocolReturn::PubrecSent => write!(f, "Pubrec Enviado."),
+            ProtocolReturn::PubrelSent => write!(f, "Pubrel Enviado."),
+            ProtocolReturn::PubcompSent => write!(f, "Pubcomp Enviado."),
+            ProtocolReturn::PubcompRecieved => write!(f, "Pubcomp Recibido."),
             ProtocolReturn::PingrespSent => write!(f, "Pingresp Enviado."),
             ProtocolReturn::UnsubackSent => write!(f, "Unsuback Enviado."),
             ProtocolReturn::DisconnectRecieved => write!(f, "Disconnect Recibido."),
@@ -41,6 +51,8 @@ mod tests {
         assert_eq!(ProtocolReturn::ConnackSent.to_string(), "Connack Enviado.");
         assert_eq!(ProtocolReturn::SubackSent.to_string(), "Suback Enviado.");
         assert_eq!(ProtocolReturn::PubackSent.to_string(), "Puback Enviado.");
+        assert_eq!(ProtocolReturn::PubrecSent.to_string(), "Pubrec Enviado.");
+        assert_eq!(ProtocolReturn::PubcompSent.to_string(), "Pubcomp Enviado.");
         assert_eq!(
             ProtocolReturn::PingrespSent.to_string(),
             "Pingresp Enviado."
@@ -4,8 +4,10 @@ use serde::Deserialize;
 
 use crate::{
     mqtt::{
-        client_message::ClientMessage, messages_config::MessagesConfig,
-        protocol_error::ProtocolError, publish::publish_properties::PublishProperties,
+        client_message::ClientMessage,
+        messages_config::MessagesConfig,
+        protocol_error::ProtocolError,
+        publish::publish_properties::{PublishProperties, TopicProperties},
     },
     utils::payload_types::PayloadTypes,
 };
@@ -91,6 +93,46 @@ impl PublishConfig {
             publish_properties: config.publish_properties,
         })
     }
+
+    /// Arma la respuesta a un request: un Publish a su response topic, con su misma correlation data.
+    ///
+    /// La respuesta se envia con QoS 1, para que quien hizo el request no la pierda.
+    /// Devuelve error si el mensaje recibido no es un request.
+    pub fn response_to(
+        request: &ClientMessage,
+        payload: PayloadTypes,
+    ) -> Result<PublishConfig, ProtocolError> {
+        let properties = match request {
+            ClientMessage::Publish { properties, .. } if request.is_request() => properties,
+            _ => {
+                return Err(ProtocolError::InvalidCommand(
+                    "El mensaje no es un request".to_string(),
+                ))
+            }
+        };
+
+        let response_properties = PublishProperties::new(
+            properties.payload_format_indicator,
+            0,
+            TopicProperties {
+                topic_alias: 0,
+                response_topic: String::new(),
+            },
+            properties.correlation_data.clone(),
+            String::new(),
+            0,
+            String::new(),
+        );
+
+        Ok(PublishConfig::new(
+            0,
+            1,
+            0,
+            properties.topic_properties.response_topic.clone(),
+            payload,
+            response_properties,
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -143,4 +185,53 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_response_to_request() {
+        let location = Location::new(12.1, 25.0);
+        let payload = PayloadTypes::IncidentLocation(incident_payload::IncidentPayload::new(
+            Incident::new(location),
+        ));
+        let request_properties = PublishProperties::new(
+            1,
+            5,
+            TopicProperties {
+                topic_alias: 0,
+                response_topic: "responses/monitoring_app".to_string(),
+            },
+            vec![4, 2],
+            String::new(),
+            0,
+            String::new(),
+        );
+        let request = PublishConfig::new(
+            0,
+            0,
+            0,
+            "drone_commands".to_string(),
+            payload.clone(),
+            request_properties.clone(),
+        )
+        .parse_message(1);
+
+        let response = PublishConfig::response_to(&request, payload.clone()).unwrap();
+        assert_eq!(response.topic_name, "responses/monitoring_app");
+        assert_eq!(response.qos, 1);
+        assert_eq!(response.publish_properties.correlation_data, vec![4, 2]);
+        assert!(!response.parse_message(2).is_request());
+
+        let mut not_a_request_properties = request_properties;
+        not_a_request_properties.topic_properties.response_topic = String::new();
+        let not_a_request = PublishConfig::new(
+            0,
+            0,
+            0,
+            "incident".to_string(),
+            payload.clone(),
+            not_a_request_properties,
+        )
+        .parse_message(3);
+        assert!(!not_a_request.is_request());
+        assert!(PublishConfig::response_to(&not_a_request, payload).is_err());
+    }
 }
@@ -1,8 +1,8 @@
-use std::io::{Error, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
 
 use serde::{Deserialize, Serialize};
 
-use crate::utils::{reader::*, writer::*};
+use crate::utils::{payload_types::PayloadTypes, reader::*, writer::*};
 
 use crate::mqtt::protocol_error::ProtocolError;
 
@@ -60,89 +60,133 @@ impl PublishProperties {
         }
     }
 
+    /// Escribe las properties como las define el protocolo: un bloque precedido por su largo, en el que solo
+    /// aparecen las properties con valor.
+    ///
+    /// El user_property se envia como una user property con ese nombre y un valor vacio.
     pub fn write_properties(&self, stream: &mut dyn Write) -> Result<(), ProtocolError> {
-        //payload format indicator
-        write_u8(stream, &PAYLOAD_FORMAT_INDICATOR_ID)?;
-        write_u8(stream, &self.payload_format_indicator)?;
+        let mut properties = Vec::new();
 
-        //message expiry interval
-        write_u8(stream, &MESSAGE_EXPIRY_INTERVAL_ID)?;
-        write_u32(stream, &self.message_expiry_interval)?;
+        if self.payload_format_indicator != 0 {
+            write_u8(&mut properties, &PAYLOAD_FORMAT_INDICATOR_ID)?;
+            write_u8(&mut properties, &self.payload_format_indicator)?;
+        }
 
-        //topic alias
-        write_u8(stream, &TOPIC_ALIAS_ID)?;
-        write_u16(stream, &self.topic_properties.topic_alias)?;
+        if self.message_expiry_interval != 0 {
+            write_u8(&mut properties, &MESSAGE_EXPIRY_INTERVAL_ID)?;
+            write_u32(&mut properties, &self.message_expiry_interval)?;
+        }
 
-        //response topic
-        write_u8(stream, &RESPONSE_TOPIC_ID)?;
-        write_string(stream, &self.topic_properties.response_topic)?;
+        if self.topic_properties.topic_alias != 0 {
+            write_u8(&mut properties, &TOPIC_ALIAS_ID)?;
+            write_u16(&mut properties, &self.topic_properties.topic_alias)?;
+        }
 
-        //correlation data
-        write_u8(stream, &CORRELATION_DATA_ID)?;
-        write_bin_vec(stream, &self.correlation_data)?;
+        if !self.topic_properties.response_topic.is_empty() {
+            write_u8(&mut properties, &RESPONSE_TOPIC_ID)?;
+            write_string(&mut properties, &self.topic_properties.response_topic)?;
+        }
 
-        //user property
-        write_u8(stream, &USER_PROPERTY_ID)?;
-        write_string(stream, &self.user_property)?;
+        if !self.correlation_data.is_empty() {
+            write_u8(&mut properties, &CORRELATION_DATA_ID)?;
+            write_bin_vec(&mut properties, &self.correlation_data)?;
+        }
 
-        //subscription identifier
-        write_u8(stream, &SUBSCRIPTION_IDENTIFIER_ID)?;
-        write_u32(stream, &self.subscription_identifier)?;
+        if !self.user_property.is_empty() {
+            write_user_properties(
+                &mut properties,
+                &[(self.user_property.clone(), String::new())],
+            )?;
+        }
 
-        //content type
-        write_u8(stream, &CONTENT_TYPE_ID)?;
-        write_string(stream, &self.content_type)?;
+        if self.subscription_identifier != 0 {
+            write_u8(&mut properties, &SUBSCRIPTION_IDENTIFIER_ID)?;
+            write_variable_byte_integer(&mut properties, &self.subscription_identifier)?;
+        }
+
+        if !self.content_type.is_empty() {
+            write_u8(&mut properties, &CONTENT_TYPE_ID)?;
+            write_string(&mut properties, &self.content_type)?;
+        }
 
-        Ok(())
+        write_properties_block(stream, &properties)
     }
 
+    /// Lee el bloque de properties. Las que no vienen quedan con su valor por defecto.
+    ///
+    /// De las user properties solo se conserva el nombre de la primera.
     pub fn read_from(stream: &mut dyn Read) -> Result<PublishProperties, Error> {
-        //payload format indicator
-        let _payload_format_indicator_id = read_u8(stream)?;
-        let payload_format_indicator = read_u8(stream)?;
-
-        //message expiry interval
-        let _message_expiry_interval_id = read_u8(stream)?;
-        let message_expiry_interval = read_u32(stream)?;
-
-        //topic alias
-        let _topic_alias_id = read_u8(stream)?;
-        let topic_alias = read_u16(stream)?;
-
-        //response topic
-        let _response_topic_id = read_u8(stream)?;
-        let response_topic = read_string(stream)?;
-
-        let topic_properties = TopicProperties {
-            topic_alias,
-            response_topic,
-        };
-
-        //correlation data
-        let _correlation_data_id = read_u8(stream)?;
-        let correlation_data = read_bin_vec(stream)?;
+        let block = read_properties_block(stream)?;
+        let mut properties = block.as_slice();
+
+        let mut publish_properties = PublishProperties::new(
+            0,
+            0,
+            TopicProperties {
+                topic_alias: 0,
+                response_topic: String::new(),
+            },
+            Vec::new(),
+            String::new(),
+            0,
+            String::new(),
+        );
 
-        //user property
-        let _user_property_id = read_u8(stream)?;
-        let user_property = read_string(stream)?;
+        while !properties.is_empty() {
+            match read_u8(&mut properties)? {
+                PAYLOAD_FORMAT_INDICATOR_ID => {
+                    publish_properties.payload_format_indicator = read_u8(&mut properties)?
+                }
+                MESSAGE_EXPIRY_INTERVAL_ID => {
+                    publish_properties.message_expiry_interval = read_u32(&mut properties)?
+                }
+                TOPIC_ALIAS_ID => {
+                    publish_properties.topic_properties.topic_alias = read_u16(&mut properties)?
+                }
+                RESPONSE_TOPIC_ID => {
+                    publish_properties.topic_properties.response_topic =
+                        read_string(&mut properties)?
+                }
+                CORRELATION_DATA_ID => {
+                    publish_properties.correlation_data = read_bin_vec(&mut properties)?
+                }
+                USER_PROPERTY_ID => {
+                    let key = read_string(&mut properties)?;
+                    let _value = read_string(&mut properties)?;
+                    if publish_properties.user_property.is_empty() {
+                        publish_properties.user_property = key;
+                    }
+                }
+                SUBSCRIPTION_IDENTIFIER_ID => {
+                    publish_properties.subscription_identifier =
+                        read_variable_byte_integer(&mut properties)?
+                }
+                CONTENT_TYPE_ID => publish_properties.content_type = read_string(&mut properties)?,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido")),
+            }
+        }
 
-        //subscription identifier
-        let _subscription_identifier_id = read_u8(stream)?;
-        let subscription_identifier = read_u32(stream)?;
+        Ok(publish_properties)
+    }
 
-        //content type
-        let _content_type_id = read_u8(stream)?;
-        let content_type = read_string(stream)?;
+    /// Devuelve las properties con el payload format indicator y el content type que corresponden al payload.
+    ///
+    /// Los payloads binarios y JSON se reconocen del otro lado por su content type, por lo que se completa
+    /// al enviarlos si quien publica no indico uno. Si lo indico, se respetan el suyo y su payload format
+    /// indicator. Para el resto de los payloads las properties no cambian.
+    pub fn for_payload(&self, payload: &PayloadTypes) -> PublishProperties {
+        let mut properties = self.clone();
+
+        if let Some(content_type) = payload.content_type() {
+            if properties.content_type.is_empty() {
+                properties.content_type = content_type.to_string();
+                if let PayloadTypes::Json(_) = payload {
+                    properties.payload_format_indicator = 1;
+                }
+            }
+        }
 
-        Ok(PublishProperties {
-            payload_format_indicator,
-            message_expiry_interval,
-            topic_properties,
-            correlation_data,
-            user_property,
-            subscription_identifier,
-            content_type,
-        })
+        properties
     }
 }
 
@@ -151,6 +195,7 @@ mod tests {
     use std::io::Cursor;
 
     use super::*;
+    use crate::utils::payload_types::{BINARY_CONTENT_TYPE, JSON_CONTENT_TYPE};
 
     #[test]
     fn test_01_publish_properties_ok() {
@@ -183,4 +228,66 @@ mod tests {
         };
         assert_eq!(properties, publish_properties_read);
     }
+
+    #[test]
+    fn test_02_empty_properties_are_not_written() {
+        let properties = PublishProperties::new(
+            0,
+            0,
+            TopicProperties {
+                topic_alias: 0,
+                response_topic: String::new(),
+            },
+            vec![],
+            String::new(),
+            0,
+            String::new(),
+        );
+
+        let mut buffer = Vec::new();
+        properties.write_properties(&mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x00]);
+
+        let read_properties = PublishProperties::read_from(&mut buffer.as_slice()).unwrap();
+        assert_eq!(properties, read_properties);
+    }
+
+    #[test]
+    fn test_03_payload_properties_are_only_filled_when_unset() {
+        let properties = |payload_format_indicator: u8, content_type: &str| {
+            PublishProperties::new(
+                payload_format_indicator,
+                0,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                content_type.to_string(),
+            )
+        };
+        let json = PayloadTypes::Json(serde_json::json!({"drone": 3}));
+        let binary = PayloadTypes::Binary(vec![0x01]);
+
+        assert_eq!(
+            properties(0, "").for_payload(&json),
+            properties(1, JSON_CONTENT_TYPE)
+        );
+        assert_eq!(
+            properties(0, "").for_payload(&binary),
+            properties(0, BINARY_CONTENT_TYPE)
+        );
+
+        // El content type y el payload format indicator de quien publica no se pisan.
+        assert_eq!(
+            properties(0, "application/geo+json").for_payload(&json),
+            properties(0, "application/geo+json")
+        );
+        assert_eq!(
+            properties(1, "").for_payload(&binary),
+            properties(1, BINARY_CONTENT_TYPE)
+        );
+    }
 }
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use crate::mqtt::protocol_error::ProtocolError;
+
+/// Tabla de topic aliases de una conexion, en un unico sentido(del cliente al broker o del broker al cliente).
+///
+/// Un topic alias permite enviar un Publish con el topic name vacio: el receptor lo reemplaza por el topic
+/// asociado a ese alias en la conexion. El alias 0 indica que no se usa alias, y no puede superarse el
+/// maximo negociado en el Connect/Connack.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TopicAliases {
+    maximum: u16,
+    topics: HashMap<u16, String>,
+}
+
+impl TopicAliases {
+    pub fn new(maximum: u16) -> TopicAliases {
+        TopicAliases {
+            maximum,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// Del lado del receptor, obtiene el topic name de un Publish recibido.
+    ///
+    /// Si el Publish trae un topic name y un alias, se guarda la asociacion. Si trae el topic name vacio,
+    /// se usa el topic asociado a su alias.
+    pub fn resolve(&mut self, topic_name: &str, topic_alias: u16) -> Result<String, ProtocolError> {
+        if topic_alias == 0 {
+            if topic_name.is_empty() {
+                return Err(ProtocolError::TopicAliasInvalid);
+            }
+            return Ok(topic_name.to_string());
+        }
+
+        if topic_alias > self.maximum {
+            return Err(ProtocolError::TopicAliasInvalid);
+        }
+
+        if topic_name.is_empty() {
+            return self
+                .topics
+                .get(&topic_alias)
+                .cloned()
+                .ok_or(ProtocolError::TopicAliasInvalid);
+        }
+
+        self.topics.insert(topic_alias, topic_name.to_string());
+        Ok(topic_name.to_string())
+    }
+
+    /// Del lado del emisor, devuelve el topic name y el alias con los que debe enviarse un Publish.
+    ///
+    /// Si el alias ya esta asociado al topic, el topic name se envia vacio. Si el alias supera el maximo
+    /// permitido por el receptor, el Publish se envia sin alias.
+    pub fn apply(&mut self, topic_name: &str, topic_alias: u16) -> (String, u16) {
+        if topic_alias == 0 || topic_alias > self.maximum {
+            return (topic_name.to_string(), 0);
+        }
+
+        if self.topics.get(&topic_alias).map(String::as_str) == Some(topic_name) {
+            return (String::new(), topic_alias);
+        }
+
+        self.topics.insert(topic_alias, topic_name.to_string());
+        (topic_name.to_string(), topic_alias)
+    }
+
+    /// Del lado del emisor, elige el alias de un topic: el que ya tenga asignado, o uno nuevo si
+    /// todavia no se alcanzo el maximo. Devuelve el topic name y el alias con los que debe enviarse el Publish.
+    pub fn assign(&mut self, topic_name: &str) -> (String, u16) {
+        let topic_alias = self
+            .topics
+            .iter()
+            .find(|(_, topic)| *topic == topic_name)
+            .map(|(alias, _)| *alias)
+            .unwrap_or(self.topics.len() as u16 + 1);
+
+        self.apply(topic_name, topic_alias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_01_resolving_received_aliases() {
+        let mut topic_aliases = TopicAliases::new(10);
+
+        assert_eq!(
+            topic_aliases.resolve("drone_locations", 1),
+            Ok("drone_locations".to_string())
+        );
+        assert_eq!(
+            topic_aliases.resolve("", 1),
+            Ok("drone_locations".to_string())
+        );
+        assert_eq!(
+            topic_aliases.resolve("", 2),
+            Err(ProtocolError::TopicAliasInvalid)
+        );
+        assert_eq!(
+            topic_aliases.resolve("incident", 11),
+            Err(ProtocolError::TopicAliasInvalid)
+        );
+        assert_eq!(
+            topic_aliases.resolve("", 0),
+            Err(ProtocolError::TopicAliasInvalid)
+        );
+    }
+
+    #[test]
+    fn test_02_applying_aliases_to_sent_publishes() {
+        let mut topic_aliases = TopicAliases::new(10);
+
+        assert_eq!(
+            topic_aliases.apply("drone_locations", 10),
+            ("drone_locations".to_string(), 10)
+        );
+        assert_eq!(
+            topic_aliases.apply("drone_locations", 10),
+            (String::new(), 10)
+        );
+        assert_eq!(
+            topic_aliases.apply("incident", 10),
+            ("incident".to_string(), 10)
+        );
+        assert_eq!(
+            topic_aliases.apply("incident", 11),
+            ("incident".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_03_assigning_aliases_within_the_maximum() {
+        let mut topic_aliases = TopicAliases::new(1);
+
+        assert_eq!(
+            topic_aliases.assign("drone_locations"),
+            ("drone_locations".to_string(), 1)
+        );
+        assert_eq!(topic_aliases.assign("drone_locations"), (String::new(), 1));
+        assert_eq!(
+            topic_aliases.assign("incident"),
+            ("incident".to_string(), 0)
+        );
+        assert_eq!(
+            TopicAliases::new(0).assign("incident"),
+            ("incident".to_string(), 0)
+        );
+    }
+}
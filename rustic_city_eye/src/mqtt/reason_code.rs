@@ -2,26 +2,45 @@ use std::io::Error;
 
 ///  reason codes in HEX
 pub const SUCCESS_HEX: u8 = 0x00;
+pub const DISCONNECT_WITH_WILL_HEX: u8 = 0x04;
 pub const NO_MATCHING_SUBSCRIBERS_HEX: u8 = 0x10;
+pub const CONTINUE_AUTHENTICATION_HEX: u8 = 0x18;
 pub const UNSPECIFIED_ERROR_HEX: u8 = 0x80;
+pub const MALFORMED_PACKET_HEX: u8 = 0x81;
 pub const IMPLEMENTATION_SPECIFIC_ERROR_HEX: u8 = 0x83;
+pub const BAD_USERNAME_OR_PASSWORD_HEX: u8 = 0x86;
 pub const NOT_AUTHORIZED_HEX: u8 = 0x87;
+pub const BAD_AUTHENTICATION_METHOD_HEX: u8 = 0x8C;
+pub const TOPIC_FILTER_INVALID_HEX: u8 = 0x8F;
 pub const TOPIC_NAME_INVALID_HEX: u8 = 0x90;
 pub const PACKET_ID_IN_USE_HEX: u8 = 0x91;
+pub const PACKET_ID_NOT_FOUND_HEX: u8 = 0x92;
+pub const RECEIVE_MAXIMUM_EXCEEDED_HEX: u8 = 0x93;
+pub const TOPIC_ALIAS_INVALID_HEX: u8 = 0x94;
+pub const PACKET_TOO_LARGE_HEX: u8 = 0x95;
 pub const QUOTA_EXCEEDED_HEX: u8 = 0x97;
 pub const PAYLOAD_FORMAT_INVALID_HEX: u8 = 0x99;
 pub const SUB_ID_DUP_HEX: u8 = 0x85;
-pub const NO_MATCHING_USER_HEX: u8 = 0x11;
+pub const NO_SUBSCRIPTION_EXISTED_HEX: u8 = 0x11;
 
 #[derive(Debug, PartialEq)]
 pub enum ReasonCode {
     Success { reason_code: u8 },
+    DisconnectWithWill { reason_code: u8 },
     NoMatchingSubscribers { reason_code: u8 },
+    ContinueAuthentication { reason_code: u8 },
     UnspecifiedError { reason_code: u8 },
     ImplementationSpecificError { reason_code: u8 },
+    BadUsernameOrPassword { reason_code: u8 },
     NotAuthorized { reason_code: u8 },
+    BadAuthenticationMethod { reason_code: u8 },
+    TopicFilterInvalid { reason_code: u8 },
     TopicNameInvalid { reason_code: u8 },
     PacketIdentifierInUse { reason_code: u8 },
+    PacketIdentifierNotFound { reason_code: u8 },
+    ReceiveMaximumExceeded { reason_code: u8 },
+    TopicAliasInvalid { reason_code: u8 },
+    PacketTooLarge { reason_code: u8 },
     QuotaExceeded { reason_code: u8 },
     PayloadFormatInvalid { reason_code: u8 },
     SubIdDup { reason_code: u8 },
@@ -31,14 +50,25 @@ impl ReasonCode {
     pub fn new(reason_code: u8) -> Result<ReasonCode, Error> {
         match reason_code {
             SUCCESS_HEX => Ok(ReasonCode::Success { reason_code }),
+            DISCONNECT_WITH_WILL_HEX => Ok(ReasonCode::DisconnectWithWill { reason_code }),
             NO_MATCHING_SUBSCRIBERS_HEX => Ok(ReasonCode::NoMatchingSubscribers { reason_code }),
+            CONTINUE_AUTHENTICATION_HEX => Ok(ReasonCode::ContinueAuthentication { reason_code }),
             UNSPECIFIED_ERROR_HEX => Ok(ReasonCode::UnspecifiedError { reason_code }),
             IMPLEMENTATION_SPECIFIC_ERROR_HEX => {
                 Ok(ReasonCode::ImplementationSpecificError { reason_code })
             }
+            BAD_USERNAME_OR_PASSWORD_HEX => Ok(ReasonCode::BadUsernameOrPassword { reason_code }),
             NOT_AUTHORIZED_HEX => Ok(ReasonCode::NotAuthorized { reason_code }),
+            BAD_AUTHENTICATION_METHOD_HEX => {
+                Ok(ReasonCode::BadAuthenticationMethod { reason_code })
+            }
+            TOPIC_FILTER_INVALID_HEX => Ok(ReasonCode::TopicFilterInvalid { reason_code }),
             TOPIC_NAME_INVALID_HEX => Ok(ReasonCode::TopicNameInvalid { reason_code }),
             PACKET_ID_IN_USE_HEX => Ok(ReasonCode::PacketIdentifierInUse { reason_code }),
+            PACKET_ID_NOT_FOUND_HEX => Ok(ReasonCode::PacketIdentifierNotFound { reason_code }),
+            RECEIVE_MAXIMUM_EXCEEDED_HEX => Ok(ReasonCode::ReceiveMaximumExceeded { reason_code }),
+            TOPIC_ALIAS_INVALID_HEX => Ok(ReasonCode::TopicAliasInvalid { reason_code }),
+            PACKET_TOO_LARGE_HEX => Ok(ReasonCode::PacketTooLarge { reason_code }),
             QUOTA_EXCEEDED_HEX => Ok(ReasonCode::QuotaExceeded { reason_code }),
             PAYLOAD_FORMAT_INVALID_HEX => Ok(ReasonCode::PayloadFormatInvalid { reason_code }),
             SUB_ID_DUP_HEX => Ok(ReasonCode::SubIdDup { reason_code }),
@@ -72,6 +102,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_new_reason_code_disconnect_with_will() {
+        let reason_code = ReasonCode::new(DISCONNECT_WITH_WILL_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::DisconnectWithWill { reason_code: 0x04 }
+        );
+    }
+
     #[test]
     fn test_new_reason_code_no_matching_subscribers() {
         let reason_code = ReasonCode::new(NO_MATCHING_SUBSCRIBERS_HEX);
@@ -81,6 +120,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_new_reason_code_continue_authentication() {
+        let reason_code = ReasonCode::new(CONTINUE_AUTHENTICATION_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::ContinueAuthentication { reason_code: 0x18 }
+        );
+    }
+
     #[test]
     fn test_new_reason_code_unspecified_error() {
         let reason_code = ReasonCode::new(UNSPECIFIED_ERROR_HEX);
@@ -99,6 +147,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_new_reason_code_bad_username_or_password() {
+        let reason_code = ReasonCode::new(BAD_USERNAME_OR_PASSWORD_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::BadUsernameOrPassword { reason_code: 0x86 }
+        );
+    }
+
     #[test]
     fn test_new_reason_code_not_authorized() {
         let reason_code = ReasonCode::new(NOT_AUTHORIZED_HEX);
@@ -108,6 +165,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_new_reason_code_bad_authentication_method() {
+        let reason_code = ReasonCode::new(BAD_AUTHENTICATION_METHOD_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::BadAuthenticationMethod { reason_code: 0x8C }
+        );
+    }
+
+    #[test]
+    fn test_new_reason_code_topic_filter_invalid() {
+        let reason_code = ReasonCode::new(TOPIC_FILTER_INVALID_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::TopicFilterInvalid { reason_code: 0x8F }
+        );
+    }
+
     #[test]
     fn test_new_reason_code_topic_name_invalid() {
         let reason_code = ReasonCode::new(TOPIC_NAME_INVALID_HEX);
@@ -126,6 +201,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_new_reason_code_packet_identifier_not_found() {
+        let reason_code = ReasonCode::new(PACKET_ID_NOT_FOUND_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::PacketIdentifierNotFound { reason_code: 0x92 }
+        );
+    }
+
+    #[test]
+    fn test_new_reason_code_receive_maximum_exceeded() {
+        let reason_code = ReasonCode::new(RECEIVE_MAXIMUM_EXCEEDED_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::ReceiveMaximumExceeded { reason_code: 0x93 }
+        );
+    }
+
+    #[test]
+    fn test_new_reason_code_topic_alias_invalid() {
+        let reason_code = ReasonCode::new(TOPIC_ALIAS_INVALID_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::TopicAliasInvalid { reason_code: 0x94 }
+        );
+    }
+
+    #[test]
+    fn test_new_reason_code_packet_too_large() {
+        let reason_code = ReasonCode::new(PACKET_TOO_LARGE_HEX);
+        assert_eq!(
+            reason_code.unwrap(),
+            ReasonCode::PacketTooLarge { reason_code: 0x95 }
+        );
+    }
+
     #[test]
     fn test_new_reason_code_quota_exceeded() {
         let reason_code = ReasonCode::new(QUOTA_EXCEEDED_HEX);
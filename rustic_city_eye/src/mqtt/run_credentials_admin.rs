@@ -0,0 +1,52 @@
+use std::{env::args, io::stdin};
+
+use rustic_city_eye::mqtt::{credentials::CredentialStore, protocol_error::ProtocolError};
+
+const USAGE: &str = "Uso: credentials_admin <archivo de clientes> <add <client_id> <username> | remove <client_id> | rotate <client_id>>";
+
+/// Administra el archivo de credenciales del Broker.
+///
+/// Los passwords de add y rotate se leen de la entrada estandar, para que no queden en el
+/// historial de la shell; en el archivo solo se guarda su hash.
+fn main() -> Result<(), ProtocolError> {
+    let argv = args().collect::<Vec<String>>();
+    if argv.len() < 4 {
+        return Err(ProtocolError::InvalidCommand(USAGE.to_string()));
+    }
+
+    let file_path = &argv[1];
+    let mut store = match CredentialStore::read_from_file(file_path) {
+        Ok(store) => store,
+        Err(_) if argv[2] == "add" && !std::path::Path::new(file_path).exists() => {
+            CredentialStore::default()
+        }
+        Err(e) => return Err(e),
+    };
+
+    match (argv[2].as_str(), argv.len()) {
+        ("add", 5) => store.add_user(&argv[3], &argv[4], &read_password()?)?,
+        ("remove", 4) => store.remove_user(&argv[3])?,
+        ("rotate", 4) => store.rotate_password(&argv[3], &read_password()?)?,
+        _ => return Err(ProtocolError::InvalidCommand(USAGE.to_string())),
+    }
+
+    store.write_to_file(file_path)?;
+    println!("Archivo de clientes {} actualizado", file_path);
+    Ok(())
+}
+
+fn read_password() -> Result<Vec<u8>, ProtocolError> {
+    println!("Ingrese el password:");
+    let mut password = String::new();
+    stdin()
+        .read_line(&mut password)
+        .map_err(|_| ProtocolError::InvalidCommand("No se pudo leer el password".to_string()))?;
+
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(ProtocolError::InvalidCommand(
+            "El password no puede estar vacio".to_string(),
+        ));
+    }
+    Ok(password.as_bytes().to_vec())
+}
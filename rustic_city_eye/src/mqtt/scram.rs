@@ -0,0 +1,383 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use super::{
+    authenticator::{AuthStep, Authenticator},
+    credentials::{constant_time_eq, derive_scram_keys, hmac_sha256, pbkdf2_sha256, Credential},
+    reason_code::{BAD_USERNAME_OR_PASSWORD_HEX, NOT_AUTHORIZED_HEX},
+};
+
+/// Nombre del metodo de autenticacion extendida.
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+/// Header GS2 sin channel binding, y su codificacion en base64 en el client-final-message.
+const GS2_HEADER: &str = "n,,";
+const GS2_HEADER_BASE64: &str = "biws";
+
+/// Minimo de iteraciones que acepta el Client, para que un Broker falso no pueda degradar el hash (RFC 7677).
+const MINIMUM_ITERATIONS: u32 = 4096;
+
+const NONCE_LEN: usize = 18;
+
+fn generate_nonce() -> String {
+    let mut nonce = vec![0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    STANDARD.encode(nonce)
+}
+
+/// En los usernames, '=' y ',' se escapan como '=3D' y '=2C'.
+fn encode_username(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn decode_username(username: &str) -> String {
+    username.replace("=2C", ",").replace("=3D", "=")
+}
+
+/// Devuelve el valor del atributo `<name>=<valor>` de un mensaje SCRAM.
+fn attribute(message: &str, name: char) -> Option<&str> {
+    message
+        .split(',')
+        .find_map(|part| part.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+#[derive(Debug)]
+enum ScramClientState {
+    Initial,
+    WaitingServerFirst {
+        client_first_bare: String,
+        nonce: String,
+    },
+    WaitingServerFinal {
+        server_signature: Vec<u8>,
+    },
+    Finished,
+}
+
+/// Lado del Client de SCRAM-SHA-256 (RFC 5802 y RFC 7677).
+///
+/// El password no viaja por la red: el Client prueba que lo conoce firmando los mensajes del intercambio,
+/// y a su vez verifica la firma del Broker para asegurarse de que este tambien conoce la credencial.
+pub struct ScramClient {
+    username: String,
+    password: Vec<u8>,
+    state: ScramClientState,
+}
+
+impl ScramClient {
+    pub fn new(username: String, password: Vec<u8>) -> ScramClient {
+        ScramClient {
+            username,
+            password,
+            state: ScramClientState::Initial,
+        }
+    }
+
+    /// Con el server-first-message, arma el client-final-message con la prueba del password.
+    fn client_final(
+        &mut self,
+        client_first_bare: &str,
+        client_nonce: &str,
+        server_first: &str,
+    ) -> AuthStep {
+        let (nonce, salt, iterations) = match (
+            attribute(server_first, 'r'),
+            attribute(server_first, 's').and_then(|salt| STANDARD.decode(salt).ok()),
+            attribute(server_first, 'i').and_then(|i| i.parse::<u32>().ok()),
+        ) {
+            (Some(nonce), Some(salt), Some(iterations)) => (nonce, salt, iterations),
+            _ => return AuthStep::Failure(NOT_AUTHORIZED_HEX),
+        };
+        if !nonce.starts_with(client_nonce)
+            || nonce.len() == client_nonce.len()
+            || iterations < MINIMUM_ITERATIONS
+        {
+            return AuthStep::Failure(NOT_AUTHORIZED_HEX);
+        }
+
+        let salted_password = pbkdf2_sha256(&self.password, &salt, iterations);
+        let (client_key, stored_key, server_key) = derive_scram_keys(&salted_password);
+
+        let client_final_without_proof = format!("c={},r={}", GS2_HEADER_BASE64, nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            client_first_bare, server_first, client_final_without_proof
+        );
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let proof = xor(&client_key, &client_signature);
+
+        self.state = ScramClientState::WaitingServerFinal {
+            server_signature: hmac_sha256(&server_key, auth_message.as_bytes()),
+        };
+        AuthStep::Continue(
+            format!(
+                "{},p={}",
+                client_final_without_proof,
+                STANDARD.encode(proof)
+            )
+            .into_bytes(),
+        )
+    }
+}
+
+impl Authenticator for ScramClient {
+    fn method(&self) -> String {
+        SCRAM_SHA_256.to_string()
+    }
+
+    fn initial_data(&mut self) -> Vec<u8> {
+        let nonce = generate_nonce();
+        let client_first_bare = format!("n={},r={}", encode_username(&self.username), nonce);
+        let client_first = format!("{}{}", GS2_HEADER, client_first_bare);
+
+        self.state = ScramClientState::WaitingServerFirst {
+            client_first_bare,
+            nonce,
+        };
+        client_first.into_bytes()
+    }
+
+    fn step(&mut self, data: &[u8]) -> AuthStep {
+        let message = String::from_utf8_lossy(data).to_string();
+
+        match std::mem::replace(&mut self.state, ScramClientState::Finished) {
+            ScramClientState::WaitingServerFirst {
+                client_first_bare,
+                nonce,
+            } => self.client_final(&client_first_bare, &nonce, &message),
+            ScramClientState::WaitingServerFinal { server_signature } => {
+                match attribute(&message, 'v').and_then(|v| STANDARD.decode(v).ok()) {
+                    Some(signature) if constant_time_eq(&signature, &server_signature) => {
+                        AuthStep::Success(Vec::new())
+                    }
+                    _ => AuthStep::Failure(NOT_AUTHORIZED_HEX),
+                }
+            }
+            ScramClientState::Initial | ScramClientState::Finished => {
+                AuthStep::Failure(NOT_AUTHORIZED_HEX)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ScramServerState {
+    WaitingClientFirst,
+    WaitingClientFinal {
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+    },
+    Finished,
+}
+
+/// Lado del Broker de SCRAM-SHA-256.
+///
+/// Trabaja con el StoredKey y el ServerKey del archivo de clientes, por lo que el Broker nunca necesita conocer
+/// el password ni el SaltedPassword: con el StoredKey puede verificar la prueba del cliente, pero no generarla.
+pub struct ScramServer {
+    credential: Option<Credential>,
+    state: ScramServerState,
+}
+
+impl ScramServer {
+    /// Recibe la credencial del client_id que envio el Connect, o None si no tiene una.
+    pub fn new(credential: Option<Credential>) -> ScramServer {
+        ScramServer {
+            credential,
+            state: ScramServerState::WaitingClientFirst,
+        }
+    }
+
+    /// Con el client-first-message, arma el server-first-message con el nonce, la salt y las iteraciones.
+    fn server_first(&mut self, client_first: &str) -> AuthStep {
+        let client_first_bare = match client_first.strip_prefix(GS2_HEADER) {
+            Some(bare) => bare,
+            None => return AuthStep::Failure(NOT_AUTHORIZED_HEX),
+        };
+        let (username, client_nonce) = match (
+            attribute(client_first_bare, 'n'),
+            attribute(client_first_bare, 'r'),
+        ) {
+            (Some(username), Some(nonce)) if !nonce.is_empty() => {
+                (decode_username(username), nonce)
+            }
+            _ => return AuthStep::Failure(NOT_AUTHORIZED_HEX),
+        };
+        let credential = match &self.credential {
+            Some(credential) if credential.get_username() == username => credential,
+            _ => return AuthStep::Failure(BAD_USERNAME_OR_PASSWORD_HEX),
+        };
+
+        let nonce = format!("{}{}", client_nonce, generate_nonce());
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            STANDARD.encode(credential.get_salt()),
+            credential.get_iterations()
+        );
+
+        self.state = ScramServerState::WaitingClientFinal {
+            client_first_bare: client_first_bare.to_string(),
+            server_first: server_first.clone(),
+            nonce,
+        };
+        AuthStep::Continue(server_first.into_bytes())
+    }
+
+    /// Verifica la prueba del client-final-message y, si es correcta, devuelve la firma del Broker.
+    fn server_final(
+        &self,
+        client_first_bare: &str,
+        server_first: &str,
+        nonce: &str,
+        client_final: &str,
+    ) -> AuthStep {
+        let credential = match &self.credential {
+            Some(credential) => credential,
+            None => return AuthStep::Failure(BAD_USERNAME_OR_PASSWORD_HEX),
+        };
+        let (client_final_without_proof, proof) = match client_final.rsplit_once(",p=") {
+            Some((without_proof, proof)) => match STANDARD.decode(proof) {
+                Ok(proof) => (without_proof, proof),
+                Err(_) => return AuthStep::Failure(NOT_AUTHORIZED_HEX),
+            },
+            None => return AuthStep::Failure(NOT_AUTHORIZED_HEX),
+        };
+        if attribute(client_final_without_proof, 'c') != Some(GS2_HEADER_BASE64)
+            || attribute(client_final_without_proof, 'r') != Some(nonce)
+        {
+            return AuthStep::Failure(NOT_AUTHORIZED_HEX);
+        }
+
+        let stored_key = credential.get_stored_key();
+        let auth_message = format!(
+            "{},{},{}",
+            client_first_bare, server_first, client_final_without_proof
+        );
+        let client_signature = hmac_sha256(stored_key, auth_message.as_bytes());
+        let client_key = xor(&proof, &client_signature);
+
+        if proof.len() != client_signature.len()
+            || !constant_time_eq(&Sha256::digest(&client_key), stored_key)
+        {
+            return AuthStep::Failure(BAD_USERNAME_OR_PASSWORD_HEX);
+        }
+
+        let server_signature = hmac_sha256(credential.get_server_key(), auth_message.as_bytes());
+        AuthStep::Success(format!("v={}", STANDARD.encode(server_signature)).into_bytes())
+    }
+}
+
+impl Authenticator for ScramServer {
+    fn method(&self) -> String {
+        SCRAM_SHA_256.to_string()
+    }
+
+    fn step(&mut self, data: &[u8]) -> AuthStep {
+        let message = String::from_utf8_lossy(data).to_string();
+
+        match std::mem::replace(&mut self.state, ScramServerState::Finished) {
+            ScramServerState::WaitingClientFirst => self.server_first(&message),
+            ScramServerState::WaitingClientFinal {
+                client_first_bare,
+                server_first,
+                nonce,
+            } => self.server_final(&client_first_bare, &server_first, &nonce, &message),
+            ScramServerState::Finished => AuthStep::Failure(NOT_AUTHORIZED_HEX),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lleva a cabo el intercambio completo, y devuelve el resultado del lado del Client.
+    fn exchange(client: &mut ScramClient, server: &mut ScramServer) -> AuthStep {
+        let server_first = match server.step(&client.initial_data()) {
+            AuthStep::Continue(data) => data,
+            other => return other,
+        };
+        let client_final = match client.step(&server_first) {
+            AuthStep::Continue(data) => data,
+            other => return other,
+        };
+        match server.step(&client_final) {
+            AuthStep::Success(server_final) => client.step(&server_final),
+            other => other,
+        }
+    }
+
+    #[test]
+    fn test_01_successful_exchange() {
+        let credential = Credential::new("monitoreo".to_string(), b"un password");
+        let mut client = ScramClient::new("monitoreo".to_string(), b"un password".to_vec());
+        let mut server = ScramServer::new(Some(credential));
+
+        let client_first = String::from_utf8(client.initial_data()).unwrap();
+        assert!(client_first.starts_with("n,,n=monitoreo,r="));
+        assert!(!client_first.contains("un password"));
+
+        let mut client = ScramClient::new("monitoreo".to_string(), b"un password".to_vec());
+        assert_eq!(
+            exchange(&mut client, &mut server),
+            AuthStep::Success(vec![])
+        );
+    }
+
+    #[test]
+    fn test_02_wrong_password_or_username_fails() {
+        let credential = Credential::new("monitoreo".to_string(), b"un password");
+
+        let mut client = ScramClient::new("monitoreo".to_string(), b"otro password".to_vec());
+        let mut server = ScramServer::new(Some(credential.clone()));
+        assert_eq!(
+            exchange(&mut client, &mut server),
+            AuthStep::Failure(BAD_USERNAME_OR_PASSWORD_HEX)
+        );
+
+        let mut client = ScramClient::new("otro".to_string(), b"un password".to_vec());
+        let mut server = ScramServer::new(Some(credential));
+        assert_eq!(
+            exchange(&mut client, &mut server),
+            AuthStep::Failure(BAD_USERNAME_OR_PASSWORD_HEX)
+        );
+
+        let mut client = ScramClient::new("monitoreo".to_string(), b"un password".to_vec());
+        let mut server = ScramServer::new(None);
+        assert_eq!(
+            exchange(&mut client, &mut server),
+            AuthStep::Failure(BAD_USERNAME_OR_PASSWORD_HEX)
+        );
+    }
+
+    #[test]
+    fn test_03_client_rejects_a_forged_server_signature() {
+        let mut client = ScramClient::new("monitoreo".to_string(), b"un password".to_vec());
+        let client_first = String::from_utf8(client.initial_data()).unwrap();
+        let nonce = attribute(&client_first, 'r').unwrap().to_string();
+
+        let server_first = format!("r={}abc,s={},i=4096", nonce, STANDARD.encode(b"salt"));
+        assert!(matches!(
+            client.step(server_first.as_bytes()),
+            AuthStep::Continue(_)
+        ));
+        assert_eq!(
+            client.step(format!("v={}", STANDARD.encode([0; 32])).as_bytes()),
+            AuthStep::Failure(NOT_AUTHORIZED_HEX)
+        );
+
+        let mut client = ScramClient::new("monitoreo".to_string(), b"un password".to_vec());
+        client.initial_data();
+        assert_eq!(
+            client.step(b"r=otro_nonce,s=c2FsdA==,i=4096"),
+            AuthStep::Failure(NOT_AUTHORIZED_HEX)
+        );
+    }
+}
@@ -3,20 +3,28 @@ use serde::{Deserialize, Serialize};
 use crate::mqtt::subscribe_properties::SubscribeProperties;
 
 use super::{
-    client_message::ClientMessage, messages_config::MessagesConfig, subscription::Subscription,
+    client_message::ClientMessage,
+    messages_config::MessagesConfig,
+    subscription::{Subscription, SubscriptionOptions},
 };
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SubscribeConfig {
-    pub(crate) topic_name: String,
+    /// Topic filters a los que se subscribe el cliente en un mismo Subscribe, junto a las opciones de cada uno.
+    pub(crate) topic_filters: Vec<(String, SubscriptionOptions)>,
     pub(crate) properties: SubscribeProperties,
     pub(crate) client_id: String,
 }
 
 impl MessagesConfig for SubscribeConfig {
     fn parse_message(&self, packet_id: u16) -> ClientMessage {
-        let payload = Subscription::new(self.topic_name.clone(), self.client_id.clone());
-        //creo un vector cno la subscription
+        let payload = self
+            .topic_filters
+            .iter()
+            .map(|(topic_name, options)| {
+                Subscription::new(topic_name.clone(), self.client_id.clone()).with_options(*options)
+            })
+            .collect();
 
         ClientMessage::Subscribe {
             packet_id,
@@ -27,18 +35,36 @@ impl MessagesConfig for SubscribeConfig {
 }
 
 impl SubscribeConfig {
+    /// Crea la configuracion de un Subscribe a todos los topic filters recibidos, con las opciones por defecto.
     pub fn new(
-        topic_name: String,
+        topic_filters: Vec<String>,
         properties: SubscribeProperties,
         client_id: String,
     ) -> SubscribeConfig {
         SubscribeConfig {
-            topic_name,
+            topic_filters: topic_filters
+                .into_iter()
+                .map(|topic_name| (topic_name, SubscriptionOptions::default()))
+                .collect(),
             properties,
             client_id,
         }
     }
 
+    /// Setea las opciones de la subscripcion a un topic filter(No Local, Retain As Published y Retain Handling).
+    pub fn with_options(
+        mut self,
+        topic_name: &str,
+        options: SubscriptionOptions,
+    ) -> SubscribeConfig {
+        for (topic_filter, topic_options) in self.topic_filters.iter_mut() {
+            if topic_filter == topic_name {
+                *topic_options = options;
+            }
+        }
+        self
+    }
+
     pub fn json_to_publish_config(path: &str) -> SubscribeConfig {
         let config: SubscribeConfig = match serde_json::from_str(path) {
             Ok(config) => config,
@@ -46,7 +72,7 @@ impl SubscribeConfig {
         };
 
         SubscribeConfig {
-            topic_name: config.topic_name,
+            topic_filters: config.topic_filters,
             properties: config.properties,
             client_id: config.client_id,
         }
@@ -72,24 +98,37 @@ mod tests {
         let topic_name = "topic".to_string();
         let properties =
             SubscribeProperties::new(1, vec![("key".to_string(), "value".to_string())]);
-        let subscribe_config =
-            SubscribeConfig::new(topic_name.clone(), properties.clone(), "client".to_string());
-        assert_eq!(subscribe_config.topic_name, topic_name);
+        let subscribe_config = SubscribeConfig::new(
+            vec![topic_name.clone()],
+            properties.clone(),
+            "client".to_string(),
+        );
+        assert_eq!(
+            subscribe_config.topic_filters,
+            vec![(topic_name, SubscriptionOptions::default())]
+        );
         assert_eq!(subscribe_config.properties, properties);
     }
 
     #[test]
     fn test_parse_message() {
-        let topic_name = "topic".to_string();
         let properties =
             SubscribeProperties::new(1, vec![("key".to_string(), "value".to_string())]);
+        let no_local = SubscriptionOptions::new(true, false, 0);
 
-        let subscribe_config =
-            SubscribeConfig::new(topic_name.clone(), properties.clone(), "client".to_string());
+        let subscribe_config = SubscribeConfig::new(
+            vec!["topic".to_string(), "incident".to_string()],
+            properties.clone(),
+            "client".to_string(),
+        )
+        .with_options("incident", no_local);
         let packet_id = 1;
         let message = subscribe_config.parse_message(packet_id);
 
-        let payload_1 = Subscription::new(topic_name.clone(), "client".to_string());
+        let expected_payload = vec![
+            Subscription::new("topic".to_string(), "client".to_string()),
+            Subscription::new("incident".to_string(), "client".to_string()).with_options(no_local),
+        ];
 
         match message {
             ClientMessage::Subscribe {
@@ -99,7 +138,7 @@ mod tests {
             } => {
                 assert_eq!(message_packet_id, packet_id);
                 assert_eq!(message_properties, properties);
-                assert_eq!(payload_1, payload);
+                assert_eq!(payload, expected_payload);
             }
             _ => panic!("Wrong message type"),
         }
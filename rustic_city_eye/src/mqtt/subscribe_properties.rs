@@ -1,34 +1,71 @@
 use serde::{Deserialize, Serialize};
 
 use crate::utils::{reader::*, writer::*};
-use std::io::{Error, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
+
+const SUBSCRIPTION_IDENTIFIER_ID: u8 = 0x0B;
+const USER_PROPERTY_ID: u8 = 0x26;
 
 use super::protocol_error::ProtocolError;
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 
 pub struct SubscribeProperties {
-    pub sub_id: u8,
+    /// Subscription identifier. Se guarda en la subscripcion y se envia en cada Publish que esta entregue.
+    /// 0 indica que el Subscribe no lo incluye.
+    pub sub_id: u32,
     user_properties: Vec<(String, String)>,
 }
 
 impl SubscribeProperties {
-    pub fn new(sub_id: u8, user_properties: Vec<(String, String)>) -> SubscribeProperties {
+    pub fn new(sub_id: u32, user_properties: Vec<(String, String)>) -> SubscribeProperties {
         SubscribeProperties {
             sub_id,
             user_properties,
         }
     }
 
+    /// Escribe las properties de un Subscribe: el subscription identifier(si no es 0) y las user properties.
     pub fn write_properties(&self, stream: &mut dyn Write) -> Result<(), ProtocolError> {
-        write_u8(stream, &self.sub_id)?;
-        write_string_pairs(stream, &self.user_properties)?;
-        Ok(())
+        let mut properties = Vec::new();
+        if self.sub_id != 0 {
+            write_u8(&mut properties, &SUBSCRIPTION_IDENTIFIER_ID)?;
+            write_variable_byte_integer(&mut properties, &self.sub_id)?;
+        }
+        write_user_properties(&mut properties, &self.user_properties)?;
+
+        write_properties_block(stream, &properties)
+    }
+
+    /// Un Unsubscribe no lleva subscription identifier, solo user properties.
+    pub fn write_unsubscribe_properties(
+        &self,
+        stream: &mut dyn Write,
+    ) -> Result<(), ProtocolError> {
+        let mut properties = Vec::new();
+        write_user_properties(&mut properties, &self.user_properties)?;
+
+        write_properties_block(stream, &properties)
     }
 
     pub fn read_properties(stream: &mut dyn Read) -> Result<SubscribeProperties, Error> {
-        let sub_id = read_u8(stream)?;
-        let user_properties = read_string_pairs(stream)?;
+        let block = read_properties_block(stream)?;
+        let mut properties = block.as_slice();
+
+        let mut sub_id = 0;
+        let mut user_properties = Vec::new();
+        while !properties.is_empty() {
+            match read_u8(&mut properties)? {
+                SUBSCRIPTION_IDENTIFIER_ID => sub_id = read_variable_byte_integer(&mut properties)?,
+                USER_PROPERTY_ID => {
+                    let key = read_string(&mut properties)?;
+                    let value = read_string(&mut properties)?;
+                    user_properties.push((key, value));
+                }
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Property ID inválido")),
+            }
+        }
+
         Ok(SubscribeProperties::new(sub_id, user_properties))
     }
 }
@@ -57,4 +94,20 @@ mod tests {
         let read_subscribe_properties = SubscribeProperties::read_properties(&mut cursor).unwrap();
         assert_eq!(read_subscribe_properties, subscribe_properties);
     }
+
+    #[test]
+    fn test_unsubscribe_properties_omit_sub_id() {
+        let user_properties = vec![("key".to_string(), "value".to_string())];
+        let subscribe_properties = SubscribeProperties::new(1, user_properties.clone());
+        let mut buffer = Vec::new();
+        subscribe_properties
+            .write_unsubscribe_properties(&mut buffer)
+            .unwrap();
+        let read_subscribe_properties =
+            SubscribeProperties::read_properties(&mut buffer.as_slice()).unwrap();
+        assert_eq!(
+            read_subscribe_properties,
+            SubscribeProperties::new(0, user_properties)
+        );
+    }
 }
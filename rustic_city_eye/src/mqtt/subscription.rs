@@ -1,5 +1,91 @@
 use serde::{Deserialize, Serialize};
 
+use super::protocol_error::ProtocolError;
+
+/// QoS maxima que se le puede otorgar a una subscripcion.
+pub const MAXIMUM_QOS: u8 = 2;
+
+fn default_maximum_qos() -> u8 {
+    MAXIMUM_QOS
+}
+
+/// Opciones de una subscripcion, enviadas en el byte que sigue a cada topic filter del Subscribe.
+#[derive(Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub struct SubscriptionOptions {
+    /// QoS maxima con la que el cliente quiere recibir los mensajes de la subscripcion. Los Publish
+    /// se le entregan con la menor entre esta y la QoS con la que fueron publicados.
+    #[serde(default = "default_maximum_qos")]
+    pub maximum_qos: u8,
+    /// Si esta seteado, el cliente no recibe los mensajes que el mismo publica.
+    pub no_local: bool,
+    /// Si esta seteado, los mensajes se reenvian con el retain_flag con el que fueron publicados.
+    /// Si no, se envian con el retain_flag en 0.
+    pub retain_as_published: bool,
+    /// Indica cuando se envian los retained messages al subscribirse:
+    /// 0 siempre, 1 solo si la subscripcion no existia, 2 nunca.
+    pub retain_handling: u8,
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> SubscriptionOptions {
+        SubscriptionOptions::new(false, false, 0)
+    }
+}
+
+impl SubscriptionOptions {
+    /// Crea las opciones con la QoS maxima en MAXIMUM_QOS.
+    pub fn new(
+        no_local: bool,
+        retain_as_published: bool,
+        retain_handling: u8,
+    ) -> SubscriptionOptions {
+        SubscriptionOptions {
+            maximum_qos: MAXIMUM_QOS,
+            no_local,
+            retain_as_published,
+            retain_handling,
+        }
+    }
+
+    pub fn with_maximum_qos(mut self, maximum_qos: u8) -> SubscriptionOptions {
+        self.maximum_qos = maximum_qos;
+        self
+    }
+
+    /// Codifica las opciones en un byte: bits 0-1 QoS maxima, bit 2 No Local, bit 3 Retain As Published
+    /// y bits 4-5 Retain Handling.
+    pub fn to_byte(&self) -> u8 {
+        let mut byte = ((self.retain_handling & 0b11) << 4) | (self.maximum_qos & 0b11);
+        if self.retain_as_published {
+            byte |= 0b0000_1000;
+        }
+        if self.no_local {
+            byte |= 0b0000_0100;
+        }
+        byte
+    }
+
+    /// Decodifica el byte de opciones de una subscripcion.
+    ///
+    /// Una QoS maxima de 3, un Retain Handling de 3 o los bits reservados seteados son un error.
+    pub fn from_byte(byte: u8) -> Result<SubscriptionOptions, ProtocolError> {
+        let maximum_qos = byte & 0b11;
+        let retain_handling = (byte >> 4) & 0b11;
+        if maximum_qos > MAXIMUM_QOS || retain_handling == 3 || byte & 0b1100_0000 != 0 {
+            return Err(ProtocolError::InvalidCommand(
+                "Opciones de subscripcion invalidas".to_string(),
+            ));
+        }
+
+        Ok(SubscriptionOptions {
+            maximum_qos,
+            no_local: byte & 0b0000_0100 != 0,
+            retain_as_published: byte & 0b0000_1000 != 0,
+            retain_handling,
+        })
+    }
+}
+
 /// Subscription struct que se usa para almacenar la suscripción de un cliente a un topic
 #[derive(Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Subscription {
@@ -7,12 +93,34 @@ pub struct Subscription {
     pub topic: String,
     /// Id del cliente que se suscribe
     pub client_id: String,
+    /// Opciones de la subscripcion
+    #[serde(default)]
+    pub options: SubscriptionOptions,
+    /// Subscription identifier recibido en el Subscribe, que se envia en los Publish entregados
+    /// por esta subscripcion. 0 indica que no tiene.
+    #[serde(default)]
+    pub subscription_identifier: u32,
 }
 
 /// Implementación de Subscription
 impl Subscription {
     pub fn new(topic: String, client_id: String) -> Subscription {
-        Subscription { topic, client_id }
+        Subscription {
+            topic,
+            client_id,
+            options: SubscriptionOptions::default(),
+            subscription_identifier: 0,
+        }
+    }
+
+    pub fn with_options(mut self, options: SubscriptionOptions) -> Subscription {
+        self.options = options;
+        self
+    }
+
+    /// Indica si ambas subscripciones son del mismo cliente al mismo topic filter, sin importar sus opciones.
+    pub fn same_subscription(&self, other: &Subscription) -> bool {
+        self.topic == other.topic && self.client_id == other.client_id
     }
 }
 
@@ -27,4 +135,25 @@ mod tests {
         let subscription = Subscription::new(topic.clone(), client_id.clone());
         assert_eq!(subscription.topic, topic);
     }
+
+    #[test]
+    fn test_subscription_options_byte() -> Result<(), ProtocolError> {
+        let options = SubscriptionOptions::new(true, true, 2);
+        assert_eq!(options.to_byte(), 0b0010_1110);
+        assert_eq!(SubscriptionOptions::from_byte(options.to_byte())?, options);
+
+        let options = options.with_maximum_qos(1);
+        assert_eq!(options.to_byte(), 0b0010_1101);
+        assert_eq!(SubscriptionOptions::from_byte(options.to_byte())?, options);
+
+        assert_eq!(
+            SubscriptionOptions::from_byte(0b0000_0010)?,
+            SubscriptionOptions::default()
+        );
+        assert_eq!(SubscriptionOptions::from_byte(0)?.maximum_qos, 0);
+        assert!(SubscriptionOptions::from_byte(0b0000_0011).is_err());
+        assert!(SubscriptionOptions::from_byte(0b0011_0000).is_err());
+        assert!(SubscriptionOptions::from_byte(0b0100_0000).is_err());
+        Ok(())
+    }
 }
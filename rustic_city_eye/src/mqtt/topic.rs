@@ -1,15 +1,24 @@
 use std::fmt::Debug;
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
+use super::client_message::ClientMessage;
 use super::subscription::Subscription;
 
 use super::reason_code;
 
+/// Prefijo de los topic filters de las shared subscriptions.
+const SHARED_SUBSCRIPTION_PREFIX: &str = "$share/";
+
 /// Estructura que representa un topic.
 #[derive(Debug, Clone)]
 pub struct Topic {
     /// Hashmap de subscriptores.
     users: Arc<RwLock<Vec<Subscription>>>,
+
+    /// Ultimo publish con retain_flag recibido en el topic, junto al momento en que se recibio. Se le envia a
+    /// cada cliente que se subscriba al topic, mientras no expire.
+    retained_message: Arc<RwLock<Option<(ClientMessage, Instant)>>>,
     // vector de subtopics
     // subtopic: Vec<Topic>,
 }
@@ -25,40 +34,35 @@ impl Topic {
     pub fn new() -> Self {
         Self {
             users: Arc::new(RwLock::new(Vec::new())),
+            retained_message: Arc::new(RwLock::new(None)),
         }
     }
 
     /// Agrega un usuario a un topic
     /// Si el usuario no existe en el tópic, lo agrega
-    /// Si el usuario ya existe en el tópic, no lo agrega pero la operacion es exitosa de todas maneras ya que el objetivo era concretar la subscription
+    /// Si el usuario ya existe en el tópic, se reemplaza su subscripcion, ya que puede traer nuevas opciones
+    /// o un nuevo subscription identifier
     /// Retorna el reason code de la operación
     pub fn add_user_to_topic(&mut self, subscription: Subscription) -> u8 {
         println!("Adding user to topic: {:?}", subscription);
-        //verificar si el user_id ya existe en topic users
-        let mut existe = false;
-        match self.users.read() {
-            Ok(guard) => {
-                if guard.contains(&subscription) {
-                    existe = true;
-                }
-            }
-            Err(_) => return reason_code::UNSPECIFIED_ERROR_HEX,
-        }
-
         let mut lock = match self.users.write() {
             Ok(guard) => guard,
             Err(_) => return reason_code::UNSPECIFIED_ERROR_HEX,
         };
 
-        if !existe {
-            lock.push(subscription);
+        match lock
+            .iter_mut()
+            .find(|user| user.same_subscription(&subscription))
+        {
+            Some(user) => *user = subscription,
+            None => lock.push(subscription),
         }
 
         reason_code::SUCCESS_HEX
     }
 
     /// Elimina un usuario de un topic
-    /// Si el usuario no existe en el topic, no hace nada
+    /// Si el usuario no existe en el topic, no hace nada y retorna NO_SUBSCRIPTION_EXISTED_HEX
     /// Retorna el reason code de la operación
     pub fn remove_user_from_topic(&mut self, subscription: Subscription) -> u8 {
         let mut lock = match self.users.write() {
@@ -66,13 +70,26 @@ impl Topic {
             Err(_) => return reason_code::UNSPECIFIED_ERROR_HEX,
         };
 
-        if lock.contains(&subscription) {
-            lock.retain(|x| x != &subscription);
+        let previous_len = lock.len();
+        lock.retain(|user| !user.same_subscription(&subscription));
+
+        if lock.len() == previous_len {
+            return reason_code::NO_SUBSCRIPTION_EXISTED_HEX;
         }
 
         reason_code::SUCCESS_HEX
     }
 
+    /// Indica si el cliente ya tiene la subscripcion en el topic, sin importar sus opciones.
+    pub fn has_subscription(&self, subscription: &Subscription) -> bool {
+        match self.users.read() {
+            Ok(guard) => guard
+                .iter()
+                .any(|user| user.same_subscription(subscription)),
+            Err(_) => false,
+        }
+    }
+
     pub fn get_users_from_topic(&self) -> Vec<Subscription> {
         let lock = match self.users.read() {
             Ok(guard) => guard,
@@ -86,12 +103,135 @@ impl Topic {
 
         users
     }
+
+    /// Guarda el retained message del topic, reemplazando al anterior.
+    /// Si se recibe None, se elimina el retained message.
+    pub fn set_retained_message(&self, message: Option<ClientMessage>) -> u8 {
+        let mut lock = match self.retained_message.write() {
+            Ok(guard) => guard,
+            Err(_) => return reason_code::UNSPECIFIED_ERROR_HEX,
+        };
+
+        *lock = message.map(|message| (message, Instant::now()));
+
+        reason_code::SUCCESS_HEX
+    }
+
+    /// Devuelve el retained message del topic, en caso de que haya uno y no haya expirado.
+    /// Su message expiry interval se devuelve descontando el tiempo que estuvo retenido.
+    ///
+    /// Si el retained message expiro, se lo elimina.
+    pub fn get_retained_message(&self) -> Option<ClientMessage> {
+        self.get_retained_message_at(Instant::now())
+    }
+
+    /// Igual que get_retained_message, tomando now como el momento actual.
+    fn get_retained_message_at(&self, now: Instant) -> Option<ClientMessage> {
+        let mut lock = self.retained_message.write().ok()?;
+        let (message, retained_at) = lock.clone()?;
+
+        let message =
+            message.with_remaining_expiry(now.saturating_duration_since(retained_at).as_secs());
+        if message.is_none() {
+            *lock = None;
+        }
+        message
+    }
+
+    /// Separa una shared subscription('$share/<group>/<filter>') en su share name y su topic filter.
+    ///
+    /// Devuelve None si no es una shared subscription valida: el share name no puede ser vacio ni
+    /// contener wildcards, y el filtro debe ser valido.
+    pub fn parse_shared_filter(filter: &str) -> Option<(&str, &str)> {
+        let (share_name, topic_filter) = filter
+            .strip_prefix(SHARED_SUBSCRIPTION_PREFIX)?
+            .split_once('/')?;
+
+        if share_name.is_empty()
+            || Topic::is_wildcard_filter(share_name)
+            || !Topic::is_valid_filter(topic_filter)
+        {
+            return None;
+        }
+
+        Some((share_name, topic_filter))
+    }
+
+    /// Indica si el topic filter corresponde a una shared subscription, sea valida o no.
+    pub fn is_shared_filter(filter: &str) -> bool {
+        filter.starts_with(SHARED_SUBSCRIPTION_PREFIX)
+    }
+
+    /// Indica si el topic filter contiene wildcards ('+' o '#').
+    pub fn is_wildcard_filter(filter: &str) -> bool {
+        filter.contains('+') || filter.contains('#')
+    }
+
+    /// Valida un topic filter segun las reglas de MQTT:
+    /// - '#' debe ocupar un nivel completo y ser el ultimo caracter del filtro.
+    /// - '+' debe ocupar un nivel completo.
+    /// - El filtro no puede ser vacio.
+    pub fn is_valid_filter(filter: &str) -> bool {
+        if filter.is_empty() {
+            return false;
+        }
+
+        let levels: Vec<&str> = filter.split('/').collect();
+        let last_level_index = levels.len() - 1;
+
+        for (index, level) in levels.iter().enumerate() {
+            if level.contains('#') && (*level != "#" || index != last_level_index) {
+                return false;
+            }
+            if level.contains('+') && *level != "+" {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Verifica si un topic name coincide con un topic filter.
+    ///
+    /// '+' coincide con exactamente un nivel, y '#' coincide con el nivel padre y
+    /// con cualquier cantidad de niveles hijos.
+    ///
+    /// Los topics que empiezan con '$' no coinciden con filtros que empiezan con un wildcard.
+    pub fn filter_matches(filter: &str, topic_name: &str) -> bool {
+        if topic_name.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+            return false;
+        }
+
+        let mut filter_levels = filter.split('/');
+        let mut topic_levels = topic_name.split('/');
+
+        loop {
+            match (filter_levels.next(), topic_levels.next()) {
+                (Some("#"), _) => return true,
+                (Some("+"), Some(_)) => {}
+                (Some(filter_level), Some(topic_level)) => {
+                    if filter_level != topic_level {
+                        return false;
+                    }
+                }
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::{
+        mqtt::{
+            publish::publish_properties::{PublishProperties, TopicProperties},
+            subscription::SubscriptionOptions,
+        },
+        utils::payload_types::PayloadTypes,
+    };
 
     #[test]
     fn test_new_topic() {
@@ -119,6 +259,26 @@ mod tests {
         assert_eq!(result, 0x00);
     }
 
+    #[test]
+    fn test_resubscribing_replaces_the_options() {
+        let mut topic = Topic::new();
+        let subscription = Subscription::new("topic".to_string(), "user".to_string());
+        topic.add_user_to_topic(subscription.clone());
+        topic.add_user_to_topic(
+            subscription
+                .clone()
+                .with_options(SubscriptionOptions::new(true, false, 0)),
+        );
+
+        let users = topic.get_users_from_topic();
+        assert_eq!(users.len(), 1);
+        assert!(users[0].options.no_local);
+        assert!(topic.has_subscription(&subscription));
+
+        topic.remove_user_from_topic(subscription.clone());
+        assert!(!topic.has_subscription(&subscription));
+    }
+
     #[test]
     fn test_remove_subscriber() {
         let mut topic = Topic::new();
@@ -137,7 +297,122 @@ mod tests {
         let subscription = Subscription::new("topic".to_string(), user_id);
         let result = topic.add_user_to_topic(subscription.clone());
         assert_eq!(result, 0x00);
+        let other = Subscription::new("topic".to_string(), "other".to_string());
+        let result = topic.remove_user_from_topic(other);
+        assert_eq!(result, 0x11);
+        let result = topic.remove_user_from_topic(subscription.clone());
+        assert_eq!(result, 0x00);
         let result = topic.remove_user_from_topic(subscription);
+        assert_eq!(result, 0x11);
+    }
+
+    #[test]
+    fn test_retained_message() {
+        let topic = Topic::new();
+        assert!(topic.get_retained_message().is_none());
+
+        let result = topic.set_retained_message(Some(ClientMessage::Pingreq));
         assert_eq!(result, 0x00);
+        assert_eq!(topic.get_retained_message(), Some(ClientMessage::Pingreq));
+
+        let result = topic.set_retained_message(None);
+        assert_eq!(result, 0x00);
+        assert!(topic.get_retained_message().is_none());
+    }
+
+    #[test]
+    fn test_retained_message_expires() {
+        let topic = Topic::new();
+        let publish = ClientMessage::Publish {
+            packet_id: 1,
+            topic_name: "drone_locations".to_string(),
+            qos: 0,
+            retain_flag: 1,
+            payload: PayloadTypes::WillPayload("location".to_string()),
+            dup_flag: 0,
+            properties: PublishProperties::new(
+                1,
+                1,
+                TopicProperties {
+                    topic_alias: 0,
+                    response_topic: String::new(),
+                },
+                vec![],
+                String::new(),
+                0,
+                String::new(),
+            ),
+        };
+
+        topic.set_retained_message(Some(publish.clone()));
+        assert_eq!(topic.get_retained_message(), Some(publish));
+
+        let expired_at = Instant::now() + std::time::Duration::from_secs(1);
+        assert!(topic.get_retained_message_at(expired_at).is_none());
+        assert!(topic.get_retained_message().is_none());
+    }
+
+    #[test]
+    fn test_valid_filters() {
+        assert!(Topic::is_valid_filter("incident"));
+        assert!(Topic::is_valid_filter("drones/+/location"));
+        assert!(Topic::is_valid_filter("incident/#"));
+        assert!(Topic::is_valid_filter("#"));
+        assert!(Topic::is_valid_filter("+/+"));
+
+        assert!(!Topic::is_valid_filter(""));
+        assert!(!Topic::is_valid_filter("incident#"));
+        assert!(!Topic::is_valid_filter("incident/#/resolved"));
+        assert!(!Topic::is_valid_filter("drones/a+/location"));
+    }
+
+    #[test]
+    fn test_single_level_wildcard_matches() {
+        assert!(Topic::filter_matches(
+            "drones/+/location",
+            "drones/7/location"
+        ));
+        assert!(!Topic::filter_matches(
+            "drones/+/location",
+            "drones/7/battery"
+        ));
+        assert!(!Topic::filter_matches(
+            "drones/+/location",
+            "drones/7/location/extra"
+        ));
+        assert!(!Topic::filter_matches("drones/+", "drones"));
+    }
+
+    #[test]
+    fn test_multi_level_wildcard_matches() {
+        assert!(Topic::filter_matches("incident/#", "incident"));
+        assert!(Topic::filter_matches("incident/#", "incident/resolved"));
+        assert!(Topic::filter_matches("incident/#", "incident/a/b/c"));
+        assert!(Topic::filter_matches("#", "camera_update"));
+        assert!(!Topic::filter_matches("incident/#", "incidents"));
+    }
+
+    #[test]
+    fn test_wildcards_do_not_match_dollar_topics() {
+        assert!(!Topic::filter_matches("#", "$SYS/uptime"));
+        assert!(!Topic::filter_matches("+/uptime", "$SYS/uptime"));
+        assert!(Topic::filter_matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn test_shared_filters() {
+        assert_eq!(
+            Topic::parse_shared_filter("$share/workers/incident"),
+            Some(("workers", "incident"))
+        );
+        assert_eq!(
+            Topic::parse_shared_filter("$share/workers/drones/+/location"),
+            Some(("workers", "drones/+/location"))
+        );
+        assert_eq!(Topic::parse_shared_filter("$share//incident"), None);
+        assert_eq!(Topic::parse_shared_filter("$share/workers"), None);
+        assert_eq!(Topic::parse_shared_filter("$share/work+/incident"), None);
+        assert_eq!(Topic::parse_shared_filter("incident"), None);
+        assert!(Topic::is_shared_filter("$share/workers"));
     }
 }
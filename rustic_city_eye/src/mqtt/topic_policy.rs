@@ -0,0 +1,123 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use super::{protocol_error::ProtocolError, topic::Topic};
+
+/// Politica que usa el Broker para decidir si un topic puede crearse (o usarse)
+/// cuando un cliente publica o se subscribe a el por primera vez.
+///
+/// Las listas contienen topic filters, por lo que admiten wildcards('+' y '#').
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum TopicPolicy {
+    /// Cualquier topic puede crearse.
+    #[default]
+    AllowAll,
+
+    /// Solo pueden crearse los topics que coincidan con algun filtro de la lista.
+    AllowList(Vec<String>),
+
+    /// Pueden crearse todos los topics, excepto los que coincidan con algun filtro de la lista.
+    DenyList(Vec<String>),
+}
+
+impl TopicPolicy {
+    /// Indica si la politica permite usar el topic name (o topic filter) recibido.
+    pub fn is_allowed(&self, topic_name: &str) -> bool {
+        match self {
+            TopicPolicy::AllowAll => true,
+            TopicPolicy::AllowList(filters) => filters
+                .iter()
+                .any(|filter| Topic::filter_matches(filter, topic_name)),
+            TopicPolicy::DenyList(filters) => !filters
+                .iter()
+                .any(|filter| Topic::filter_matches(filter, topic_name)),
+        }
+    }
+
+    /// Lee una politica de topics desde un archivo.
+    ///
+    /// La primera linea indica el tipo de politica("allow" o "deny"), y cada una de las
+    /// lineas siguientes contiene un topic filter. Las lineas vacias se ignoran.
+    pub fn read_policy_file(file_path: &str) -> Result<TopicPolicy, ProtocolError> {
+        let file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => return Err(ProtocolError::ReadingTopicConfigFileError),
+        };
+
+        let mut lines = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match line {
+                Ok(line) => {
+                    let line = line.trim().to_string();
+                    if !line.is_empty() {
+                        lines.push(line);
+                    }
+                }
+                Err(_) => return Err(ProtocolError::ReadingTopicConfigFileError),
+            }
+        }
+
+        if lines.is_empty() {
+            return Err(ProtocolError::ReadingTopicConfigFileError);
+        }
+
+        let policy_type = lines.remove(0);
+        for filter in &lines {
+            if !Topic::is_valid_filter(filter) {
+                return Err(ProtocolError::ReadingTopicConfigFileError);
+            }
+        }
+
+        match policy_type.to_lowercase().as_str() {
+            "allow" => Ok(TopicPolicy::AllowList(lines)),
+            "deny" => Ok(TopicPolicy::DenyList(lines)),
+            _ => Err(ProtocolError::ReadingTopicConfigFileError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_01_allow_all_policy() {
+        let policy = TopicPolicy::default();
+
+        assert!(policy.is_allowed("incident"));
+        assert!(policy.is_allowed("drones/7/location"));
+    }
+
+    #[test]
+    fn test_02_allow_list_policy() {
+        let policy = TopicPolicy::AllowList(vec!["incident".to_string(), "drones/#".to_string()]);
+
+        assert!(policy.is_allowed("incident"));
+        assert!(policy.is_allowed("drones/7/location"));
+        assert!(!policy.is_allowed("camera_update"));
+    }
+
+    #[test]
+    fn test_03_deny_list_policy() {
+        let policy = TopicPolicy::DenyList(vec!["single_camera_disconnect".to_string()]);
+
+        assert!(policy.is_allowed("incident"));
+        assert!(!policy.is_allowed("single_camera_disconnect"));
+    }
+
+    #[test]
+    fn test_04_reading_policy_file() -> Result<(), ProtocolError> {
+        let policy = TopicPolicy::read_policy_file("./src/monitoring/topics.txt")?;
+
+        assert!(policy.is_allowed("incident"));
+        assert!(policy.is_allowed("drone_locations"));
+        assert!(policy.is_allowed("responses/monitoring_app"));
+        assert!(!policy.is_allowed("topic_inventado"));
+
+        assert!(TopicPolicy::read_policy_file("./este/archivo/no/existe").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+use super::protocol_error::ProtocolError;
+
+/// Subprotocolo que deben pedir los clientes MQTT sobre WebSocket.
+pub const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+/// Valor fijo que se concatena al Sec-WebSocket-Key para calcular el Sec-WebSocket-Accept(RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largo maximo del request HTTP con el que el cliente abre la conexion.
+const MAXIMUM_HANDSHAKE_SIZE: usize = 8 * 1024;
+
+/// Tiempo total que se espera al request HTTP del cliente antes de descartar la conexion.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largo maximo del payload de un frame. Un packet MQTT puede ocupar varios frames.
+const MAXIMUM_FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Opcodes de los frames(RFC 6455, seccion 5.2).
+const CONTINUATION_OPCODE: u8 = 0x0;
+const TEXT_OPCODE: u8 = 0x1;
+const BINARY_OPCODE: u8 = 0x2;
+const CLOSE_OPCODE: u8 = 0x8;
+const PING_OPCODE: u8 = 0x9;
+const PONG_OPCODE: u8 = 0xA;
+
+/// Bits del primer byte del header: FIN, los reservados para extensiones(RSV1-3) y el opcode.
+const FIN_BIT: u8 = 0x80;
+const RSV_BITS: u8 = 0x70;
+const OPCODE_BITS: u8 = 0x0F;
+
+/// Los opcodes de los frames de control tienen este bit en 1.
+const CONTROL_OPCODE_BIT: u8 = 0x08;
+
+/// Largo maximo del payload de un frame de control(RFC 6455, seccion 5.5).
+const MAXIMUM_CONTROL_FRAME_SIZE: u64 = 125;
+
+/// Status code del Close que se envia cuando el cliente no respeta el protocolo.
+const PROTOCOL_ERROR_STATUS: u16 = 1002;
+
+/// Conexion MQTT sobre WebSocket, del lado del Broker.
+///
+/// Los packets MQTT viajan como el contenido de frames binarios: al leer se juntan los payloads
+/// de los frames que manda el cliente, y cada escritura se envia en un frame.
+///
+/// Al igual que un TcpStream, se puede leer y escribir a traves de una referencia compartida: el thread de
+/// lectura y el de escritura del Broker usan la misma conexion.
+pub struct WebSocketStream {
+    stream: TcpStream,
+
+    /// Bytes de frames ya leidos que todavia no se consumieron, y si el ultimo frame de datos no tenia el FIN
+    /// (es decir, si el proximo frame de datos debe ser un continuation).
+    pending: Mutex<(VecDeque<u8>, bool)>,
+
+    /// Evita que se mezclen frames escritos desde distintos threads(por ejemplo, un Pong y un Publish).
+    write_lock: Mutex<()>,
+}
+
+impl WebSocketStream {
+    /// Lleva a cabo el handshake de apertura: lee el request HTTP del cliente y, si pide el upgrade a WebSocket
+    /// con el subprotocolo "mqtt", le responde con un 101.
+    ///
+    /// Si el request no es valido, se le responde con un 400 y se devuelve error.
+    pub fn accept(stream: TcpStream) -> Result<WebSocketStream, ProtocolError> {
+        let handshake_error = |e: Error| ProtocolError::WebSocketHandshakeError(e.to_string());
+        let request = WebSocketStream::read_handshake_request(&stream)?;
+
+        let response = match WebSocketStream::handshake_response(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                let _ = (&stream).write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(e);
+            }
+        };
+        (&stream)
+            .write_all(response.as_bytes())
+            .map_err(handshake_error)?;
+        stream.set_read_timeout(None).map_err(handshake_error)?;
+
+        Ok(WebSocketStream {
+            stream,
+            pending: Mutex::new((VecDeque::new(), false)),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// El TcpStream sobre el que viajan los frames.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Lee el request HTTP byte a byte hasta la linea vacia que lo termina, para no consumir ningun frame.
+    ///
+    /// Si el request completo no llega dentro de HANDSHAKE_TIMEOUT, se descarta la conexion.
+    fn read_handshake_request(stream: &TcpStream) -> Result<String, ProtocolError> {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+
+        while !request.ends_with(b"\r\n\r\n") {
+            if request.len() >= MAXIMUM_HANDSHAKE_SIZE {
+                return Err(ProtocolError::WebSocketHandshakeError(
+                    "Request demasiado largo".to_string(),
+                ));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ProtocolError::WebSocketHandshakeError(
+                    "Timeout durante el handshake".to_string(),
+                ));
+            }
+            stream
+                .set_read_timeout(Some(remaining))
+                .map_err(|e| ProtocolError::WebSocketHandshakeError(e.to_string()))?;
+
+            match (&*stream).read(&mut byte) {
+                Ok(0) => {
+                    return Err(ProtocolError::WebSocketHandshakeError(
+                        "Conexion cerrada durante el handshake".to_string(),
+                    ))
+                }
+                Ok(_) => request.push(byte[0]),
+                Err(e) => return Err(ProtocolError::WebSocketHandshakeError(e.to_string())),
+            }
+        }
+
+        String::from_utf8(request)
+            .map_err(|_| ProtocolError::WebSocketHandshakeError("Request invalido".to_string()))
+    }
+
+    /// Arma la respuesta 101 a un request de upgrade. El cliente debe pedir el subprotocolo "mqtt".
+    fn handshake_response(request: &str) -> Result<String, ProtocolError> {
+        let invalid = |reason: &str| ProtocolError::WebSocketHandshakeError(reason.to_string());
+
+        let mut lines = request.split("\r\n");
+        match lines.next() {
+            Some(request_line) if request_line.starts_with("GET ") => {}
+            _ => return Err(invalid("Se esperaba un GET")),
+        }
+
+        let mut upgrade = false;
+        let mut key = None;
+        let mut mqtt_subprotocol = false;
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+
+            match name.as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.to_string()),
+                "sec-websocket-protocol" => {
+                    mqtt_subprotocol |= value
+                        .split(',')
+                        .any(|protocol| protocol.trim() == MQTT_SUBPROTOCOL)
+                }
+                _ => {}
+            }
+        }
+
+        if !upgrade {
+            return Err(invalid("Falta el upgrade a websocket"));
+        }
+        if !mqtt_subprotocol {
+            return Err(invalid("El cliente no pidio el subprotocolo mqtt"));
+        }
+        let key = key.ok_or_else(|| invalid("Falta el Sec-WebSocket-Key"))?;
+
+        Ok(format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\
+             Sec-WebSocket-Protocol: {}\r\n\r\n",
+            accept_key(&key),
+            MQTT_SUBPROTOCOL
+        ))
+    }
+
+    /// Lee frames hasta encontrar uno con datos, y devuelve su payload ya desenmascarado.
+    ///
+    /// Un packet puede venir fragmentado: un frame binario sin el FIN seguido de frames continuation, entre los
+    /// que pueden aparecer frames de control. Los Ping se responden con un Pong. Si el cliente cierra la conexion,
+    /// se le responde el Close y se devuelve None.
+    ///
+    /// Si el cliente no respeta el protocolo(RFC 6455, secciones 5.2 y 5.5), se le envia un Close y se devuelve error.
+    fn read_frame(&self, continuing: &mut bool) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let mut header = [0u8; 2];
+            (&self.stream).read_exact(&mut header)?;
+
+            let fin = header[0] & FIN_BIT != 0;
+            let opcode = header[0] & OPCODE_BITS;
+            let masked = header[1] & 0x80 != 0;
+            let length = match header[1] & 0x7F {
+                126 => {
+                    let mut length = [0u8; 2];
+                    (&self.stream).read_exact(&mut length)?;
+                    u16::from_be_bytes(length) as u64
+                }
+                127 => {
+                    let mut length = [0u8; 8];
+                    (&self.stream).read_exact(&mut length)?;
+                    u64::from_be_bytes(length)
+                }
+                length => length as u64,
+            };
+
+            // No se negocia ninguna extension, asi que los bits reservados deben estar en 0.
+            if header[0] & RSV_BITS != 0 {
+                return Err(self.protocol_error("Bits reservados seteados"));
+            }
+            // Los frames que envia el cliente siempre van enmascarados.
+            if !masked {
+                return Err(self.protocol_error("Frame sin mascara"));
+            }
+            if opcode & CONTROL_OPCODE_BIT != 0 {
+                if !fin {
+                    return Err(self.protocol_error("Frame de control fragmentado"));
+                }
+                if length > MAXIMUM_CONTROL_FRAME_SIZE {
+                    return Err(self.protocol_error("Frame de control demasiado largo"));
+                }
+            }
+            if length > MAXIMUM_FRAME_SIZE {
+                return Err(self.protocol_error("Frame demasiado largo"));
+            }
+
+            let mut mask = [0u8; 4];
+            (&self.stream).read_exact(&mut mask)?;
+            let mut payload = vec![0u8; length as usize];
+            (&self.stream).read_exact(&mut payload)?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            match opcode {
+                BINARY_OPCODE if *continuing => {
+                    return Err(self.protocol_error("Se esperaba un frame continuation"))
+                }
+                CONTINUATION_OPCODE if !*continuing => {
+                    return Err(self.protocol_error("Frame continuation sin mensaje"))
+                }
+                BINARY_OPCODE | CONTINUATION_OPCODE => {
+                    *continuing = !fin;
+                    return Ok(Some(payload));
+                }
+                CLOSE_OPCODE => {
+                    let _ = self.write_frame(CLOSE_OPCODE, &[]);
+                    return Ok(None);
+                }
+                PING_OPCODE => self.write_frame(PONG_OPCODE, &payload)?,
+                PONG_OPCODE => {}
+                TEXT_OPCODE => {
+                    return Err(
+                        self.protocol_error("Los packets MQTT deben viajar en frames binarios")
+                    )
+                }
+                _ => return Err(self.protocol_error("Opcode invalido")),
+            }
+        }
+    }
+
+    /// Cierra la conexion con el status code de error de protocolo, y devuelve el error a informar.
+    fn protocol_error(&self, reason: &str) -> Error {
+        let _ = self.write_frame(CLOSE_OPCODE, &PROTOCOL_ERROR_STATUS.to_be_bytes());
+        Error::new(ErrorKind::InvalidData, reason)
+    }
+
+    /// Escribe un frame completo(con el bit FIN seteado). Los frames del servidor no se enmascaran.
+    fn write_frame(&self, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        let _lock = self
+            .write_lock
+            .lock()
+            .map_err(|_| Error::other("Error en Lock"))?;
+        (&self.stream).write_all(&frame)
+    }
+}
+
+impl Read for &WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| Error::other("Error en Lock"))?;
+
+        let (pending, continuing) = &mut *pending;
+        while pending.is_empty() {
+            match self.read_frame(continuing)? {
+                Some(payload) => pending.extend(payload),
+                None => return Ok(0),
+            }
+        }
+
+        let read = buf.len().min(pending.len());
+        for (byte, pending_byte) in buf.iter_mut().zip(pending.drain(..read)) {
+            *byte = pending_byte;
+        }
+        Ok(read)
+    }
+}
+
+impl Write for &WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.write_frame(BINARY_OPCODE, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Calcula el Sec-WebSocket-Accept que corresponde al Sec-WebSocket-Key del cliente.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// Frame enmascarado, como los que envia un cliente.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        client_frame_with_header(FIN_BIT | opcode, payload)
+    }
+
+    fn client_frame_with_header(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first_byte, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn connect_pair(request: &'static str) -> (TcpStream, Result<WebSocketStream, ProtocolError>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            client
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let server = WebSocketStream::accept(stream);
+        (client.join().unwrap(), server)
+    }
+
+    #[test]
+    fn test_01_accept_key() {
+        // Ejemplo de la RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_02_handshake_and_frames() {
+        let (mut client, server) = connect_pair(
+            "GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Protocol: mqttv3.1, mqtt\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+        );
+        let server = server.unwrap();
+
+        let mut response = vec![0u8; 256];
+        let read = client.read(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response[..read]).to_string();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(response.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+
+        // Un packet partido en dos frames, con un Ping en el medio.
+        client
+            .write_all(&client_frame_with_header(BINARY_OPCODE, &[0xC0]))
+            .unwrap();
+        client
+            .write_all(&client_frame(PING_OPCODE, b"hola"))
+            .unwrap();
+        client
+            .write_all(&client_frame(CONTINUATION_OPCODE, &[0x00]))
+            .unwrap();
+
+        let mut packet = [0u8; 2];
+        (&server).read_exact(&mut packet).unwrap();
+        assert_eq!(packet, [0xC0, 0x00]);
+
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x8A, 0x04, b'h', b'o', b'l', b'a']);
+
+        (&server).write_all(&[0xD0, 0x00]).unwrap();
+        let mut frame = [0u8; 4];
+        client.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, [0x82, 0x02, 0xD0, 0x00]);
+
+        // Al cerrar el cliente, la lectura termina.
+        client.write_all(&client_frame(CLOSE_OPCODE, &[])).unwrap();
+        let mut rest = [0u8; 1];
+        assert_eq!((&server).read(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_03_handshake_without_mqtt_subprotocol_is_rejected() {
+        let (mut client, server) = connect_pair(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        );
+        assert!(server.is_err());
+
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response);
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+
+    /// Abre una conexion, le envia un frame invalido y verifica que se la cierre con un error de protocolo.
+    fn assert_rejected(frame: Vec<u8>) {
+        let (mut client, server) = connect_pair(
+            "GET /mqtt HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n",
+        );
+        let server = server.unwrap();
+        let mut response = vec![0u8; 256];
+        let _ = client.read(&mut response).unwrap();
+
+        client.write_all(&frame).unwrap();
+        // Los datos de frames validos previos al invalido se pueden leer igual.
+        let mut buf = [0u8; 1];
+        while let Ok(read) = (&server).read(&mut buf) {
+            assert_ne!(read, 0);
+        }
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).unwrap();
+        assert_eq!(close, [0x88, 0x02, 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn test_04_invalid_frames_are_rejected() {
+        // Bits reservados seteados.
+        assert_rejected(client_frame_with_header(
+            FIN_BIT | 0x40 | BINARY_OPCODE,
+            &[0xC0, 0x00],
+        ));
+        // Ping fragmentado.
+        assert_rejected(client_frame_with_header(PING_OPCODE, b"hola"));
+        // Ping con mas de 125 bytes.
+        let mut frame = vec![FIN_BIT | PING_OPCODE, 0x80 | 126, 0x00, 126];
+        frame.extend_from_slice(&[0u8; 4]);
+        frame.extend_from_slice(&[0u8; 126]);
+        assert_rejected(frame);
+        // Continuation sin un mensaje en curso.
+        assert_rejected(client_frame(CONTINUATION_OPCODE, &[0x00]));
+        // Frame binario nuevo en medio de un mensaje fragmentado.
+        let mut frame = client_frame_with_header(BINARY_OPCODE, &[0xC0]);
+        frame.extend(client_frame(BINARY_OPCODE, &[0x00]));
+        assert_rejected(frame);
+    }
+}
@@ -85,22 +85,11 @@ impl<T: ClientTrait + Clone + Send + Sync + 'static> CameraSystem<T> {
         let camera_system_client = client_factory(rx, address, connect_config, tx2)?;
         let client_id = camera_system_client.get_client_id();
         let subscribe_config = SubscribeConfig::new(
-            "incident".to_string(),
-            SubscribeProperties::new(1, vec![]),
-            client_id.clone(),
-        );
-
-        let _ = tx.send(Box::new(subscribe_config));
-        let subscribe_config = SubscribeConfig::new(
-            "incident_resolved".to_string(),
-            SubscribeProperties::new(1, vec![]),
-            client_id.clone(),
-        );
-
-        let _ = tx.send(Box::new(subscribe_config));
-
-        let subscribe_config = SubscribeConfig::new(
-            "single_camera_disconnect".to_string(),
+            vec![
+                "incident".to_string(),
+                "incident_resolved".to_string(),
+                "single_camera_disconnect".to_string(),
+            ],
             SubscribeProperties::new(1, vec![]),
             client_id,
         );
@@ -239,7 +228,7 @@ impl<T: ClientTrait + Clone + Send + Sync + 'static> CameraSystem<T> {
     pub fn disconnect(&self) -> Result<(), ProtocolError> {
         let disconnect_config = DisconnectConfig::new(
             0x00_u8,
-            1,
+            Some(1),
             "normal".to_string(),
             self.camera_system_client.get_client_id(),
         );
@@ -1488,24 +1477,14 @@ mod tests {
                     payload,
                     properties: _,
                 } => {
-                    assert_eq!(payload.topic, "incident");
-                }
-                _ => {
-                    panic!("Unexpected message type");
-                }
-            }
-            let message = reciever.recv().unwrap();
-            //conver message to a ClientMessage
-            let packet_id = camera_system.camera_system_client.assign_packet_id();
-            let message = message.parse_message(packet_id);
-            // Recibe el sub que hace el camera_system cuando se crea por primera vez
-            match message {
-                ClientMessage::Subscribe {
-                    packet_id: _,
-                    payload,
-                    properties: _,
-                } => {
-                    assert_eq!(payload.topic, "incident_resolved");
+                    let topics: Vec<&str> = payload
+                        .iter()
+                        .map(|subscription| subscription.topic.as_str())
+                        .collect();
+                    assert_eq!(
+                        topics,
+                        vec!["incident", "incident_resolved", "single_camera_disconnect"]
+                    );
                 }
                 _ => {
                     panic!("Unexpected message type");
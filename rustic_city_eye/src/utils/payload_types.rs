@@ -1,13 +1,17 @@
 use std::{
     any::Any,
-    io::{Error, ErrorKind, Read},
+    io::{Error, ErrorKind, Read, Write},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     monitoring::incident::Incident,
-    mqtt::{payload::Payload, protocol_error::ProtocolError},
+    mqtt::{
+        payload::Payload, protocol_error::ProtocolError,
+        publish::publish_properties::PublishProperties,
+    },
     surveilling::camera::Camera,
     utils::{
         incident_payload::IncidentPayload,
@@ -19,11 +23,64 @@ use crate::{
 
 use super::{
     reader::read_u32,
-    writer::{write_string, write_u8},
+    writer::{write_bytes, write_string, write_u32, write_u8},
 };
 
+/// Marca el inicio de un payload con encoding versionado. Ningun tag del encoding anterior la usa.
+pub const VERSIONED_PAYLOAD_MARKER: u8 = 0xFF;
+
+/// Version del esquema de payloads que escribe esta version de la aplicacion con el encoding versionado.
+pub const PAYLOAD_SCHEMA_VERSION: u8 = 1;
+
+/// Encoding con el que se escriben los payloads propios de la aplicacion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadWriteVersion {
+    /// El tag seguido del cuerpo, sin version ni largo. Lo entienden todas las versiones de la aplicacion,
+    /// por lo que se usa hasta que todas se actualicen.
+    #[default]
+    Legacy,
+    /// La marca, la version del esquema, el tag y el cuerpo precedido por su largo.
+    Versioned,
+}
+
+impl std::str::FromStr for PayloadWriteVersion {
+    type Err = ProtocolError;
+
+    fn from_str(version: &str) -> Result<PayloadWriteVersion, ProtocolError> {
+        match version {
+            "legacy" => Ok(PayloadWriteVersion::Legacy),
+            "versioned" => Ok(PayloadWriteVersion::Versioned),
+            _ => Err(ProtocolError::ServerConfigError(format!(
+                "Version de payload desconocida: {}",
+                version
+            ))),
+        }
+    }
+}
+
+/// Si esta en true, write_to usa el encoding versionado. Ver PayloadTypes::set_write_version.
+static WRITE_VERSIONED_PAYLOADS: AtomicBool = AtomicBool::new(false);
+
+const INCIDENT_LOCATION_TAG: u8 = 1;
+const WILL_PAYLOAD_TAG: u8 = 2;
+const LOCATION_PAYLOAD_TAG: u8 = 3;
+const CAMERAS_UPDATE_TAG: u8 = 4;
+const DRONE_LOCATION_TAG: u8 = 5;
+const ATTENDING_INCIDENT_TAG: u8 = 6;
+const SINGLE_DRONE_DISCONNECT_TAG: u8 = 7;
+const SINGLE_CAMERA_DISCONNECT_TAG: u8 = 8;
+
+/// Content type con el que viajan los payloads JSON.
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Content type con el que viajan los payloads binarios opacos.
+pub const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
 /// Aqui se definen los distintos tipos de payload que va a soportar nuestra aplicacion.
 /// La idea es que implemente el trait de Payload, de forma tal que sepa escribirse sobre un stream dado.
+///
+/// Binary y Json no llevan tag: viajan tal cual, y se reconocen por el content type del Publish.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PayloadTypes {
     IncidentLocation(IncidentPayload),
@@ -34,35 +91,122 @@ pub enum PayloadTypes {
     DroneLocation(u32, Location, Location),
     SingleDroneDisconnect(SingleDisconnectPayload),
     SingleCameraDisconnect(SingleDisconnectPayload),
+    Binary(Vec<u8>),
+    Json(serde_json::Value),
 }
 
 impl Payload for PayloadTypes {
+    /// Escribe el payload con el encoding configurado en PayloadTypes::set_write_version.
     fn write_to(&self, stream: &mut dyn std::io::prelude::Write) -> Result<(), ProtocolError> {
-        match self {
-            PayloadTypes::IncidentLocation(payload) => {
-                write_u8(stream, &1)?;
-                payload.write_to(stream)?;
+        let version = if WRITE_VERSIONED_PAYLOADS.load(Ordering::Relaxed) {
+            PayloadWriteVersion::Versioned
+        } else {
+            PayloadWriteVersion::Legacy
+        };
+        self.write_with_version(stream, version)
+    }
 
-                Ok(())
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl PayloadTypes {
+    /// Configura el encoding con el que se escriben los payloads en toda la aplicacion.
+    /// Por defecto se usa el encoding anterior(PayloadWriteVersion::Legacy).
+    pub fn set_write_version(version: PayloadWriteVersion) {
+        WRITE_VERSIONED_PAYLOADS
+            .store(version == PayloadWriteVersion::Versioned, Ordering::Relaxed);
+    }
+
+    /// Escribe el payload con el encoding indicado. Los payloads binarios y JSON se escriben tal cual.
+    pub fn write_with_version(
+        &self,
+        stream: &mut dyn Write,
+        version: PayloadWriteVersion,
+    ) -> Result<(), ProtocolError> {
+        let tag = match self.tag() {
+            Some(tag) => tag,
+            None => return self.write_body(stream),
+        };
+
+        match version {
+            PayloadWriteVersion::Legacy => {
+                write_u8(stream, &tag)?;
+                self.write_legacy_body(stream)
             }
-            PayloadTypes::WillPayload(payload) => {
-                write_u8(stream, &2)?;
-                write_string(stream, payload)?;
+            PayloadWriteVersion::Versioned => {
+                let mut body = Vec::new();
+                self.write_body(&mut body)?;
+
+                write_u8(stream, &VERSIONED_PAYLOAD_MARKER)?;
+                write_u8(stream, &PAYLOAD_SCHEMA_VERSION)?;
+                write_u8(stream, &tag)?;
+                write_u32(stream, &(body.len() as u32))?;
+                write_bytes(stream, &body)
+            }
+        }
+    }
+
+    /// Indica si el payload se envia con largo cero. Un publish retenido con un
+    /// payload vacio elimina el retained message del topic.
+    ///
+    /// Los payloads propios de la aplicacion siempre llevan su tag, por lo que nunca son vacios, aunque no
+    /// contengan datos(por ejemplo, una lista de camaras vacia).
+    pub fn is_empty(&self) -> bool {
+        match self {
+            PayloadTypes::Binary(bytes) => bytes.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Tag que identifica al payload en el encoding. Los payloads binarios y JSON no tienen tag.
+    fn tag(&self) -> Option<u8> {
+        match self {
+            PayloadTypes::IncidentLocation(_) => Some(INCIDENT_LOCATION_TAG),
+            PayloadTypes::WillPayload(_) => Some(WILL_PAYLOAD_TAG),
+            PayloadTypes::LocationPayload(_) => Some(LOCATION_PAYLOAD_TAG),
+            PayloadTypes::CamerasUpdatePayload(_) => Some(CAMERAS_UPDATE_TAG),
+            PayloadTypes::DroneLocation(..) => Some(DRONE_LOCATION_TAG),
+            PayloadTypes::AttendingIncident(_) => Some(ATTENDING_INCIDENT_TAG),
+            PayloadTypes::SingleDroneDisconnect(_) => Some(SINGLE_DRONE_DISCONNECT_TAG),
+            PayloadTypes::SingleCameraDisconnect(_) => Some(SINGLE_CAMERA_DISCONNECT_TAG),
+            PayloadTypes::Binary(_) | PayloadTypes::Json(_) => None,
+        }
+    }
 
+    /// Escribe el cuerpo del payload tal como lo leen las versiones que no conocen el encoding versionado:
+    /// los incidentes no incluyen su id y las ubicaciones de los drones van con la longitud primero.
+    fn write_legacy_body(&self, stream: &mut dyn Write) -> Result<(), ProtocolError> {
+        match self {
+            PayloadTypes::IncidentLocation(payload) | PayloadTypes::AttendingIncident(payload) => {
+                payload.write_to(stream)
+            }
+            PayloadTypes::DroneLocation(drone_id, location, target_location) => {
+                write_string(stream, &drone_id.to_string())?;
+                for location in [location, target_location] {
+                    write_string(stream, &location.get_longitude().to_string())?;
+                    write_string(stream, &location.get_latitude().to_string())?;
+                }
                 Ok(())
             }
-            PayloadTypes::LocationPayload(payload) => {
-                write_u8(stream, &3)?;
-                write_string(stream, &payload.get_latitude().to_string())?;
-                write_string(stream, &payload.get_longitude().to_string())?;
+            _ => self.write_body(stream),
+        }
+    }
 
-                Ok(())
+    /// Escribe el cuerpo del payload, sin tag ni largo.
+    fn write_body(&self, stream: &mut dyn std::io::prelude::Write) -> Result<(), ProtocolError> {
+        match self {
+            PayloadTypes::IncidentLocation(payload) | PayloadTypes::AttendingIncident(payload) => {
+                write_u8(stream, &payload.id)?;
+                payload.write_to(stream)
             }
-            PayloadTypes::CamerasUpdatePayload(payload) => {
-                write_u8(stream, &4)?;
-                write_u8(stream, &(payload.len() as u8))?;
+            PayloadTypes::WillPayload(payload) => write_string(stream, payload),
+            PayloadTypes::LocationPayload(location) => write_location(stream, location),
+            PayloadTypes::CamerasUpdatePayload(cameras) => {
+                write_u8(stream, &(cameras.len() as u8))?;
 
-                for camera in payload {
+                for camera in cameras {
                     let mut camera_clone = camera.clone();
                     match camera_clone.write_to(stream) {
                         Ok(_) => {}
@@ -73,187 +217,170 @@ impl Payload for PayloadTypes {
                 Ok(())
             }
             PayloadTypes::DroneLocation(drone_id, location, target_location) => {
-                write_u8(stream, &5)?;
                 write_string(stream, &drone_id.to_string())?;
-                write_string(stream, &location.get_latitude().to_string())?;
-                write_string(stream, &location.get_longitude().to_string())?;
-                write_string(stream, &target_location.get_latitude().to_string())?;
-                write_string(stream, &target_location.get_longitude().to_string())?;
-
-
-                Ok(())
+                write_location(stream, location)?;
+                write_location(stream, target_location)
             }
-            PayloadTypes::AttendingIncident(payload) => {
-                write_u8(stream, &6)?;
-                payload.write_to(stream)?;
-
-                Ok(())
+            PayloadTypes::SingleDroneDisconnect(payload)
+            | PayloadTypes::SingleCameraDisconnect(payload) => payload.write_to(stream),
+            PayloadTypes::Binary(bytes) => write_bytes(stream, bytes),
+            PayloadTypes::Json(value) => {
+                let bytes = serde_json::to_vec(value).map_err(|_| ProtocolError::WriteError)?;
+                write_bytes(stream, &bytes)
             }
-            PayloadTypes::SingleDroneDisconnect(payload) => {
-                write_u8(stream, &7)?;
-                payload.write_to(stream)?;
+        }
+    }
 
-                Ok(())
-            }
-            PayloadTypes::SingleCameraDisconnect(payload) => {
-                write_u8(stream, &8)?;
-                payload.write_to(stream)?;
+    /// Content type que corresponde al payload, si no es uno de los payloads propios de la aplicacion.
+    pub fn content_type(&self) -> Option<&'static str> {
+        match self {
+            PayloadTypes::Binary(_) => Some(BINARY_CONTENT_TYPE),
+            PayloadTypes::Json(_) => Some(JSON_CONTENT_TYPE),
+            _ => None,
+        }
+    }
 
-                Ok(())
+    /// Lee el payload de un Publish, que ocupa el resto del packet.
+    ///
+    /// Si el content type es JSON, el payload debe ser un JSON valido (y por lo tanto UTF-8); si es binario,
+    /// se guardan los bytes tal cual. Sin content type se lee uno de los payloads propios de la aplicacion, y si
+    /// los bytes no corresponden a ninguno se conservan como un payload binario, para poder reenviarlos sin cambios.
+    /// Un content type que no se reconoce tambien se trata como un payload binario opaco.
+    ///
+    /// Con un payload format indicator de 1, un payload que no es UTF-8 valido se rechaza.
+    pub fn read_with_properties(
+        stream: &mut dyn Read,
+        properties: &PublishProperties,
+    ) -> Result<PayloadTypes, std::io::Error> {
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes)?;
+
+        match properties.content_type.as_str() {
+            JSON_CONTENT_TYPE => {
+                return serde_json::from_slice(&bytes)
+                    .map(PayloadTypes::Json)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Payload JSON invalido"))
+            }
+            BINARY_CONTENT_TYPE => return Ok(PayloadTypes::Binary(bytes)),
+            "" => {
+                let mut remaining = bytes.as_slice();
+                if let Ok(payload) = PayloadTypes::read_from(&mut remaining) {
+                    if remaining.is_empty() {
+                        return Ok(payload);
+                    }
+                }
             }
+            _ => {}
         }
-    }
 
-    fn as_any(&self) -> &dyn Any {
-        self
+        if properties.payload_format_indicator == 1 && std::str::from_utf8(&bytes).is_err() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "El payload no esta encodeado en UTF-8",
+            ));
+        }
+        Ok(PayloadTypes::Binary(bytes))
     }
-}
 
-impl PayloadTypes {
-    /// Dependiendo del id del payload que se lea, se va a reconstruir el payload a partir de lo
-    /// leido efectivamente del stream.
+    /// Lee un payload propio de la aplicacion.
+    ///
+    /// Si empieza con la marca del encoding versionado, se lee el cuerpo completo segun su largo
+    /// y se decodifica segun su tag. Las versiones nuevas del esquema solo agregan campos al final del
+    /// cuerpo, por lo que los campos que no se conocen se ignoran. Un tag desconocido devuelve error,
+    /// pero deja el stream despues del payload, para poder seguir leyendo.
+    ///
+    /// Si no tiene la marca, se lee con el encoding anterior (el tag seguido del cuerpo), para
+    /// seguir entendiendo a las aplicaciones que todavia no se actualizaron.
     pub fn read_from(stream: &mut dyn Read) -> Result<PayloadTypes, std::io::Error> {
-        let payload_type_id = read_u8(stream)?;
-
-        let payload_type = match payload_type_id {
-            1 => {
-                let longitude_string = read_string(stream)?;
-                let long = match longitude_string.parse::<f64>() {
-                    Ok(long) => long,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
+        let first_byte = read_u8(stream)?;
 
-                let latitude_string = read_string(stream)?;
-
-                let lat = match latitude_string.parse::<f64>() {
-                    Ok(lat) => lat,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
+        if first_byte != VERSIONED_PAYLOAD_MARKER {
+            return PayloadTypes::read_legacy_body(first_byte, stream);
+        }
 
-                let location = Location::new(lat, long);
-                let incident = Incident::new(location);
+        let version = read_u8(stream)?;
+        if version == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Version de payload invalida".to_string(),
+            ));
+        }
 
-                PayloadTypes::IncidentLocation(IncidentPayload::new(incident))
-            }
-            4 => {
-                let lenght = read_u8(stream)?;
-                let mut cameras = Vec::new();
-                for _ in 0..lenght {
-                    let camera = Camera::read_from(stream)?;
-                    cameras.push(camera);
-                }
+        let tag = read_u8(stream)?;
+        let length = read_u32(stream)?;
+        let mut body = vec![0; length as usize];
+        stream.read_exact(&mut body)?;
 
-                PayloadTypes::CamerasUpdatePayload(cameras)
-            }
-            5 => {
-                let drone_id_string = read_string(stream)?;
-                let drone_id = match drone_id_string.parse::<u32>() {
-                    Ok(drone_id) => drone_id,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
-
-                let longitude_string = read_string(stream)?;
-                let long = match longitude_string.parse::<f64>() {
-                    Ok(long) => long,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
-
-                let latitude_string = read_string(stream)?;
-                let lat = match latitude_string.parse::<f64>() {
-                    Ok(lat) => lat,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
-
-                let target_longitude_string = read_string(stream)?;
-                let target_long = match target_longitude_string.parse::<f64>() {
-                    Ok(long) => long,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
-
-                let target_latitude_string = read_string(stream)?;
-                let target_lat = match target_latitude_string.parse::<f64>() {
-                    Ok(lat) => lat,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
+        PayloadTypes::read_body(tag, &mut body.as_slice())
+    }
 
-                let target_location = Location::new(target_lat, target_long);
-                let location = Location::new(lat, long);
+    /// Decodifica el cuerpo de un payload con encoding versionado.
+    fn read_body(tag: u8, stream: &mut dyn Read) -> Result<PayloadTypes, std::io::Error> {
+        let payload_type = match tag {
+            INCIDENT_LOCATION_TAG => PayloadTypes::IncidentLocation(read_incident_payload(stream)?),
+            WILL_PAYLOAD_TAG => PayloadTypes::WillPayload(read_string(stream)?),
+            LOCATION_PAYLOAD_TAG => PayloadTypes::LocationPayload(read_location(stream)?),
+            CAMERAS_UPDATE_TAG => PayloadTypes::CamerasUpdatePayload(read_cameras(stream)?),
+            DRONE_LOCATION_TAG => {
+                let drone_id = read_number::<u32>(stream)?;
+                let location = read_location(stream)?;
+                let target_location = read_location(stream)?;
 
                 PayloadTypes::DroneLocation(drone_id, location, target_location)
             }
-            6 => {
-                let longitude_string = read_string(stream)?;
-                let long = match longitude_string.parse::<f64>() {
-                    Ok(long) => long,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
-
-                let latitude_string = read_string(stream)?;
-
-                let lat = match latitude_string.parse::<f64>() {
-                    Ok(lat) => lat,
-                    Err(_) => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Error while reading payload".to_string(),
-                        ))
-                    }
-                };
-
-                let location = Location::new(lat, long);
-                let incident = Incident::new(location);
-                PayloadTypes::AttendingIncident(IncidentPayload::new(incident))
+            ATTENDING_INCIDENT_TAG => {
+                PayloadTypes::AttendingIncident(read_incident_payload(stream)?)
             }
-            7 => {
-                let id = read_u32(stream)?;
-
-                PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(id))
+            SINGLE_DRONE_DISCONNECT_TAG => {
+                PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(read_u32(stream)?))
+            }
+            SINGLE_CAMERA_DISCONNECT_TAG => PayloadTypes::SingleCameraDisconnect(
+                SingleDisconnectPayload::new(read_u32(stream)?),
+            ),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Tipo de payload desconocido".to_string(),
+                ))
             }
-            8 => {
-                let id = read_u32(stream)?;
+        };
+        Ok(payload_type)
+    }
 
-                PayloadTypes::SingleCameraDisconnect(SingleDisconnectPayload::new(id))
+    /// Decodifica un payload escrito con el encoding anterior, que no tiene version ni largo.
+    ///
+    /// En ese encoding los incidentes no incluyen su id, y las ubicaciones de los drones se leen
+    /// con la longitud primero.
+    fn read_legacy_body(tag: u8, stream: &mut dyn Read) -> Result<PayloadTypes, std::io::Error> {
+        let payload_type = match tag {
+            INCIDENT_LOCATION_TAG => {
+                PayloadTypes::IncidentLocation(IncidentPayload::new(read_incident(stream)?))
             }
+            WILL_PAYLOAD_TAG => PayloadTypes::WillPayload(read_string(stream)?),
+            LOCATION_PAYLOAD_TAG => PayloadTypes::LocationPayload(read_location(stream)?),
+            CAMERAS_UPDATE_TAG => PayloadTypes::CamerasUpdatePayload(read_cameras(stream)?),
+            DRONE_LOCATION_TAG => {
+                let drone_id = read_number::<u32>(stream)?;
+                let long = read_number::<f64>(stream)?;
+                let lat = read_number::<f64>(stream)?;
+                let target_long = read_number::<f64>(stream)?;
+                let target_lat = read_number::<f64>(stream)?;
+
+                PayloadTypes::DroneLocation(
+                    drone_id,
+                    Location::new(lat, long),
+                    Location::new(target_lat, target_long),
+                )
+            }
+            ATTENDING_INCIDENT_TAG => {
+                PayloadTypes::AttendingIncident(IncidentPayload::new(read_incident(stream)?))
+            }
+            SINGLE_DRONE_DISCONNECT_TAG => {
+                PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(read_u32(stream)?))
+            }
+            SINGLE_CAMERA_DISCONNECT_TAG => PayloadTypes::SingleCameraDisconnect(
+                SingleDisconnectPayload::new(read_u32(stream)?),
+            ),
             _ => {
                 return Err(Error::new(
                     ErrorKind::InvalidData,
@@ -265,6 +392,49 @@ impl PayloadTypes {
     }
 }
 
+/// Los numeros de los payloads viajan como strings.
+fn read_number<T: std::str::FromStr>(stream: &mut dyn Read) -> Result<T, std::io::Error> {
+    read_string(stream)?.parse::<T>().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "Error while reading payload".to_string(),
+        )
+    })
+}
+
+fn write_location(stream: &mut dyn Write, location: &Location) -> Result<(), ProtocolError> {
+    write_string(stream, &location.get_latitude().to_string())?;
+    write_string(stream, &location.get_longitude().to_string())
+}
+
+fn read_location(stream: &mut dyn Read) -> Result<Location, std::io::Error> {
+    let lat = read_number::<f64>(stream)?;
+    let long = read_number::<f64>(stream)?;
+    Ok(Location::new(lat, long))
+}
+
+/// El incidente se escribe con la longitud primero.
+fn read_incident(stream: &mut dyn Read) -> Result<Incident, std::io::Error> {
+    let long = read_number::<f64>(stream)?;
+    let lat = read_number::<f64>(stream)?;
+    Ok(Incident::new(Location::new(lat, long)))
+}
+
+fn read_incident_payload(stream: &mut dyn Read) -> Result<IncidentPayload, std::io::Error> {
+    let id = read_u8(stream)?;
+    let incident = read_incident(stream)?;
+    Ok(IncidentPayload { id, incident })
+}
+
+fn read_cameras(stream: &mut dyn Read) -> Result<Vec<Camera>, std::io::Error> {
+    let length = read_u8(stream)?;
+    let mut cameras = Vec::new();
+    for _ in 0..length {
+        cameras.push(Camera::read_from(stream)?);
+    }
+    Ok(cameras)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -279,7 +449,9 @@ mod tests {
         let payload = PayloadTypes::IncidentLocation(incident_payload.clone());
 
         let mut cursor = Cursor::new(Vec::new());
-        payload.write_to(&mut cursor).unwrap();
+        payload
+            .write_with_version(&mut cursor, PayloadWriteVersion::Versioned)
+            .unwrap();
         cursor.set_position(0);
 
         let read_payload = PayloadTypes::read_from(&mut cursor).unwrap();
@@ -294,12 +466,14 @@ mod tests {
         let payload = PayloadTypes::IncidentLocation(incident_payload.clone());
 
         let mut cursor = Cursor::new(Vec::new());
-        payload.write_to(&mut cursor).unwrap();
+        payload
+            .write_with_version(&mut cursor, PayloadWriteVersion::Versioned)
+            .unwrap();
         cursor.set_position(0);
 
-        let mut buf = [0u8; 1];
+        let mut buf = [0u8; 3];
         cursor.read_exact(&mut buf).unwrap();
-        assert_eq!(buf[0], 1);
+        assert_eq!(buf, [VERSIONED_PAYLOAD_MARKER, PAYLOAD_SCHEMA_VERSION, 1]);
     }
 
     #[test]
@@ -332,6 +506,14 @@ mod tests {
         assert_eq!(payload, payload);
     }
 
+    #[test]
+    fn test_is_empty() {
+        assert!(PayloadTypes::Binary(Vec::new()).is_empty());
+        assert!(!PayloadTypes::WillPayload(String::new()).is_empty());
+        assert!(!PayloadTypes::CamerasUpdatePayload(Vec::new()).is_empty());
+        assert!(!PayloadTypes::WillPayload("offline".to_string()).is_empty());
+    }
+
     #[test]
     fn test_single_drone_disconnect() {
         let disc_payload = SingleDisconnectPayload::new(1);
@@ -357,4 +539,251 @@ mod tests {
         let read_payload = PayloadTypes::read_from(&mut cursor).unwrap();
         assert_eq!(read_payload, payload);
     }
+
+    fn properties(payload_format_indicator: u8, content_type: &str) -> PublishProperties {
+        PublishProperties::new(
+            payload_format_indicator,
+            0,
+            crate::mqtt::publish::publish_properties::TopicProperties {
+                topic_alias: 0,
+                response_topic: String::new(),
+            },
+            vec![],
+            String::new(),
+            0,
+            content_type.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_json_payload() {
+        let payload = PayloadTypes::Json(serde_json::json!({"drone": 3, "battery": 87.5}));
+
+        let mut buffer = Vec::new();
+        payload.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer[0], b'{');
+
+        let read_payload = PayloadTypes::read_with_properties(
+            &mut buffer.as_slice(),
+            &properties(1, JSON_CONTENT_TYPE),
+        )
+        .unwrap();
+        assert_eq!(read_payload, payload);
+
+        assert!(PayloadTypes::read_with_properties(
+            &mut b"{no es json".as_slice(),
+            &properties(1, JSON_CONTENT_TYPE)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_binary_payload() {
+        let payload = PayloadTypes::Binary(vec![0x01, 0xFF, 0x00, 0x80]);
+
+        let mut buffer = Vec::new();
+        payload.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x01, 0xFF, 0x00, 0x80]);
+
+        let read_payload = PayloadTypes::read_with_properties(
+            &mut buffer.as_slice(),
+            &properties(0, BINARY_CONTENT_TYPE),
+        )
+        .unwrap();
+        assert_eq!(read_payload, payload);
+        assert!(PayloadTypes::Binary(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_payload_without_content_type() {
+        // Los payloads propios de la aplicacion se siguen leyendo por su tag.
+        let payload = PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(4));
+        let mut buffer = Vec::new();
+        payload.write_to(&mut buffer).unwrap();
+        let read_payload =
+            PayloadTypes::read_with_properties(&mut buffer.as_slice(), &properties(0, "")).unwrap();
+        assert_eq!(read_payload, payload);
+
+        // Lo que no corresponde a ninguno se conserva tal cual.
+        let read_payload =
+            PayloadTypes::read_with_properties(&mut b"hola".as_slice(), &properties(1, ""))
+                .unwrap();
+        assert_eq!(read_payload, PayloadTypes::Binary(b"hola".to_vec()));
+
+        // Con payload format indicator 1 el payload debe ser UTF-8.
+        assert!(PayloadTypes::read_with_properties(
+            &mut [0xC3_u8, 0x28].as_slice(),
+            &properties(1, "")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_payload_with_unknown_content_type_is_opaque() {
+        // Aunque los bytes coincidan con un payload propio, con otro content type no se interpretan.
+        let payload = PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(4));
+        let mut buffer = Vec::new();
+        payload.write_to(&mut buffer).unwrap();
+
+        let read_payload = PayloadTypes::read_with_properties(
+            &mut buffer.as_slice(),
+            &properties(0, "application/x-protobuf"),
+        )
+        .unwrap();
+        assert_eq!(read_payload, PayloadTypes::Binary(buffer));
+    }
+
+    #[test]
+    fn test_versioned_encoding_layout() {
+        let payload = PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(9));
+
+        let mut buffer = Vec::new();
+        payload
+            .write_with_version(&mut buffer, PayloadWriteVersion::Versioned)
+            .unwrap();
+
+        // marca, version, tag, largo del cuerpo y cuerpo
+        assert_eq!(buffer, vec![0xFF, 0x01, 0x07, 0, 0, 0, 4, 0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn test_legacy_encoding_layout() {
+        // Por defecto se escriben los mismos bytes que en las versiones anteriores.
+        let disconnect = PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(9));
+        let mut buffer = Vec::new();
+        disconnect.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x07, 0, 0, 0, 9]);
+
+        let incident = PayloadTypes::IncidentLocation(IncidentPayload::new(Incident::new(
+            Location::new(1.0, 2.0),
+        )));
+        let mut buffer = Vec::new();
+        incident
+            .write_with_version(&mut buffer, PayloadWriteVersion::Legacy)
+            .unwrap();
+        let mut expected = vec![0x01_u8];
+        write_string(&mut expected, "2").unwrap();
+        write_string(&mut expected, "1").unwrap();
+        assert_eq!(buffer, expected);
+
+        let drone_location =
+            PayloadTypes::DroneLocation(3, Location::new(1.0, 2.0), Location::new(3.0, 4.0));
+        let mut buffer = Vec::new();
+        drone_location
+            .write_with_version(&mut buffer, PayloadWriteVersion::Legacy)
+            .unwrap();
+        let mut expected = vec![0x05_u8];
+        for field in ["3", "2", "1", "4", "3"] {
+            write_string(&mut expected, field).unwrap();
+        }
+        assert_eq!(buffer, expected);
+
+        for payload in [
+            disconnect,
+            drone_location,
+            PayloadTypes::WillPayload("offline".to_string()),
+            PayloadTypes::LocationPayload(Location::new(-34.6, -58.4)),
+        ] {
+            let mut buffer = Vec::new();
+            payload
+                .write_with_version(&mut buffer, PayloadWriteVersion::Legacy)
+                .unwrap();
+            assert_eq!(
+                PayloadTypes::read_from(&mut buffer.as_slice()).unwrap(),
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn test_reading_payloads_from_the_previous_encoding() {
+        // Escrito por una aplicacion con el encoding anterior: tag seguido del cuerpo.
+        let legacy_disconnect = [0x08_u8, 0, 0, 0, 5];
+        let read_payload = PayloadTypes::read_from(&mut legacy_disconnect.as_slice()).unwrap();
+        assert_eq!(
+            read_payload,
+            PayloadTypes::SingleCameraDisconnect(SingleDisconnectPayload::new(5))
+        );
+
+        let mut legacy_incident = vec![0x01_u8];
+        write_string(&mut legacy_incident, "2").unwrap();
+        write_string(&mut legacy_incident, "1").unwrap();
+        match PayloadTypes::read_from(&mut legacy_incident.as_slice()).unwrap() {
+            PayloadTypes::IncidentLocation(payload) => {
+                assert_eq!(
+                    payload.get_incident(),
+                    Incident::new(Location::new(1.0, 2.0))
+                )
+            }
+            other => panic!("Se esperaba un incidente, se leyo {:?}", other),
+        }
+
+        let mut legacy_drone_location = vec![0x05_u8];
+        for field in ["3", "2", "1", "4", "3"] {
+            write_string(&mut legacy_drone_location, field).unwrap();
+        }
+        let read_payload = PayloadTypes::read_from(&mut legacy_drone_location.as_slice()).unwrap();
+        assert_eq!(
+            read_payload,
+            PayloadTypes::DroneLocation(3, Location::new(1.0, 2.0), Location::new(3.0, 4.0))
+        );
+    }
+
+    #[test]
+    fn test_new_encoding_round_trip() {
+        let payloads = vec![
+            PayloadTypes::IncidentLocation(IncidentPayload::new(Incident::new(Location::new(
+                1.5, 2.5,
+            )))),
+            PayloadTypes::AttendingIncident(IncidentPayload::new(Incident::new(Location::new(
+                3.0, 4.0,
+            )))),
+            PayloadTypes::WillPayload("offline".to_string()),
+            PayloadTypes::LocationPayload(Location::new(-34.6, -58.4)),
+            PayloadTypes::CamerasUpdatePayload(Vec::new()),
+            PayloadTypes::DroneLocation(7, Location::new(1.0, 2.0), Location::new(3.0, 4.0)),
+            PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(1)),
+            PayloadTypes::SingleCameraDisconnect(SingleDisconnectPayload::new(2)),
+        ];
+
+        for payload in payloads {
+            let mut buffer = Vec::new();
+            payload
+                .write_with_version(&mut buffer, PayloadWriteVersion::Versioned)
+                .unwrap();
+
+            let mut stream = buffer.as_slice();
+            assert_eq!(PayloadTypes::read_from(&mut stream).unwrap(), payload);
+            assert!(stream.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_newer_schema_versions_and_unknown_payloads() {
+        // Una version posterior del esquema agrega campos al final del cuerpo: se ignoran.
+        let newer_disconnect = [0xFF_u8, 0x02, 0x07, 0, 0, 0, 6, 0, 0, 0, 3, 0xAB, 0xCD];
+        // Un tipo de payload que esta version no conoce.
+        let unknown = [0xFF_u8, 0x02, 0x2A, 0, 0, 0, 3, 1, 2, 3];
+        let next = PayloadTypes::SingleCameraDisconnect(SingleDisconnectPayload::new(4));
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&newer_disconnect);
+        buffer.extend_from_slice(&unknown);
+        next.write_to(&mut buffer).unwrap();
+
+        let mut stream = buffer.as_slice();
+        assert_eq!(
+            PayloadTypes::read_from(&mut stream).unwrap(),
+            PayloadTypes::SingleDroneDisconnect(SingleDisconnectPayload::new(3))
+        );
+        assert!(PayloadTypes::read_from(&mut stream).is_err());
+        assert_eq!(PayloadTypes::read_from(&mut stream).unwrap(), next);
+        assert!(stream.is_empty());
+
+        // Dentro de un Publish, el payload desconocido se conserva para reenviarlo sin cambios.
+        let read_payload =
+            PayloadTypes::read_with_properties(&mut unknown.as_slice(), &properties(0, ""))
+                .unwrap();
+        assert_eq!(read_payload, PayloadTypes::Binary(unknown.to_vec()));
+    }
 }
@@ -1,12 +1,14 @@
-use std::io::{Error, Read};
+use std::io::{Error, ErrorKind, Read};
 
 pub fn read_string(stream: &mut dyn Read) -> Result<String, Error> {
     let string_length = read_u16(stream)?;
     let mut string_buf = vec![0; string_length as usize];
     stream.read_exact(&mut string_buf)?;
 
-    let protocol_name = std::str::from_utf8(&string_buf).expect("Error al leer protocol_name");
-    Ok(protocol_name.to_string())
+    match String::from_utf8(string_buf) {
+        Ok(string) => Ok(string),
+        Err(_) => Err(Error::new(ErrorKind::InvalidData, "String UTF-8 inválido")),
+    }
 }
 
 pub fn read_u8(stream: &mut dyn Read) -> Result<u8, Error> {
@@ -61,6 +63,83 @@ pub fn read_bool(stream: &mut dyn Read) -> Result<bool, Error> {
     Ok(buf[0] != 0)
 }
 
+/// Lee un Variable Byte Integer. Si ocupa mas de 4 bytes, el packet esta mal formado.
+pub fn read_variable_byte_integer(stream: &mut dyn Read) -> Result<u32, Error> {
+    let mut value = 0_u32;
+    for position in 0..4 {
+        let byte = read_u8(stream)?;
+        value += ((byte & 0x7F) as u32) << (7 * position);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "Variable Byte Integer mal formado",
+    ))
+}
+
+/// Error de read_packet cuando el packet supera el maximum packet size de quien lo lee.
+#[derive(Debug)]
+struct PacketTooLarge;
+
+impl std::fmt::Display for PacketTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "El packet supera el maximum packet size")
+    }
+}
+
+impl std::error::Error for PacketTooLarge {}
+
+/// Indica si la lectura de un packet fallo porque superaba el maximum packet size.
+pub fn is_packet_too_large(error: &Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<PacketTooLarge>())
+}
+
+/// Cantidad de bytes que ocupa un Variable Byte Integer.
+fn variable_byte_integer_length(value: u32) -> usize {
+    match value {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+/// Lee un packet completo: devuelve el primer byte del fixed header y los bytes que indica el remaining length.
+///
+/// El tamaño del packet se controla contra maximum_packet_size apenas se lee el fixed header, antes de reservar
+/// memoria para el resto. Un maximum_packet_size de 0 indica que no hay limite.
+pub fn read_packet(
+    stream: &mut dyn Read,
+    maximum_packet_size: u32,
+) -> Result<(u8, Vec<u8>), Error> {
+    let header = read_u8(stream)?;
+    let remaining_length = read_variable_byte_integer(stream)?;
+
+    let packet_size =
+        1 + variable_byte_integer_length(remaining_length) + remaining_length as usize;
+    if maximum_packet_size != 0 && packet_size > maximum_packet_size as usize {
+        return Err(Error::new(ErrorKind::InvalidData, PacketTooLarge));
+    }
+
+    let mut body = vec![0; remaining_length as usize];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+/// Lee el bloque de properties de un packet, precedido por su largo.
+pub fn read_properties_block(stream: &mut dyn Read) -> Result<Vec<u8>, Error> {
+    let length = read_variable_byte_integer(stream)?;
+
+    let mut properties = vec![0; length as usize];
+    stream.read_exact(&mut properties)?;
+    Ok(properties)
+}
+
 #[cfg(test)]
 
 mod tests {
@@ -114,4 +193,55 @@ mod tests {
         let mut cursor = Cursor::new(vec![1]);
         assert!(read_bool(&mut cursor).unwrap());
     }
+
+    #[test]
+    fn test_read_variable_byte_integer() {
+        for (bytes, expected) in [
+            (vec![0x00], 0),
+            (vec![0x7F], 127),
+            (vec![0x80, 0x01], 128),
+            (vec![0xFF, 0x7F], 16_383),
+            (vec![0xFF, 0xFF, 0xFF, 0x7F], 268_435_455),
+        ] {
+            let mut cursor = Cursor::new(bytes);
+            assert_eq!(read_variable_byte_integer(&mut cursor).unwrap(), expected);
+        }
+
+        let mut cursor = Cursor::new(vec![0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert!(read_variable_byte_integer(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let mut cursor = Cursor::new(vec![0xD0, 0x00, 0x40, 0x02, 0x00, 0x01]);
+        assert_eq!(read_packet(&mut cursor, 0).unwrap(), (0xD0, vec![]));
+        assert_eq!(
+            read_packet(&mut cursor, 0).unwrap(),
+            (0x40, vec![0x00, 0x01])
+        );
+
+        let mut cursor = Cursor::new(vec![0x40, 0x03, 0x00, 0x01]);
+        let error = read_packet(&mut cursor, 0).unwrap_err();
+        assert!(!is_packet_too_large(&error));
+    }
+
+    #[test]
+    fn test_read_packet_checks_the_maximum_packet_size_before_reading_the_body() {
+        let mut cursor = Cursor::new(vec![0x40, 0x02, 0x00, 0x01]);
+        assert_eq!(
+            read_packet(&mut cursor, 4).unwrap(),
+            (0x40, vec![0x00, 0x01])
+        );
+
+        // El remaining length anuncia 256 MB, pero el packet se rechaza sin leer el resto.
+        let mut cursor = Cursor::new(vec![0x30, 0xFF, 0xFF, 0xFF, 0x7F]);
+        let error = read_packet(&mut cursor, 1024).unwrap_err();
+        assert!(is_packet_too_large(&error));
+        assert_eq!(cursor.position(), 5);
+
+        let mut cursor = Cursor::new(vec![0x40, 0x02, 0x00, 0x01]);
+        assert!(is_packet_too_large(
+            &read_packet(&mut cursor, 3).unwrap_err()
+        ));
+    }
 }
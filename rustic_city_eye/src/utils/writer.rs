@@ -55,6 +55,14 @@ pub fn write_string_tuple(
     Ok(())
 }
 
+/// Escribe los bytes tal cual, sin prefijo de largo.
+pub fn write_bytes(stream: &mut dyn Write, bytes: &[u8]) -> Result<(), ProtocolError> {
+    match stream.write_all(bytes) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(ProtocolError::WriteError),
+    }
+}
+
 pub fn write_u8(stream: &mut dyn Write, value: &u8) -> Result<(), ProtocolError> {
     let _ = stream
         .write_all(&[*value])
@@ -102,6 +110,72 @@ pub fn write_string_pairs(
     Ok(())
 }
 
+/// Mayor valor que se puede codificar como Variable Byte Integer(4 bytes).
+pub const MAXIMUM_VARIABLE_BYTE_INTEGER: u32 = 268_435_455;
+
+const USER_PROPERTY_ID: u8 = 0x26;
+
+/// Escribe un Variable Byte Integer: 7 bits de valor por byte, y el bit mas significativo indica si sigue otro byte.
+pub fn write_variable_byte_integer(
+    stream: &mut dyn Write,
+    value: &u32,
+) -> Result<(), ProtocolError> {
+    if *value > MAXIMUM_VARIABLE_BYTE_INTEGER {
+        return Err(ProtocolError::WriteError);
+    }
+
+    let mut value = *value;
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        stream
+            .write_all(&[byte])
+            .map_err(|_e| ProtocolError::WriteError)?;
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Escribe un packet completo: el primer byte del fixed header, el remaining length y el resto del packet.
+///
+/// El packet se escribe en una sola escritura, para que sobre WebSocket viaje en un unico frame.
+pub fn write_packet(stream: &mut dyn Write, byte_1: u8, body: &[u8]) -> Result<(), ProtocolError> {
+    let mut packet = vec![byte_1];
+    write_variable_byte_integer(&mut packet, &(body.len() as u32))?;
+    packet.extend_from_slice(body);
+    stream
+        .write_all(&packet)
+        .map_err(|_e| ProtocolError::WriteError)
+}
+
+/// Escribe las properties ya codificadas de un packet, precedidas por su largo.
+pub fn write_properties_block(
+    stream: &mut dyn Write,
+    properties: &[u8],
+) -> Result<(), ProtocolError> {
+    write_variable_byte_integer(stream, &(properties.len() as u32))?;
+    stream
+        .write_all(properties)
+        .map_err(|_e| ProtocolError::WriteError)
+}
+
+/// Cada user property se escribe como un par de strings precedido por su propio identificador.
+pub fn write_user_properties(
+    stream: &mut dyn Write,
+    user_properties: &[(String, String)],
+) -> Result<(), ProtocolError> {
+    for (key, value) in user_properties {
+        write_u8(stream, &USER_PROPERTY_ID)?;
+        write_string(stream, key)?;
+        write_string(stream, value)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 
 mod tests {
@@ -239,4 +313,37 @@ mod tests {
         cursor.read_exact(&mut buf).unwrap();
         assert_eq!(String::from_utf8(buf.to_vec()).unwrap(), "Chau");
     }
+
+    #[test]
+    fn test_write_variable_byte_integer() {
+        for (value, expected) in [
+            (0, vec![0x00]),
+            (127, vec![0x7F]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xFF, 0x7F]),
+            (16_384, vec![0x80, 0x80, 0x01]),
+            (MAXIMUM_VARIABLE_BYTE_INTEGER, vec![0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let mut buffer = Vec::new();
+            write_variable_byte_integer(&mut buffer, &value).unwrap();
+            assert_eq!(buffer, expected);
+        }
+
+        let mut buffer = Vec::new();
+        assert!(
+            write_variable_byte_integer(&mut buffer, &(MAXIMUM_VARIABLE_BYTE_INTEGER + 1)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_write_packet() {
+        let mut buffer = Vec::new();
+        write_packet(&mut buffer, 0xC0, &[]).unwrap();
+        assert_eq!(buffer, vec![0xC0, 0x00]);
+
+        let mut buffer = Vec::new();
+        write_packet(&mut buffer, 0x30, &[7; 200]).unwrap();
+        assert_eq!(buffer[..3], [0x30, 0xC8, 0x01]);
+        assert_eq!(buffer.len(), 203);
+    }
 }
@@ -3,6 +3,7 @@ mod tests {
     use rustic_city_eye::monitoring::incident::Incident;
     use rustic_city_eye::mqtt::broker::Broker;
     use rustic_city_eye::mqtt::broker_message::BrokerMessage;
+    use rustic_city_eye::mqtt::broker_stream::BrokerStream;
     use rustic_city_eye::mqtt::client_message;
     use rustic_city_eye::mqtt::publish::publish_properties::{PublishProperties, TopicProperties};
     use rustic_city_eye::mqtt::subscribe_properties::SubscribeProperties;
@@ -63,8 +64,8 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
-            assert!(ClientMessage::read_from(stream.get_ref()).is_err());
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
+            assert!(ClientMessage::read_from(stream.as_ref()).is_err());
         }
         Ok(())
     }
@@ -116,7 +117,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -146,7 +147,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -171,7 +172,10 @@ mod tests {
         let properties =
             SubscribeProperties::new(1, vec![("propiedad".to_string(), "valor".to_string())]);
 
-        let payload = Subscription::new("incident".to_string(), "kvtr33".to_string());
+        let payload = vec![
+            Subscription::new("incident".to_string(), "kvtr33".to_string()),
+            Subscription::new("drones/#/status".to_string(), "kvtr33".to_string()),
+        ];
 
         let sub = ClientMessage::Subscribe {
             packet_id: 1,
@@ -215,7 +219,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -227,9 +231,10 @@ mod tests {
                         BrokerMessage::Suback {
                             packet_id_msb: _,
                             packet_id_lsb: _,
-                            reason_code,
+                            reason_codes,
                         } => {
-                            assert_eq!(reason_code, 0x00_u8);
+                            // El segundo topic filter es invalido.
+                            assert_eq!(reason_codes, vec![0x00_u8, 0x8F_u8]);
                         }
                         _ => {
                             panic!("Assertion failed: No se recibio un Suback");
@@ -239,7 +244,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -322,7 +327,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -346,7 +351,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -428,7 +433,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -440,7 +445,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -464,7 +469,10 @@ mod tests {
         let properties =
             SubscribeProperties::new(1, vec![("propiedad".to_string(), "valor".to_string())]);
 
-        let payload = Subscription::new("incident".to_string(), "kvtr33".to_string());
+        let payload = vec![Subscription::new(
+            "incident".to_string(),
+            "kvtr33".to_string(),
+        )];
 
         let unsub = ClientMessage::Unsubscribe {
             packet_id: 1,
@@ -508,7 +516,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -520,9 +528,10 @@ mod tests {
                         BrokerMessage::Unsuback {
                             packet_id_msb: _,
                             packet_id_lsb: _,
-                            reason_code,
+                            reason_codes,
                         } => {
-                            assert_eq!(reason_code, 0x00_u8);
+                            // El cliente no estaba subscripto al topic.
+                            assert_eq!(reason_codes, vec![0x11_u8]);
                         }
                         _ => {
                             panic!("Assertion failed: No se recibio un Unsuback");
@@ -532,7 +541,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -554,7 +563,7 @@ mod tests {
         let addr = listener.local_addr().unwrap();
         let disconnect = ClientMessage::Disconnect {
             reason_code: 1,
-            session_expiry_interval: 1,
+            session_expiry_interval: Some(1),
             reason_string: "pasaron_cosas".to_string(),
             client_id: "kvtr33".to_string(),
         };
@@ -595,7 +604,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -620,7 +629,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -679,7 +688,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -697,7 +706,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -762,7 +771,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -786,7 +795,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -849,7 +858,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -879,7 +888,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -897,7 +906,7 @@ mod tests {
 
         let disconnect = ClientMessage::Disconnect {
             reason_code: 1,
-            session_expiry_interval: 1,
+            session_expiry_interval: Some(1),
             reason_string: "desconecto_normal".to_string(),
             client_id: "monitoreo".to_string(),
         };
@@ -938,7 +947,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
             let stream_ref = Arc::clone(&stream);
@@ -963,7 +972,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
@@ -1013,7 +1022,7 @@ mod tests {
             };
 
             let tls_stream = StreamOwned::new(server_connection, stream);
-            let stream = Arc::new(tls_stream);
+            let stream = Arc::new(BrokerStream::Tls(Box::new(tls_stream)));
             let (tx, rx) = mpsc::channel();
 
             let (message_to_write_sender, message_to_write_receiver) = mpsc::channel();
@@ -1043,7 +1052,7 @@ mod tests {
                 }
             });
 
-            match ClientMessage::read_from(stream.get_ref()) {
+            match ClientMessage::read_from(stream.as_ref()) {
                 Ok(message) => {
                     let result =
                         broker.handle_message(message, &message_to_write_sender, stream_ref, tx)?;
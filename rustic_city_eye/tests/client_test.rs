@@ -123,7 +123,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,
@@ -213,7 +215,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,
@@ -309,7 +313,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,
@@ -334,7 +340,7 @@ mod tests {
         let suback = BrokerMessage::Suback {
             packet_id_msb: 3,
             packet_id_lsb: 1,
-            reason_code: 3,
+            reason_codes: vec![3],
         };
 
         let listener = TcpListener::bind("127.0.0.1:5004").unwrap();
@@ -405,7 +411,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,
@@ -516,7 +524,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,
@@ -541,7 +551,7 @@ mod tests {
         let unsuback = BrokerMessage::Unsuback {
             packet_id_msb: 1,
             packet_id_lsb: 1,
-            reason_code: 1,
+            reason_codes: vec![1],
         };
 
         let listener = TcpListener::bind("127.0.0.1:5001").unwrap();
@@ -612,7 +622,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,
@@ -698,7 +710,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,
@@ -712,6 +726,98 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_recibir_pubrel() -> Result<(), ProtocolError> {
+        let pubrel = BrokerMessage::Pubrel {
+            packet_id_msb: 0,
+            packet_id_lsb: 7,
+            reason_code: 0x00,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:5011").unwrap();
+        thread::spawn(move || {
+            let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+            let certs = certs(&mut BufReader::new(
+                &mut File::open("./src/mqtt/certs/cert.pem").unwrap(),
+            ))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+            let private_key = private_key(&mut BufReader::new(
+                &mut File::open("./src/mqtt/certs/private_key.pem").unwrap(),
+            ))
+            .unwrap()
+            .unwrap();
+
+            let server_config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, private_key)
+                .unwrap();
+
+            let server_config = Arc::new(server_config);
+
+            loop {
+                if let Ok((stream, _)) = listener.accept() {
+                    let server_connection = match ServerConnection::new(server_config.clone()) {
+                        Ok(c) => c,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            break;
+                        }
+                    };
+
+                    let tls_stream = StreamOwned::new(server_connection, stream);
+                    let stream = Arc::new(tls_stream);
+
+                    assert!(pubrel.write_to(stream.get_ref()).is_ok());
+                }
+            }
+        });
+
+        let stream = TcpStream::connect("127.0.0.1:5011").unwrap();
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let mut root_store = RootCertStore::empty();
+        let cert_file = &mut BufReader::new(File::open("./src/mqtt/certs/cert.pem").unwrap());
+        root_store.add_parsable_certificates(
+            rustls_pemfile::certs(cert_file).map(|result| result.unwrap()),
+        );
+
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+        config.key_log = Arc::new(KeyLogFile::new());
+
+        let server_name = "rustic_city_eye".try_into().unwrap();
+        let conn = ClientConnection::new(Arc::new(config), server_name).expect("me rompi");
+
+        let tls_stream = StreamOwned::new(conn, stream);
+        let tls_stream = Arc::new(tls_stream);
+
+        loop {
+            let (puback_notify_sender, _) = mpsc::channel();
+            let (sender_channel, _) = mpsc::channel();
+            if let Ok(message) = BrokerMessage::read_from(tls_stream.get_ref()) {
+                let client_id = "client_test".to_string();
+                let pending_messages = Vec::new();
+                let result = Client::handle_message(
+                    message,
+                    &tls_stream,
+                    pending_messages,
+                    &mut Vec::new(),
+                    puback_notify_sender,
+                    sender_channel,
+                    client_id,
+                )
+                .unwrap();
+                assert_eq!(result, ClientReturn::PubrelRecieved);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_recibir_auth() -> Result<(), ProtocolError> {
         let auth = BrokerMessage::Auth {
@@ -790,7 +896,9 @@ mod tests {
                 let pending_messages = Vec::new();
                 let result = Client::handle_message(
                     message,
+                    &tls_stream,
                     pending_messages,
+                    &mut Vec::new(),
                     puback_notify_sender,
                     sender_channel,
                     client_id,